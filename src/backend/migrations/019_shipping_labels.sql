@@ -0,0 +1,18 @@
+-- Shipping labels purchased through a carrier HTTP API
+ALTER TABLE shipments ADD COLUMN label_url TEXT;
+
+-- Carrier label API settings (single row, like email_settings)
+CREATE TABLE IF NOT EXISTS carrier_settings (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    provider TEXT NOT NULL DEFAULT 'generic',
+    api_url TEXT NOT NULL DEFAULT '',
+    api_key TEXT NOT NULL DEFAULT '',
+    label_format TEXT NOT NULL DEFAULT 'PDF',
+    active INTEGER DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+-- Seed default (inactive)
+INSERT OR IGNORE INTO carrier_settings (id, provider, api_url, api_key, label_format, active, created_at, updated_at)
+VALUES (1, 'generic', '', '', 'PDF', 0, strftime('%s', 'now'), strftime('%s', 'now'));
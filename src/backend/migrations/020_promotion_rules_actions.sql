@@ -0,0 +1,36 @@
+-- Promotion rules and actions
+-- Referenced by apply_coupon / admin_add_promotion_rule / admin_add_promotion_action
+-- but never created by an earlier migration.
+
+-- ============================================
+-- PROMOTION RULES
+-- ============================================
+-- rule_type: ItemTotal, FirstOrder, Product, Taxon, User, UserRole,
+--            Quantity, NthOrder, ShipToCountry
+CREATE TABLE IF NOT EXISTS promotion_rules (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    promotion_id    INTEGER NOT NULL,
+    rule_type       TEXT NOT NULL,
+    preferences     TEXT NOT NULL DEFAULT '{}',  -- JSON
+    created_at      INTEGER NOT NULL,
+    updated_at      INTEGER NOT NULL,
+    FOREIGN KEY (promotion_id) REFERENCES promotions(id)
+);
+CREATE INDEX IF NOT EXISTS idx_promotion_rules_promotion ON promotion_rules(promotion_id);
+
+-- ============================================
+-- PROMOTION ACTIONS
+-- ============================================
+-- action_type: CreateAdjustment, CreateItemAdjustments, FreeShipping, BuyXGetY
+-- calculator_type: FlatRate, PercentOff, TieredPercent
+CREATE TABLE IF NOT EXISTS promotion_actions (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    promotion_id    INTEGER NOT NULL,
+    action_type     TEXT NOT NULL,
+    calculator_type TEXT NOT NULL DEFAULT '',
+    preferences     TEXT NOT NULL DEFAULT '{}',  -- JSON
+    created_at      INTEGER NOT NULL,
+    updated_at      INTEGER NOT NULL,
+    FOREIGN KEY (promotion_id) REFERENCES promotions(id)
+);
+CREATE INDEX IF NOT EXISTS idx_promotion_actions_promotion ON promotion_actions(promotion_id);
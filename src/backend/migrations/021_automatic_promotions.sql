@@ -0,0 +1,24 @@
+-- Automatic (code-less) promotions
+ALTER TABLE promotions ADD COLUMN apply_automatically INTEGER NOT NULL DEFAULT 0;
+
+-- Promotions attached to an order through a coupon code.
+-- Their adjustments are recomputed on every order recalculation.
+CREATE TABLE IF NOT EXISTS order_promotions (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id            INTEGER NOT NULL,
+    promotion_id        INTEGER NOT NULL,
+    promotion_code_id   INTEGER,
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    FOREIGN KEY (order_id) REFERENCES orders(id),
+    FOREIGN KEY (promotion_id) REFERENCES promotions(id),
+    FOREIGN KEY (promotion_code_id) REFERENCES promotion_codes(id),
+    UNIQUE(order_id, promotion_id)
+);
+CREATE INDEX IF NOT EXISTS idx_order_promotions_order ON order_promotions(order_id);
+CREATE INDEX IF NOT EXISTS idx_order_promotions_promotion ON order_promotions(promotion_id);
+
+-- Carry over coupons applied before this migration
+INSERT OR IGNORE INTO order_promotions (order_id, promotion_id, created_at, updated_at)
+SELECT DISTINCT order_id, source_id, created_at, created_at
+FROM adjustments WHERE source_type = 'Promotion' AND source_id IS NOT NULL;
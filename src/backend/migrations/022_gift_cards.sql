@@ -0,0 +1,111 @@
+-- Gift Cards
+-- Products flagged as gift cards issue a redeemable code when the order completes.
+
+ALTER TABLE products ADD COLUMN gift_card INTEGER NOT NULL DEFAULT 0;
+
+-- Optional recipient for gift card line items (defaults to the order email)
+ALTER TABLE line_items ADD COLUMN gift_card_recipient_email TEXT;
+ALTER TABLE line_items ADD COLUMN gift_card_recipient_name TEXT;
+ALTER TABLE line_items ADD COLUMN gift_card_message TEXT;
+
+-- ============================================
+-- GIFT CARDS
+-- ============================================
+CREATE TABLE IF NOT EXISTS gift_cards (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    code                TEXT UNIQUE,  -- NULL until activated
+    initial_amount      INTEGER NOT NULL,  -- cents
+    balance             INTEGER NOT NULL,  -- cents
+    currency            TEXT NOT NULL DEFAULT 'USD',
+    state               TEXT NOT NULL DEFAULT 'pending',  -- pending, active, depleted, disabled
+    order_id            INTEGER,  -- purchase order
+    line_item_id        INTEGER,
+    purchaser_email     TEXT,
+    recipient_email     TEXT,
+    recipient_name      TEXT,
+    message             TEXT,
+    expires_at          INTEGER,
+    sent_at             INTEGER,
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    FOREIGN KEY (order_id) REFERENCES orders(id),
+    FOREIGN KEY (line_item_id) REFERENCES line_items(id)
+);
+CREATE INDEX IF NOT EXISTS idx_gift_cards_order ON gift_cards(order_id);
+CREATE INDEX IF NOT EXISTS idx_gift_cards_line_item ON gift_cards(line_item_id);
+
+-- Ledger of every balance change
+CREATE TABLE IF NOT EXISTS gift_card_transactions (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    gift_card_id    INTEGER NOT NULL,
+    order_id        INTEGER,
+    payment_id      INTEGER,
+    action          TEXT NOT NULL,  -- issue, redeem, refund, adjust
+    amount          INTEGER NOT NULL,  -- cents, negative for redemptions
+    balance_after   INTEGER NOT NULL,
+    created_at      INTEGER NOT NULL,
+    FOREIGN KEY (gift_card_id) REFERENCES gift_cards(id),
+    FOREIGN KEY (order_id) REFERENCES orders(id),
+    FOREIGN KEY (payment_id) REFERENCES payments(id)
+);
+CREATE INDEX IF NOT EXISTS idx_gift_card_transactions_card ON gift_card_transactions(gift_card_id);
+CREATE INDEX IF NOT EXISTS idx_gift_card_transactions_order ON gift_card_transactions(order_id);
+
+-- Payment method used for gift card redemptions (applied through apply_gift_card, not listed to users)
+INSERT INTO payment_methods (type, name, description, active, display_on, position, available_to_users, available_to_admin, created_at, updated_at)
+SELECT 'GiftCard', 'Gift Card', 'Pay with a gift card balance', 1, 'back_end', 4, 0, 1, 0, 0
+WHERE NOT EXISTS (SELECT 1 FROM payment_methods WHERE type = 'GiftCard');
+
+-- Gift card email
+INSERT OR IGNORE INTO email_templates (event_type, name, subject, body_html, body_text, active, created_at, updated_at) VALUES
+('gift_card', 'Gift Card', 'You received a {{amount}} gift card from {{store_name}}',
+'<!DOCTYPE html>
+<html>
+<head>
+  <style>
+    body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, sans-serif; line-height: 1.6; color: #333; }
+    .container { max-width: 600px; margin: 0 auto; padding: 20px; }
+    .header { background: #000; color: #fff; padding: 30px; text-align: center; }
+    .content { padding: 30px; background: #f9f9f9; }
+    .card-box { background: #fff; border: 1px solid #eee; padding: 20px; margin: 20px 0; text-align: center; }
+    .code { font-size: 24px; font-weight: bold; letter-spacing: 3px; color: #000; }
+    .amount { font-size: 20px; margin-bottom: 10px; }
+    .footer { text-align: center; padding: 20px; color: #666; font-size: 12px; }
+  </style>
+</head>
+<body>
+  <div class="container">
+    <div class="header">
+      <h1>{{store_name}}</h1>
+    </div>
+    <div class="content">
+      <h2>You have a gift card!</h2>
+      <p>Hi {{recipient_name}},</p>
+      <p>{{sender_name}} sent you a gift card.</p>
+      <p>{{message}}</p>
+      <div class="card-box">
+        <div class="amount">{{amount}}</div>
+        <div class="code">{{gift_card_code}}</div>
+      </div>
+      <p>Enter this code at checkout to use your balance.</p>
+    </div>
+    <div class="footer">
+      <p>&copy; {{store_name}}</p>
+    </div>
+  </div>
+</body>
+</html>',
+'You have a gift card!
+
+Hi {{recipient_name}},
+
+{{sender_name}} sent you a {{amount}} gift card.
+
+{{message}}
+
+Gift card code: {{gift_card_code}}
+
+Enter this code at checkout to use your balance.
+
+- {{store_name}}',
+1, strftime('%s', 'now'), strftime('%s', 'now'));
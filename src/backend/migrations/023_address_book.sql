@@ -0,0 +1,23 @@
+-- Customer Address Book
+-- Saved addresses per user with default shipping and billing addresses.
+-- is_default marks the default shipping address.
+
+ALTER TABLE addresses ADD COLUMN is_default_billing INTEGER NOT NULL DEFAULT 0;
+
+-- Addresses used by placed orders are soft-deleted so order history keeps them
+ALTER TABLE addresses ADD COLUMN deleted_at INTEGER;
+
+-- Checkout used to insert a new row every time; hide the older duplicates
+UPDATE addresses SET deleted_at = updated_at
+WHERE user_id IS NOT NULL
+AND id NOT IN (
+    SELECT MAX(id) FROM addresses
+    WHERE user_id IS NOT NULL
+    GROUP BY user_id,
+        LOWER(TRIM(firstname)), LOWER(TRIM(lastname)),
+        LOWER(TRIM(address1)), LOWER(TRIM(COALESCE(address2, ''))),
+        LOWER(TRIM(city)), LOWER(TRIM(COALESCE(state_name, ''))),
+        UPPER(TRIM(zipcode)), UPPER(TRIM(country_code)), TRIM(COALESCE(phone, ''))
+);
+
+CREATE INDEX IF NOT EXISTS idx_addresses_user_active ON addresses(user_id, deleted_at);
@@ -0,0 +1,8 @@
+-- Guest Order Lookup
+-- Failed lookups are counted per order number to slow down guessing.
+
+CREATE TABLE IF NOT EXISTS order_lookup_failures (
+    scope           TEXT PRIMARY KEY,  -- 'order:<number>'
+    failures        INTEGER NOT NULL DEFAULT 0,
+    window_start    INTEGER NOT NULL
+);
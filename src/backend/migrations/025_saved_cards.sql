@@ -0,0 +1,14 @@
+-- Saved Cards
+-- Signed-in buyers get a Stripe Customer; cards paid with setup_future_usage are kept in payment_sources.
+
+ALTER TABLE users ADD COLUMN stripe_customer_id TEXT;
+CREATE INDEX IF NOT EXISTS idx_users_stripe_customer ON users(stripe_customer_id);
+
+ALTER TABLE payment_sources ADD COLUMN card_fingerprint TEXT;
+ALTER TABLE payment_sources ADD COLUMN deleted_at INTEGER;
+
+-- Whether the intent was created with setup_future_usage (so it isn't reused for a plain payment)
+ALTER TABLE payment_intents ADD COLUMN save_card INTEGER NOT NULL DEFAULT 0;
+
+-- Saved card an intent charges, copied onto the payment when it succeeds
+ALTER TABLE payment_intents ADD COLUMN payment_source_id INTEGER;
@@ -0,0 +1,8 @@
+-- Stripe Refunds
+-- Refunds of Stripe payments go through /v1/refunds; state mirrors Stripe's refund status.
+
+ALTER TABLE refunds ADD COLUMN state TEXT NOT NULL DEFAULT 'succeeded';  -- pending, requires_action, succeeded, failed, canceled
+ALTER TABLE refunds ADD COLUMN failure_reason TEXT;
+CREATE INDEX IF NOT EXISTS idx_refunds_transaction ON refunds(transaction_id);
+
+CREATE INDEX IF NOT EXISTS idx_payments_stripe_intent ON payments(stripe_payment_intent_id);
@@ -0,0 +1,8 @@
+-- Authorize-then-capture
+-- With auto_capture off, Stripe payments are only authorized at checkout (payments.state = 'pending')
+-- and captured when the order ships.
+
+ALTER TABLE payments ADD COLUMN authorization_expires_at INTEGER;
+
+-- Stripe always captured immediately until now; keep that unless the merchant turns auto_capture off
+UPDATE payment_methods SET auto_capture = 1 WHERE type = 'stripe';
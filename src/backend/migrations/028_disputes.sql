@@ -0,0 +1,77 @@
+-- Disputes
+-- Stripe chargebacks and inquiries, kept in sync from charge.dispute.* webhooks.
+
+CREATE TABLE IF NOT EXISTS disputes (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    stripe_dispute_id   TEXT UNIQUE NOT NULL,
+    stripe_charge_id    TEXT,
+    payment_id          INTEGER,           -- NULL when the charge can't be matched to a payment
+    order_id            INTEGER,
+    amount              INTEGER NOT NULL,
+    currency            TEXT NOT NULL DEFAULT 'USD',
+    reason              TEXT,              -- Stripe reason: fraudulent, product_not_received, ...
+    status              TEXT NOT NULL,     -- warning_needs_response, needs_response, under_review, won, lost, ...
+    evidence_due_by     INTEGER,
+    closed_at           INTEGER,
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    FOREIGN KEY (payment_id) REFERENCES payments(id),
+    FOREIGN KEY (order_id) REFERENCES orders(id)
+);
+CREATE INDEX IF NOT EXISTS idx_disputes_order ON disputes(order_id);
+CREATE INDEX IF NOT EXISTS idx_disputes_status ON disputes(status);
+
+-- Set once an order has been disputed; payment_state is 'disputed' while the dispute is open
+ALTER TABLE orders ADD COLUMN disputed INTEGER NOT NULL DEFAULT 0;
+
+-- Where store notifications (disputes) go; falls back to sender_email
+ALTER TABLE email_settings ADD COLUMN notification_email TEXT;
+
+INSERT OR IGNORE INTO email_templates (event_type, name, subject, body_html, body_text, active, created_at, updated_at) VALUES
+('dispute', 'Dispute (store notification)', 'Dispute {{status}} on order {{order_number}}',
+'<!DOCTYPE html>
+<html>
+<head>
+  <style>
+    body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, sans-serif; line-height: 1.6; color: #333; }
+    .container { max-width: 600px; margin: 0 auto; padding: 20px; }
+    .header { background: #000; color: #fff; padding: 30px; text-align: center; }
+    .content { padding: 30px; background: #f9f9f9; }
+    .details { background: #fff; border: 1px solid #eee; padding: 20px; margin: 20px 0; }
+    .footer { text-align: center; padding: 20px; color: #666; font-size: 12px; }
+  </style>
+</head>
+<body>
+  <div class="container">
+    <div class="header">
+      <h1>{{store_name}}</h1>
+    </div>
+    <div class="content">
+      <h2>Payment dispute: {{status}}</h2>
+      <div class="details">
+        <p><strong>Order:</strong> {{order_number}}</p>
+        <p><strong>Amount:</strong> {{amount}}</p>
+        <p><strong>Reason:</strong> {{reason}}</p>
+        <p><strong>Evidence due:</strong> {{evidence_due_by}}</p>
+        <p><strong>Stripe dispute:</strong> {{dispute_id}}</p>
+      </div>
+      <p>Respond to the dispute in the Stripe dashboard before the evidence deadline.</p>
+    </div>
+    <div class="footer">
+      <p>&copy; {{store_name}}</p>
+    </div>
+  </div>
+</body>
+</html>',
+'Payment dispute: {{status}}
+
+Order: {{order_number}}
+Amount: {{amount}}
+Reason: {{reason}}
+Evidence due: {{evidence_due_by}}
+Stripe dispute: {{dispute_id}}
+
+Respond to the dispute in the Stripe dashboard before the evidence deadline.
+
+- {{store_name}}',
+1, strftime('%s', 'now'), strftime('%s', 'now'));
@@ -0,0 +1,30 @@
+-- Stripe Webhook Event Log
+-- Every verified event is stored with its payload and processing result so failed ones can be replayed.
+
+ALTER TABLE stripe_webhook_events ADD COLUMN payload TEXT;
+ALTER TABLE stripe_webhook_events ADD COLUMN stripe_created_at INTEGER;  -- Stripe's event.created (seconds)
+ALTER TABLE stripe_webhook_events ADD COLUMN source TEXT NOT NULL DEFAULT 'webhook';  -- webhook, reconcile
+ALTER TABLE stripe_webhook_events ADD COLUMN result TEXT;
+ALTER TABLE stripe_webhook_events ADD COLUMN error TEXT;
+ALTER TABLE stripe_webhook_events ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE stripe_webhook_events ADD COLUMN processed_at INTEGER;
+ALTER TABLE stripe_webhook_events ADD COLUMN updated_at INTEGER;
+CREATE INDEX IF NOT EXISTS idx_stripe_events_processed ON stripe_webhook_events(processed, created_at);
+CREATE INDEX IF NOT EXISTS idx_stripe_events_stripe_created ON stripe_webhook_events(stripe_created_at);
+
+-- ============================================
+-- SCHEDULED JOBS (run from the canister global timer)
+-- ============================================
+CREATE TABLE IF NOT EXISTS scheduled_jobs (
+    name                    TEXT PRIMARY KEY,
+    interval_seconds        INTEGER NOT NULL,
+    enabled                 INTEGER NOT NULL DEFAULT 1,
+    next_run_at             INTEGER NOT NULL DEFAULT 0,
+    last_run_at             INTEGER,
+    last_result             TEXT,
+    last_error              TEXT,
+    updated_at              INTEGER NOT NULL DEFAULT 0
+);
+
+-- Poll Stripe for events the webhook missed
+INSERT OR IGNORE INTO scheduled_jobs (name, interval_seconds) VALUES ('stripe_reconcile', 3600);
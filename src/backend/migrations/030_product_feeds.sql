@@ -0,0 +1,29 @@
+-- Product Feeds
+-- Google Merchant Center (RSS 2.0) and Meta catalog (CSV) feeds built from the catalog.
+
+-- Per-variant property values (GTIN and MPN usually differ by size/colour);
+-- a variant without a value falls back to the product's property
+CREATE TABLE IF NOT EXISTS variant_properties (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    variant_id      INTEGER NOT NULL,
+    property_id     INTEGER NOT NULL,
+    value           TEXT,
+    created_at      INTEGER NOT NULL,
+    updated_at      INTEGER NOT NULL,
+    FOREIGN KEY (variant_id) REFERENCES variants(id),
+    FOREIGN KEY (property_id) REFERENCES properties(id),
+    UNIQUE(variant_id, property_id)
+);
+CREATE INDEX IF NOT EXISTS idx_variant_properties_variant ON variant_properties(variant_id);
+
+-- Properties the feeds read
+INSERT INTO properties (name, presentation, created_at, updated_at)
+SELECT 'gtin', 'GTIN', strftime('%s', 'now'), strftime('%s', 'now') WHERE NOT EXISTS (SELECT 1 FROM properties WHERE name = 'gtin');
+INSERT INTO properties (name, presentation, created_at, updated_at)
+SELECT 'mpn', 'MPN', strftime('%s', 'now'), strftime('%s', 'now') WHERE NOT EXISTS (SELECT 1 FROM properties WHERE name = 'mpn');
+INSERT INTO properties (name, presentation, created_at, updated_at)
+SELECT 'brand', 'Brand', strftime('%s', 'now'), strftime('%s', 'now') WHERE NOT EXISTS (SELECT 1 FROM properties WHERE name = 'brand');
+
+-- Public storefront URL, used for product links and to make relative image URLs absolute
+INSERT OR IGNORE INTO store_settings (key, value, created_at, updated_at) VALUES
+('store_url', '', strftime('%s', 'now'), strftime('%s', 'now'));
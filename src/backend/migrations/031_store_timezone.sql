@@ -0,0 +1,5 @@
+-- Store Timezone
+-- UTC offset (e.g. -05:00) that sales reports use for day/week/month boundaries.
+
+INSERT OR IGNORE INTO store_settings (key, value, created_at, updated_at) VALUES
+('timezone', 'UTC', strftime('%s', 'now'), strftime('%s', 'now'));
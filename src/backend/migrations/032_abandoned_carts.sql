@@ -0,0 +1,96 @@
+-- Abandoned Cart Recovery
+-- Carts with an email that stall before completion get a timed sequence of recovery emails
+-- with a restore link; the last email can carry a single-use promotion code.
+
+CREATE TABLE IF NOT EXISTS cart_recoveries (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id            INTEGER NOT NULL UNIQUE,
+    token               TEXT NOT NULL UNIQUE,  -- restore link token
+    email               TEXT NOT NULL,
+    emails_sent         INTEGER NOT NULL DEFAULT 0,
+    last_email_at       INTEGER,
+    promotion_code_id   INTEGER,  -- single-use code sent with the sequence
+    restored_at         INTEGER,  -- first time the restore link was used
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    FOREIGN KEY (order_id) REFERENCES orders(id),
+    FOREIGN KEY (promotion_code_id) REFERENCES promotion_codes(id)
+);
+
+CREATE TABLE IF NOT EXISTS cart_recovery_emails (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    cart_recovery_id    INTEGER NOT NULL,
+    step                INTEGER NOT NULL,  -- 1 = first email in the sequence
+    sent_at             INTEGER NOT NULL,
+    error               TEXT,
+    FOREIGN KEY (cart_recovery_id) REFERENCES cart_recoveries(id)
+);
+CREATE INDEX IF NOT EXISTS idx_cart_recovery_emails_recovery ON cart_recovery_emails(cart_recovery_id);
+CREATE INDEX IF NOT EXISTS idx_orders_state_updated ON orders(state, updated_at);
+
+-- Sequence settings. cart_recovery_promotion_id must point at an active promotion with
+-- per_code_usage_limit = 1; a fresh code is generated for each cart's last email.
+INSERT OR IGNORE INTO store_settings (key, value, created_at, updated_at) VALUES
+('cart_recovery_after_hours', '4', strftime('%s', 'now'), strftime('%s', 'now')),
+('cart_recovery_interval_hours', '24', strftime('%s', 'now'), strftime('%s', 'now')),
+('cart_recovery_max_emails', '2', strftime('%s', 'now'), strftime('%s', 'now')),
+('cart_recovery_promotion_id', '', strftime('%s', 'now'), strftime('%s', 'now'));
+
+-- Sends customer email, so it starts disabled (admin_update_scheduled_job to turn on)
+INSERT OR IGNORE INTO scheduled_jobs (name, interval_seconds, enabled) VALUES ('cart_recovery', 900, 0);
+
+-- Recovery email
+INSERT OR IGNORE INTO email_templates (event_type, name, subject, body_html, body_text, active, created_at, updated_at) VALUES
+('cart_abandoned', 'Abandoned Cart', 'You left something in your cart at {{store_name}}',
+'<!DOCTYPE html>
+<html>
+<head>
+  <style>
+    body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, sans-serif; line-height: 1.6; color: #333; }
+    .container { max-width: 600px; margin: 0 auto; padding: 20px; }
+    .header { background: #000; color: #fff; padding: 30px; text-align: center; }
+    .content { padding: 30px; background: #f9f9f9; }
+    .cart-box { background: #fff; border: 1px solid #eee; padding: 20px; margin: 20px 0; }
+    .total { font-size: 18px; font-weight: bold; text-align: right; padding-top: 10px; }
+    .btn { display: inline-block; background: #000; color: #fff; padding: 12px 30px; text-decoration: none; margin-top: 20px; }
+    .footer { text-align: center; padding: 20px; color: #666; font-size: 12px; }
+  </style>
+</head>
+<body>
+  <div class="container">
+    <div class="header">
+      <h1>{{store_name}}</h1>
+    </div>
+    <div class="content">
+      <h2>Still thinking it over?</h2>
+      <p>Hi {{customer_name}},</p>
+      <p>You left these items in your cart. We saved them for you.</p>
+      <div class="cart-box">
+        <p>{{cart_items}}</p>
+        <div class="total">Total: {{cart_total}}</div>
+      </div>
+      <p>{{promotion_message}}</p>
+      <a href="{{restore_url}}" class="btn">Return to your cart</a>
+    </div>
+    <div class="footer">
+      <p>&copy; {{store_name}}</p>
+    </div>
+  </div>
+</body>
+</html>',
+'Still thinking it over?
+
+Hi {{customer_name}},
+
+You left these items in your cart. We saved them for you.
+
+{{cart_items}}
+
+Total: {{cart_total}}
+
+{{promotion_message}}
+
+Return to your cart: {{restore_url}}
+
+- {{store_name}}',
+1, strftime('%s', 'now'), strftime('%s', 'now'));
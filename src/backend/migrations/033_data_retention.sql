@@ -0,0 +1,35 @@
+-- Data Retention
+-- Stale carts, orphaned addresses, expired payment intents and old log entries are removed
+-- by the data_retention job. A retention value of 0 days keeps that data forever.
+
+INSERT OR IGNORE INTO store_settings (key, value, created_at, updated_at) VALUES
+('retention_cart_days', '90', strftime('%s', 'now'), strftime('%s', 'now')),
+('retention_cart_mode', 'purge', strftime('%s', 'now'), strftime('%s', 'now')),  -- purge or archive
+('retention_address_days', '30', strftime('%s', 'now'), strftime('%s', 'now')),
+('retention_payment_intent_days', '30', strftime('%s', 'now'), strftime('%s', 'now')),
+('retention_log_days', '180', strftime('%s', 'now'), strftime('%s', 'now'));
+
+-- One compact row per removed cart when retention_cart_mode = 'archive'
+CREATE TABLE IF NOT EXISTS archived_carts (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    order_id            INTEGER NOT NULL,
+    number              TEXT NOT NULL,
+    email               TEXT,
+    user_id             INTEGER,
+    state               TEXT NOT NULL,
+    item_total          INTEGER NOT NULL,
+    total               INTEGER NOT NULL,
+    currency            TEXT,
+    line_items          TEXT NOT NULL,  -- JSON: [{variant_id, quantity, price}]
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    archived_at         INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_archived_carts_email ON archived_carts(email);
+
+CREATE INDEX IF NOT EXISTS idx_orders_bill_address ON orders(bill_address_id);
+CREATE INDEX IF NOT EXISTS idx_orders_ship_address ON orders(ship_address_id);
+CREATE INDEX IF NOT EXISTS idx_log_entries_created ON log_entries(created_at);
+
+-- Deletes data, so it starts disabled; check admin_get_retention_report before enabling
+INSERT OR IGNORE INTO scheduled_jobs (name, interval_seconds, enabled) VALUES ('data_retention', 3600, 0);
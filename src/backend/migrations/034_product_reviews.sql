@@ -0,0 +1,28 @@
+-- Product Reviews
+-- One review per signed-in user and product. Reviews start pending; only approved ones
+-- are shown and counted in the product's rating aggregate.
+
+CREATE TABLE IF NOT EXISTS product_reviews (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    product_id          INTEGER NOT NULL,
+    user_id             INTEGER NOT NULL,
+    rating              INTEGER NOT NULL CHECK (rating BETWEEN 1 AND 5),
+    title               TEXT,
+    body                TEXT,
+    name                TEXT NOT NULL,  -- author name shown with the review
+    verified_purchase   INTEGER NOT NULL DEFAULT 0,  -- user has a completed order with this product
+    state               TEXT NOT NULL DEFAULT 'pending',  -- pending, approved, rejected
+    moderation_note     TEXT,
+    moderated_at        INTEGER,
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    UNIQUE (product_id, user_id),
+    FOREIGN KEY (product_id) REFERENCES products(id),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+CREATE INDEX IF NOT EXISTS idx_product_reviews_product_state ON product_reviews(product_id, state);
+CREATE INDEX IF NOT EXISTS idx_product_reviews_state ON product_reviews(state, created_at);
+
+-- Approved review aggregate, kept in step by moderation so listings can sort on it
+ALTER TABLE products ADD COLUMN avg_rating REAL NOT NULL DEFAULT 0;
+ALTER TABLE products ADD COLUMN reviews_count INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,97 @@
+-- Wishlists
+-- Named lists of variants per signed-in user. A list made public can be viewed by anyone
+-- with its share token.
+
+CREATE TABLE IF NOT EXISTS wishlists (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id             INTEGER NOT NULL,
+    name                TEXT NOT NULL,
+    is_public           INTEGER NOT NULL DEFAULT 0,
+    share_token         TEXT UNIQUE,  -- generated the first time the list is made public
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+CREATE INDEX IF NOT EXISTS idx_wishlists_user ON wishlists(user_id);
+
+CREATE TABLE IF NOT EXISTS wishlist_items (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    wishlist_id         INTEGER NOT NULL,
+    variant_id          INTEGER NOT NULL,
+    quantity            INTEGER NOT NULL DEFAULT 1,
+    notify_in_stock     INTEGER NOT NULL DEFAULT 1,  -- email the owner when the variant is restocked
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    UNIQUE (wishlist_id, variant_id),
+    FOREIGN KEY (wishlist_id) REFERENCES wishlists(id),
+    FOREIGN KEY (variant_id) REFERENCES variants(id)
+);
+CREATE INDEX IF NOT EXISTS idx_wishlist_items_variant ON wishlist_items(variant_id);
+
+-- ============================================
+-- STOCK NOTIFICATIONS (queued by move_stock when a variant comes back in stock)
+-- ============================================
+CREATE TABLE IF NOT EXISTS stock_notifications (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    variant_id          INTEGER NOT NULL,
+    user_id             INTEGER,
+    email               TEXT NOT NULL,
+    source              TEXT NOT NULL,  -- wishlist
+    state               TEXT NOT NULL DEFAULT 'pending',  -- pending, sent, failed
+    error               TEXT,
+    created_at          INTEGER NOT NULL,
+    sent_at             INTEGER,
+    FOREIGN KEY (variant_id) REFERENCES variants(id),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+CREATE INDEX IF NOT EXISTS idx_stock_notifications_state ON stock_notifications(state, created_at);
+CREATE INDEX IF NOT EXISTS idx_stock_notifications_variant ON stock_notifications(variant_id, state);
+
+INSERT OR IGNORE INTO scheduled_jobs (name, interval_seconds) VALUES ('stock_notifications', 300);
+
+-- Back in stock email
+INSERT OR IGNORE INTO email_templates (event_type, name, subject, body_html, body_text, active, created_at, updated_at) VALUES
+('back_in_stock', 'Back in Stock', '{{product_name}} is back in stock at {{store_name}}',
+'<!DOCTYPE html>
+<html>
+<head>
+  <style>
+    body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, sans-serif; line-height: 1.6; color: #333; }
+    .container { max-width: 600px; margin: 0 auto; padding: 20px; }
+    .header { background: #000; color: #fff; padding: 30px; text-align: center; }
+    .content { padding: 30px; background: #f9f9f9; }
+    .product-box { background: #fff; border: 1px solid #eee; padding: 20px; margin: 20px 0; text-align: center; }
+    .product { font-size: 20px; font-weight: bold; color: #000; }
+    .btn { display: inline-block; background: #000; color: #fff; padding: 12px 30px; text-decoration: none; margin-top: 20px; }
+    .footer { text-align: center; padding: 20px; color: #666; font-size: 12px; }
+  </style>
+</head>
+<body>
+  <div class="container">
+    <div class="header">
+      <h1>{{store_name}}</h1>
+    </div>
+    <div class="content">
+      <h2>Good news - it is back!</h2>
+      <div class="product-box">
+        <div class="product">{{product_name}}</div>
+        <p>{{variant_name}}</p>
+        <p>{{price}}</p>
+      </div>
+      <p>Stock is limited, so get it while you can.</p>
+      <a href="{{product_url}}" class="btn">Shop now</a>
+    </div>
+    <div class="footer">
+      <p>&copy; {{store_name}}</p>
+    </div>
+  </div>
+</body>
+</html>',
+'Good news - it is back!
+
+{{product_name}} {{variant_name}} is back in stock for {{price}}.
+
+Stock is limited, so get it while you can: {{product_url}}
+
+- {{store_name}}',
+1, strftime('%s', 'now'), strftime('%s', 'now'));
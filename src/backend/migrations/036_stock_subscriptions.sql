@@ -0,0 +1,20 @@
+-- Back-in-stock subscriptions
+-- Shoppers (signed in or guests with an email) ask to be told when an out-of-stock variant
+-- returns. move_stock queues them into stock_notifications; the subscription is closed once
+-- its email has been sent.
+
+CREATE TABLE IF NOT EXISTS stock_subscriptions (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    variant_id          INTEGER NOT NULL,
+    user_id             INTEGER,  -- NULL for guests
+    email               TEXT NOT NULL,  -- stored lowercased
+    state               TEXT NOT NULL DEFAULT 'active',  -- active, notified, canceled
+    notified_at         INTEGER,
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    FOREIGN KEY (variant_id) REFERENCES variants(id),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_stock_subscriptions_active ON stock_subscriptions(variant_id, email) WHERE state = 'active';
+CREATE INDEX IF NOT EXISTS idx_stock_subscriptions_email ON stock_subscriptions(email, state);
+CREATE INDEX IF NOT EXISTS idx_stock_subscriptions_user ON stock_subscriptions(user_id);
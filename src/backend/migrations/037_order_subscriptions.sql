@@ -0,0 +1,110 @@
+-- Subscription (recurring) orders
+-- A customer turns a line item into a subscription: every interval the order_subscriptions
+-- job places a copy of it, charges the saved card and completes the order. Failed charges
+-- are retried on the same order (dunning) until the subscription goes past_due.
+
+CREATE TABLE IF NOT EXISTS order_subscriptions (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id             INTEGER NOT NULL,
+    variant_id          INTEGER NOT NULL,
+    quantity            INTEGER NOT NULL DEFAULT 1,
+    interval_days       INTEGER NOT NULL,
+    next_run_at         INTEGER NOT NULL,
+    payment_source_id   INTEGER NOT NULL,  -- saved Stripe card
+    ship_address_id     INTEGER,  -- NULL = the source order's addresses
+    shipping_method_id  INTEGER,  -- NULL = the source order's shipping method
+    source_order_id     INTEGER NOT NULL,  -- order the line item came from
+    pending_order_id    INTEGER,  -- order placed but not yet paid (retried while dunning)
+    last_order_id       INTEGER,  -- last order paid
+    state               TEXT NOT NULL DEFAULT 'active',  -- active, paused, past_due, canceled
+    failed_attempts     INTEGER NOT NULL DEFAULT 0,
+    last_error          TEXT,
+    canceled_at         INTEGER,
+    created_at          INTEGER NOT NULL,
+    updated_at          INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id),
+    FOREIGN KEY (variant_id) REFERENCES variants(id),
+    FOREIGN KEY (payment_source_id) REFERENCES payment_sources(id),
+    FOREIGN KEY (ship_address_id) REFERENCES addresses(id),
+    FOREIGN KEY (shipping_method_id) REFERENCES shipping_methods(id),
+    FOREIGN KEY (source_order_id) REFERENCES orders(id)
+);
+CREATE INDEX IF NOT EXISTS idx_order_subscriptions_due ON order_subscriptions(state, next_run_at);
+CREATE INDEX IF NOT EXISTS idx_order_subscriptions_user ON order_subscriptions(user_id);
+
+-- One row per charge attempt or skipped cycle
+CREATE TABLE IF NOT EXISTS order_subscription_runs (
+    id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+    subscription_id     INTEGER NOT NULL,
+    order_id            INTEGER,
+    state               TEXT NOT NULL,  -- paid, failed, skipped
+    amount              INTEGER NOT NULL DEFAULT 0,  -- cents
+    error               TEXT,
+    created_at          INTEGER NOT NULL,
+    FOREIGN KEY (subscription_id) REFERENCES order_subscriptions(id),
+    FOREIGN KEY (order_id) REFERENCES orders(id)
+);
+CREATE INDEX IF NOT EXISTS idx_order_subscription_runs_subscription ON order_subscription_runs(subscription_id);
+
+-- Dunning: hours between retries of a failed charge, and attempts before the subscription
+-- goes past_due. subscription_discount_percent is taken off every subscription order.
+INSERT OR IGNORE INTO store_settings (key, value, created_at, updated_at) VALUES
+('subscription_retry_hours', '24', strftime('%s', 'now'), strftime('%s', 'now')),
+('subscription_max_payment_attempts', '3', strftime('%s', 'now'), strftime('%s', 'now')),
+('subscription_discount_percent', '0', strftime('%s', 'now'), strftime('%s', 'now'));
+
+-- Only charges customers who opted in, so it runs by default
+INSERT OR IGNORE INTO scheduled_jobs (name, interval_seconds) VALUES ('order_subscriptions', 3600);
+
+-- Failed subscription charge
+INSERT OR IGNORE INTO email_templates (event_type, name, subject, body_html, body_text, active, created_at, updated_at) VALUES
+('subscription_payment_failed', 'Subscription Payment Failed', 'We could not charge your {{store_name}} subscription',
+'<!DOCTYPE html>
+<html>
+<head>
+  <style>
+    body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, sans-serif; line-height: 1.6; color: #333; }
+    .container { max-width: 600px; margin: 0 auto; padding: 20px; }
+    .header { background: #000; color: #fff; padding: 30px; text-align: center; }
+    .content { padding: 30px; background: #f9f9f9; }
+    .order-box { background: #fff; border: 1px solid #eee; padding: 20px; margin: 20px 0; }
+    .btn { display: inline-block; background: #000; color: #fff; padding: 12px 30px; text-decoration: none; margin-top: 20px; }
+    .footer { text-align: center; padding: 20px; color: #666; font-size: 12px; }
+  </style>
+</head>
+<body>
+  <div class="container">
+    <div class="header">
+      <h1>{{store_name}}</h1>
+    </div>
+    <div class="content">
+      <h2>Hi {{customer_name}},</h2>
+      <p>We tried to charge your saved card for your subscription, but the payment did not go through.</p>
+      <div class="order-box">
+        <p><strong>{{quantity}} x {{product_name}}</strong></p>
+        <p>Order {{order_number}} - {{order_total}}</p>
+        <p>{{error}}</p>
+      </div>
+      <p>{{retry_message}}</p>
+      <a href="{{manage_url}}" class="btn">Update payment method</a>
+    </div>
+    <div class="footer">
+      <p>&copy; {{store_name}}</p>
+    </div>
+  </div>
+</body>
+</html>',
+'Hi {{customer_name}},
+
+We tried to charge your saved card for your subscription, but the payment did not go through.
+
+{{quantity}} x {{product_name}}
+Order {{order_number}} - {{order_total}}
+{{error}}
+
+{{retry_message}}
+
+Update your payment method: {{manage_url}}
+
+- {{store_name}}',
+1, strftime('%s', 'now'), strftime('%s', 'now'));
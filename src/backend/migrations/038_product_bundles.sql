@@ -0,0 +1,22 @@
+-- Product bundles (kits)
+-- A bundle product's master variant is made of component variants. It has no stock of its
+-- own: availability comes from the components, and selling it takes the components out of
+-- stock. Pricing is either the master variant's own (fixed) price or the sum of the
+-- components less bundle_discount_percent.
+
+ALTER TABLE products ADD COLUMN bundle INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE products ADD COLUMN bundle_pricing TEXT NOT NULL DEFAULT 'fixed';  -- fixed, components
+ALTER TABLE products ADD COLUMN bundle_discount_percent INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS bundle_components (
+    id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+    bundle_variant_id       INTEGER NOT NULL,  -- master variant of the bundle product
+    component_variant_id    INTEGER NOT NULL,
+    quantity                INTEGER NOT NULL DEFAULT 1,  -- units of the component per bundle
+    created_at              INTEGER NOT NULL,
+    updated_at              INTEGER NOT NULL,
+    UNIQUE (bundle_variant_id, component_variant_id),
+    FOREIGN KEY (bundle_variant_id) REFERENCES variants(id),
+    FOREIGN KEY (component_variant_id) REFERENCES variants(id)
+);
+CREATE INDEX IF NOT EXISTS idx_bundle_components_component ON bundle_components(component_variant_id);
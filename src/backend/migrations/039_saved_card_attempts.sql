@@ -0,0 +1,5 @@
+-- Saved-card payment attempts per order
+-- The count is part of the Stripe idempotency key: a call retried after a lost response
+-- reuses the same key (and so the same charge), and only a settled attempt moves it on.
+
+ALTER TABLE orders ADD COLUMN saved_card_attempts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,68 @@
+-- Back-in-stock subscription confirmation and unsubscribe
+-- A subscription for an address that isn't the signed-in caller's own starts 'pending' and
+-- only becomes active once the link in the confirmation email is followed; an address gets
+-- at most one confirmation email a day. The token is also used for the unsubscribe link.
+
+ALTER TABLE stock_subscriptions ADD COLUMN token TEXT;
+ALTER TABLE stock_subscriptions ADD COLUMN confirmation_sent_at INTEGER;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_stock_subscriptions_token ON stock_subscriptions(token);
+
+-- Unsubscribe link in back-in-stock emails
+UPDATE email_templates SET
+    body_html = REPLACE(body_html, '<p>&copy; {{store_name}}</p>',
+        '<p>&copy; {{store_name}}</p>
+      <p><a href="{{unsubscribe_url}}">Stop these emails</a></p>'),
+    body_text = body_text || '
+
+Stop these emails: {{unsubscribe_url}}',
+    updated_at = strftime('%s', 'now')
+WHERE event_type = 'back_in_stock' AND body_text NOT LIKE '%{{unsubscribe_url}}%';
+
+-- Confirm a back-in-stock request made for this address
+INSERT OR IGNORE INTO email_templates (event_type, name, subject, body_html, body_text, active, created_at, updated_at) VALUES
+('stock_subscription_confirm', 'Confirm Back in Stock Alert', 'Confirm your back-in-stock alert from {{store_name}}',
+'<!DOCTYPE html>
+<html>
+<head>
+  <style>
+    body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, sans-serif; line-height: 1.6; color: #333; }
+    .container { max-width: 600px; margin: 0 auto; padding: 20px; }
+    .header { background: #000; color: #fff; padding: 30px; text-align: center; }
+    .content { padding: 30px; background: #f9f9f9; }
+    .product-box { background: #fff; border: 1px solid #eee; padding: 20px; margin: 20px 0; text-align: center; }
+    .product { font-size: 20px; font-weight: bold; color: #000; }
+    .btn { display: inline-block; background: #000; color: #fff; padding: 12px 30px; text-decoration: none; margin-top: 20px; }
+    .footer { text-align: center; padding: 20px; color: #666; font-size: 12px; }
+  </style>
+</head>
+<body>
+  <div class="container">
+    <div class="header">
+      <h1>{{store_name}}</h1>
+    </div>
+    <div class="content">
+      <h2>Confirm your alert</h2>
+      <p>Someone asked us to email this address when an item is back in stock:</p>
+      <div class="product-box">
+        <div class="product">{{product_name}}</div>
+        <p>{{variant_name}}</p>
+      </div>
+      <p>Confirm to get the email. If it was not you, ignore this message and you will not hear from us.</p>
+      <a href="{{confirm_url}}" class="btn">Confirm</a>
+    </div>
+    <div class="footer">
+      <p>&copy; {{store_name}}</p>
+    </div>
+  </div>
+</body>
+</html>',
+'Someone asked us to email this address when an item is back in stock:
+
+{{product_name}} {{variant_name}}
+
+Confirm to get the email: {{confirm_url}}
+
+If it was not you, ignore this message and you will not hear from us.
+
+- {{store_name}}',
+1, strftime('%s', 'now'), strftime('%s', 'now'));
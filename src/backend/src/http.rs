@@ -0,0 +1,187 @@
+// HTTP gateway: serves plain HTTP requests so Stripe and headless clients can reach the
+// canister without a Candid relay.
+//
+// Query responses are not certified, so read endpoints should be fetched through the raw
+// domain (<canister-id>.raw.icp0.io). The Stripe webhook is upgraded to an update call;
+// update responses go through consensus and are accepted on either domain.
+
+use crate::types::{HttpRequest, HttpResponse, ProductQueryParams};
+use crate::StripeWebhookInput;
+use serde::Serialize;
+
+#[ic_cdk::query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    route(req, false)
+}
+
+#[ic_cdk::update]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    route(req, true)
+}
+
+fn route(req: HttpRequest, is_update: bool) -> HttpResponse {
+    let (path, query) = match req.url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (req.url.as_str(), ""),
+    };
+    let path = path.trim_end_matches('/');
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    if req.method == "OPTIONS" {
+        return response(204, "text/plain", Vec::new());
+    }
+
+    match (req.method.as_str(), segments.as_slice()) {
+        ("POST", ["webhooks", "stripe"]) => {
+            // Processing writes to the database, which a query can't keep
+            if !is_update {
+                return HttpResponse {
+                    status_code: 200,
+                    headers: vec![],
+                    body: Vec::new(),
+                    upgrade: Some(true),
+                };
+            }
+            stripe_webhook(&req)
+        }
+        ("GET", ["health"]) => {
+            let database_ok = ic_rusqlite::with_connection(|conn| {
+                conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0)).is_ok()
+            });
+            json(
+                if database_ok { 200 } else { 503 },
+                &serde_json::json!({
+                    "status": if database_ok { "ok" } else { "error" },
+                    "database": database_ok,
+                    "time": crate::now(),
+                }),
+            )
+        }
+        ("GET", ["api", "products"]) => {
+            let params = query_params(query);
+            let param = |key: &str| params.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+            let number = |key: &str| param(key).and_then(|v| v.parse::<i64>().ok());
+
+            let product_params = ProductQueryParams {
+                q: param("q"),
+                taxon_id: number("taxon_id"),
+                sort: param("sort"),
+                page: number("page"),
+                per_page: number("per_page"),
+                in_stock: param("in_stock").map(|v| v == "true" || v == "1"),
+            };
+            result_json(crate::get_products(product_params))
+        }
+        ("GET", ["api", "products", slug]) => {
+            match crate::get_product(url_decode(slug)) {
+                Ok(product) => json(200, &product),
+                Err(e) => error(404, &e),
+            }
+        }
+        ("GET", ["api", "taxonomies"]) => result_json(crate::get_taxonomies()),
+        ("GET", ["feeds", "google.xml"]) => feed("google", "application/xml; charset=utf-8"),
+        ("GET", ["feeds", "meta.csv"]) => feed("meta", "text/csv; charset=utf-8"),
+        (_, ["webhooks", "stripe"]) | (_, ["health"]) | (_, ["api", "products"])
+        | (_, ["api", "products", _]) | (_, ["api", "taxonomies"])
+        | (_, ["feeds", "google.xml"]) | (_, ["feeds", "meta.csv"]) => error(405, "Method not allowed"),
+        _ => error(404, "Not found"),
+    }
+}
+
+// Stripe POSTs the raw event; the signature header covers the exact body bytes
+fn stripe_webhook(req: &HttpRequest) -> HttpResponse {
+    let signature = req.headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("stripe-signature"))
+        .map(|(_, value)| value.clone());
+    let signature = match signature {
+        Some(s) => s,
+        None => return error(400, "Missing Stripe-Signature header"),
+    };
+    let payload = match String::from_utf8(req.body.clone()) {
+        Ok(p) => p,
+        Err(_) => return error(400, "Body is not valid UTF-8"),
+    };
+
+    // Any non-2xx makes Stripe retry the delivery
+    match crate::handle_stripe_webhook(StripeWebhookInput { payload, stripe_signature: signature }) {
+        Ok(message) => json(200, &serde_json::json!({ "received": true, "result": message })),
+        Err(e) => error(400, &e),
+    }
+}
+
+// Product feed for Google Merchant Center / Meta catalogs to fetch on a schedule
+fn feed(format: &str, content_type: &str) -> HttpResponse {
+    match crate::get_product_feed(format.to_string()) {
+        Ok(body) => response(200, content_type, body.into_bytes()),
+        Err(e) => error(503, &e),
+    }
+}
+
+fn response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+            ("Access-Control-Allow-Methods".to_string(), "GET, POST, OPTIONS".to_string()),
+            ("Access-Control-Allow-Headers".to_string(), "Content-Type".to_string()),
+        ],
+        body,
+        upgrade: None,
+    }
+}
+
+fn json<T: Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    match serde_json::to_vec(value) {
+        Ok(body) => response(status_code, "application/json", body),
+        Err(e) => error(500, &e.to_string()),
+    }
+}
+
+fn result_json<T: Serialize>(result: Result<T, String>) -> HttpResponse {
+    match result {
+        Ok(value) => json(200, &value),
+        Err(e) => error(400, &e),
+    }
+}
+
+fn error(status_code: u16, message: &str) -> HttpResponse {
+    json(status_code, &serde_json::json!({ "error": message }))
+}
+
+// Decoded key/value pairs of a query string
+fn query_params(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (url_decode(key), url_decode(value))
+        })
+        .collect()
+}
+
+// Percent-decoding, with '+' as a space (form encoding)
+fn url_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -14,6 +14,8 @@ use ic_rusqlite::{close_connection, with_connection, Connection};
 
 mod types;
 mod api;
+mod promotions;
+mod http;
 
 use types::*;
 
@@ -42,14 +44,403 @@ fn generate_shipment_number() -> String {
     format!("H{:012X}", timestamp)
 }
 
+// Secure random bytes: one raw_rand call from the management canister,
+// stretched with SHA-256 in counter mode when more than 32 bytes are needed
+async fn random_bytes(len: usize) -> Result<Vec<u8>, String> {
+    use sha2::{Digest, Sha256};
+
+    let (seed,) = ic_cdk::api::management_canister::main::raw_rand().await
+        .map_err(|(code, msg)| format!("Randomness unavailable: {:?} - {}", code, msg))?;
+
+    let mut out = Vec::with_capacity(len);
+    let mut counter: u64 = 0;
+    while out.len() < len {
+        let mut hasher = Sha256::new();
+        hasher.update(&seed);
+        hasher.update(counter.to_be_bytes());
+        out.extend_from_slice(&hasher.finalize());
+        counter += 1;
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+// Quote a CSV field when it contains a delimiter, quote or newline
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') || value.contains('\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Format a nanosecond timestamp as "YYYY-MM-DD HH:MM UTC"
+fn format_utc_datetime(ns: i64) -> String {
+    let secs = ns.div_euclid(1_000_000_000);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let rem = secs.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60)
+}
+
+// Format a nanosecond timestamp as ISO 8601 UTC, e.g. "2024-01-31T09:05:00Z"
+fn format_iso_datetime(ns: i64) -> String {
+    let secs = ns.div_euclid(1_000_000_000);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let rem = secs.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+// Days since 1970-01-01 to (year, month, day) in the proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// (year, month, day) to days since 1970-01-01; inverse of civil_from_days
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
 #[init]
-fn canister_init() { run_migrations(); }
+fn canister_init() {
+    run_migrations();
+    arm_job_timer();
+}
 
 #[pre_upgrade]
 fn pre_upgrade() { close_connection(); }
 
 #[post_upgrade]
-fn post_upgrade() { run_migrations(); }
+fn post_upgrade() {
+    run_migrations();
+    arm_job_timer();
+}
+
+// ============================================
+// SCHEDULED JOBS
+// ============================================
+
+// Jobs run from the canister global timer. Schedules live in scheduled_jobs so they
+// survive upgrades; the timer itself is cleared on upgrade and re-armed above.
+
+#[export_name = "canister_global_timer"]
+extern "C" fn canister_global_timer() {
+    ic_cdk::futures::in_executor_context(|| {
+        ic_cdk::futures::spawn(run_due_jobs());
+    });
+}
+
+// Point the global timer at the next enabled job
+fn arm_job_timer() {
+    let next: Option<i64> = with_connection(|conn| {
+        conn.query_row(
+            "SELECT MIN(next_run_at) FROM scheduled_jobs WHERE enabled = 1",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(None)
+    });
+
+    // 0 cancels the timer; otherwise fire no sooner than a second from now
+    let at = next.map(|t| t.max(now() + 1_000_000_000)).unwrap_or(0);
+    ic_cdk::api::global_timer_set(at as u64);
+}
+
+async fn run_due_jobs() {
+    let started_at = now();
+
+    // Claim every due job's slot before running anything, so a job that traps
+    // after an await still moves on to its next interval
+    let due: Vec<String> = with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT name FROM scheduled_jobs WHERE enabled = 1 AND next_run_at <= ?1 ORDER BY next_run_at"
+        ).map_err(|e| e.to_string())?;
+        let due: Vec<String> = stmt.query_map((started_at,), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        conn.execute(
+            r#"UPDATE scheduled_jobs SET next_run_at = ?1 + interval_seconds * 1000000000, last_run_at = ?1, updated_at = ?1
+               WHERE enabled = 1 AND next_run_at <= ?1"#,
+            (started_at,)
+        ).map_err(|e| e.to_string())?;
+
+        Ok::<_, String>(due)
+    }).unwrap_or_default();
+    arm_job_timer();
+
+    for name in due {
+        let result = run_job(&name).await;
+        let (message, error) = match result {
+            Ok(message) => (Some(message), None),
+            Err(error) => (None, Some(error)),
+        };
+        with_connection(|conn| {
+            conn.execute(
+                "UPDATE scheduled_jobs SET last_result = ?1, last_error = ?2, updated_at = ?3 WHERE name = ?4",
+                (message, error, now(), &name)
+            ).ok();
+        });
+    }
+}
+
+async fn run_job(name: &str) -> Result<String, String> {
+    match name {
+        "stripe_reconcile" => reconcile_stripe_events().await
+            .map(|missed| format!("{} missed events applied", missed)),
+        "cart_recovery" => send_cart_recovery_emails().await,
+        "stock_notifications" => send_stock_notifications().await,
+        "order_subscriptions" => run_order_subscriptions().await,
+        "data_retention" => with_connection(|conn| apply_retention_policy(&conn, false))
+            .map(|results| results.iter()
+                .map(|r| format!("{} {}", r.removed, r.category))
+                .collect::<Vec<_>>()
+                .join(", ") + " removed"),
+        _ => Err(format!("Unknown job: {}", name)),
+    }
+}
+
+/// Background jobs and when they last ran
+#[ic_cdk::query]
+fn admin_get_scheduled_jobs() -> Result<Vec<ScheduledJob>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT name, interval_seconds, enabled, next_run_at, last_run_at, last_result, last_error FROM scheduled_jobs ORDER BY name"
+        ).map_err(|e| e.to_string())?;
+
+        let jobs = stmt.query_map([], |row| {
+            Ok(ScheduledJob {
+                name: row.get(0)?,
+                interval_seconds: row.get(1)?,
+                enabled: row.get(2)?,
+                next_run_at: row.get(3)?,
+                last_run_at: row.get(4)?,
+                last_result: row.get(5)?,
+                last_error: row.get(6)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        Ok(jobs)
+    })
+}
+
+/// Enable/disable a background job or change its interval (minimum one minute)
+#[ic_cdk::update]
+fn admin_update_scheduled_job(name: String, enabled: Option<bool>, interval_seconds: Option<i64>) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    if let Some(interval) = interval_seconds {
+        if interval < 60 {
+            return Err("Interval must be at least 60 seconds".to_string());
+        }
+    }
+
+    with_connection(|conn| {
+        let now = now();
+        let updated = conn.execute(
+            r#"UPDATE scheduled_jobs SET
+               enabled = COALESCE(?1, enabled),
+               interval_seconds = COALESCE(?2, interval_seconds),
+               next_run_at = MIN(next_run_at, ?3 + COALESCE(?2, interval_seconds) * 1000000000),
+               updated_at = ?3
+               WHERE name = ?4"#,
+            (enabled, interval_seconds, now, &name)
+        ).map_err(|e| e.to_string())?;
+
+        if updated == 0 {
+            return Err("Job not found".to_string());
+        }
+        Ok(())
+    })?;
+
+    arm_job_timer();
+    Ok(())
+}
+
+// ============================================
+// DATA RETENTION
+// ============================================
+
+// Policy lives in store_settings (retention_*). Each run removes at most one batch per
+// category, so a large backlog is worked off over several runs of the data_retention job.
+const RETENTION_BATCH: i64 = 500;
+
+// Carts that never completed and that no payment, gift card or dispute still depends on
+const RETENTION_CART_FILTER: &str = r#"o.completed_at IS NULL
+    AND o.state IN ('cart', 'address', 'delivery', 'payment', 'confirm', 'canceled')
+    AND o.updated_at < ?1
+    AND NOT EXISTS (SELECT 1 FROM payments p WHERE p.order_id = o.id AND p.state NOT IN ('checkout', 'failed', 'void', 'invalid'))
+    AND NOT EXISTS (SELECT 1 FROM payment_intents pi WHERE pi.order_id = o.id AND pi.status IN ('succeeded', 'requires_capture', 'processing'))
+    AND NOT EXISTS (SELECT 1 FROM gift_card_transactions gt WHERE gt.order_id = o.id)
+    AND NOT EXISTS (SELECT 1 FROM disputes d WHERE d.order_id = o.id)"#;
+
+// Guest checkout addresses and removed address book entries no order points at
+const RETENTION_ADDRESS_FILTER: &str = r#"a.updated_at < ?1
+    AND (a.user_id IS NULL OR a.deleted_at IS NOT NULL)
+    AND NOT EXISTS (SELECT 1 FROM orders o WHERE o.bill_address_id = a.id)
+    AND NOT EXISTS (SELECT 1 FROM orders o WHERE o.ship_address_id = a.id)"#;
+
+// Payment intents that never went through, on orders that never completed
+const RETENTION_INTENT_FILTER: &str = r#"pi.created_at < ?1
+    AND pi.status NOT IN ('succeeded', 'requires_capture', 'processing')
+    AND NOT EXISTS (SELECT 1 FROM orders o WHERE o.id = pi.order_id AND o.completed_at IS NOT NULL)"#;
+
+const RETENTION_LOG_FILTER: &str = "l.created_at < ?1";
+
+// Apply the retention policy, or with dry_run only report what it would remove
+fn apply_retention_policy(conn: &Connection, dry_run: bool) -> Result<Vec<RetentionResult>, String> {
+    let now = now();
+    let archive_carts = conn.query_row(
+        "SELECT value FROM store_settings WHERE key = 'retention_cart_mode'",
+        [],
+        |row| row.get::<_, String>(0)
+    ).map(|mode| mode.trim() == "archive").unwrap_or(false);
+
+    // Removal order matters: purged carts leave their addresses orphaned
+    let policies = [
+        ("payment_intents", "retention_payment_intent_days", 30, "payment_intents pi", "pi.id", "pi.created_at", RETENTION_INTENT_FILTER),
+        ("carts", "retention_cart_days", 90, "orders o", "o.id", "o.updated_at", RETENTION_CART_FILTER),
+        ("addresses", "retention_address_days", 30, "addresses a", "a.id", "a.updated_at", RETENTION_ADDRESS_FILTER),
+        ("log_entries", "retention_log_days", 180, "log_entries l", "l.id", "l.created_at", RETENTION_LOG_FILTER),
+    ];
+
+    let mut results = Vec::new();
+    for (category, setting, default_days, table, id_column, age_column, filter) in policies {
+        let retention_days = store_setting_i64(conn, setting, default_days).max(0);
+        let action = match (retention_days, category) {
+            (0, _) => "keep",
+            (_, "carts") if archive_carts => "archive",
+            _ => "purge",
+        };
+        if retention_days == 0 {
+            results.push(RetentionResult {
+                category: category.to_string(),
+                retention_days,
+                action: action.to_string(),
+                eligible: 0,
+                removed: 0,
+                oldest_at: None,
+            });
+            continue;
+        }
+
+        let cutoff = now - retention_days * 24 * HOUR_NS;
+        let (eligible, oldest_at): (i64, Option<i64>) = conn.query_row(
+            &format!("SELECT COUNT(*), MIN({}) FROM {} WHERE {}", age_column, table, filter),
+            (cutoff,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|e| e.to_string())?;
+
+        let mut removed = 0;
+        if !dry_run && eligible > 0 {
+            let mut stmt = conn.prepare(
+                &format!("SELECT {} FROM {} WHERE {} ORDER BY {} LIMIT ?2", id_column, table, filter, age_column)
+            ).map_err(|e| e.to_string())?;
+            let ids: Vec<i64> = stmt.query_map((cutoff, RETENTION_BATCH), |row| row.get(0))
+                .map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+            for id in &ids {
+                match category {
+                    "carts" => purge_cart(conn, *id, archive_carts, now)?,
+                    "payment_intents" => {
+                        conn.execute("DELETE FROM payment_intents WHERE id = ?1", (id,)).map_err(|e| e.to_string())?;
+                    }
+                    "addresses" => {
+                        conn.execute("DELETE FROM addresses WHERE id = ?1", (id,)).map_err(|e| e.to_string())?;
+                    }
+                    _ => {
+                        conn.execute("DELETE FROM log_entries WHERE id = ?1", (id,)).map_err(|e| e.to_string())?;
+                    }
+                }
+            }
+            removed = ids.len() as i64;
+        }
+
+        results.push(RetentionResult {
+            category: category.to_string(),
+            retention_days,
+            action: action.to_string(),
+            eligible,
+            removed,
+            oldest_at,
+        });
+    }
+
+    Ok(results)
+}
+
+// Delete a cart and everything hanging off it, optionally keeping a one-row summary
+fn purge_cart(conn: &Connection, order_id: i64, archive: bool, now: i64) -> Result<(), String> {
+    if archive {
+        let mut stmt = conn.prepare("SELECT variant_id, quantity, price FROM line_items WHERE order_id = ?1 ORDER BY id")
+            .map_err(|e| e.to_string())?;
+        let items: Vec<serde_json::Value> = stmt.query_map((order_id,), |row| {
+            Ok(serde_json::json!({
+                "variant_id": row.get::<_, i64>(0)?,
+                "quantity": row.get::<_, i64>(1)?,
+                "price": row.get::<_, i64>(2)?,
+            }))
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        conn.execute(
+            r#"INSERT INTO archived_carts (order_id, number, email, user_id, state, item_total, total, currency, line_items, created_at, updated_at, archived_at)
+               SELECT id, number, email, user_id, state, item_total, total, currency, ?2, created_at, updated_at, ?3
+               FROM orders WHERE id = ?1"#,
+            (order_id, serde_json::Value::Array(items).to_string(), now)
+        ).map_err(|e| e.to_string())?;
+    }
+
+    conn.execute("DELETE FROM shipping_rates WHERE shipment_id IN (SELECT id FROM shipments WHERE order_id = ?1)", (order_id,))
+        .map_err(|e| e.to_string())?;
+    conn.execute("DELETE FROM inventory_units WHERE line_item_id IN (SELECT id FROM line_items WHERE order_id = ?1)", (order_id,))
+        .map_err(|e| e.to_string())?;
+    conn.execute("DELETE FROM cart_recovery_emails WHERE cart_recovery_id IN (SELECT id FROM cart_recoveries WHERE order_id = ?1)", (order_id,))
+        .map_err(|e| e.to_string())?;
+    conn.execute("DELETE FROM gift_cards WHERE order_id = ?1 AND state = 'pending'", (order_id,))
+        .map_err(|e| e.to_string())?;
+    for table in ["cart_recoveries", "shipments", "adjustments", "order_promotions", "payments", "payment_intents", "line_items"] {
+        conn.execute(&format!("DELETE FROM {} WHERE order_id = ?1", table), (order_id,))
+            .map_err(|e| e.to_string())?;
+    }
+    conn.execute("DELETE FROM orders WHERE id = ?1", (order_id,))
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Dry run of the retention policy: what the data_retention job would remove right now
+#[ic_cdk::query]
+fn admin_get_retention_report() -> Result<Vec<RetentionResult>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| apply_retention_policy(&conn, true))
+}
+
+/// Remove one batch per category now, whether or not the job is enabled
+#[ic_cdk::update]
+fn admin_run_retention_cleanup() -> Result<Vec<RetentionResult>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| apply_retention_policy(&conn, false))
+}
 
 // ============================================
 // AUTH: First user becomes admin
@@ -229,7 +620,10 @@ fn get_products(params: ProductQueryParams) -> Result<ProductListResponse, Strin
 
         // In-stock filter
         if let Some(true) = params.in_stock {
-            conditions.push("EXISTS (SELECT 1 FROM stock_items si2 JOIN variants v2 ON si2.variant_id = v2.id WHERE v2.product_id = p.id AND si2.count_on_hand > 0 AND si2.deleted_at IS NULL AND v2.deleted_at IS NULL)".to_string());
+            conditions.push(format!(
+                "((p.bundle = 0 AND EXISTS (SELECT 1 FROM stock_items si2 JOIN variants v2 ON si2.variant_id = v2.id WHERE v2.product_id = p.id AND si2.count_on_hand > 0 AND si2.deleted_at IS NULL AND v2.deleted_at IS NULL)) OR (p.bundle = 1 AND {} > 0))",
+                bundle_stock_sql("(SELECT v3.id FROM variants v3 WHERE v3.product_id = p.id AND v3.is_master = 1 AND v3.deleted_at IS NULL)")
+            ));
         }
 
         // Search (escape SQL LIKE wildcards to prevent query slowdown attacks)
@@ -274,6 +668,7 @@ fn get_products(params: ProductQueryParams) -> Result<ProductListResponse, Strin
             Some("price_desc") => "pr.amount DESC",
             Some("name_asc") => "p.name ASC",
             Some("name_desc") => "p.name DESC",
+            Some("rating") => "p.avg_rating DESC, p.reviews_count DESC, p.created_at DESC",
             _ => "p.created_at DESC",
         };
 
@@ -284,8 +679,9 @@ fn get_products(params: ProductQueryParams) -> Result<ProductListResponse, Strin
                 p.available_on, p.discontinue_on, p.promotionable, p.created_at, p.updated_at,
                 v.id as variant_id, v.sku,
                 pr.amount as price,
-                COALESCE(si.count_on_hand, 0) as stock,
-                (SELECT attachment_url FROM assets WHERE viewable_type = 'Variant' AND viewable_id = v.id LIMIT 1) as image_url
+                COALESCE({}, si.count_on_hand, 0) as stock,
+                (SELECT attachment_url FROM assets WHERE viewable_type = 'Variant' AND viewable_id = v.id LIMIT 1) as image_url,
+                p.avg_rating, p.reviews_count
             FROM products p
             LEFT JOIN variants v ON v.product_id = p.id AND v.is_master = 1 AND v.deleted_at IS NULL
             LEFT JOIN prices pr ON pr.variant_id = v.id AND pr.deleted_at IS NULL
@@ -294,6 +690,7 @@ fn get_products(params: ProductQueryParams) -> Result<ProductListResponse, Strin
             ORDER BY {}
             LIMIT ?{} OFFSET ?{}
             "#,
+            bundle_stock_sql("v.id"),
             where_clause,
             sort,
             query_params.len() + 1,
@@ -330,6 +727,8 @@ fn get_products(params: ProductQueryParams) -> Result<ProductListResponse, Strin
                 stock,
                 image_url: row.get(15)?,
                 available: is_available,
+                rating_average: row.get(16)?,
+                rating_count: row.get(17)?,
             })
         }).map_err(|e| e.to_string())?
         .collect::<ic_rusqlite::Result<Vec<_>>>()
@@ -382,17 +781,18 @@ fn get_product(slug_or_id: String) -> Result<ProductDetail, String> {
         let product_id = product.0;
 
         // Get variants with prices
-        let mut variant_stmt = conn.prepare(
+        let mut variant_stmt = conn.prepare(&format!(
             r#"SELECT v.id, v.sku, v.is_master, v.position,
                pr.amount as price,
-               COALESCE(si.count_on_hand, 0) as stock,
+               COALESCE({}, si.count_on_hand, 0) as stock,
                si.backorderable
                FROM variants v
                LEFT JOIN prices pr ON pr.variant_id = v.id AND pr.deleted_at IS NULL
                LEFT JOIN stock_items si ON si.variant_id = v.id AND si.deleted_at IS NULL
                WHERE v.product_id = ?1 AND v.deleted_at IS NULL
-               ORDER BY v.is_master DESC, v.position ASC"#
-        ).map_err(|e| e.to_string())?;
+               ORDER BY v.is_master DESC, v.position ASC"#,
+            bundle_stock_sql("v.id")
+        )).map_err(|e| e.to_string())?;
 
         let mut variants: Vec<VariantDetail> = variant_stmt.query_map((product_id,), |row| {
             // For non-admins, hide exact stock counts - only show 1 (in stock) or 0 (out of stock)
@@ -503,6 +903,21 @@ fn get_product(slug_or_id: String) -> Result<ProductDetail, String> {
             .map(|v| v.price)
             .unwrap_or(0);
 
+        let gift_card: bool = conn.query_row(
+            "SELECT gift_card = 1 FROM products WHERE id = ?1",
+            (product_id,),
+            |row| row.get(0)
+        ).unwrap_or(false);
+
+        // Approved reviews only
+        let (rating_average, rating_count): (f64, i64) = conn.query_row(
+            "SELECT avg_rating, reviews_count FROM products WHERE id = ?1",
+            (product_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap_or((0.0, 0));
+
+        let bundle = load_product_bundle(&conn, product_id, is_admin_user)?;
+
         Ok(ProductDetail {
             id: product.0,
             name: product.1,
@@ -513,11 +928,15 @@ fn get_product(slug_or_id: String) -> Result<ProductDetail, String> {
             available_on: product.6,
             discontinue_on: product.7,
             promotionable: product.8 == 1,
+            gift_card,
             price: master_price,
+            bundle,
             variants,
             images,
             taxons,
             properties,
+            rating_average,
+            rating_count,
             created_at: product.9,
             updated_at: product.10,
         })
@@ -561,8 +980,8 @@ fn create_product(input: CreateProductInput) -> Result<i64, String> {
         // Create product
         let product_id: i64 = conn.query_row(
             r#"INSERT INTO products (name, slug, description, meta_title, meta_description,
-               available_on, promotionable, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8) RETURNING id"#,
+               available_on, promotionable, gift_card, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9) RETURNING id"#,
             (
                 &input.name,
                 &input.slug,
@@ -571,6 +990,7 @@ fn create_product(input: CreateProductInput) -> Result<i64, String> {
                 &input.meta_description,
                 input.available_on,
                 if input.promotionable.unwrap_or(true) { 1 } else { 0 },
+                if input.gift_card.unwrap_or(false) { 1 } else { 0 },
                 now
             ),
             |row| row.get(0)
@@ -653,6 +1073,10 @@ fn update_product(id: i64, input: UpdateProductInput) -> Result<(), String> {
             params.push(Box::new(discontinue_on));
             updates.push(format!("discontinue_on = ?{}", params.len()));
         }
+        if let Some(gift_card) = input.gift_card {
+            params.push(Box::new(if gift_card { 1 } else { 0 }));
+            updates.push(format!("gift_card = ?{}", params.len()));
+        }
 
         params.push(Box::new(id));
         let sql = format!(
@@ -681,6 +1105,10 @@ fn update_product(id: i64, input: UpdateProductInput) -> Result<(), String> {
                 "UPDATE prices SET amount = ?1, updated_at = ?2 WHERE variant_id = ?3",
                 (price, now, variant_id)
             ).map_err(|e| e.to_string())?;
+
+            refresh_bundles_containing(&conn, variant_id)?;
+            // Component-priced bundles keep their computed price
+            refresh_bundle_price(&conn, id)?;
         }
 
         // Update stock if provided
@@ -807,1308 +1235,1226 @@ fn reorder_product_images(product_id: i64, image_ids: Vec<i64>) -> Result<(), St
 }
 
 // ============================================
-// CATEGORIES (TAXONS) API
+// PRODUCT BUNDLES
 // ============================================
 
-#[ic_cdk::query]
-fn get_taxonomies() -> Result<Vec<TaxonomyWithTaxons>, String> {
-    with_connection(|conn| {
-        let mut stmt = conn.prepare(
-            "SELECT id, name, position FROM taxonomies ORDER BY position ASC"
-        ).map_err(|e| e.to_string())?;
-
-        let taxonomies: Vec<(i64, String, i64)> = stmt.query_map([], |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
-
-        let mut result = vec![];
-        for (id, name, position) in taxonomies {
-            let mut taxon_stmt = conn.prepare(
-                r#"SELECT id, name, permalink, parent_id, position, depth,
-                   (SELECT COUNT(*) FROM products_taxons WHERE taxon_id = taxons.id) as product_count
-                   FROM taxons WHERE taxonomy_id = ?1
-                   ORDER BY lft ASC, position ASC"#
-            ).map_err(|e| e.to_string())?;
-
-            let taxons: Vec<TaxonDetail> = taxon_stmt.query_map((id,), |row| {
-                Ok(TaxonDetail {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    permalink: row.get(2)?,
-                    parent_id: row.get(3)?,
-                    position: row.get(4)?,
-                    depth: row.get(5)?,
-                    product_count: row.get(6)?,
-                })
-            }).map_err(|e| e.to_string())?
-            .collect::<ic_rusqlite::Result<Vec<_>>>()
-            .map_err(|e| e.to_string())?;
+const MAX_BUNDLE_COMPONENTS: usize = 20;
+
+// Components of a bundle product; None when the product is not a bundle.
+// Non-admins only see whether stock is available, as elsewhere.
+fn load_product_bundle(conn: &Connection, product_id: i64, is_admin_user: bool) -> Result<Option<ProductBundle>, String> {
+    let (is_bundle, pricing, discount_percent, master_id): (bool, String, i64, Option<i64>) = conn.query_row(
+        r#"SELECT p.bundle = 1, p.bundle_pricing, p.bundle_discount_percent,
+           (SELECT id FROM variants WHERE product_id = p.id AND is_master = 1 AND deleted_at IS NULL)
+           FROM products p WHERE p.id = ?1"#,
+        (product_id,),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    ).map_err(|_| "Product not found".to_string())?;
+
+    let master_id = match (is_bundle, master_id) {
+        (true, Some(id)) => id,
+        _ => return Ok(None),
+    };
+    let visible = |stock: i64| if is_admin_user { stock } else if stock > 0 { 1 } else { 0 };
 
-            result.push(TaxonomyWithTaxons { id, name, position, taxons });
-        }
+    let mut stmt = conn.prepare(&format!(
+        r#"SELECT bc.component_variant_id, p.id, p.name, v.sku, bc.quantity, COALESCE(pr.amount, 0), {}
+           FROM bundle_components bc
+           JOIN variants v ON v.id = bc.component_variant_id
+           JOIN products p ON p.id = v.product_id
+           LEFT JOIN prices pr ON pr.variant_id = v.id AND pr.deleted_at IS NULL
+           WHERE bc.bundle_variant_id = ?1
+           ORDER BY bc.id"#,
+        available_stock_sql("v.id")
+    )).map_err(|e| e.to_string())?;
+
+    let components = stmt.query_map((master_id,), |row| {
+        Ok(BundleComponent {
+            variant_id: row.get(0)?,
+            product_id: row.get(1)?,
+            product_name: row.get(2)?,
+            sku: row.get(3)?,
+            quantity: row.get(4)?,
+            price: row.get(5)?,
+            stock: visible(row.get(6)?),
+        })
+    }).map_err(|e| e.to_string())?
+    .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
 
-        Ok(result)
-    })
+    Ok(Some(ProductBundle {
+        pricing,
+        discount_percent,
+        components_total: components.iter().map(|c| c.price * c.quantity).sum(),
+        stock: visible(variant_stock(conn, master_id)),
+        components,
+    }))
 }
 
-#[ic_cdk::update]
-fn admin_create_taxonomy(input: CreateTaxonomyInput) -> Result<i64, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+// Keep a component-priced bundle at the sum of its components less its discount
+fn refresh_bundle_price(conn: &Connection, product_id: i64) -> Result<(), String> {
+    let bundle: Option<(i64, i64)> = conn.query_row(
+        r#"SELECT v.id, p.bundle_discount_percent FROM products p
+           JOIN variants v ON v.product_id = p.id AND v.is_master = 1
+           WHERE p.id = ?1 AND p.bundle = 1 AND p.bundle_pricing = 'components'"#,
+        (product_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).ok();
 
-    with_connection(|conn| {
-        let now = now();
-        let taxonomy_id: i64 = conn.query_row(
-            "INSERT INTO taxonomies (name, position, created_at, updated_at) VALUES (?1, COALESCE((SELECT MAX(position)+1 FROM taxonomies), 0), ?2, ?2) RETURNING id",
-            (&input.name, now),
+    if let Some((variant_id, discount_percent)) = bundle {
+        let components_total: i64 = conn.query_row(
+            r#"SELECT COALESCE(SUM(pr.amount * bc.quantity), 0) FROM bundle_components bc
+               JOIN prices pr ON pr.variant_id = bc.component_variant_id AND pr.deleted_at IS NULL
+               WHERE bc.bundle_variant_id = ?1"#,
+            (variant_id,),
             |row| row.get(0)
         ).map_err(|e| e.to_string())?;
 
-        // Create root taxon
         conn.execute(
-            "INSERT INTO taxons (taxonomy_id, name, permalink, position, depth, created_at, updated_at) VALUES (?1, ?2, ?3, 0, 0, ?4, ?4)",
-            (taxonomy_id, &input.name, &input.name.to_lowercase(), now)
+            "UPDATE prices SET amount = ?1, updated_at = ?2 WHERE variant_id = ?3 AND deleted_at IS NULL",
+            (components_total * (100 - discount_percent) / 100, now(), variant_id)
         ).map_err(|e| e.to_string())?;
+    }
 
-        Ok(taxonomy_id)
-    })
+    Ok(())
 }
 
-#[ic_cdk::update]
-fn admin_update_taxonomy(id: i64, name: String) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+// Re-price the component-priced bundles a variant belongs to after its price changed
+fn refresh_bundles_containing(conn: &Connection, variant_id: i64) -> Result<(), String> {
+    let mut stmt = conn.prepare(
+        r#"SELECT DISTINCT bv.product_id FROM bundle_components bc
+           JOIN variants bv ON bv.id = bc.bundle_variant_id
+           WHERE bc.component_variant_id = ?1"#
+    ).map_err(|e| e.to_string())?;
+    let product_ids: Vec<i64> = stmt.query_map((variant_id,), |row| row.get(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
 
-    with_connection(|conn| {
-        let now = now();
-        conn.execute(
-            "UPDATE taxonomies SET name = ?1, updated_at = ?2 WHERE id = ?3",
-            (name, now, id)
-        ).map_err(|e| e.to_string())?;
-        Ok(())
-    })
+    for product_id in product_ids {
+        refresh_bundle_price(conn, product_id)?;
+    }
+    Ok(())
 }
 
+/// Make a product a bundle of component variants (replacing any previous components), or a
+/// plain product again with an empty list. Pricing is 'fixed' (the product's own price) or
+/// 'components' (sum of the components less discount_percent, kept up to date).
 #[ic_cdk::update]
-fn admin_delete_taxonomy(id: i64) -> Result<(), String> {
+fn admin_set_bundle(product_id: i64, input: SetBundleInput) -> Result<Option<ProductBundle>, String> {
     if !is_admin() { return Err("Admin only".to_string()); }
 
-    with_connection(|conn| {
-        conn.execute("DELETE FROM taxons WHERE taxonomy_id = ?1", (id,))
-            .map_err(|e| e.to_string())?;
-        conn.execute("DELETE FROM taxonomies WHERE id = ?1", (id,))
-            .map_err(|e| e.to_string())?;
-        Ok(())
-    })
-}
-
-#[ic_cdk::update]
-fn admin_create_taxon(input: CreateTaxonInput) -> Result<i64, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+    if input.components.len() > MAX_BUNDLE_COMPONENTS {
+        return Err(format!("A bundle can have at most {} components", MAX_BUNDLE_COMPONENTS));
+    }
+    let mut seen = std::collections::HashSet::new();
+    for component in &input.components {
+        if !(1..=100).contains(&component.quantity) {
+            return Err("Component quantity must be between 1 and 100".to_string());
+        }
+        if !seen.insert(component.variant_id) {
+            return Err("Each component variant can only be listed once".to_string());
+        }
+    }
+    if let Some(ref pricing) = input.pricing {
+        if pricing != "fixed" && pricing != "components" {
+            return Err("Pricing must be 'fixed' or 'components'".to_string());
+        }
+    }
+    if input.discount_percent.is_some_and(|p| !(0..=100).contains(&p)) {
+        return Err("Discount must be between 0 and 100 percent".to_string());
+    }
 
     with_connection(|conn| {
         let now = now();
-        
-        let depth: i64 = if let Some(parent_id) = input.parent_id {
-            conn.query_row("SELECT depth + 1 FROM taxons WHERE id = ?1", (parent_id,), |row| row.get(0))
-                .unwrap_or(0)
-        } else {
-            0
-        };
 
-        let permalink = if let Some(parent_id) = input.parent_id {
-            let parent_permalink: String = conn.query_row("SELECT permalink FROM taxons WHERE id = ?1", (parent_id,), |row| row.get(0))
-                .unwrap_or_default();
-            format!("{}/{}", parent_permalink, input.name.to_lowercase())
-        } else {
-            input.name.to_lowercase()
-        };
+        let (master_id, gift_card): (i64, bool) = conn.query_row(
+            r#"SELECT v.id, p.gift_card = 1 FROM products p
+               JOIN variants v ON v.product_id = p.id AND v.is_master = 1 AND v.deleted_at IS NULL
+               WHERE p.id = ?1 AND p.deleted_at IS NULL"#,
+            (product_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Product not found".to_string())?;
 
-        let taxon_id: i64 = conn.query_row(
-            r#"INSERT INTO taxons (taxonomy_id, parent_id, name, permalink, position, depth, description, created_at, updated_at) 
-               VALUES (?1, ?2, ?3, ?4, COALESCE((SELECT MAX(position)+1 FROM taxons WHERE parent_id IS ?2), 0), ?5, ?6, ?7, ?7) RETURNING id"#,
-            (input.taxonomy_id, input.parent_id, &input.name, permalink, depth, &input.description, now),
-            |row| row.get(0)
+        if !input.components.is_empty() {
+            if gift_card {
+                return Err("Gift cards cannot be bundles".to_string());
+            }
+            if conn.query_row(
+                "SELECT 1 FROM bundle_components WHERE component_variant_id = ?1",
+                (master_id,),
+                |_| Ok(())
+            ).is_ok() {
+                return Err("This product is a component of another bundle".to_string());
+            }
+        }
+
+        // Components are plain, live variants of other products
+        for component in &input.components {
+            let (component_product_id, is_bundle, is_gift_card): (i64, bool, bool) = conn.query_row(
+                r#"SELECT p.id, p.bundle = 1, p.gift_card = 1 FROM variants v
+                   JOIN products p ON p.id = v.product_id
+                   WHERE v.id = ?1 AND v.deleted_at IS NULL AND p.deleted_at IS NULL"#,
+                (component.variant_id,),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            ).map_err(|_| format!("Component variant {} not found", component.variant_id))?;
+
+            if component_product_id == product_id {
+                return Err("A bundle cannot contain its own variants".to_string());
+            }
+            if is_bundle {
+                return Err("Bundles cannot contain other bundles".to_string());
+            }
+            if is_gift_card {
+                return Err("Gift cards cannot be bundle components".to_string());
+            }
+        }
+
+        conn.execute("DELETE FROM bundle_components WHERE bundle_variant_id = ?1", (master_id,))
+            .map_err(|e| e.to_string())?;
+        for component in &input.components {
+            conn.execute(
+                r#"INSERT INTO bundle_components (bundle_variant_id, component_variant_id, quantity, created_at, updated_at)
+                   VALUES (?1, ?2, ?3, ?4, ?4)"#,
+                (master_id, component.variant_id, component.quantity, now)
+            ).map_err(|e| e.to_string())?;
+        }
+
+        conn.execute(
+            r#"UPDATE products SET bundle = ?1, bundle_pricing = COALESCE(?2, bundle_pricing),
+               bundle_discount_percent = COALESCE(?3, bundle_discount_percent), updated_at = ?4
+               WHERE id = ?5"#,
+            (!input.components.is_empty(), &input.pricing, input.discount_percent, now, product_id)
         ).map_err(|e| e.to_string())?;
 
-        Ok(taxon_id)
+        refresh_bundle_price(&conn, product_id)?;
+        load_product_bundle(&conn, product_id, true)
     })
 }
 
-#[ic_cdk::update]
-fn admin_update_taxon(id: i64, input: UpdateTaxonInput) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+// ============================================
+// PRODUCT REVIEWS
+// ============================================
 
-    with_connection(|conn| {
-        let now = now();
-        
-        if let Some(name) = input.name {
-            conn.execute("UPDATE taxons SET name = ?1, updated_at = ?2 WHERE id = ?3", (&name, now, id))
-                .map_err(|e| e.to_string())?;
-        }
+const REVIEW_SELECT: &str = r#"SELECT r.id, r.product_id, p.name, r.user_id, r.rating, r.title, r.body, r.name,
+    r.verified_purchase, r.state, r.moderation_note, r.created_at, r.updated_at
+    FROM product_reviews r
+    JOIN products p ON p.id = r.product_id"#;
+
+fn review_from_row(row: &ic_rusqlite::Row) -> ic_rusqlite::Result<ProductReview> {
+    Ok(ProductReview {
+        id: row.get(0)?,
+        product_id: row.get(1)?,
+        product_name: row.get(2)?,
+        user_id: row.get(3)?,
+        rating: row.get(4)?,
+        title: row.get(5)?,
+        body: row.get(6)?,
+        name: row.get(7)?,
+        verified_purchase: row.get(8)?,
+        state: row.get(9)?,
+        moderation_note: row.get(10)?,
+        created_at: row.get(11)?,
+        updated_at: row.get(12)?,
+    })
+}
 
-        if let Some(description) = input.description {
-            conn.execute("UPDATE taxons SET description = ?1, updated_at = ?2 WHERE id = ?3", (&description, now, id))
-                .map_err(|e| e.to_string())?;
-        }
+// Recompute the approved-review aggregate stored on the product
+fn refresh_product_rating(conn: &Connection, product_id: i64) -> Result<(), String> {
+    conn.execute(
+        r#"UPDATE products SET
+           avg_rating = COALESCE((SELECT AVG(rating) FROM product_reviews WHERE product_id = ?1 AND state = 'approved'), 0),
+           reviews_count = (SELECT COUNT(*) FROM product_reviews WHERE product_id = ?1 AND state = 'approved')
+           WHERE id = ?1"#,
+        (product_id,)
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
 
-        if let Some(parent_id) = input.parent_id {
-             let depth: i64 = conn.query_row("SELECT depth + 1 FROM taxons WHERE id = ?1", (parent_id,), |row| row.get(0))
-                .unwrap_or(0);
-            conn.execute("UPDATE taxons SET parent_id = ?1, depth = ?2, updated_at = ?3 WHERE id = ?4", (parent_id, depth, now, id))
-                .map_err(|e| e.to_string())?;
+// Reviews matching the filters, with the approved rating aggregate over the same product scope
+fn list_reviews(conn: &Connection, params: &ReviewQueryParams, state: Option<&str>) -> Result<ProductReviewListResponse, String> {
+    let mut conditions: Vec<String> = vec![];
+    let mut query_params: Vec<Box<dyn ic_rusqlite::ToSql>> = vec![];
+
+    if let Some(product_id) = params.product_id {
+        query_params.push(Box::new(product_id));
+        conditions.push(format!("r.product_id = ?{}", query_params.len()));
+    }
+    // The aggregate covers every approved review in the product scope, not just this page's filters
+    let scope_where = if conditions.is_empty() { String::new() } else { format!("AND {}", conditions.join(" AND ")) };
+    let scope_len = query_params.len();
+
+    if let Some(state) = state {
+        query_params.push(Box::new(state.to_string()));
+        conditions.push(format!("r.state = ?{}", query_params.len()));
+    }
+    if let Some(rating) = params.rating {
+        query_params.push(Box::new(rating));
+        conditions.push(format!("r.rating = ?{}", query_params.len()));
+    }
+    if let Some(true) = params.verified_only {
+        conditions.push("r.verified_purchase = 1".to_string());
+    }
+
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+
+    let param_refs: Vec<&dyn ic_rusqlite::ToSql> = query_params.iter().map(|p| p.as_ref()).collect();
+    let total_count: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM product_reviews r {}", where_clause),
+        param_refs.as_slice(),
+        |row| row.get(0)
+    ).map_err(|e| e.to_string())?;
+
+    let mut rating_breakdown = vec![0i64; 5];
+    let mut stmt = conn.prepare(&format!(
+        "SELECT r.rating, COUNT(*) FROM product_reviews r WHERE r.state = 'approved' {} GROUP BY r.rating",
+        scope_where
+    )).map_err(|e| e.to_string())?;
+    let counts: Vec<(i64, i64)> = stmt.query_map(&param_refs[..scope_len], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+    for (rating, count) in counts {
+        if (1..=5).contains(&rating) {
+            rating_breakdown[(rating - 1) as usize] = count;
         }
+    }
+    let rating_count: i64 = rating_breakdown.iter().sum();
+    let rating_average = if rating_count > 0 {
+        rating_breakdown.iter().enumerate().map(|(i, n)| (i as i64 + 1) * n).sum::<i64>() as f64 / rating_count as f64
+    } else {
+        0.0
+    };
 
-        Ok(())
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(20).clamp(1, 100);
+    let sort = match params.sort.as_deref() {
+        Some("rating_desc") => "r.rating DESC, r.created_at DESC",
+        Some("rating_asc") => "r.rating ASC, r.created_at DESC",
+        Some("oldest") => "r.created_at ASC",
+        _ => "r.created_at DESC",
+    };
+
+    let sql = format!(
+        "{} {} ORDER BY {} LIMIT ?{} OFFSET ?{}",
+        REVIEW_SELECT, where_clause, sort, query_params.len() + 1, query_params.len() + 2
+    );
+    query_params.push(Box::new(per_page));
+    query_params.push(Box::new((page - 1) * per_page));
+    let param_refs: Vec<&dyn ic_rusqlite::ToSql> = query_params.iter().map(|p| p.as_ref()).collect();
+
+    let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+    let reviews = stmt.query_map(param_refs.as_slice(), review_from_row)
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+    let total_pages = ((total_count as f64) / (per_page as f64)).ceil() as i64;
+
+    Ok(ProductReviewListResponse {
+        reviews,
+        total_count,
+        page,
+        per_page,
+        total_pages,
+        rating_average,
+        rating_count,
+        rating_breakdown,
     })
 }
 
-#[ic_cdk::update]
-fn admin_delete_taxon(id: i64) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+/// Approved reviews for a product. sort: newest (default), oldest, rating_desc, rating_asc.
+/// rating_breakdown holds the number of 1 to 5 star reviews.
+#[ic_cdk::query]
+fn get_product_reviews(params: ReviewQueryParams) -> Result<PublicReviewListResponse, String> {
+    if params.product_id.is_none() {
+        return Err("product_id is required".to_string());
+    }
 
-    with_connection(|conn| {
-        // Delete child taxons recursively 
-        conn.execute("DELETE FROM taxons WHERE id = ?1 OR parent_id = ?1", (id,))
-            .map_err(|e| e.to_string())?;
-        Ok(())
+    let list = with_connection(|conn| list_reviews(&conn, &params, Some("approved")))?;
+
+    // Public: leave out who wrote each review and the moderator's notes
+    let reviews = list.reviews.into_iter().map(|r| PublicProductReview {
+        id: r.id,
+        product_id: r.product_id,
+        product_name: r.product_name,
+        rating: r.rating,
+        title: r.title,
+        body: r.body,
+        name: r.name,
+        verified_purchase: r.verified_purchase,
+        created_at: r.created_at,
+        updated_at: r.updated_at,
+    }).collect();
+
+    Ok(PublicReviewListResponse {
+        reviews,
+        total_count: list.total_count,
+        page: list.page,
+        per_page: list.per_page,
+        total_pages: list.total_pages,
+        rating_average: list.rating_average,
+        rating_count: list.rating_count,
+        rating_breakdown: list.rating_breakdown,
     })
 }
 
-// ============================================
-// CART API
-// ============================================
-
+/// The caller's own review of a product, whatever its moderation state
 #[ic_cdk::query]
-fn get_cart(session_id: Option<String>) -> Result<Option<OrderDetail>, String> {
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
+fn get_my_review(product_id: i64) -> Result<Option<ProductReview>, String> {
+    let user_id = get_current_user_id().ok_or("Sign in to review products")?;
 
     with_connection(|conn| {
-        if is_anonymous {
-            if let Some(sess_id) = session_id {
-                // Find guest cart by token
-                 conn.query_row(
-                    "SELECT id FROM orders WHERE guest_token = ?1 AND state = 'cart'",
-                    (sess_id,),
-                    |row| row.get(0)
-                ).ok()
-                 .map(|id| get_order_detail(&conn, id))
-                 .transpose()
-            } else {
-                Ok(None)
-            }
-        } else {
-             // Find active cart for user
-            get_order_by_state(&conn, &caller_str, "cart")
-        }
+        Ok(conn.query_row(
+            &format!("{} WHERE r.product_id = ?1 AND r.user_id = ?2", REVIEW_SELECT),
+            (product_id, user_id),
+            review_from_row
+        ).ok())
     })
 }
 
+/// Create or update the caller's review of a product. Every submission goes back to
+/// pending until an admin approves it.
 #[ic_cdk::update]
-fn add_to_cart(variant_id: i64, quantity: i64, session_id: Option<String>) -> Result<OrderDetail, String> {
-    // Validate quantity limits to prevent abuse
-    if quantity <= 0 {
-        return Err("Quantity must be positive".to_string());
+fn submit_review(input: SubmitReviewInput) -> Result<i64, String> {
+    let user_id = get_current_user_id().ok_or("Sign in to review products")?;
+
+    if !(1..=5).contains(&input.rating) {
+        return Err("Rating must be between 1 and 5".to_string());
     }
-    const MAX_QUANTITY_PER_ITEM: i64 = 999;
-    if quantity > MAX_QUANTITY_PER_ITEM {
-        return Err(format!("Maximum quantity per item is {}", MAX_QUANTITY_PER_ITEM));
+    let clean = |text: Option<String>| text.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+    let title = clean(input.title);
+    let body = clean(input.body);
+    let name = clean(input.name);
+    if title.as_ref().map(|t| t.chars().count() > 120).unwrap_or(false) {
+        return Err("Title must be at most 120 characters".to_string());
     }
-
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
-
-    // Get user_id outside of connection to avoid nested borrow
-    let user_id = if !is_anonymous { get_current_user_id() } else { None };
-
-    if is_anonymous && session_id.is_none() {
-        return Err("Session ID required for guest checkout".to_string());
+    if body.as_ref().map(|b| b.chars().count() > 5000).unwrap_or(false) {
+        return Err("Review must be at most 5000 characters".to_string());
+    }
+    if name.as_ref().map(|n| n.chars().count() > 60).unwrap_or(false) {
+        return Err("Name must be at most 60 characters".to_string());
     }
 
     with_connection(|conn| {
         let now = now();
 
-        // Get or create cart
-        let order_id: i64 = if is_anonymous {
-             let sess_id = session_id.as_ref().ok_or("Session ID missing")?;
-             match conn.query_row(
-                "SELECT id FROM orders WHERE guest_token = ?1 AND state = 'cart'",
-                (&sess_id,),
-                |row| row.get(0)
-            ) {
-                Ok(id) => id,
-                Err(_) => {
-                    let number = generate_order_number();
-                    conn.query_row(
-                        r#"INSERT INTO orders (number, user_principal, guest_token, state, created_at, updated_at) 
-                           VALUES (?1, 'anonymous', ?2, 'cart', ?3, ?3) RETURNING id"#,
-                        (&number, &sess_id, now),
-                        |row| row.get(0)
-                    ).map_err(|e| e.to_string())?
-                }
-            }
-        } else {
-            match conn.query_row(
-                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state = 'cart'",
-                (user_id, &caller_str),
-                |row| row.get(0)
-            ) {
-                Ok(id) => id,
-                Err(_) => {
-                    let number = generate_order_number();
-                    conn.query_row(
-                        r#"INSERT INTO orders (number, user_id, user_principal, state, created_at, updated_at)
-                           VALUES (?1, ?2, ?3, 'cart', ?4, ?4) RETURNING id"#,
-                        (&number, user_id, &caller_str, now),
-                        |row| row.get(0)
-                    ).map_err(|e| e.to_string())?
-                }
-            }
-        };
-
-        // Get variant price
-        let (price, currency): (i64, String) = conn.query_row(
-            r#"SELECT pr.amount, pr.currency FROM prices pr
-               JOIN variants v ON v.id = pr.variant_id
-               WHERE pr.variant_id = ?1 AND pr.deleted_at IS NULL AND v.deleted_at IS NULL"#,
-            (variant_id,),
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).map_err(|_| "Variant not found or has no price".to_string())?;
+        conn.query_row(
+            "SELECT 1 FROM products WHERE id = ?1 AND deleted_at IS NULL",
+            (input.product_id,),
+            |_| Ok(())
+        ).map_err(|_| "Product not found".to_string())?;
 
-        // Check stock
-        let stock: i64 = conn.query_row(
-            "SELECT COALESCE(count_on_hand, 0) FROM stock_items WHERE variant_id = ?1 AND deleted_at IS NULL",
-            (variant_id,),
+        let verified_purchase: bool = conn.query_row(
+            r#"SELECT EXISTS (
+                 SELECT 1 FROM line_items li
+                 JOIN orders o ON o.id = li.order_id
+                 JOIN variants v ON v.id = li.variant_id
+                 WHERE o.user_id = ?1 AND v.product_id = ?2
+                 AND o.completed_at IS NOT NULL AND o.state != 'canceled'
+               )"#,
+            (user_id, input.product_id),
             |row| row.get(0)
-        ).unwrap_or(0);
-
-        if stock < quantity {
-            return Err(format!("Insufficient stock. Available: {}", stock));
-        }
+        ).unwrap_or(false);
 
-        // Check if line item exists
-        let existing_line_item: Option<(i64, i64)> = conn.query_row(
-            "SELECT id, quantity FROM line_items WHERE order_id = ?1 AND variant_id = ?2",
-            (order_id, variant_id),
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).ok();
+        // Default author: first name and last initial from the address book
+        let name = name.unwrap_or_else(|| {
+            conn.query_row(
+                r#"SELECT firstname || ' ' || SUBSTR(lastname, 1, 1) || '.' FROM addresses
+                   WHERE user_id = ?1 AND deleted_at IS NULL
+                   ORDER BY is_default DESC, id DESC LIMIT 1"#,
+                (user_id,),
+                |row| row.get(0)
+            ).unwrap_or_else(|_| "Customer".to_string())
+        });
 
-        if let Some((line_item_id, existing_qty)) = existing_line_item {
-            // Update quantity
-            let new_qty = existing_qty + quantity;
-            if stock < new_qty {
-                return Err(format!("Insufficient stock. Available: {}", stock));
-            }
-            conn.execute(
-                "UPDATE line_items SET quantity = ?1, updated_at = ?2 WHERE id = ?3",
-                (new_qty, now, line_item_id)
-            ).map_err(|e| e.to_string())?;
-        } else {
-            // Create new line item
-            conn.execute(
-                r#"INSERT INTO line_items (order_id, variant_id, quantity, price, currency, created_at, updated_at)
-                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)"#,
-                (order_id, variant_id, quantity, price, &currency, now)
-            ).map_err(|e| e.to_string())?;
-        }
+        let id: i64 = conn.query_row(
+            r#"INSERT INTO product_reviews (product_id, user_id, rating, title, body, name, verified_purchase, state, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 'pending', ?8, ?8)
+               ON CONFLICT(product_id, user_id) DO UPDATE SET
+               rating = ?3, title = ?4, body = ?5, name = ?6, verified_purchase = ?7,
+               state = 'pending', moderation_note = NULL, moderated_at = NULL, updated_at = ?8
+               RETURNING id"#,
+            (input.product_id, user_id, input.rating, &title, &body, &name, verified_purchase, now),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
 
-        // Recalculate order totals
-        recalculate_order(&conn, order_id)?;
+        // An edited review that was approved drops out of the aggregate until re-approved
+        refresh_product_rating(&conn, input.product_id)?;
 
-        // Return updated cart
-        get_order_detail(&conn, order_id)
+        Ok(id)
     })
 }
 
-#[ic_cdk::update]
-fn update_line_item(line_item_id: i64, quantity: i64, session_id: Option<String>) -> Result<OrderDetail, String> {
-    // Validate quantity limits to prevent abuse
-    if quantity < 0 {
-        return Err("Quantity cannot be negative".to_string());
-    }
-    const MAX_QUANTITY_PER_ITEM: i64 = 999;
-    if quantity > MAX_QUANTITY_PER_ITEM {
-        return Err(format!("Maximum quantity per item is {}", MAX_QUANTITY_PER_ITEM));
-    }
+/// Reviews for moderation. state: pending, approved, rejected (all when omitted)
+#[ic_cdk::query]
+fn admin_get_reviews(params: ReviewQueryParams) -> Result<ProductReviewListResponse, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
 
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
+    with_connection(|conn| list_reviews(&conn, &params, params.state.as_deref()))
+}
 
-    if is_anonymous && session_id.is_none() {
-        return Err("Session ID required for guest checkout".to_string());
+/// Approve, reject or reset a review to pending, with an optional note for the record
+#[ic_cdk::update]
+fn admin_moderate_review(id: i64, state: String, note: Option<String>) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    if !matches!(state.as_str(), "pending" | "approved" | "rejected") {
+        return Err("State must be pending, approved or rejected".to_string());
     }
 
     with_connection(|conn| {
         let now = now();
+        let product_id: i64 = conn.query_row(
+            r#"UPDATE product_reviews SET state = ?1, moderation_note = ?2, moderated_at = ?3, updated_at = ?3
+               WHERE id = ?4 RETURNING product_id"#,
+            (&state, &note, now, id),
+            |row| row.get(0)
+        ).map_err(|_| "Review not found".to_string())?;
 
-        // Verify ownership and get details
-        let (order_id, variant_id, order_guest_token, _order_user_principal): (i64, i64, Option<String>, String) = conn.query_row(
-            r#"SELECT li.order_id, li.variant_id, o.guest_token, o.user_principal
-               FROM line_items li
-               JOIN orders o ON o.id = li.order_id
-               WHERE li.id = ?1"#,
-            (line_item_id,),
-            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-        ).map_err(|_| "Line item not found".to_string())?;
-
-        // Check permissions
-        if is_anonymous {
-             let sess_id = session_id.as_ref().ok_or("Session ID required")?;
-             if order_guest_token.as_ref() != Some(sess_id) {
-                 return Err("Unauthorized".to_string());
-             }
-        } else {
-            // Check if user owns order via:
-            // 1. user_principal match
-            // 2. user_id match (via users table principal lookup)
-            // 3. guest_token match if session_id provided (for carts created before login)
-            let mut is_owner: bool = conn.query_row(
-                "SELECT COUNT(*) FROM orders WHERE id = ?1 AND (user_principal = ?2 OR user_id IN (SELECT id FROM users WHERE principal = ?2))",
-                (order_id, &caller_str),
-                |row| row.get::<_, i64>(0)
-            ).unwrap_or(0) > 0;
-
-            // Also allow if session_id matches the guest_token (cart created before login)
-            if !is_owner {
-                if let Some(sess_id) = session_id.as_ref() {
-                    is_owner = order_guest_token.as_ref() == Some(sess_id);
-                }
-            }
-
-            if !is_owner { return Err("Unauthorized".to_string()); }
-        }
-
-        if quantity <= 0 {
-            // Remove line item
-            conn.execute("DELETE FROM line_items WHERE id = ?1", (line_item_id,))
-                .map_err(|e| e.to_string())?;
-        } else {
-            // Check stock
-            let stock: i64 = conn.query_row(
-                "SELECT COALESCE(count_on_hand, 0) FROM stock_items WHERE variant_id = ?1",
-                (variant_id,),
-                |row| row.get(0)
-            ).unwrap_or(0);
+        refresh_product_rating(&conn, product_id)
+    })
+}
 
-            if stock < quantity {
-                return Err(format!("Insufficient stock. Available: {}", stock));
-            }
+#[ic_cdk::update]
+fn admin_delete_review(id: i64) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
 
-            conn.execute(
-                "UPDATE line_items SET quantity = ?1, updated_at = ?2 WHERE id = ?3",
-                (quantity, now, line_item_id)
-            ).map_err(|e| e.to_string())?;
-        }
+    with_connection(|conn| {
+        let product_id: i64 = conn.query_row(
+            "DELETE FROM product_reviews WHERE id = ?1 RETURNING product_id",
+            (id,),
+            |row| row.get(0)
+        ).map_err(|_| "Review not found".to_string())?;
 
-        recalculate_order(&conn, order_id)?;
-        get_order_detail(&conn, order_id)
+        refresh_product_rating(&conn, product_id)
     })
 }
 
-#[ic_cdk::update]
-fn remove_from_cart(line_item_id: i64, session_id: Option<String>) -> Result<OrderDetail, String> {
-    update_line_item(line_item_id, 0, session_id)
+// ============================================
+// PRODUCT FEEDS
+// ============================================
+
+// One purchasable variant in a Google Merchant / Meta catalog feed
+struct FeedItem {
+    id: String,                     // SKU, or "variant-{id}" without one
+    item_group_id: Option<String>,  // product id when the product has several variants
+    title: String,
+    description: String,
+    link: String,
+    image_links: Vec<String>,       // main image first
+    in_stock: bool,
+    backorder: bool,
+    price: i64,
+    currency: String,
+    brand: String,
+    gtin: Option<String>,
+    mpn: Option<String>,
+    product_type: Option<String>,   // category path, e.g. "Apparel > Shirts"
 }
 
-#[ic_cdk::update]
-fn apply_coupon(input: ApplyCouponInput, session_id: Option<String>) -> Result<OrderDetail, String> {
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
-    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+// Google allows one main image plus 10 additional ones
+const FEED_MAX_IMAGES: usize = 11;
 
-    if is_anonymous && session_id.is_none() {
-        return Err("Session ID required".to_string());
+// Make an asset URL absolute; feeds reject relative links
+fn absolute_url(store_url: &str, url: &str) -> String {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        url.to_string()
+    } else if let Some(rest) = url.strip_prefix("//") {
+        format!("https://{}", rest)
+    } else {
+        format!("{}/{}", store_url, url.trim_start_matches('/'))
     }
+}
 
-    with_connection(|conn| {
-        let now = now();
-
-        // Get active cart
-        let order_id: i64 = if is_anonymous {
-            let sess_id = session_id.as_ref().ok_or("Session ID required")?;
-            conn.query_row(
-                "SELECT id FROM orders WHERE guest_token = ?1 AND state = 'cart'",
-                (&sess_id,),
-                |row| row.get(0)
-            )
-        } else {
-            conn.query_row(
-                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state = 'cart'",
-                (user_id, &caller_str),
-                |row| row.get(0)
-            )
-        }.map_err(|_| "No active cart found".to_string())?;
-
-        // Find promotion by code
-        let promotion: (i64, String, Option<i64>, Option<i64>, Option<i64>, i64) = match conn.query_row(
-            r#"SELECT p.id, p.name, p.starts_at, p.expires_at, p.usage_limit,
-               (SELECT COUNT(*) FROM adjustments WHERE source_type = 'Promotion' AND source_id = p.id) as usage_count
-               FROM promotions p
-               JOIN promotion_codes pc ON pc.promotion_id = p.id
-               WHERE pc.value = ?1 AND p.active = 1"#,
-            (&input.code,),
-            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
-        ) {
-            Ok(p) => p,
-            Err(_) => return Err("Invalid or inactive promotion code".to_string()),
-        };
+// First `max` characters of a string
+fn truncate_chars(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
 
-        let (promo_id, promo_name, starts_at, expires_at, usage_limit, usage_count) = promotion;
+// "12.99 USD"
+fn feed_price(cents: i64, currency: &str) -> String {
+    format!("{}.{:02} {}", cents / 100, cents % 100, currency)
+}
 
-        // Validate dates
-        if let Some(start) = starts_at {
-            if now < start { return Err("Promotion has not started yet".to_string()); }
-        }
-        if let Some(end) = expires_at {
-            if now > end { return Err("Promotion has expired".to_string()); }
-        }
+// Variant property value, falling back to the product's value for the same property
+fn feed_property(conn: &Connection, product_id: i64, variant_id: i64, name: &str) -> Option<String> {
+    conn.query_row(
+        r#"SELECT COALESCE(
+               (SELECT NULLIF(TRIM(vp.value), '') FROM variant_properties vp JOIN properties pr ON pr.id = vp.property_id
+                WHERE vp.variant_id = ?1 AND LOWER(pr.name) = LOWER(?3)),
+               (SELECT NULLIF(TRIM(pp.value), '') FROM product_properties pp JOIN properties pr ON pr.id = pp.property_id
+                WHERE pp.product_id = ?2 AND LOWER(pr.name) = LOWER(?3) ORDER BY pp.position LIMIT 1))"#,
+        (variant_id, product_id, name),
+        |row| row.get(0)
+    ).unwrap_or(None)
+}
 
-        // Validate usage limit
-        if let Some(limit) = usage_limit {
-            if usage_count >= limit { return Err("Promotion usage limit reached".to_string()); }
-        }
+// Store name and public URL (without trailing slash) for feed links
+fn feed_store(conn: &Connection) -> Result<(String, String), String> {
+    let setting = |key: &str| -> String {
+        conn.query_row(
+            "SELECT value FROM store_settings WHERE key = ?1",
+            (key,),
+            |row| row.get::<_, Option<String>>(0)
+        ).ok().flatten().unwrap_or_default()
+    };
 
-        // Check if already applied
-        let already_applied: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM adjustments WHERE order_id = ?1 AND source_type = 'Promotion' AND source_id = ?2",
-            (order_id, promo_id),
-            |row| row.get(0)
-        ).unwrap_or(0);
+    let store_url = setting("store_url").trim().trim_end_matches('/').to_string();
+    if !store_url.starts_with("http://") && !store_url.starts_with("https://") {
+        return Err("Set the store_url store setting (e.g. https://shop.example.com) to generate feeds".to_string());
+    }
+    Ok((setting("store_name"), store_url))
+}
 
-        if already_applied > 0 {
-            return Err("Promotion already applied to this order".to_string());
-        }
+// Every variant a shopper can buy, with the same visibility rules as get_products
+fn feed_items(conn: &Connection, store_name: &str, store_url: &str) -> Result<Vec<FeedItem>, String> {
+    let current_time = now();
 
-        // Evaluate rules
-        let rules_met = evaluate_promotion_rules(&conn, promo_id, order_id)?;
-        if !rules_met {
-            return Err("Order does not meet the requirements for this promotion".to_string());
+    // Category paths: taxon id -> (name, parent)
+    let mut taxon_stmt = conn.prepare("SELECT id, name, parent_id FROM taxons").map_err(|e| e.to_string())?;
+    let taxons: std::collections::HashMap<i64, (String, Option<i64>)> = taxon_stmt
+        .query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+    drop(taxon_stmt);
+    let taxon_path = |taxon_id: i64| -> String {
+        let mut names = Vec::new();
+        let mut current = Some(taxon_id);
+        // Depth cap guards against a parent cycle
+        while let Some(id) = current {
+            if names.len() >= 10 { break; }
+            match taxons.get(&id) {
+                Some((name, parent)) => {
+                    names.push(name.clone());
+                    current = *parent;
+                }
+                None => break,
+            }
         }
+        names.reverse();
+        names.join(" > ")
+    };
 
-        // Apply actions
-        apply_promotion_actions(&conn, promo_id, order_id, &promo_name)?;
-
-        recalculate_order(&conn, order_id)?;
-        get_order_detail(&conn, order_id)
-    })
-}
-
-fn evaluate_promotion_rules(conn: &Connection, promo_id: i64, order_id: i64) -> Result<bool, String> {
-    let mut stmt = conn.prepare(
-        "SELECT rule_type, preferences FROM promotion_rules WHERE promotion_id = ?1"
+    let mut product_stmt = conn.prepare(
+        r#"SELECT id, name, slug, COALESCE(NULLIF(description, ''), NULLIF(meta_description, ''), name),
+           (SELECT taxon_id FROM products_taxons WHERE product_id = products.id ORDER BY position ASC LIMIT 1)
+           FROM products
+           WHERE deleted_at IS NULL
+           AND (available_on IS NULL OR available_on <= ?1)
+           AND (discontinue_on IS NULL OR discontinue_on > ?1)
+           ORDER BY id"#
     ).map_err(|e| e.to_string())?;
+    let products: Vec<(i64, String, String, String, Option<i64>)> = product_stmt
+        .query_map((current_time,), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+    drop(product_stmt);
+
+    let mut items = Vec::new();
+    for (product_id, name, slug, description, taxon_id) in products {
+        // Sellable variants; the master only stands in when there are no others
+        let mut variant_stmt = conn.prepare(&format!(
+            r#"SELECT v.id, v.sku, COALESCE(v.track_inventory, 1), pr.amount, pr.currency,
+               {},
+               EXISTS (SELECT 1 FROM stock_items WHERE variant_id = v.id AND deleted_at IS NULL AND backorderable = 1)
+               FROM variants v
+               JOIN prices pr ON pr.id = (SELECT id FROM prices WHERE variant_id = v.id AND deleted_at IS NULL ORDER BY id DESC LIMIT 1)
+               WHERE v.product_id = ?1 AND v.deleted_at IS NULL
+               AND (v.is_master = 0 OR NOT EXISTS (SELECT 1 FROM variants WHERE product_id = ?1 AND is_master = 0 AND deleted_at IS NULL))
+               ORDER BY v.position ASC, v.id ASC"#,
+            available_stock_sql("v.id")
+        )).map_err(|e| e.to_string())?;
+        let variants: Vec<(i64, String, bool, i64, String, i64, bool)> = variant_stmt
+            .query_map((product_id,), |row| Ok((
+                row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?,
+            )))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        drop(variant_stmt);
 
-    let rules = stmt.query_map((promo_id,), |row| {
-        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-    }).map_err(|e| e.to_string())?;
+        // Images of the whole product, used after a variant's own images
+        let mut image_stmt = conn.prepare(
+            r#"SELECT viewable_id, attachment_url FROM assets
+               WHERE viewable_type = 'Variant' AND viewable_id IN (SELECT id FROM variants WHERE product_id = ?1)
+               ORDER BY position ASC, id ASC"#
+        ).map_err(|e| e.to_string())?;
+        let images: Vec<(i64, String)> = image_stmt
+            .query_map((product_id,), |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        drop(image_stmt);
 
-    for rule in rules {
-        let (rule_type, prefs_json) = rule.map_err(|e| e.to_string())?;
-        let prefs: serde_json::Value = serde_json::from_str(&prefs_json).map_err(|e| e.to_string())?;
+        let grouped = variants.len() > 1;
+        for (variant_id, sku, track_inventory, price, currency, stock, backorderable) in variants {
+            let mut option_stmt = conn.prepare(
+                r#"SELECT ov.presentation FROM option_values ov
+                   JOIN option_values_variants ovv ON ovv.option_value_id = ov.id
+                   JOIN option_types ot ON ot.id = ov.option_type_id
+                   WHERE ovv.variant_id = ?1
+                   ORDER BY ot.position ASC"#
+            ).map_err(|e| e.to_string())?;
+            let options: Vec<String> = option_stmt.query_map((variant_id,), |row| row.get(0))
+                .map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+            drop(option_stmt);
 
-        match rule_type.as_str() {
-            "ItemTotal" => {
-                let threshold = prefs["amount"].as_i64().unwrap_or(0);
-                let item_total: i64 = conn.query_row(
-                    "SELECT item_total FROM orders WHERE id = ?1",
-                    (order_id,),
-                    |row| row.get(0)
-                ).unwrap_or(0);
-                if item_total < threshold { return Ok(false); }
-            },
-            "FirstOrder" => {
-                let user_id: Option<i64> = conn.query_row(
-                    "SELECT user_id FROM orders WHERE id = ?1",
-                    (order_id,),
-                    |row| row.get(0)
-                ).ok().flatten();
+            let title = if options.is_empty() { name.clone() } else { format!("{} - {}", name, options.join(" / ")) };
 
-                if let Some(uid) = user_id {
-                    let order_count: i64 = conn.query_row(
-                        "SELECT COUNT(*) FROM orders WHERE user_id = ?1 AND state = 'complete'",
-                        (uid,),
-                        |row| row.get(0)
-                    ).unwrap_or(0);
-                    if order_count > 0 { return Ok(false); }
-                } else {
-                    // Guests don't qualify for first order promotions? 
-                    // Solidus usually requires a user or tracks by email.
-                    return Ok(false);
-                }
-            },
-            unknown => {
-                // Reject unknown rule types to prevent unintended discounts
-                return Err(format!("Unknown promotion rule type: '{}'", unknown));
-            }
+            let mut image_links: Vec<String> = images.iter().filter(|(v, _)| *v == variant_id)
+                .chain(images.iter().filter(|(v, _)| *v != variant_id))
+                .map(|(_, url)| absolute_url(store_url, url))
+                .collect();
+            image_links.dedup();
+            image_links.truncate(FEED_MAX_IMAGES);
+
+            items.push(FeedItem {
+                id: if sku.trim().is_empty() { format!("variant-{}", variant_id) } else { sku.trim().to_string() },
+                item_group_id: if grouped { Some(product_id.to_string()) } else { None },
+                title: truncate_chars(&title, 150),
+                description: truncate_chars(&description, 5000),
+                link: format!("{}/products/{}", store_url, url_encode(&slug)),
+                image_links,
+                in_stock: !track_inventory || stock > 0,
+                backorder: backorderable,
+                price,
+                currency,
+                brand: feed_property(conn, product_id, variant_id, "brand").unwrap_or_else(|| store_name.to_string()),
+                gtin: feed_property(conn, product_id, variant_id, "gtin"),
+                mpn: feed_property(conn, product_id, variant_id, "mpn"),
+                product_type: taxon_id.map(taxon_path).filter(|p| !p.is_empty()),
+            });
         }
     }
 
-    Ok(true)
+    Ok(items)
 }
 
-fn apply_promotion_actions(conn: &Connection, promo_id: i64, order_id: i64, promo_name: &str) -> Result<(), String> {
-    let mut stmt = conn.prepare(
-        "SELECT id, action_type, calculator_type, preferences FROM promotion_actions WHERE promotion_id = ?1"
-    ).map_err(|e| e.to_string())?;
-
-    let actions = stmt.query_map((promo_id,), |row| {
-        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?))
-    }).map_err(|e| e.to_string())?;
-
-    let now = now();
+// Google Merchant Center feed (RSS 2.0 with the g: namespace)
+fn render_google_feed(items: &[FeedItem], store_name: &str, store_url: &str) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\" xmlns:g=\"http://base.google.com/ns/1.0\">\n<channel>\n");
+    xml.push_str(&format!("<title>{}</title>\n<link>{}</link>\n<description>{}</description>\n",
+        html_escape(store_name), html_escape(store_url), html_escape(&format!("{} products", store_name))));
+
+    for item in items {
+        let mut fields = vec![
+            ("g:id", item.id.clone()),
+            ("g:title", item.title.clone()),
+            ("g:description", item.description.clone()),
+            ("g:link", item.link.clone()),
+        ];
+        for (i, image) in item.image_links.iter().enumerate() {
+            fields.push((if i == 0 { "g:image_link" } else { "g:additional_image_link" }, image.clone()));
+        }
+        let availability = if item.in_stock { "in_stock" } else if item.backorder { "backorder" } else { "out_of_stock" };
+        fields.push(("g:availability", availability.to_string()));
+        fields.push(("g:price", feed_price(item.price, &item.currency)));
+        fields.push(("g:condition", "new".to_string()));
+        fields.push(("g:brand", item.brand.clone()));
+        if let Some(ref gtin) = item.gtin { fields.push(("g:gtin", gtin.clone())); }
+        if let Some(ref mpn) = item.mpn { fields.push(("g:mpn", mpn.clone())); }
+        if item.gtin.is_none() && item.mpn.is_none() {
+            fields.push(("g:identifier_exists", "no".to_string()));
+        }
+        if let Some(ref group) = item.item_group_id { fields.push(("g:item_group_id", group.clone())); }
+        if let Some(ref product_type) = item.product_type { fields.push(("g:product_type", product_type.clone())); }
 
-    for action in actions {
-        let (_action_id, action_type, calc_type, prefs_json) = action.map_err(|e| e.to_string())?;
-        let prefs: serde_json::Value = serde_json::from_str(&prefs_json).map_err(|e| e.to_string())?;
+        xml.push_str("<item>\n");
+        for (tag, value) in fields {
+            xml.push_str(&format!("<{}>{}</{}>\n", tag, html_escape(&value), tag));
+        }
+        xml.push_str("</item>\n");
+    }
 
-        if action_type == "CreateAdjustment" {
-            let amount: i64 = if calc_type == "FlatRate" {
-                -prefs["amount"].as_i64().unwrap_or(0)
-            } else if calc_type == "PercentOff" {
-                let percent = prefs["percent"].as_i64().unwrap_or(0);
-                let item_total: i64 = conn.query_row(
-                    "SELECT item_total FROM orders WHERE id = ?1",
-                    (order_id,),
-                    |row| row.get(0)
-                ).unwrap_or(0);
-                -(item_total * percent / 100)
-            } else {
-                // Reject unknown calculator types
-                return Err(format!("Unknown promotion calculator type: '{}'", calc_type));
-            };
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
 
-            if amount != 0 {
-                conn.execute(
-                    r#"INSERT INTO adjustments (source_type, source_id, adjustable_type, adjustable_id, order_id, amount, label, created_at, updated_at)
-                       VALUES ('Promotion', ?1, 'Order', ?2, ?2, ?3, ?4, ?5, ?5)"#,
-                    (promo_id, order_id, amount, promo_name, now)
-                ).map_err(|e| e.to_string())?;
-            }
-        } else {
-            // Reject unknown action types
-            return Err(format!("Unknown promotion action type: '{}'", action_type));
-        }
+// Meta (Facebook/Instagram) catalog feed
+fn render_meta_feed(items: &[FeedItem]) -> String {
+    let mut csv = String::from("id,title,description,availability,condition,price,link,image_link,additional_image_link,brand,gtin,mpn,item_group_id,product_type\n");
+
+    for item in items {
+        let availability = if item.in_stock { "in stock" } else if item.backorder { "available for order" } else { "out of stock" };
+        let row = [
+            item.id.clone(),
+            item.title.clone(),
+            item.description.clone(),
+            availability.to_string(),
+            "new".to_string(),
+            feed_price(item.price, &item.currency),
+            item.link.clone(),
+            item.image_links.first().cloned().unwrap_or_default(),
+            item.image_links.iter().skip(1).cloned().collect::<Vec<_>>().join(","),
+            item.brand.clone(),
+            item.gtin.clone().unwrap_or_default(),
+            item.mpn.clone().unwrap_or_default(),
+            item.item_group_id.clone().unwrap_or_default(),
+            item.product_type.clone().unwrap_or_default(),
+        ];
+        csv.push_str(&row.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+        csv.push('\n');
     }
 
-    Ok(())
+    csv
 }
 
+/// Product feed for ad platforms: format "google" (Merchant Center XML) or "meta" (catalog CSV)
 #[ic_cdk::query]
-fn admin_get_promotions() -> Result<Vec<Promotion>, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+fn get_product_feed(format: String) -> Result<String, String> {
     with_connection(|conn| {
-        let mut stmt = conn.prepare("SELECT id, name, description, usage_limit, active, starts_at, expires_at FROM promotions ORDER BY created_at DESC")
-            .map_err(|e| e.to_string())?;
-        
-        let promos = stmt.query_map([], |row| {
-            let id: i64 = row.get(0)?;
-            
-            // Get usage count
-            let usage_count: i64 = conn.query_row(
-                "SELECT COUNT(*) FROM adjustments WHERE source_type = 'Promotion' AND source_id = ?1",
-                (id,),
-                |row| row.get(0)
-            ).unwrap_or(0);
-
-            // Get code (simplified, just first code if multiple exist)
-            let code: Option<String> = conn.query_row(
-                "SELECT value FROM promotion_codes WHERE promotion_id = ?1 LIMIT 1",
-                (id,),
-                |row| row.get(0)
-            ).ok();
+        let (store_name, store_url) = feed_store(&conn)?;
+        let items = feed_items(&conn, &store_name, &store_url)?;
 
-            Ok(Promotion {
-                id,
-                name: row.get(1)?,
-                description: row.get(2)?,
-                code,
-                starts_at: row.get(5)?,
-                expires_at: row.get(6)?,
-                usage_limit: row.get(3)?,
-                usage_count,
-                active: row.get::<_, i64>(4)? == 1,
-                rules: vec![], // don't load all rules for list
-                actions: vec![], // don't load all actions for list
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
-        
-        Ok(promos)
+        match format.as_str() {
+            "google" => Ok(render_google_feed(&items, &store_name, &store_url)),
+            "meta" => Ok(render_meta_feed(&items)),
+            _ => Err("Unknown feed format (expected google or meta)".to_string()),
+        }
     })
 }
 
+/// Set (or clear, with None) a property on a variant, e.g. gtin, mpn or brand
 #[ic_cdk::update]
-fn admin_create_promotion(input: CreatePromotionInput) -> Result<i64, String> {
+fn admin_set_variant_property(variant_id: i64, name: String, value: Option<String>) -> Result<(), String> {
     if !is_admin() { return Err("Admin only".to_string()); }
+
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        return Err("Property name is required".to_string());
+    }
+    let value = value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+
     with_connection(|conn| {
         let now = now();
-        let id: i64 = conn.query_row(
-            r#"INSERT INTO promotions (name, description, active, usage_limit, starts_at, expires_at, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7) RETURNING id"#,
-            (&input.name, &input.description, if input.active.unwrap_or(true) { 1 } else { 0 }, &input.usage_limit, &input.starts_at, &input.expires_at, now),
-            |row| row.get(0)
-        ).map_err(|e| e.to_string())?;
 
-        if let Some(code) = input.code {
-            conn.execute(
-                "INSERT INTO promotion_codes (promotion_id, value, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
-                (id, &code, now)
-            ).map_err(|e| e.to_string())?;
-        }
+        conn.query_row(
+            "SELECT 1 FROM variants WHERE id = ?1 AND deleted_at IS NULL",
+            (variant_id,),
+            |_| Ok(())
+        ).map_err(|_| "Variant not found".to_string())?;
 
-        Ok(id)
+        let property_id: i64 = match conn.query_row(
+            "SELECT id FROM properties WHERE name = ?1",
+            (&name,),
+            |row| row.get(0)
+        ) {
+            Ok(id) => id,
+            Err(_) => {
+                conn.execute(
+                    "INSERT INTO properties (name, presentation, created_at, updated_at) VALUES (?1, ?1, ?2, ?2)",
+                    (&name, now)
+                ).map_err(|e| e.to_string())?;
+                conn.last_insert_rowid()
+            }
+        };
+
+        match value {
+            Some(value) => conn.execute(
+                r#"INSERT INTO variant_properties (variant_id, property_id, value, created_at, updated_at)
+                   VALUES (?1, ?2, ?3, ?4, ?4)
+                   ON CONFLICT(variant_id, property_id) DO UPDATE SET value = ?3, updated_at = ?4"#,
+                (variant_id, property_id, value, now)
+            ),
+            None => conn.execute(
+                "DELETE FROM variant_properties WHERE variant_id = ?1 AND property_id = ?2",
+                (variant_id, property_id)
+            ),
+        }.map_err(|e| e.to_string())?;
+
+        Ok(())
     })
 }
 
-#[ic_cdk::update]
-fn admin_update_promotion(id: i64, input: UpdatePromotionInput) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+// ============================================
+// CATEGORIES (TAXONS) API
+// ============================================
+
+#[ic_cdk::query]
+fn get_taxonomies() -> Result<Vec<TaxonomyWithTaxons>, String> {
     with_connection(|conn| {
-        let now = now();
-        if let Some(name) = input.name {
-            conn.execute("UPDATE promotions SET name = ?1, updated_at = ?2 WHERE id = ?3", (name, now, id)).ok();
-        }
-        if let Some(active) = input.active {
-            conn.execute("UPDATE promotions SET active = ?1, updated_at = ?2 WHERE id = ?3", (if active { 1 } else { 0 }, now, id)).ok();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, position FROM taxonomies ORDER BY position ASC"
+        ).map_err(|e| e.to_string())?;
+
+        let taxonomies: Vec<(i64, String, i64)> = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        let mut result = vec![];
+        for (id, name, position) in taxonomies {
+            let mut taxon_stmt = conn.prepare(
+                r#"SELECT id, name, permalink, parent_id, position, depth,
+                   (SELECT COUNT(*) FROM products_taxons WHERE taxon_id = taxons.id) as product_count
+                   FROM taxons WHERE taxonomy_id = ?1
+                   ORDER BY lft ASC, position ASC"#
+            ).map_err(|e| e.to_string())?;
+
+            let taxons: Vec<TaxonDetail> = taxon_stmt.query_map((id,), |row| {
+                Ok(TaxonDetail {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    permalink: row.get(2)?,
+                    parent_id: row.get(3)?,
+                    position: row.get(4)?,
+                    depth: row.get(5)?,
+                    product_count: row.get(6)?,
+                })
+            }).map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+
+            result.push(TaxonomyWithTaxons { id, name, position, taxons });
         }
-        Ok(())
+
+        Ok(result)
     })
 }
 
 #[ic_cdk::update]
-fn admin_add_promotion_rule(input: AddPromotionRuleInput) -> Result<i64, String> {
+fn admin_create_taxonomy(input: CreateTaxonomyInput) -> Result<i64, String> {
     if !is_admin() { return Err("Admin only".to_string()); }
+
     with_connection(|conn| {
         let now = now();
-        let id: i64 = conn.query_row(
-            "INSERT INTO promotion_rules (promotion_id, rule_type, preferences, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4) RETURNING id",
-            (input.promotion_id, &input.rule_type, &input.preferences, now),
+        let taxonomy_id: i64 = conn.query_row(
+            "INSERT INTO taxonomies (name, position, created_at, updated_at) VALUES (?1, COALESCE((SELECT MAX(position)+1 FROM taxonomies), 0), ?2, ?2) RETURNING id",
+            (&input.name, now),
             |row| row.get(0)
         ).map_err(|e| e.to_string())?;
-        Ok(id)
+
+        // Create root taxon
+        conn.execute(
+            "INSERT INTO taxons (taxonomy_id, name, permalink, position, depth, created_at, updated_at) VALUES (?1, ?2, ?3, 0, 0, ?4, ?4)",
+            (taxonomy_id, &input.name, &input.name.to_lowercase(), now)
+        ).map_err(|e| e.to_string())?;
+
+        Ok(taxonomy_id)
     })
 }
 
 #[ic_cdk::update]
-fn admin_add_promotion_action(input: AddPromotionActionInput) -> Result<i64, String> {
+fn admin_update_taxonomy(id: i64, name: String) -> Result<(), String> {
     if !is_admin() { return Err("Admin only".to_string()); }
+
     with_connection(|conn| {
         let now = now();
-        let id: i64 = conn.query_row(
-            "INSERT INTO promotion_actions (promotion_id, action_type, calculator_type, preferences, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?5) RETURNING id",
-            (input.promotion_id, &input.action_type, &input.calculator_type, &input.preferences, now),
-            |row| row.get(0)
+        conn.execute(
+            "UPDATE taxonomies SET name = ?1, updated_at = ?2 WHERE id = ?3",
+            (name, now, id)
         ).map_err(|e| e.to_string())?;
-        Ok(id)
+        Ok(())
     })
 }
 
-// ============================================
-// CHECKOUT API
-// ============================================
-
-// Helper to validate address field lengths
-fn validate_address(addr: &AddressInput, label: &str) -> Result<(), String> {
-    const MAX_NAME_LEN: usize = 100;
-    const MAX_ADDRESS_LEN: usize = 255;
-    const MAX_CITY_LEN: usize = 100;
-    const MAX_STATE_LEN: usize = 100;
-    const MAX_ZIP_LEN: usize = 20;
-    const MAX_PHONE_LEN: usize = 30;
+#[ic_cdk::update]
+fn admin_delete_taxonomy(id: i64) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
 
-    if addr.firstname.len() > MAX_NAME_LEN {
-        return Err(format!("{} first name too long (max {} chars)", label, MAX_NAME_LEN));
-    }
-    if addr.lastname.len() > MAX_NAME_LEN {
-        return Err(format!("{} last name too long (max {} chars)", label, MAX_NAME_LEN));
-    }
-    if addr.address1.len() > MAX_ADDRESS_LEN {
-        return Err(format!("{} address1 too long (max {} chars)", label, MAX_ADDRESS_LEN));
-    }
-    if let Some(ref a2) = addr.address2 {
-        if a2.len() > MAX_ADDRESS_LEN { return Err(format!("{} address2 too long (max {} chars)", label, MAX_ADDRESS_LEN)); }
-    }
-    if addr.city.len() > MAX_CITY_LEN {
-        return Err(format!("{} city too long (max {} chars)", label, MAX_CITY_LEN));
-    }
-    if let Some(ref s) = addr.state_name {
-        if s.len() > MAX_STATE_LEN { return Err(format!("{} state too long (max {} chars)", label, MAX_STATE_LEN)); }
-    }
-    if addr.zipcode.len() > MAX_ZIP_LEN {
-        return Err(format!("{} zipcode too long (max {} chars)", label, MAX_ZIP_LEN));
-    }
-    if let Some(ref p) = addr.phone {
-        if p.len() > MAX_PHONE_LEN { return Err(format!("{} phone too long (max {} chars)", label, MAX_PHONE_LEN)); }
-    }
-    Ok(())
+    with_connection(|conn| {
+        conn.execute("DELETE FROM taxons WHERE taxonomy_id = ?1", (id,))
+            .map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM taxonomies WHERE id = ?1", (id,))
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    })
 }
 
 #[ic_cdk::update]
-fn set_order_address(input: SetAddressInput, session_id: Option<String>) -> Result<OrderDetail, String> {
-    // Validate email
-    if input.email.len() > 254 {
-        return Err("Email address too long (max 254 chars)".to_string());
-    }
-    // Validate address fields
-    validate_address(&input.shipping, "Shipping")?;
-    if let Some(ref billing) = input.billing {
-        validate_address(billing, "Billing")?;
-    }
-
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
-    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+fn admin_create_taxon(input: CreateTaxonInput) -> Result<i64, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
 
     with_connection(|conn| {
         let now = now();
-
-        // Get cart order
-        let order_id: i64 = if is_anonymous {
-             if let Some(sess_id) = session_id {
-                 conn.query_row(
-                    "SELECT id FROM orders WHERE guest_token = ?1 AND state IN ('cart', 'address')",
-                    (&sess_id,),
-                    |row| row.get(0)
-                ).map_err(|_| "No active cart found".to_string())?
-             } else {
-                 return Err("Session ID required for guest checkout".to_string());
-             }
+        
+        let depth: i64 = if let Some(parent_id) = input.parent_id {
+            conn.query_row("SELECT depth + 1 FROM taxons WHERE id = ?1", (parent_id,), |row| row.get(0))
+                .unwrap_or(0)
         } else {
-            conn.query_row(
-                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state IN ('cart', 'address')",
-                (user_id, &caller_str),
-                |row| row.get(0)
-            ).map_err(|_| "No active cart found".to_string())?
+            0
         };
 
-        // Create or update shipping address
-        let ship_address_id: i64 = conn.query_row(
-            r#"INSERT INTO addresses (user_id, firstname, lastname, address1, address2, city, state_name, zipcode, country_code, phone, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?11) RETURNING id"#,
-            (
-                user_id,
-                &input.shipping.firstname,
-                &input.shipping.lastname,
-                &input.shipping.address1,
-                &input.shipping.address2,
-                &input.shipping.city,
-                &input.shipping.state_name,
-                &input.shipping.zipcode,
-                &input.shipping.country_code.as_deref().unwrap_or("US"),
-                &input.shipping.phone,
-                now
-            ),
-            |row| row.get(0)
-        ).map_err(|e| e.to_string())?;
-
-        // Use same for billing or create separate
-        let bill_address_id = if input.use_shipping_for_billing.unwrap_or(true) {
-            ship_address_id
-        } else if let Some(billing) = input.billing {
-            conn.query_row(
-                r#"INSERT INTO addresses (user_id, firstname, lastname, address1, address2, city, state_name, zipcode, country_code, phone, created_at, updated_at)
-                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?11) RETURNING id"#,
-                (
-                    user_id,
-                    &billing.firstname,
-                    &billing.lastname,
-                    &billing.address1,
-                    &billing.address2,
-                    &billing.city,
-                    &billing.state_name,
-                    &billing.zipcode,
-                    &billing.country_code.as_deref().unwrap_or("US"),
-                    &billing.phone,
-                    now
-                ),
-                |row| row.get(0)
-            ).map_err(|e| e.to_string())?
+        let permalink = if let Some(parent_id) = input.parent_id {
+            let parent_permalink: String = conn.query_row("SELECT permalink FROM taxons WHERE id = ?1", (parent_id,), |row| row.get(0))
+                .unwrap_or_default();
+            format!("{}/{}", parent_permalink, input.name.to_lowercase())
         } else {
-            ship_address_id
+            input.name.to_lowercase()
         };
 
-        // Update order with email and addresses
-        conn.execute(
-            r#"UPDATE orders SET
-               email = ?1, ship_address_id = ?2, bill_address_id = ?3,
-               state = 'address', updated_at = ?4
-               WHERE id = ?5"#,
-            (&input.email, ship_address_id, bill_address_id, now, order_id)
+        let taxon_id: i64 = conn.query_row(
+            r#"INSERT INTO taxons (taxonomy_id, parent_id, name, permalink, position, depth, description, created_at, updated_at) 
+               VALUES (?1, ?2, ?3, ?4, COALESCE((SELECT MAX(position)+1 FROM taxons WHERE parent_id IS ?2), 0), ?5, ?6, ?7, ?7) RETURNING id"#,
+            (input.taxonomy_id, input.parent_id, &input.name, permalink, depth, &input.description, now),
+            |row| row.get(0)
         ).map_err(|e| e.to_string())?;
 
-        // Calculate taxes
-        calculate_taxes(&conn, order_id)?;
-
-        recalculate_order(&conn, order_id)?;
-        get_order_detail(&conn, order_id)
+        Ok(taxon_id)
     })
 }
 
-fn calculate_taxes(conn: &Connection, order_id: i64) -> Result<(), String> {
-    let now = now();
+#[ic_cdk::update]
+fn admin_update_taxon(id: i64, input: UpdateTaxonInput) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
 
-    // Clear existing tax adjustments
-    conn.execute(
-        "DELETE FROM adjustments WHERE order_id = ?1 AND source_type = 'TaxRate'",
-        (order_id,)
-    ).map_err(|e| e.to_string())?;
+    with_connection(|conn| {
+        let now = now();
+        
+        if let Some(name) = input.name {
+            conn.execute("UPDATE taxons SET name = ?1, updated_at = ?2 WHERE id = ?3", (&name, now, id))
+                .map_err(|e| e.to_string())?;
+        }
 
-    // Get order shipping address country/state
-    let address: (String, Option<String>) = match conn.query_row(
-        r#"SELECT country_code, state_name FROM addresses
-           WHERE id = (SELECT ship_address_id FROM orders WHERE id = ?1)"#,
-        (order_id,),
-        |row| Ok((row.get(0)?, row.get(1)?))
-    ) {
-        Ok(a) => a,
-        Err(_) => return Ok(()), // No address, no tax
-    };
+        if let Some(description) = input.description {
+            conn.execute("UPDATE taxons SET description = ?1, updated_at = ?2 WHERE id = ?3", (&description, now, id))
+                .map_err(|e| e.to_string())?;
+        }
 
-    let (country_code, state_name) = address;
-
-    // Find applicable tax rates
-    // This is a simplified version of Solidus zone matching
-    let mut stmt = conn.prepare(
-        r#"SELECT tr.id, tr.amount, tr.name, tr.included_in_price
-           FROM tax_rates tr
-           JOIN zones z ON z.id = tr.zone_id
-           JOIN zone_members zm ON zm.zone_id = z.id
-           WHERE tr.deleted_at IS NULL
-           AND (
-               (zm.zoneable_type = 'Country' AND zm.zoneable_id = ?1)
-               OR (zm.zoneable_type = 'State' AND zm.zoneable_id = ?2)
-           )"#
-    ).map_err(|e| e.to_string())?;
-
-    let tax_rates = stmt.query_map((&country_code, &state_name), |row| {
-        Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, i64>(3)? == 1))
-    }).map_err(|e| e.to_string())?;
-
-    // For each line item, apply applicable tax rates
-    // Simplified: apply all matching rates to all line items
-    let mut line_items_stmt = conn.prepare(
-        "SELECT id, price, quantity FROM line_items WHERE order_id = ?1"
-    ).map_err(|e| e.to_string())?;
-
-    let line_items: Vec<(i64, i64, i64)> = line_items_stmt.query_map((order_id,), |row| {
-        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-    }).map_err(|e| e.to_string())?.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
-
-    for tax_rate in tax_rates {
-        let (rate_id, amount_rate, name, included) = tax_rate.map_err(|e| e.to_string())?;
-        let label = name.unwrap_or_else(|| format!("Tax ({:.1}%)", amount_rate * 100.0));
-
-        for (line_item_id, price, quantity) in &line_items {
-            let tax_amount = if included {
-                // price = total / (1 + rate)
-                // tax = total - (total / (1 + rate))
-                let total = price * quantity;
-                total - (total as f64 / (1.0 + amount_rate)).round() as i64
-            } else {
-                ((price * quantity) as f64 * amount_rate).round() as i64
-            };
-
-            if tax_amount != 0 {
-                conn.execute(
-                    r#"INSERT INTO adjustments (source_type, source_id, adjustable_type, adjustable_id, order_id, amount, label, included, created_at, updated_at)
-                       VALUES ('TaxRate', ?1, 'LineItem', ?2, ?3, ?4, ?5, ?6, ?7, ?7)"#,
-                    (rate_id, line_item_id, order_id, tax_amount, &label, if included { 1 } else { 0 }, now)
-                ).map_err(|e| e.to_string())?;
-            }
-        }
-    }
-
-    Ok(())
-}
-
-fn calculate_shipping_cost(conn: &Connection, shipping_method_id: i64, order_id: i64) -> Result<i64, String> {
-    // Basic calculation: base price from method + weight-based cost
-    let base_cost: i64 = match conn.query_row(
-        "SELECT base_cost FROM shipping_methods WHERE id = ?1",
-        (shipping_method_id,),
-        |row| row.get(0)
-    ) {
-        Ok(c) => c,
-        Err(_) => return Err("Shipping method not found".to_string()),
-    };
-
-    let total_weight: f64 = conn.query_row(
-        r#"SELECT COALESCE(SUM(v.weight * li.quantity), 0.0)
-           FROM line_items li
-           JOIN variants v ON v.id = li.variant_id
-           WHERE li.order_id = ?1"#,
-        (order_id,),
-        |row| row.get(0)
-    ).unwrap_or(0.0);
-
-    // Add $1 per unit of weight (simplified)
-    let weight_cost = (total_weight * 100.0) as i64;
-    
-    Ok(base_cost + weight_cost)
-}
-
-fn move_stock(conn: &Connection, variant_id: i64, stock_location_id: i64, quantity: i64, action: &str, originator_type: &str, originator_id: i64) -> Result<(), String> {
-    let now = now();
-
-    // Find stock item for the given location
-    let stock_item_id: i64 = match conn.query_row(
-        "SELECT id FROM stock_items WHERE variant_id = ?1 AND stock_location_id = ?2",
-        (variant_id, stock_location_id),
-        |row| row.get(0)
-    ) {
-        Ok(id) => id,
-        Err(_) => {
-            // Create stock item if it doesn't exist
-            conn.query_row(
-                "INSERT INTO stock_items (variant_id, stock_location_id, count_on_hand, backorderable, created_at, updated_at) VALUES (?1, ?2, 0, 1, ?3, ?3) RETURNING id",
-                (variant_id, stock_location_id, now),
-                |row| row.get(0)
-            ).map_err(|e| e.to_string())?
+        if let Some(parent_id) = input.parent_id {
+             let depth: i64 = conn.query_row("SELECT depth + 1 FROM taxons WHERE id = ?1", (parent_id,), |row| row.get(0))
+                .unwrap_or(0);
+            conn.execute("UPDATE taxons SET parent_id = ?1, depth = ?2, updated_at = ?3 WHERE id = ?4", (parent_id, depth, now, id))
+                .map_err(|e| e.to_string())?;
         }
-    };
-
-    // Update count on hand
-    conn.execute(
-        "UPDATE stock_items SET count_on_hand = count_on_hand + ?1, updated_at = ?2 WHERE id = ?3",
-        (quantity, now, stock_item_id)
-    ).map_err(|e| e.to_string())?;
-
-    // Record movement
-    conn.execute(
-        r#"INSERT INTO stock_movements (stock_item_id, quantity, action, originator_type, originator_id, created_at, updated_at)
-           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)"#,
-        (stock_item_id, quantity, action, originator_type, originator_id, now)
-    ).map_err(|e| e.to_string())?;
-
-    Ok(())
-}
 
-#[ic_cdk::query]
-fn admin_get_stock_locations() -> Result<Vec<StockLocation>, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
-    with_connection(|conn| {
-        let mut stmt = conn.prepare("SELECT id, name, code, active, address1, city, state_name, country_code, zipcode, is_default FROM stock_locations ORDER BY id ASC")
-            .map_err(|e| e.to_string())?;
-        let locations = stmt.query_map([], |row| {
-            Ok(StockLocation {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                code: row.get(2)?,
-                active: row.get::<_, i64>(3)? == 1,
-                address1: row.get(4)?,
-                address2: None, // Missing from query but expected by struct
-                city: row.get(5)?,
-                state_name: row.get(6)?,
-                zipcode: row.get(8)?,
-                country_code: row.get(7)?,
-                phone: None, // Missing from query
-                is_default: row.get::<_, i64>(9)? == 1,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
-        Ok(locations)
+        Ok(())
     })
 }
 
 #[ic_cdk::update]
-fn admin_create_stock_location(input: CreateStockLocationInput) -> Result<i64, String> {
+fn admin_delete_taxon(id: i64) -> Result<(), String> {
     if !is_admin() { return Err("Admin only".to_string()); }
+
     with_connection(|conn| {
-        let now = now();
-        let id: i64 = conn.query_row(
-            r#"INSERT INTO stock_locations (name, code, active, address1, city, state_name, country_code, zipcode, is_default, created_at, updated_at)
-               VALUES (?1, ?2, 1, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9) RETURNING id"#,
-            (&input.name, &input.code, &input.address1, &input.city, &input.state_name, &input.country_code, &input.zipcode, if input.is_default.unwrap_or(false) { 1 } else { 0 }, now),
-            |row| row.get(0)
-        ).map_err(|e| e.to_string())?;
-        Ok(id)
+        // Delete child taxons recursively 
+        conn.execute("DELETE FROM taxons WHERE id = ?1 OR parent_id = ?1", (id,))
+            .map_err(|e| e.to_string())?;
+        Ok(())
     })
 }
 
-#[ic_cdk::update]
-fn admin_update_stock_location(id: i64, input: UpdateStockLocationInput) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+// ============================================
+// CART API
+// ============================================
+
+#[ic_cdk::query]
+fn get_cart(session_id: Option<String>) -> Result<Option<OrderDetail>, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+
     with_connection(|conn| {
-        let now = now();
-        if let Some(name) = input.name {
-            conn.execute("UPDATE stock_locations SET name = ?1, updated_at = ?2 WHERE id = ?3", (name, now, id)).ok();
-        }
-        if let Some(code) = input.code {
-            conn.execute("UPDATE stock_locations SET code = ?1, updated_at = ?2 WHERE id = ?3", (code, now, id)).ok();
-        }
-        if let Some(active) = input.active {
-            conn.execute("UPDATE stock_locations SET active = ?1, updated_at = ?2 WHERE id = ?3", (if active { 1 } else { 0 }, now, id)).ok();
+        if is_anonymous {
+            if let Some(sess_id) = session_id {
+                // Find guest cart by token
+                 conn.query_row(
+                    "SELECT id FROM orders WHERE guest_token = ?1 AND state = 'cart'",
+                    (sess_id,),
+                    |row| row.get(0)
+                ).ok()
+                 .map(|id| get_order_detail(&conn, id))
+                 .transpose()
+            } else {
+                Ok(None)
+            }
+        } else {
+             // Find active cart for user
+            get_order_by_state(&conn, &caller_str, "cart")
         }
-        Ok(())
     })
 }
 
 #[ic_cdk::update]
-fn admin_delete_stock_location(id: i64) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
-    with_connection(|conn| {
-        // Check if this is the default location
-        let is_default: bool = conn.query_row(
-            "SELECT is_default FROM stock_locations WHERE id = ?1",
-            [id],
-            |row| row.get(0)
-        ).unwrap_or(false);
-
-        if is_default {
-            return Err("Cannot delete the default stock location. Set another location as default first.".to_string());
-        }
+fn add_to_cart(variant_id: i64, quantity: i64, session_id: Option<String>) -> Result<OrderDetail, String> {
+    // Validate quantity limits to prevent abuse
+    if quantity <= 0 {
+        return Err("Quantity must be positive".to_string());
+    }
+    const MAX_QUANTITY_PER_ITEM: i64 = 999;
+    if quantity > MAX_QUANTITY_PER_ITEM {
+        return Err(format!("Maximum quantity per item is {}", MAX_QUANTITY_PER_ITEM));
+    }
 
-        // Check if there are stock items at this location
-        let stock_count: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM stock_items WHERE stock_location_id = ?1 AND count_on_hand > 0",
-            [id],
-            |row| row.get(0)
-        ).unwrap_or(0);
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
 
-        if stock_count > 0 {
-            return Err(format!("Cannot delete location with {} items in stock. Transfer or zero out stock first.", stock_count));
-        }
+    // Get user_id outside of connection to avoid nested borrow
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
 
-        // Delete stock items (should be zero anyway)
-        conn.execute("DELETE FROM stock_items WHERE stock_location_id = ?1", [id]).ok();
-        // Delete the location
-        conn.execute("DELETE FROM stock_locations WHERE id = ?1", [id]).ok();
-        Ok(())
-    })
-}
+    if is_anonymous && session_id.is_none() {
+        return Err("Session ID required for guest checkout".to_string());
+    }
 
-#[ic_cdk::query]
-fn admin_get_stock_items(params: StockQueryParams) -> Result<StockListResponse, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
     with_connection(|conn| {
-        let limit = params.per_page.unwrap_or(20);
-        let offset = (params.page.unwrap_or(1) - 1) * limit;
+        let now = now();
 
-        // Get low stock threshold from settings (default 10)
-        let threshold: i64 = if params.low_stock.unwrap_or(false) {
-            let threshold_str: String = conn.query_row(
-                "SELECT value FROM store_settings WHERE key = 'low_stock_threshold'",
-                [],
+        // Get or create cart
+        let order_id: i64 = if is_anonymous {
+             let sess_id = session_id.as_ref().ok_or("Session ID missing")?;
+             match conn.query_row(
+                "SELECT id FROM orders WHERE guest_token = ?1 AND state = 'cart'",
+                (&sess_id,),
                 |row| row.get(0)
-            ).unwrap_or("10".to_string());
-            threshold_str.parse().unwrap_or(10)
+            ) {
+                Ok(id) => id,
+                Err(_) => {
+                    let number = generate_order_number();
+                    conn.query_row(
+                        r#"INSERT INTO orders (number, user_principal, guest_token, state, created_at, updated_at) 
+                           VALUES (?1, 'anonymous', ?2, 'cart', ?3, ?3) RETURNING id"#,
+                        (&number, &sess_id, now),
+                        |row| row.get(0)
+                    ).map_err(|e| e.to_string())?
+                }
+            }
         } else {
-            0 // not used when not filtering
+            match conn.query_row(
+                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state = 'cart'",
+                (user_id, &caller_str),
+                |row| row.get(0)
+            ) {
+                Ok(id) => id,
+                Err(_) => {
+                    let number = generate_order_number();
+                    conn.query_row(
+                        r#"INSERT INTO orders (number, user_id, user_principal, state, created_at, updated_at)
+                           VALUES (?1, ?2, ?3, 'cart', ?4, ?4) RETURNING id"#,
+                        (&number, user_id, &caller_str, now),
+                        |row| row.get(0)
+                    ).map_err(|e| e.to_string())?
+                }
+            }
         };
 
-        // Find stock items for a location or all (using parameterized queries)
-        let base_query = r#"SELECT si.id, v.id, v.sku, p.name, si.count_on_hand, si.backorderable
-                           FROM stock_items si
-                           JOIN variants v ON v.id = si.variant_id
-                           JOIN products p ON p.id = v.product_id
-                           WHERE si.deleted_at IS NULL"#;
+        // Get variant price
+        let (price, currency): (i64, String) = conn.query_row(
+            r#"SELECT pr.amount, pr.currency FROM prices pr
+               JOIN variants v ON v.id = pr.variant_id
+               WHERE pr.variant_id = ?1 AND pr.deleted_at IS NULL AND v.deleted_at IS NULL"#,
+            (variant_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Variant not found or has no price".to_string())?;
 
-        let (items, total_count): (Vec<StockItem>, i64) = match (params.stock_location_id, params.low_stock.unwrap_or(false)) {
-            (Some(loc_id), true) => {
-                let query = format!("{} AND si.stock_location_id = ?1 AND si.count_on_hand <= ?2 ORDER BY si.count_on_hand ASC LIMIT ?3 OFFSET ?4", base_query);
-                let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
-                let items = stmt.query_map((loc_id, threshold, limit, offset), |row| {
-                    Ok(StockItem {
-                        id: row.get(0)?,
-                        stock_location_id: loc_id,
-                        stock_location_name: "Default".to_string(),
-                        variant_id: row.get(1)?,
-                        variant_sku: row.get(2)?,
-                        product_name: row.get(3)?,
-                        count_on_hand: row.get(4)?,
-                        backorderable: row.get::<_, i64>(5)? == 1,
-                    })
-                }).map_err(|e| e.to_string())?
-                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
-                let count: i64 = conn.query_row(
-                    "SELECT COUNT(*) FROM stock_items si WHERE si.deleted_at IS NULL AND si.stock_location_id = ?1 AND si.count_on_hand <= ?2",
-                    (loc_id, threshold), |row| row.get(0)
-                ).unwrap_or(0);
-                (items, count)
-            },
-            (Some(loc_id), false) => {
-                let query = format!("{} AND si.stock_location_id = ?1 ORDER BY si.count_on_hand ASC LIMIT ?2 OFFSET ?3", base_query);
-                let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
-                let items = stmt.query_map((loc_id, limit, offset), |row| {
-                    Ok(StockItem {
-                        id: row.get(0)?,
-                        stock_location_id: loc_id,
-                        stock_location_name: "Default".to_string(),
-                        variant_id: row.get(1)?,
-                        variant_sku: row.get(2)?,
-                        product_name: row.get(3)?,
-                        count_on_hand: row.get(4)?,
-                        backorderable: row.get::<_, i64>(5)? == 1,
-                    })
-                }).map_err(|e| e.to_string())?
-                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
-                let count: i64 = conn.query_row(
-                    "SELECT COUNT(*) FROM stock_items si WHERE si.deleted_at IS NULL AND si.stock_location_id = ?1",
-                    (loc_id,), |row| row.get(0)
-                ).unwrap_or(0);
-                (items, count)
-            },
-            (None, true) => {
-                let query = format!("{} AND si.count_on_hand <= ?1 ORDER BY si.count_on_hand ASC LIMIT ?2 OFFSET ?3", base_query);
-                let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
-                let items = stmt.query_map((threshold, limit, offset), |row| {
-                    Ok(StockItem {
-                        id: row.get(0)?,
-                        stock_location_id: params.stock_location_id.unwrap_or(1),
-                        stock_location_name: "Default".to_string(),
-                        variant_id: row.get(1)?,
-                        variant_sku: row.get(2)?,
-                        product_name: row.get(3)?,
-                        count_on_hand: row.get(4)?,
-                        backorderable: row.get::<_, i64>(5)? == 1,
-                    })
-                }).map_err(|e| e.to_string())?
-                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
-                let count: i64 = conn.query_row(
-                    "SELECT COUNT(*) FROM stock_items si WHERE si.deleted_at IS NULL AND si.count_on_hand <= ?1",
-                    (threshold,), |row| row.get(0)
-                ).unwrap_or(0);
-                (items, count)
-            },
-            (None, false) => {
-                let query = format!("{} ORDER BY si.count_on_hand ASC LIMIT ?1 OFFSET ?2", base_query);
-                let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
-                let items = stmt.query_map((limit, offset), |row| {
-                    Ok(StockItem {
-                        id: row.get(0)?,
-                        stock_location_id: params.stock_location_id.unwrap_or(1),
-                        stock_location_name: "Default".to_string(),
-                        variant_id: row.get(1)?,
-                        variant_sku: row.get(2)?,
-                        product_name: row.get(3)?,
-                        count_on_hand: row.get(4)?,
-                        backorderable: row.get::<_, i64>(5)? == 1,
-                    })
-                }).map_err(|e| e.to_string())?
-                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
-                let count: i64 = conn.query_row(
-                    "SELECT COUNT(*) FROM stock_items si WHERE si.deleted_at IS NULL",
-                    [], |row| row.get(0)
-                ).unwrap_or(0);
-                (items, count)
-            },
-        };
+        // Check stock
+        let stock = variant_stock(&conn, variant_id);
 
-        Ok(StockListResponse {
-            items,
-            total_count,
-            page: params.page.unwrap_or(1),
-            per_page: limit,
-            total_pages: (total_count as f64 / limit as f64).ceil() as i64,
-        })
-    })
-}
+        if stock < quantity {
+            return Err(format!("Insufficient stock. Available: {}", stock));
+        }
 
-#[ic_cdk::update]
-fn admin_adjust_stock(input: StockAdjustmentInput) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
-    with_connection(|conn| {
-        // Need to find variant_id and stock_location_id for this stock_item_id
-        let (variant_id, stock_location_id): (i64, i64) = conn.query_row(
-            "SELECT variant_id, stock_location_id FROM stock_items WHERE id = ?1",
-            (input.stock_item_id,),
+        // Check if line item exists
+        let existing_line_item: Option<(i64, i64)> = conn.query_row(
+            "SELECT id, quantity FROM line_items WHERE order_id = ?1 AND variant_id = ?2",
+            (order_id, variant_id),
             |row| Ok((row.get(0)?, row.get(1)?))
-        ).map_err(|_| "Stock item not found".to_string())?;
+        ).ok();
 
-        move_stock(&conn, variant_id, stock_location_id, input.quantity, "admin_adjustment", "Admin", 0)
+        if let Some((line_item_id, existing_qty)) = existing_line_item {
+            // Update quantity
+            let new_qty = existing_qty + quantity;
+            if stock < new_qty {
+                return Err(format!("Insufficient stock. Available: {}", stock));
+            }
+            conn.execute(
+                "UPDATE line_items SET quantity = ?1, updated_at = ?2 WHERE id = ?3",
+                (new_qty, now, line_item_id)
+            ).map_err(|e| e.to_string())?;
+        } else {
+            // Create new line item
+            conn.execute(
+                r#"INSERT INTO line_items (order_id, variant_id, quantity, price, currency, created_at, updated_at)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)"#,
+                (order_id, variant_id, quantity, price, &currency, now)
+            ).map_err(|e| e.to_string())?;
+        }
+
+        // Recalculate order totals
+        recalculate_order(&conn, order_id)?;
+
+        // Return updated cart
+        get_order_detail(&conn, order_id)
     })
 }
 
 #[ic_cdk::update]
-fn set_shipping_method(shipping_method_id: i64, session_id: Option<String>) -> Result<OrderDetail, String> {
+fn update_line_item(line_item_id: i64, quantity: i64, session_id: Option<String>) -> Result<OrderDetail, String> {
+    // Validate quantity limits to prevent abuse
+    if quantity < 0 {
+        return Err("Quantity cannot be negative".to_string());
+    }
+    const MAX_QUANTITY_PER_ITEM: i64 = 999;
+    if quantity > MAX_QUANTITY_PER_ITEM {
+        return Err(format!("Maximum quantity per item is {}", MAX_QUANTITY_PER_ITEM));
+    }
+
     let caller = ic_cdk::api::caller();
     let caller_str = caller.to_string();
     let is_anonymous = caller == Principal::anonymous();
-    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    if is_anonymous && session_id.is_none() {
+        return Err("Session ID required for guest checkout".to_string());
+    }
 
     with_connection(|conn| {
         let now = now();
 
-        // Get order in address state (support guests)
-        let order_id: i64 = if is_anonymous {
-            if let Some(sess_id) = &session_id {
-                conn.query_row(
-                    "SELECT id FROM orders WHERE guest_token = ?1 AND state IN ('address', 'delivery')",
-                    (sess_id,),
-                    |row| row.get(0)
-                ).map_err(|_| "No order in address state".to_string())?
-            } else {
-                return Err("Session ID required for guest checkout".to_string());
-            }
+        // Verify ownership and get details
+        let (order_id, variant_id, order_guest_token, _order_user_principal): (i64, i64, Option<String>, String) = conn.query_row(
+            r#"SELECT li.order_id, li.variant_id, o.guest_token, o.user_principal
+               FROM line_items li
+               JOIN orders o ON o.id = li.order_id
+               WHERE li.id = ?1"#,
+            (line_item_id,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        ).map_err(|_| "Line item not found".to_string())?;
+
+        // Check permissions
+        if is_anonymous {
+             let sess_id = session_id.as_ref().ok_or("Session ID required")?;
+             if order_guest_token.as_ref() != Some(sess_id) {
+                 return Err("Unauthorized".to_string());
+             }
         } else {
-            conn.query_row(
-                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state IN ('address', 'delivery')",
-                (user_id, &caller_str),
-                |row| row.get(0)
-            ).map_err(|_| "No order in address state".to_string())?
-        };
+            // Check if user owns order via:
+            // 1. user_principal match
+            // 2. user_id match (via users table principal lookup)
+            // 3. guest_token match if session_id provided (for carts created before login)
+            let mut is_owner: bool = conn.query_row(
+                "SELECT COUNT(*) FROM orders WHERE id = ?1 AND (user_principal = ?2 OR user_id IN (SELECT id FROM users WHERE principal = ?2))",
+                (order_id, &caller_str),
+                |row| row.get::<_, i64>(0)
+            ).unwrap_or(0) > 0;
 
-        // Verify shipping method exists, is active, and not deleted
-        let method_valid: bool = conn.query_row(
-            "SELECT 1 FROM shipping_methods WHERE id = ?1 AND active = 1 AND deleted_at IS NULL",
-            (shipping_method_id,),
-            |_| Ok(true)
-        ).unwrap_or(false);
-
-        if !method_valid {
-            return Err("Shipping method is not available".to_string());
-        }
-
-        // Get or create shipment
-        let shipment_id: i64 = match conn.query_row(
-            "SELECT id FROM shipments WHERE order_id = ?1",
-            (order_id,),
-            |row| row.get(0)
-        ) {
-            Ok(id) => id,
-            Err(_) => {
-                let number = generate_shipment_number();
-                conn.query_row(
-                    r#"INSERT INTO shipments (order_id, number, stock_location_id, state, created_at, updated_at)
-                       VALUES (?1, ?2, 1, 'pending', ?3, ?3) RETURNING id"#,
-                    (order_id, &number, now),
-                    |row| row.get(0)
-                ).map_err(|e| e.to_string())?
+            // Also allow if session_id matches the guest_token (cart created before login)
+            if !is_owner {
+                if let Some(sess_id) = session_id.as_ref() {
+                    is_owner = order_guest_token.as_ref() == Some(sess_id);
+                }
             }
-        };
 
-        // Get shipping method cost
-        let cost: i64 = calculate_shipping_cost(&conn, shipping_method_id, order_id)?;
-
-        // Set shipping rate
-        conn.execute(
-            "DELETE FROM shipping_rates WHERE shipment_id = ?1",
-            (shipment_id,)
-        ).ok();
+            if !is_owner { return Err("Unauthorized".to_string()); }
+        }
 
-        conn.execute(
-            r#"INSERT INTO shipping_rates (shipment_id, shipping_method_id, cost, selected, created_at, updated_at)
-               VALUES (?1, ?2, ?3, 1, ?4, ?4)"#,
-            (shipment_id, shipping_method_id, cost, now)
-        ).map_err(|e| e.to_string())?;
+        if quantity <= 0 {
+            // Remove line item
+            conn.execute("DELETE FROM line_items WHERE id = ?1", (line_item_id,))
+                .map_err(|e| e.to_string())?;
+        } else {
+            // Check stock
+            let stock = variant_stock(&conn, variant_id);
 
-        // Update shipment cost
-        conn.execute(
-            "UPDATE shipments SET cost = ?1, updated_at = ?2 WHERE id = ?3",
-            (cost, now, shipment_id)
-        ).map_err(|e| e.to_string())?;
+            if stock < quantity {
+                return Err(format!("Insufficient stock. Available: {}", stock));
+            }
 
-        // Move to delivery state
-        conn.execute(
-            "UPDATE orders SET state = 'delivery', shipment_total = ?1, updated_at = ?2 WHERE id = ?3",
-            (cost, now, order_id)
-        ).map_err(|e| e.to_string())?;
+            conn.execute(
+                "UPDATE line_items SET quantity = ?1, updated_at = ?2 WHERE id = ?3",
+                (quantity, now, line_item_id)
+            ).map_err(|e| e.to_string())?;
+        }
 
         recalculate_order(&conn, order_id)?;
         get_order_detail(&conn, order_id)
@@ -2116,2417 +2462,8881 @@ fn set_shipping_method(shipping_method_id: i64, session_id: Option<String>) -> R
 }
 
 #[ic_cdk::update]
-fn complete_checkout(session_id: Option<String>) -> Result<OrderDetail, String> {
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
-    let user_id = if !is_anonymous { get_current_user_id() } else { None };
-
-    let order_detail = with_connection(|conn| {
-        let now = now();
+fn remove_from_cart(line_item_id: i64, session_id: Option<String>) -> Result<OrderDetail, String> {
+    update_line_item(line_item_id, 0, session_id)
+}
 
-        // Get order ready for completion (support guests)
-        let order_id: i64 = if is_anonymous {
-            if let Some(sess_id) = &session_id {
-                conn.query_row(
-                    "SELECT id FROM orders WHERE guest_token = ?1 AND state = 'delivery'",
-                    (sess_id,),
-                    |row| row.get(0)
-                ).map_err(|_| "No order ready for checkout".to_string())?
-            } else {
-                return Err("Session ID required for guest checkout".to_string());
-            }
-        } else {
-            conn.query_row(
-                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state = 'delivery'",
-                (user_id, &caller_str),
-                |row| row.get(0)
-            ).map_err(|_| "No order ready for checkout".to_string())?
-        };
+// How many bundles can be made from component stock; NULL when the variant is not a bundle
+fn bundle_stock_sql(variant: &str) -> String {
+    format!(
+        r#"(SELECT MIN(CASE WHEN cv.deleted_at IS NULL
+               THEN MAX(COALESCE((SELECT SUM(bsi.count_on_hand) FROM stock_items bsi
+                                  WHERE bsi.variant_id = bc.component_variant_id AND bsi.deleted_at IS NULL), 0), 0) / bc.quantity
+               ELSE 0 END)
+           FROM bundle_components bc JOIN variants cv ON cv.id = bc.component_variant_id
+           WHERE bc.bundle_variant_id = {})"#,
+        variant
+    )
+}
 
-        // Verify inventory is still available before completing (prevents race condition)
-        {
-            let mut stmt = conn.prepare(
-                "SELECT li.variant_id, li.quantity, p.name, v.sku FROM line_items li
-                 JOIN variants v ON li.variant_id = v.id
-                 JOIN products p ON v.product_id = p.id
-                 WHERE li.order_id = ?1"
-            ).map_err(|e| e.to_string())?;
+// Units available across stock locations, or buildable from components for a bundle
+fn available_stock_sql(variant: &str) -> String {
+    format!(
+        "COALESCE({}, (SELECT COALESCE(SUM(count_on_hand), 0) FROM stock_items WHERE variant_id = {} AND deleted_at IS NULL))",
+        bundle_stock_sql(variant), variant
+    )
+}
 
-            let items: Vec<(i64, i64, String, String)> = stmt.query_map((order_id,), |row| {
-                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-            }).map_err(|e| e.to_string())?
-            .collect::<ic_rusqlite::Result<Vec<_>>>()
-            .map_err(|e| e.to_string())?;
+// Units an order takes out of stock (variant_id, quantity, line_item_id); bundle lines
+// expand into their components
+const ORDER_STOCK_LINES_SQL: &str = r#"SELECT COALESCE(bc.component_variant_id, li.variant_id) AS variant_id,
+    li.quantity * COALESCE(bc.quantity, 1) AS quantity, li.id AS line_item_id
+    FROM line_items li
+    LEFT JOIN bundle_components bc ON bc.bundle_variant_id = li.variant_id
+    WHERE li.order_id = ?1"#;
+
+fn variant_stock(conn: &Connection, variant_id: i64) -> i64 {
+    conn.query_row(
+        &format!("SELECT {}", available_stock_sql("?1")),
+        (variant_id,),
+        |row| row.get(0)
+    ).unwrap_or(0)
+}
 
-            for (variant_id, quantity, product_name, sku) in items {
-                let available: i64 = conn.query_row(
-                    "SELECT COALESCE(SUM(count_on_hand), 0) FROM stock_items WHERE variant_id = ?1 AND deleted_at IS NULL",
-                    (variant_id,),
-                    |row| row.get(0)
-                ).unwrap_or(0);
+// Trim line items down to what is in stock; out-of-stock items are removed
+fn cap_line_items_to_stock(conn: &Connection, order_id: i64) -> Result<(), String> {
+    let now = now();
+    let mut stmt = conn.prepare("SELECT id, variant_id, quantity FROM line_items WHERE order_id = ?1")
+        .map_err(|e| e.to_string())?;
+    let items: Vec<(i64, i64, i64)> = stmt.query_map((order_id,), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    drop(stmt);
 
-                if available < quantity {
-                    return Err(format!(
-                        "Insufficient stock for {} (SKU: {}). Requested: {}, Available: {}",
-                        product_name, sku, quantity, available
-                    ));
-                }
-            }
+    for (line_item_id, variant_id, quantity) in items {
+        let stock = variant_stock(conn, variant_id);
+        if stock <= 0 {
+            conn.execute("DELETE FROM line_items WHERE id = ?1", (line_item_id,))
+                .map_err(|e| e.to_string())?;
+        } else if quantity > stock {
+            conn.execute(
+                "UPDATE line_items SET quantity = ?1, updated_at = ?2 WHERE id = ?3",
+                (stock, now, line_item_id)
+            ).map_err(|e| e.to_string())?;
         }
+    }
+    Ok(())
+}
 
-        // Create payment record
-        let payment_method: (i64, String) = conn.query_row(
-            "SELECT id, name FROM payment_methods WHERE active = 1 AND type = 'IcpPayment' LIMIT 1",
-            [],
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).map_err(|_| "No active payment method found".to_string())?;
-
-        let total: i64 = conn.query_row("SELECT total FROM orders WHERE id = ?1", (order_id,), |row| row.get(0)).unwrap_or(0);
-
-        conn.execute(
-            r#"INSERT INTO payments (order_id, payment_method_id, amount, state, created_at, updated_at)
-               VALUES (?1, ?2, ?3, 'completed', ?4, ?4)"#,
-            (order_id, payment_method.0, total, now)
-        ).map_err(|e| e.to_string())?;
-
-        // Decrease stock
-        let line_items: Vec<(i64, i64)> = {
-            let mut stmt = conn.prepare(
-                "SELECT variant_id, quantity FROM line_items WHERE order_id = ?1"
-            ).map_err(|e| e.to_string())?;
+// Move a guest order's items, coupons and gift cards into `order_id`, then drop the guest order
+fn merge_guest_order(conn: &Connection, guest_order_id: i64, order_id: i64) -> Result<(), String> {
+    const MAX_QUANTITY_PER_ITEM: i64 = 999;
+    let now = now();
 
-            let result: Vec<(i64, i64)> = stmt.query_map((order_id,), |row| Ok((row.get(0)?, row.get(1)?)))
-                .map_err(|e| e.to_string())?
-                .collect::<ic_rusqlite::Result<Vec<_>>>()
-                .map_err(|e| e.to_string())?;
-            result
-        };
+    let mut stmt = conn.prepare("SELECT id, variant_id, quantity FROM line_items WHERE order_id = ?1")
+        .map_err(|e| e.to_string())?;
+    let guest_items: Vec<(i64, i64, i64)> = stmt.query_map((guest_order_id,), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    drop(stmt);
 
-        for (variant_id, quantity) in line_items {
-            let line_item_id: i64 = conn.query_row(
-                "SELECT id FROM line_items WHERE order_id = ?1 AND variant_id = ?2",
-                (order_id, variant_id),
-                |row| row.get(0)
-            ).unwrap_or(0);
+    for (guest_line_item_id, variant_id, quantity) in guest_items {
+        let available = variant_stock(conn, variant_id).min(MAX_QUANTITY_PER_ITEM);
 
-            move_stock(&conn, variant_id, 1, -quantity, "sold", "Order", order_id).ok();
+        let existing: Option<(i64, i64)> = conn.query_row(
+            "SELECT id, quantity FROM line_items WHERE order_id = ?1 AND variant_id = ?2",
+            (order_id, variant_id),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).ok();
 
-            // Create inventory units
-            for _ in 0..quantity {
+        if let Some((line_item_id, existing_qty)) = existing {
+            let new_qty = (existing_qty + quantity).min(available);
+            if new_qty > existing_qty {
                 conn.execute(
-                    r#"INSERT INTO inventory_units (variant_id, shipment_id, line_item_id, state, pending, created_at, updated_at)
-                       VALUES (?1, (SELECT id FROM shipments WHERE order_id = ?2), ?3, 'on_hand', 0, ?4, ?4)"#,
-                    (variant_id, order_id, line_item_id, now)
-                ).ok();
+                    "UPDATE line_items SET quantity = ?1, updated_at = ?2 WHERE id = ?3",
+                    (new_qty, now, line_item_id)
+                ).map_err(|e| e.to_string())?;
             }
+            continue;
         }
 
-        // Complete order
-        conn.execute(
-            r#"UPDATE orders SET
-               state = 'complete', payment_state = 'paid', shipment_state = 'ready',
-               completed_at = ?1, updated_at = ?1
-               WHERE id = ?2"#,
-            (now, order_id)
-        ).map_err(|e| e.to_string())?;
+        let new_qty = quantity.min(available);
+        if new_qty <= 0 {
+            continue;
+        }
 
-        // Update shipment state
-        conn.execute(
-            "UPDATE shipments SET state = 'ready', updated_at = ?1 WHERE order_id = ?2",
-            (now, order_id)
+        // Current price, as add_to_cart would charge
+        let price: Option<(i64, String)> = conn.query_row(
+            r#"SELECT pr.amount, pr.currency FROM prices pr
+               JOIN variants v ON v.id = pr.variant_id
+               WHERE pr.variant_id = ?1 AND pr.deleted_at IS NULL AND v.deleted_at IS NULL"#,
+            (variant_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
         ).ok();
 
-        get_order_detail(&conn, order_id)
-    })?;
+        if let Some((price, currency)) = price {
+            conn.execute(
+                r#"INSERT INTO line_items (order_id, variant_id, quantity, price, currency,
+                   gift_card_recipient_email, gift_card_recipient_name, gift_card_message, created_at, updated_at)
+                   SELECT ?1, variant_id, ?2, ?3, ?4,
+                   gift_card_recipient_email, gift_card_recipient_name, gift_card_message, ?5, ?5
+                   FROM line_items WHERE id = ?6"#,
+                (order_id, new_qty, price, &currency, now, guest_line_item_id)
+            ).map_err(|e| e.to_string())?;
+        }
+    }
 
-    // Send confirmation email (async, ignore errors so we don't fail the checkout)
-    let maybe_email = order_detail.email.clone();
-    let order_number = order_detail.number.clone();
-    let total = order_detail.total;
+    // Coupons carry over; recalculate_order re-checks their rules and limits
+    conn.execute(
+        r#"INSERT OR IGNORE INTO order_promotions (order_id, promotion_id, promotion_code_id, created_at, updated_at)
+           SELECT ?1, promotion_id, promotion_code_id, ?2, ?2 FROM order_promotions WHERE order_id = ?3"#,
+        (order_id, now, guest_order_id)
+    ).map_err(|e| e.to_string())?;
 
-    // Extract customer name from shipping address
-    let customer_name = order_detail.ship_address.as_ref()
-        .map(|addr| format!("{} {}", addr.firstname, addr.lastname))
-        .unwrap_or_else(|| "Customer".to_string());
+    // Applied gift cards carry over unless already applied to the user's cart
+    conn.execute(
+        r#"UPDATE payments SET order_id = ?1, updated_at = ?2
+           WHERE order_id = ?3 AND source_type = 'GiftCard' AND state = 'checkout'
+           AND source_id NOT IN (SELECT source_id FROM payments WHERE order_id = ?1 AND source_type = 'GiftCard' AND state = 'checkout')"#,
+        (order_id, now, guest_order_id)
+    ).map_err(|e| e.to_string())?;
 
-    // Format shipping address
-    let shipping_address = order_detail.ship_address.as_ref()
-        .map(|addr| {
-            let mut parts = vec![addr.address1.clone()];
-            if let Some(ref addr2) = addr.address2 {
-                if !addr2.is_empty() {
-                    parts.push(addr2.clone());
-                }
-            }
-            parts.push(format!("{}, {} {}", addr.city, addr.state_name.clone().unwrap_or_default(), addr.zipcode));
-            parts.push(addr.country_code.clone());
-            parts.join("\n")
-        })
-        .unwrap_or_default();
+    // The cart changed, so shipping has to be chosen again
+    conn.execute(
+        r#"UPDATE orders SET state = 'cart',
+           email = COALESCE(email, (SELECT email FROM orders WHERE id = ?1)), updated_at = ?2
+           WHERE id = ?3"#,
+        (guest_order_id, now, order_id)
+    ).map_err(|e| e.to_string())?;
 
-    // Format line items for email
-    let items_text = order_detail.line_items.iter()
-        .map(|item| format!("{} x {} - ${:.2}", item.quantity, item.product_name, item.price as f64 / 100.0))
-        .collect::<Vec<_>>()
-        .join("\n");
+    // Keep the guest order if Stripe already knows about it, otherwise remove it
+    let has_intents: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM payment_intents WHERE order_id = ?1",
+        (guest_order_id,),
+        |row| row.get(0)
+    ).unwrap_or(0);
 
-    if let Some(email) = maybe_email {
-        ic_cdk::spawn(async move {
-            let _ = send_order_confirmation(email, order_number, total, customer_name, shipping_address, items_text).await;
-        });
+    if has_intents > 0 {
+        conn.execute(
+            "UPDATE orders SET state = 'canceled', guest_token = NULL, canceled_at = ?1, updated_at = ?1 WHERE id = ?2",
+            (now, guest_order_id)
+        ).map_err(|e| e.to_string())?;
+    } else {
+        conn.execute("DELETE FROM shipping_rates WHERE shipment_id IN (SELECT id FROM shipments WHERE order_id = ?1)", (guest_order_id,))
+            .map_err(|e| e.to_string())?;
+        for table in ["shipments", "adjustments", "order_promotions", "payments", "line_items"] {
+            conn.execute(&format!("DELETE FROM {} WHERE order_id = ?1", table), (guest_order_id,))
+                .map_err(|e| e.to_string())?;
+        }
+        conn.execute("DELETE FROM orders WHERE id = ?1", (guest_order_id,))
+            .map_err(|e| e.to_string())?;
     }
 
-    Ok(order_detail)
+    Ok(())
 }
 
-// ============================================
-// ORDERS API
-// ============================================
-
-#[ic_cdk::query]
-fn get_my_orders() -> Result<Vec<OrderSummary>, String> {
-    let caller_str = ic_cdk::api::caller().to_string();
-    let user_id = get_current_user_id();
+/// Attach the guest cart for `session_id` to the signed-in caller after login.
+/// With no open cart the guest order is re-owned; otherwise its items are merged
+/// in. Quantities are capped by stock and promotions re-evaluated.
+#[ic_cdk::update]
+fn claim_guest_cart(session_id: String) -> Result<Option<OrderDetail>, String> {
+    let caller = ic_cdk::api::caller();
+    if caller == Principal::anonymous() {
+        return Err("Sign in to claim a guest cart".to_string());
+    }
+    let caller_str = caller.to_string();
+    let user_id = get_current_user_id().ok_or("User not found")?;
 
     with_connection(|conn| {
-        let mut stmt = conn.prepare(
-            r#"SELECT id, number, state, total, item_count, payment_state, shipment_state, completed_at, created_at
-               FROM orders
-               WHERE (user_id = ?1 OR user_principal = ?2) AND state != 'cart'
-               ORDER BY created_at DESC"#
-        ).map_err(|e| e.to_string())?;
+        let now = now();
 
-        let orders: Vec<OrderSummary> = stmt.query_map((user_id, &caller_str), |row| {
-            Ok(OrderSummary {
-                id: row.get(0)?,
-                number: row.get(1)?,
-                state: row.get(2)?,
-                total: row.get(3)?,
-                item_count: row.get(4)?,
-                payment_state: row.get(5)?,
-                shipment_state: row.get(6)?,
-                completed_at: row.get(7)?,
-                created_at: row.get(8)?,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
-
-        Ok(orders)
-    })
-}
-
-#[ic_cdk::query]
-fn get_order(number: String) -> Result<OrderDetail, String> {
-    let caller_str = ic_cdk::api::caller().to_string();
-    let user_id = get_current_user_id();
+        let guest_order_id: Option<i64> = conn.query_row(
+            r#"SELECT id FROM orders
+               WHERE guest_token = ?1 AND user_id IS NULL AND state IN ('cart', 'address', 'delivery')
+               ORDER BY id DESC LIMIT 1"#,
+            (&session_id,),
+            |row| row.get(0)
+        ).ok();
 
-    with_connection(|conn| {
-        let order_id: i64 = conn.query_row(
+        let user_order_id: Option<i64> = conn.query_row(
             r#"SELECT id FROM orders
-               WHERE number = ?1 AND (user_id = ?2 OR user_principal = ?3)"#,
-            (&number, user_id, &caller_str),
+               WHERE (user_id = ?1 OR user_principal = ?2) AND state IN ('cart', 'address', 'delivery')
+               ORDER BY updated_at DESC LIMIT 1"#,
+            (user_id, &caller_str),
             |row| row.get(0)
-        ).map_err(|_| "Order not found".to_string())?;
+        ).ok();
 
-        get_order_detail(&conn, order_id)
+        let order_id = match (guest_order_id, user_order_id) {
+            (None, None) => return Ok(None),
+            (None, Some(order_id)) => return get_order_detail(&conn, order_id).map(Some),
+            (Some(guest_order_id), None) => {
+                conn.execute(
+                    "UPDATE orders SET user_id = ?1, user_principal = ?2, guest_token = NULL, updated_at = ?3 WHERE id = ?4",
+                    (user_id, &caller_str, now, guest_order_id)
+                ).map_err(|e| e.to_string())?;
+                cap_line_items_to_stock(&conn, guest_order_id)?;
+                guest_order_id
+            }
+            (Some(guest_order_id), Some(order_id)) => {
+                merge_guest_order(&conn, guest_order_id, order_id)?;
+                order_id
+            }
+        };
+
+        recalculate_order(&conn, order_id)?;
+        get_order_detail(&conn, order_id).map(Some)
     })
 }
 
-// ============================================
-// ADMIN: ORDERS
-// ============================================
+#[ic_cdk::update]
+fn apply_coupon(input: ApplyCouponInput, session_id: Option<String>) -> Result<OrderDetail, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
 
-#[ic_cdk::query]
-fn admin_get_orders(params: OrderQueryParams) -> Result<OrderListResponse, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+    if is_anonymous && session_id.is_none() {
+        return Err("Session ID required".to_string());
+    }
 
     with_connection(|conn| {
-        // Always exclude 'cart' state orders (incomplete checkouts)
-        let mut conditions = vec!["state != 'cart'".to_string()];
-        let mut query_params: Vec<Box<dyn ic_rusqlite::ToSql>> = vec![];
+        let now = now();
 
-        if let Some(ref state) = params.state {
-            query_params.push(Box::new(state.clone()));
-            conditions.push(format!("state = ?{}", query_params.len()));
-        }
+        // Get active cart
+        let order_id: i64 = if is_anonymous {
+            let sess_id = session_id.as_ref().ok_or("Session ID required")?;
+            conn.query_row(
+                "SELECT id FROM orders WHERE guest_token = ?1 AND state = 'cart'",
+                (&sess_id,),
+                |row| row.get(0)
+            )
+        } else {
+            conn.query_row(
+                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state = 'cart'",
+                (user_id, &caller_str),
+                |row| row.get(0)
+            )
+        }.map_err(|_| "No active cart found".to_string())?;
 
-        if let Some(ref payment_state) = params.payment_state {
-            query_params.push(Box::new(payment_state.clone()));
-            conditions.push(format!("payment_state = ?{}", query_params.len()));
+        // Find promotion by code
+        let promotion: (i64, i64, Option<i64>, Option<i64>, Option<i64>, Option<i64>) = match conn.query_row(
+            r#"SELECT p.id, pc.id, p.starts_at, p.expires_at, p.usage_limit, p.per_code_usage_limit
+               FROM promotions p
+               JOIN promotion_codes pc ON pc.promotion_id = p.id
+               WHERE pc.value = ?1 AND p.active = 1"#,
+            (&input.code,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+        ) {
+            Ok(p) => p,
+            Err(_) => return Err("Invalid or inactive promotion code".to_string()),
+        };
+
+        let (promo_id, code_id, starts_at, expires_at, usage_limit, per_code_usage_limit) = promotion;
+
+        // Validate dates
+        if let Some(start) = starts_at {
+            if now < start { return Err("Promotion has not started yet".to_string()); }
+        }
+        if let Some(end) = expires_at {
+            if now > end { return Err("Promotion has expired".to_string()); }
         }
 
-        let where_clause = format!("WHERE {}", conditions.join(" AND "));
+        // Validate usage limit
+        if let Some(limit) = usage_limit {
+            if promotion_usage_count(&conn, promo_id, order_id) >= limit {
+                return Err("Promotion usage limit reached".to_string());
+            }
+        }
+        if let Some(limit) = per_code_usage_limit {
+            if promotion_code_usage_count(&conn, code_id, order_id) >= limit {
+                return Err("Promotion code has already been used".to_string());
+            }
+        }
 
-        // Count
-        let count_sql = format!("SELECT COUNT(*) FROM orders {}", where_clause);
-        let param_refs: Vec<&dyn ic_rusqlite::ToSql> = query_params.iter().map(|p| p.as_ref()).collect();
-        let total_count: i64 = conn.query_row(&count_sql, param_refs.as_slice(), |row| row.get(0))
-            .map_err(|e| e.to_string())?;
+        // Check if already applied
+        let already_applied: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM order_promotions WHERE order_id = ?1 AND promotion_id = ?2",
+            (order_id, promo_id),
+            |row| row.get(0)
+        ).unwrap_or(0);
 
-        let page = params.page.unwrap_or(1).max(1);
-        let per_page = params.per_page.unwrap_or(20).min(100);
-        let offset = (page - 1) * per_page;
+        if already_applied > 0 {
+            return Err("Promotion already applied to this order".to_string());
+        }
 
-        let sql = format!(
-            r#"SELECT o.id, o.number, o.email, o.state, o.total, o.item_count, o.payment_state, o.shipment_state, s.tracking, o.completed_at, o.created_at
-               FROM orders o
-               LEFT JOIN shipments s ON s.order_id = o.id
-               {}
-               ORDER BY o.created_at DESC
-               LIMIT ?{} OFFSET ?{}"#,
-            where_clause.replace("state", "o.state").replace("payment_state", "o.payment_state"),
-            query_params.len() + 1,
-            query_params.len() + 2
-        );
+        // Evaluate rules
+        let order = load_promotion_order(&conn, order_id)?;
+        let rules_met = evaluate_promotion_rules(&conn, promo_id, &order)?;
+        if !rules_met {
+            return Err("Order does not meet the requirements for this promotion".to_string());
+        }
 
-        query_params.push(Box::new(per_page));
-        query_params.push(Box::new(offset));
+        // Attach to the order; the adjustments are created by recalculate_order
+        conn.execute(
+            r#"INSERT INTO order_promotions (order_id, promotion_id, promotion_code_id, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?4)"#,
+            (order_id, promo_id, code_id, now)
+        ).map_err(|e| e.to_string())?;
 
-        let param_refs: Vec<&dyn ic_rusqlite::ToSql> = query_params.iter().map(|p| p.as_ref()).collect();
+        recalculate_order(&conn, order_id)?;
+        get_order_detail(&conn, order_id)
+    })
+}
 
-        let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
-        let orders: Vec<AdminOrderSummary> = stmt.query_map(param_refs.as_slice(), |row| {
-            Ok(AdminOrderSummary {
-                id: row.get(0)?,
-                number: row.get(1)?,
-                email: row.get(2)?,
-                state: row.get(3)?,
-                total: row.get(4)?,
-                item_count: row.get(5)?,
-                payment_state: row.get(6)?,
-                shipment_state: row.get(7)?,
-                tracking_number: row.get(8)?,
-                completed_at: row.get(9)?,
-                created_at: row.get(10)?,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
+// Snapshot the order for the promotion engine
+fn load_promotion_order(conn: &Connection, order_id: i64) -> Result<promotions::PromoOrder, String> {
+    let (item_total, user_id, ship_country): (i64, Option<i64>, Option<String>) = conn.query_row(
+        r#"SELECT (SELECT COALESCE(SUM(price * quantity), 0) FROM line_items WHERE order_id = o.id),
+           o.user_id, a.country_code
+           FROM orders o
+           LEFT JOIN addresses a ON a.id = o.ship_address_id
+           WHERE o.id = ?1"#,
+        (order_id,),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    ).map_err(|e| e.to_string())?;
 
-        let total_pages = ((total_count as f64) / (per_page as f64)).ceil() as i64;
+    let mut line_stmt = conn.prepare(
+        r#"SELECT li.id, v.product_id, li.quantity, li.price
+           FROM line_items li
+           JOIN variants v ON v.id = li.variant_id
+           WHERE li.order_id = ?1"#
+    ).map_err(|e| e.to_string())?;
 
-        Ok(OrderListResponse {
-            orders,
-            total_count,
-            page,
-            per_page,
-            total_pages,
+    let mut lines: Vec<promotions::PromoLine> = line_stmt.query_map((order_id,), |row| {
+        Ok(promotions::PromoLine {
+            line_item_id: row.get(0)?,
+            product_id: row.get(1)?,
+            taxon_ids: vec![],
+            quantity: row.get(2)?,
+            price: row.get(3)?,
         })
-    })
-}
+    }).map_err(|e| e.to_string())?
+    .collect::<ic_rusqlite::Result<Vec<_>>>()
+    .map_err(|e| e.to_string())?;
 
-#[ic_cdk::update]
-fn admin_update_order_state(order_id: i64, state: String) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+    // Product taxons including their ancestors, so rules on a parent taxon match children
+    let mut taxon_stmt = conn.prepare(
+        r#"WITH RECURSIVE product_taxons(id) AS (
+               SELECT taxon_id FROM products_taxons WHERE product_id = ?1
+               UNION
+               SELECT t.parent_id FROM taxons t JOIN product_taxons pt ON t.id = pt.id
+               WHERE t.parent_id IS NOT NULL
+           )
+           SELECT id FROM product_taxons"#
+    ).map_err(|e| e.to_string())?;
 
-    // Validate state is a valid order state
-    const VALID_STATES: &[&str] = &["cart", "address", "delivery", "payment", "confirm", "complete", "canceled", "returned"];
-    if !VALID_STATES.contains(&state.as_str()) {
-        return Err(format!("Invalid order state '{}'. Valid states: {}", state, VALID_STATES.join(", ")));
+    for line in lines.iter_mut() {
+        line.taxon_ids = taxon_stmt.query_map((line.product_id,), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<i64>>>()
+            .map_err(|e| e.to_string())?;
     }
 
-    with_connection(|conn| {
-        // Get current state for transition validation
-        let current_state: String = conn.query_row(
-            "SELECT state FROM orders WHERE id = ?1",
-            (order_id,),
-            |row| row.get(0)
-        ).map_err(|_| "Order not found".to_string())?;
-
-        // Validate state transitions (prevent going backwards except for cancel/return)
-        let state_order = |s: &str| -> i32 {
-            match s {
-                "cart" => 0, "address" => 1, "delivery" => 2, "payment" => 3,
-                "confirm" => 4, "complete" => 5, "canceled" => 99, "returned" => 100, _ => -1
-            }
-        };
+    let (user_roles, completed_orders) = match user_id {
+        Some(uid) => {
+            let mut role_stmt = conn.prepare(
+                r#"SELECT r.name FROM roles r JOIN role_users ru ON ru.role_id = r.id WHERE ru.user_id = ?1
+                   UNION
+                   SELECT role FROM users WHERE id = ?1 AND role IS NOT NULL"#
+            ).map_err(|e| e.to_string())?;
+            let roles = role_stmt.query_map((uid,), |row| row.get(0))
+                .map_err(|e| e.to_string())?
+                .collect::<ic_rusqlite::Result<Vec<String>>>()
+                .map_err(|e| e.to_string())?;
 
-        let current_order = state_order(&current_state);
-        let new_order = state_order(&state);
+            let completed: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM orders WHERE user_id = ?1 AND state = 'complete' AND id != ?2",
+                (uid, order_id),
+                |row| row.get(0)
+            ).unwrap_or(0);
 
-        // Allow: forward progress, cancel from any state, return from complete
-        if new_order < current_order && state != "canceled" && !(state == "returned" && current_state == "complete") {
-            return Err(format!("Cannot transition from '{}' to '{}'. Only forward transitions allowed (except cancel/return).", current_state, state));
+            (roles, completed)
         }
+        None => (vec![], 0),
+    };
 
-        let now = now();
-        conn.execute(
-            "UPDATE orders SET state = ?1, updated_at = ?2 WHERE id = ?3",
-            (&state, now, order_id)
-        ).map_err(|e| e.to_string())?;
-        Ok(())
+    let mut ship_stmt = conn.prepare(
+        "SELECT id, COALESCE(cost, 0) FROM shipments WHERE order_id = ?1"
+    ).map_err(|e| e.to_string())?;
+    let shipments = ship_stmt.query_map((order_id,), |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<(i64, i64)>>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(promotions::PromoOrder {
+        item_total,
+        lines,
+        user_id,
+        user_roles,
+        completed_orders,
+        ship_country,
+        shipments,
     })
 }
 
-#[ic_cdk::update]
-fn admin_ship_order(order_id: i64, tracking: Option<String>) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
-
-    with_connection(|conn| {
-        let now = now();
+fn evaluate_promotion_rules(conn: &Connection, promo_id: i64, order: &promotions::PromoOrder) -> Result<bool, String> {
+    let mut stmt = conn.prepare(
+        "SELECT rule_type, preferences FROM promotion_rules WHERE promotion_id = ?1"
+    ).map_err(|e| e.to_string())?;
 
-        // Update shipment
-        conn.execute(
-            "UPDATE shipments SET state = 'shipped', tracking = ?1, shipped_at = ?2, updated_at = ?2 WHERE order_id = ?3",
-            (&tracking, now, order_id)
-        ).map_err(|e| e.to_string())?;
+    let rules = stmt.query_map((promo_id,), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    }).map_err(|e| e.to_string())?;
 
-        // Update order
-        conn.execute(
-            "UPDATE orders SET shipment_state = 'shipped', updated_at = ?1 WHERE id = ?2",
-            (now, order_id)
-        ).map_err(|e| e.to_string())?;
+    for rule in rules {
+        let (rule_type, prefs_json) = rule.map_err(|e| e.to_string())?;
+        let prefs: serde_json::Value = serde_json::from_str(&prefs_json).map_err(|e| e.to_string())?;
+        if !promotions::rule_eligible(&rule_type, &prefs, order)? {
+            return Ok(false);
+        }
+    }
 
-        Ok(())
-    })
+    Ok(true)
 }
 
-#[ic_cdk::update]
-fn admin_update_tracking(order_id: i64, tracking: Option<String>) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+fn compute_promotion_adjustments(conn: &Connection, promo_id: i64, order: &promotions::PromoOrder) -> Result<Vec<promotions::PromoAdjustment>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT action_type, calculator_type, preferences FROM promotion_actions WHERE promotion_id = ?1"
+    ).map_err(|e| e.to_string())?;
 
-    with_connection(|conn| {
-        let now = now();
+    let actions = stmt.query_map((promo_id,), |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+    }).map_err(|e| e.to_string())?;
 
-        // Try to update tracking number on existing shipment
-        let rows_updated = conn.execute(
-            "UPDATE shipments SET tracking = ?1, updated_at = ?2 WHERE order_id = ?3",
-            (&tracking, now, order_id)
-        ).map_err(|e| e.to_string())?;
+    let mut adjustments = Vec::new();
+    for action in actions {
+        let (action_type, calc_type, prefs_json) = action.map_err(|e| e.to_string())?;
+        let prefs: serde_json::Value = serde_json::from_str(&prefs_json).map_err(|e| e.to_string())?;
+        adjustments.extend(promotions::compute_action(&action_type, &calc_type, &prefs, order)?);
+    }
 
-        if rows_updated == 0 {
-            // No shipment exists - create one for this order
-            let number = format!("H{}", now / 1_000_000); // Generate shipment number
-            conn.execute(
-                r#"INSERT INTO shipments (order_id, number, stock_location_id, state, tracking, created_at, updated_at)
-                   VALUES (?1, ?2, 1, 'pending', ?3, ?4, ?4)"#,
-                (order_id, &number, &tracking, now)
-            ).map_err(|e| e.to_string())?;
+    Ok(adjustments)
+}
 
-            // Also update order shipment_state if not already set
-            conn.execute(
-                "UPDATE orders SET shipment_state = COALESCE(shipment_state, 'pending'), updated_at = ?1 WHERE id = ?2",
-                (now, order_id)
-            ).map_err(|e| e.to_string())?;
-        }
+// Number of completed orders that used a promotion (optionally ignoring one order)
+fn promotion_usage_count(conn: &Connection, promo_id: i64, exclude_order_id: i64) -> i64 {
+    conn.query_row(
+        r#"SELECT COUNT(DISTINCT a.order_id) FROM adjustments a
+           JOIN orders o ON o.id = a.order_id
+           WHERE a.source_type = 'Promotion' AND a.source_id = ?1
+           AND o.state = 'complete' AND a.order_id != ?2"#,
+        (promo_id, exclude_order_id),
+        |row| row.get(0)
+    ).unwrap_or(0)
+}
 
-        Ok(())
-    })
+// Number of completed orders that redeemed a specific code (optionally ignoring one order)
+fn promotion_code_usage_count(conn: &Connection, code_id: i64, exclude_order_id: i64) -> i64 {
+    conn.query_row(
+        r#"SELECT COUNT(DISTINCT op.order_id) FROM order_promotions op
+           JOIN orders o ON o.id = op.order_id
+           WHERE op.promotion_code_id = ?1 AND o.state = 'complete' AND op.order_id != ?2"#,
+        (code_id, exclude_order_id),
+        |row| row.get(0)
+    ).unwrap_or(0)
 }
 
-// ============================================
-// ADMIN: ANALYTICS
-// ============================================
+// Re-evaluate every promotion for an open order: automatic promotions plus
+// coupons attached through apply_coupon. Adjustments are rebuilt from scratch,
+// so promotions the order no longer qualifies for are dropped.
+fn apply_promotions(conn: &Connection, order_id: i64) -> Result<(), String> {
+    let state: String = conn.query_row(
+        "SELECT state FROM orders WHERE id = ?1",
+        (order_id,),
+        |row| row.get(0)
+    ).map_err(|e| e.to_string())?;
+
+    // Completed orders keep the discounts they were sold with
+    if state == "complete" || state == "canceled" || state == "returned" {
+        return Ok(());
+    }
+
+    conn.execute(
+        "DELETE FROM adjustments WHERE order_id = ?1 AND source_type = 'Promotion' AND finalized = 0",
+        (order_id,)
+    ).map_err(|e| e.to_string())?;
+
+    let now = now();
+    let mut stmt = conn.prepare(
+        r#"SELECT p.id, p.name, p.usage_limit, p.per_code_usage_limit,
+           (SELECT promotion_code_id FROM order_promotions WHERE order_id = ?1 AND promotion_id = p.id)
+           FROM promotions p
+           WHERE p.active = 1
+           AND (p.starts_at IS NULL OR p.starts_at <= ?2)
+           AND (p.expires_at IS NULL OR p.expires_at >= ?2)
+           AND (p.apply_automatically = 1
+                OR p.id IN (SELECT promotion_id FROM order_promotions WHERE order_id = ?1))
+           ORDER BY p.id ASC"#
+    ).map_err(|e| e.to_string())?;
+
+    let promos = stmt.query_map((order_id, now), |row| {
+        Ok((
+            row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<i64>>(2)?,
+            row.get::<_, Option<i64>>(3)?, row.get::<_, Option<i64>>(4)?
+        ))
+    }).map_err(|e| e.to_string())?
+    .collect::<ic_rusqlite::Result<Vec<_>>>()
+    .map_err(|e| e.to_string())?;
+
+    if promos.is_empty() {
+        return Ok(());
+    }
+
+    let order = load_promotion_order(conn, order_id)?;
+    let mut candidates = Vec::new();
+
+    for (promo_id, _, usage_limit, per_code_usage_limit, code_id) in &promos {
+        if let Some(limit) = usage_limit {
+            if promotion_usage_count(conn, *promo_id, order_id) >= *limit { continue; }
+        }
+        // A single-use code redeemed by another order in the meantime
+        if let (Some(limit), Some(code_id)) = (per_code_usage_limit, code_id) {
+            if promotion_code_usage_count(conn, *code_id, order_id) >= *limit { continue; }
+        }
+
+        // A misconfigured promotion must not break the cart - skip it
+        match evaluate_promotion_rules(conn, *promo_id, &order) {
+            Ok(true) => {},
+            Ok(false) => continue,
+            Err(e) => {
+                ic_cdk::print(format!("Skipping promotion {}: {}", promo_id, e));
+                continue;
+            }
+        }
+
+        match compute_promotion_adjustments(conn, *promo_id, &order) {
+            Ok(adjustments) => candidates.extend(adjustments.into_iter().map(|a| (*promo_id, a))),
+            Err(e) => ic_cdk::print(format!("Skipping promotion {}: {}", promo_id, e)),
+        }
+    }
+
+    for (promo_id, adjustment) in promotions::best_adjustments(candidates, &order) {
+        let label = promos.iter().find(|p| p.0 == promo_id).map(|p| p.1.clone()).unwrap_or_default();
+        let (adjustable_type, adjustable_id) = match adjustment {
+            promotions::PromoAdjustment::Order { .. } => ("Order", order_id),
+            promotions::PromoAdjustment::LineItem { line_item_id, .. } => ("LineItem", line_item_id),
+            promotions::PromoAdjustment::Shipment { shipment_id, .. } => ("Shipment", shipment_id),
+        };
+
+        conn.execute(
+            r#"INSERT INTO adjustments (source_type, source_id, adjustable_type, adjustable_id, order_id, amount, label, created_at, updated_at)
+               VALUES ('Promotion', ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)"#,
+            (promo_id, adjustable_type, adjustable_id, order_id, adjustment.amount(), &label, now)
+        ).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
 
 #[ic_cdk::query]
-fn get_dashboard_stats() -> Result<DashboardStats, String> {
+fn admin_get_promotions() -> Result<Vec<Promotion>, String> {
     if !is_admin() { return Err("Admin only".to_string()); }
-
     with_connection(|conn| {
-        let total_revenue: i64 = conn.query_row(
-            "SELECT COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete'",
-            [],
-            |row| row.get(0)
-        ).unwrap_or(0);
+        let mut stmt = conn.prepare("SELECT id, name, description, usage_limit, active, starts_at, expires_at, apply_automatically, per_code_usage_limit FROM promotions ORDER BY created_at DESC")
+            .map_err(|e| e.to_string())?;
+        
+        let promos = stmt.query_map([], |row| {
+            let id: i64 = row.get(0)?;
+            
+            // Get usage count (completed orders)
+            let usage_count = promotion_usage_count(&conn, id, 0);
 
-        let total_orders: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM orders WHERE state != 'cart'",
-            [],
-            |row| row.get(0)
-        ).unwrap_or(0);
+            // First code for display; the full list is in admin_get_promotion_codes
+            let (code, code_count): (Option<String>, i64) = conn.query_row(
+                r#"SELECT (SELECT value FROM promotion_codes WHERE promotion_id = ?1 ORDER BY id ASC LIMIT 1),
+                   (SELECT COUNT(*) FROM promotion_codes WHERE promotion_id = ?1)"#,
+                (id,),
+                |row| Ok((row.get(0)?, row.get(1)?))
+            ).unwrap_or((None, 0));
 
-        let pending_orders: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM orders WHERE shipment_state IN ('pending', 'ready')",
-            [],
-            |row| row.get(0)
-        ).unwrap_or(0);
+            Ok(Promotion {
+                id,
+                name: row.get(1)?,
+                description: row.get(2)?,
+                code,
+                starts_at: row.get(5)?,
+                expires_at: row.get(6)?,
+                usage_limit: row.get(3)?,
+                per_code_usage_limit: row.get(8)?,
+                usage_count,
+                code_count,
+                active: row.get::<_, i64>(4)? == 1,
+                apply_automatically: row.get::<_, i64>(7)? == 1,
+                rules: vec![], // don't load all rules for list
+                actions: vec![], // don't load all actions for list
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        
+        Ok(promos)
+    })
+}
 
-        // Count unique customers by email (includes both registered and guest)
-        let total_customers: i64 = conn.query_row(
-            "SELECT COUNT(DISTINCT email) FROM orders WHERE email IS NOT NULL AND email != ''",
-            [],
-            |row| row.get(0)
-        ).unwrap_or(0);
+#[ic_cdk::update]
+fn admin_create_promotion(input: CreatePromotionInput) -> Result<i64, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let now = now();
 
-        // Fetch low stock threshold from settings (default 10)
-        let threshold_str: String = conn.query_row(
-            "SELECT value FROM store_settings WHERE key = 'low_stock_threshold'",
-            [],
-            |row| row.get(0)
-        ).unwrap_or("10".to_string());
-        let threshold: i64 = threshold_str.parse().unwrap_or(10);
+        // Check before inserting - the promotion row would not be rolled back
+        if let Some(ref code) = input.code {
+            let exists: bool = conn.query_row(
+                "SELECT 1 FROM promotion_codes WHERE value = ?1",
+                (code,),
+                |_| Ok(true)
+            ).unwrap_or(false);
+            if exists {
+                return Err(format!("Promotion code '{}' already exists", code));
+            }
+        }
 
-        let low_stock_count: i64 = conn.query_row(
-            &format!("SELECT COUNT(*) FROM stock_items WHERE count_on_hand <= {} AND deleted_at IS NULL", threshold),
-            [],
+        let id: i64 = conn.query_row(
+            r#"INSERT INTO promotions (name, description, active, usage_limit, per_code_usage_limit, starts_at, expires_at, apply_automatically, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9) RETURNING id"#,
+            (&input.name, &input.description, if input.active.unwrap_or(true) { 1 } else { 0 }, &input.usage_limit, &input.per_code_usage_limit,
+             &input.starts_at, &input.expires_at, if input.apply_automatically.unwrap_or(false) { 1 } else { 0 }, now),
             |row| row.get(0)
-        ).unwrap_or(0);
+        ).map_err(|e| e.to_string())?;
 
+        if let Some(code) = input.code {
+            conn.execute(
+                "INSERT INTO promotion_codes (promotion_id, value, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+                (id, &code, now)
+            ).map_err(|e| e.to_string())?;
+        }
 
-        let total_products: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM products WHERE deleted_at IS NULL",
-            [],
-            |row| row.get(0)
-        ).unwrap_or(0);
+        Ok(id)
+    })
+}
 
-        Ok(DashboardStats {
-            total_revenue,
-            total_orders,
-            pending_orders,
-            total_customers,
-            low_stock_count,
-            total_products,
-        })
+#[ic_cdk::update]
+fn admin_update_promotion(id: i64, input: UpdatePromotionInput) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let now = now();
+        if let Some(name) = input.name {
+            conn.execute("UPDATE promotions SET name = ?1, updated_at = ?2 WHERE id = ?3", (name, now, id)).ok();
+        }
+        if let Some(active) = input.active {
+            conn.execute("UPDATE promotions SET active = ?1, updated_at = ?2 WHERE id = ?3", (if active { 1 } else { 0 }, now, id)).ok();
+        }
+        if let Some(automatic) = input.apply_automatically {
+            conn.execute("UPDATE promotions SET apply_automatically = ?1, updated_at = ?2 WHERE id = ?3", (if automatic { 1 } else { 0 }, now, id)).ok();
+        }
+        if let Some(limit) = input.per_code_usage_limit {
+            conn.execute("UPDATE promotions SET per_code_usage_limit = ?1, updated_at = ?2 WHERE id = ?3", (limit, now, id)).ok();
+        }
+        Ok(())
     })
 }
 
-#[ic_cdk::query]
-fn get_revenue_stats() -> Result<RevenueData, String> {
+#[ic_cdk::update]
+fn admin_add_promotion_rule(input: AddPromotionRuleInput) -> Result<i64, String> {
     if !is_admin() { return Err("Admin only".to_string()); }
+    promotions::validate_rule(&input.rule_type, &input.preferences)?;
+    with_connection(|conn| {
+        let now = now();
+        let id: i64 = conn.query_row(
+            "INSERT INTO promotion_rules (promotion_id, rule_type, preferences, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4) RETURNING id",
+            (input.promotion_id, &input.rule_type, &input.preferences, now),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
+        Ok(id)
+    })
+}
 
+#[ic_cdk::update]
+fn admin_add_promotion_action(input: AddPromotionActionInput) -> Result<i64, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    promotions::validate_action(&input.action_type, &input.calculator_type, &input.preferences)?;
     with_connection(|conn| {
-        let now_ns = now();
-        // Convert nanoseconds to seconds for date calculations
-        let now_secs = now_ns / 1_000_000_000;
-        let day_secs: i64 = 86400;
-        let week_secs: i64 = day_secs * 7;
-        let month_secs: i64 = day_secs * 30;
+        let now = now();
+        let id: i64 = conn.query_row(
+            "INSERT INTO promotion_actions (promotion_id, action_type, calculator_type, preferences, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?5) RETURNING id",
+            (input.promotion_id, &input.action_type, &input.calculator_type, &input.preferences, now),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
+        Ok(id)
+    })
+}
 
-        // Start of today (midnight)
-        let start_of_today_secs = (now_secs / day_secs) * day_secs;
-        let start_of_today_ns = start_of_today_secs * 1_000_000_000;
+// Alphabet for generated codes - no 0/O or 1/I to avoid misreading
+const PROMO_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
 
-        // Start of this week (7 days ago)
-        let start_of_week_ns = (now_secs - week_secs) * 1_000_000_000;
+/// Generate `count` unique random codes for a promotion, e.g. single-use
+/// influencer or gift codes (combine with per_code_usage_limit = 1)
+#[ic_cdk::update]
+async fn admin_generate_promotion_codes(promotion_id: i64, count: i64, prefix: String, length: i64) -> Result<Vec<String>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
 
-        // Start of this month (30 days ago)
-        let start_of_month_ns = (now_secs - month_secs) * 1_000_000_000;
+    const MAX_CODES_PER_BATCH: i64 = 1000;
+    if !(1..=MAX_CODES_PER_BATCH).contains(&count) {
+        return Err(format!("Count must be between 1 and {}", MAX_CODES_PER_BATCH));
+    }
+    if !(6..=32).contains(&length) {
+        return Err("Length must be between 6 and 32 characters".to_string());
+    }
+    let prefix = prefix.trim().to_uppercase();
+    if prefix.len() > 20 || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Prefix must be at most 20 letters, digits, '-' or '_'".to_string());
+    }
 
-        // Total revenue & orders (completed only)
-        let total_revenue: i64 = conn.query_row(
-            "SELECT COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete'",
-            [],
-            |row| row.get(0)
-        ).unwrap_or(0);
+    with_connection(|conn| {
+        conn.query_row("SELECT 1 FROM promotions WHERE id = ?1", (promotion_id,), |_| Ok(()))
+            .map_err(|_| "Promotion not found".to_string())
+    })?;
 
-        let total_orders: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM orders WHERE state = 'complete'",
-            [],
-            |row| row.get(0)
-        ).unwrap_or(0);
+    // 32 symbols, so one random byte maps to one character without bias.
+    // Ask for extra bytes so collisions can be replaced without another call.
+    let length = length as usize;
+    let wanted = count as usize;
+    let bytes = random_bytes((wanted + wanted / 10 + 10) * length).await?;
 
-        let average_order_value = if total_orders > 0 {
-            total_revenue / total_orders
-        } else {
-            0
-        };
+    with_connection(|conn| {
+        let now = now();
+        let mut codes: Vec<String> = Vec::with_capacity(wanted);
 
-        // Today's stats
-        let (orders_today, revenue_today): (i64, i64) = conn.query_row(
-            "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete' AND completed_at >= ?1",
-            (start_of_today_ns,),
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).unwrap_or((0, 0));
+        for chunk in bytes.chunks_exact(length) {
+            if codes.len() == wanted { break; }
 
-        // This week's stats
-        let (orders_this_week, revenue_this_week): (i64, i64) = conn.query_row(
-            "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete' AND completed_at >= ?1",
-            (start_of_week_ns,),
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).unwrap_or((0, 0));
+            let random: String = chunk.iter()
+                .map(|b| PROMO_CODE_ALPHABET[(*b as usize) % PROMO_CODE_ALPHABET.len()] as char)
+                .collect();
+            let code = format!("{}{}", prefix, random);
 
-        // This month's stats
-        let (orders_this_month, revenue_this_month): (i64, i64) = conn.query_row(
-            "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete' AND completed_at >= ?1",
-            (start_of_month_ns,),
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).unwrap_or((0, 0));
+            let exists: bool = conn.query_row(
+                "SELECT 1 FROM promotion_codes WHERE value = ?1",
+                (&code,),
+                |_| Ok(true)
+            ).unwrap_or(false);
+            if exists || codes.contains(&code) { continue; }
+            codes.push(code);
+        }
 
-        // Top selling products (by revenue)
-        let mut top_stmt = conn.prepare(
-            r#"SELECT p.id, p.name, SUM(li.quantity) as qty_sold, SUM(li.quantity * li.price) as revenue
-               FROM line_items li
-               JOIN orders o ON o.id = li.order_id
-               JOIN variants v ON v.id = li.variant_id
-               JOIN products p ON p.id = v.product_id
-               WHERE o.state = 'complete'
-               GROUP BY p.id
-               ORDER BY revenue DESC
-               LIMIT 5"#
-        ).map_err(|e| e.to_string())?;
-
-        let top_products: Vec<TopProduct> = top_stmt.query_map([], |row| {
-            Ok(TopProduct {
-                product_id: row.get(0)?,
-                product_name: row.get(1)?,
-                quantity_sold: row.get(2)?,
-                revenue: row.get(3)?,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
-
-        // Recent orders (last 10 completed)
-        let mut recent_stmt = conn.prepare(
-            r#"SELECT id, number, total, completed_at, email
-               FROM orders
-               WHERE state = 'complete'
-               ORDER BY completed_at DESC
-               LIMIT 10"#
-        ).map_err(|e| e.to_string())?;
+        // Nothing is saved unless the whole batch could be generated
+        if codes.len() < wanted {
+            return Err(format!("Only {} unique codes could be generated; use a longer length", codes.len()));
+        }
 
-        let recent_orders: Vec<RecentOrderSummary> = recent_stmt.query_map([], |row| {
-            Ok(RecentOrderSummary {
-                id: row.get(0)?,
-                number: row.get(1)?,
-                total: row.get(2)?,
-                created_at: row.get::<_, Option<i64>>(3)?.unwrap_or(0),
-                email: row.get(4)?,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
+        for code in &codes {
+            conn.execute(
+                "INSERT INTO promotion_codes (promotion_id, value, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+                (promotion_id, code, now)
+            ).map_err(|e| e.to_string())?;
+        }
 
-        Ok(RevenueData {
-            stats: RevenueStats {
-                total_revenue,
-                total_orders,
-                average_order_value,
-                orders_today,
-                revenue_today,
-                orders_this_week,
-                revenue_this_week,
-                orders_this_month,
-                revenue_this_month,
-            },
-            top_products,
-            recent_orders,
-        })
+        Ok(codes)
     })
 }
 
-// ============================================
-// SHIPPING METHODS
-// ============================================
+fn get_promotion_code_list(conn: &Connection, promotion_id: i64) -> Result<Vec<PromotionCode>, String> {
+    let usage_limit: Option<i64> = conn.query_row(
+        "SELECT per_code_usage_limit FROM promotions WHERE id = ?1",
+        (promotion_id,),
+        |row| row.get(0)
+    ).map_err(|_| "Promotion not found".to_string())?;
 
-#[ic_cdk::query]
-fn get_shipping_methods() -> Result<Vec<ShippingMethod>, String> {
-    with_connection(|conn| {
-        let mut stmt = conn.prepare(
-            r#"SELECT id, name, display_on, tracking_url, admin_name, code, carrier, service_level, base_cost, active
-               FROM shipping_methods WHERE deleted_at IS NULL AND active = 1
-               ORDER BY id ASC"#
-        ).map_err(|e| e.to_string())?;
+    let mut stmt = conn.prepare(
+        r#"SELECT pc.id, pc.value, pc.created_at,
+           COUNT(DISTINCT o.id), MAX(o.completed_at)
+           FROM promotion_codes pc
+           LEFT JOIN order_promotions op ON op.promotion_code_id = pc.id
+           LEFT JOIN orders o ON o.id = op.order_id AND o.state = 'complete'
+           WHERE pc.promotion_id = ?1
+           GROUP BY pc.id
+           ORDER BY pc.id ASC"#
+    ).map_err(|e| e.to_string())?;
 
-        let methods: Vec<ShippingMethod> = stmt.query_map([], |row| {
-            Ok(ShippingMethod {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                display_on: row.get(2)?,
-                tracking_url: row.get(3)?,
-                admin_name: row.get(4)?,
-                code: row.get(5)?,
-                carrier: row.get(6)?,
-                service_level: row.get(7)?,
-                cost: row.get(8)?,
-                active: row.get::<_, i64>(9)? == 1,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
+    let codes = stmt.query_map((promotion_id,), |row| {
+        let usage_count: i64 = row.get(3)?;
+        let status = if usage_count == 0 {
+            "unused"
+        } else if usage_limit.map(|l| usage_count >= l).unwrap_or(false) {
+            "exhausted"
+        } else {
+            "redeemed"
+        };
+        Ok(PromotionCode {
+            id: row.get(0)?,
+            value: row.get(1)?,
+            created_at: row.get(2)?,
+            usage_count,
+            usage_limit,
+            status: status.to_string(),
+            last_used_at: row.get(4)?,
+        })
+    }).map_err(|e| e.to_string())?
+    .collect::<ic_rusqlite::Result<Vec<_>>>()
+    .map_err(|e| e.to_string())?;
 
-        Ok(methods)
-    })
+    Ok(codes)
 }
 
-#[ic_cdk::update]
-fn admin_create_shipping_method(input: CreateShippingMethodInput) -> Result<i64, String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
-    with_connection(|conn| {
-        let active: i64 = if input.active.unwrap_or(true) { 1 } else { 0 };
-        conn.execute(
-            r#"INSERT INTO shipping_methods (name, admin_name, code, carrier, service_level, tracking_url, display_on, base_cost, active, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)"#,
-            (
-                &input.name,
-                &input.admin_name,
-                &input.code,
-                &input.carrier,
-                &input.service_level,
-                &input.tracking_url,
-                &input.display_on,
-                &input.base_cost,
-                active,
-                now(),
-            )
-        ).map_err(|e| e.to_string())?;
-        Ok(conn.last_insert_rowid())
-    })
+#[ic_cdk::query]
+fn admin_get_promotion_codes(promotion_id: i64) -> Result<Vec<PromotionCode>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| get_promotion_code_list(&conn, promotion_id))
 }
 
-#[ic_cdk::update]
-fn admin_update_shipping_method(id: i64, input: UpdateShippingMethodInput) -> Result<(), String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
+/// CSV export of a promotion's codes with redemption status
+#[ic_cdk::query]
+fn admin_export_promotion_codes(promotion_id: i64) -> Result<String, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
     with_connection(|conn| {
-        if let Some(name) = input.name {
-            conn.execute("UPDATE shipping_methods SET name = ?1, updated_at = ?2 WHERE id = ?3", (&name, now(), id))?;
-        }
-        if let Some(admin_name) = input.admin_name {
-            conn.execute("UPDATE shipping_methods SET admin_name = ?1, updated_at = ?2 WHERE id = ?3", (&admin_name, now(), id))?;
-        }
-        if let Some(code) = input.code {
-            conn.execute("UPDATE shipping_methods SET code = ?1, updated_at = ?2 WHERE id = ?3", (&code, now(), id))?;
-        }
-        if let Some(carrier) = input.carrier {
-            conn.execute("UPDATE shipping_methods SET carrier = ?1, updated_at = ?2 WHERE id = ?3", (&carrier, now(), id))?;
-        }
-        if let Some(service_level) = input.service_level {
-            conn.execute("UPDATE shipping_methods SET service_level = ?1, updated_at = ?2 WHERE id = ?3", (&service_level, now(), id))?;
-        }
-        if let Some(tracking_url) = input.tracking_url {
-            conn.execute("UPDATE shipping_methods SET tracking_url = ?1, updated_at = ?2 WHERE id = ?3", (&tracking_url, now(), id))?;
-        }
-        if let Some(display_on) = input.display_on {
-            conn.execute("UPDATE shipping_methods SET display_on = ?1, updated_at = ?2 WHERE id = ?3", (&display_on, now(), id))?;
-        }
-        if let Some(base_cost) = input.base_cost {
-            conn.execute("UPDATE shipping_methods SET base_cost = ?1, updated_at = ?2 WHERE id = ?3", (base_cost, now(), id))?;
-        }
-        if let Some(active) = input.active {
-            let active_int: i64 = if active { 1 } else { 0 };
-            conn.execute("UPDATE shipping_methods SET active = ?1, updated_at = ?2 WHERE id = ?3", (active_int, now(), id))?;
+        let codes = get_promotion_code_list(&conn, promotion_id)?;
+
+        let mut csv = String::from("code,status,usage_count,usage_limit,last_used_at,created_at\n");
+        for code in codes {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                csv_field(&code.value),
+                code.status,
+                code.usage_count,
+                code.usage_limit.map(|l| l.to_string()).unwrap_or_default(),
+                code.last_used_at.map(|t| t.to_string()).unwrap_or_default(),
+                code.created_at
+            ));
         }
-        Ok(())
-    }).map_err(|e: ic_rusqlite::Error| e.to_string())
+        Ok(csv)
+    })
 }
 
-#[ic_cdk::update]
-fn admin_delete_shipping_method(id: i64) -> Result<(), String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
-    with_connection(|conn| {
-        conn.execute("UPDATE shipping_methods SET deleted_at = ?1 WHERE id = ?2", (now(), id))?;
-        Ok(())
-    }).map_err(|e: ic_rusqlite::Error| e.to_string())
+// ============================================
+// ABANDONED CARTS
+// ============================================
+
+// Carts left in cart/address/delivery with an email are followed up by the cart_recovery
+// job. Each cart gets a random restore token; the last email of the sequence can carry a
+// single-use code from the cart_recovery_promotion_id promotion.
+
+// Stop following up on carts idle for longer than this
+const CART_RECOVERY_MAX_AGE_DAYS: i64 = 30;
+const CART_RECOVERY_BATCH: i64 = 50;
+const CART_RECOVERY_TOKEN_BYTES: usize = 16;
+const CART_RECOVERY_CODE_LENGTH: usize = 10;
+const HOUR_NS: i64 = 3_600_000_000_000;
+
+// Integer store setting, or `default` when missing or not a number
+fn store_setting_i64(conn: &Connection, key: &str, default: i64) -> i64 {
+    conn.query_row(
+        "SELECT value FROM store_settings WHERE key = ?1",
+        (key,),
+        |row| row.get::<_, String>(0)
+    ).ok()
+    .and_then(|value| value.trim().parse().ok())
+    .unwrap_or(default)
 }
 
-#[ic_cdk::query]
-fn admin_get_shipping_methods() -> Result<Vec<ShippingMethod>, String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
-    with_connection(|conn| {
+// Send the next email of every due recovery sequence
+async fn send_cart_recovery_emails() -> Result<String, String> {
+    let due: Vec<(i64, Option<i64>)> = with_connection(|conn| {
+        let email_ready: bool = conn.query_row(
+            "SELECT active = 1 AND api_key != '' AND provider = 'sendgrid' FROM email_settings WHERE id = 1",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(false);
+        if !email_ready {
+            return Err("Email sending is not configured".to_string());
+        }
+
+        let after_hours = store_setting_i64(&conn, "cart_recovery_after_hours", 4).max(1);
+        let interval_hours = store_setting_i64(&conn, "cart_recovery_interval_hours", 24).max(1);
+        let max_emails = store_setting_i64(&conn, "cart_recovery_max_emails", 2).clamp(0, 5);
+        let now = now();
+
         let mut stmt = conn.prepare(
-            r#"SELECT id, name, display_on, tracking_url, admin_name, code, carrier, service_level, base_cost, active
-               FROM shipping_methods WHERE deleted_at IS NULL
-               ORDER BY id ASC"#
+            r#"SELECT o.id, cr.id FROM orders o
+               LEFT JOIN cart_recoveries cr ON cr.order_id = o.id
+               WHERE o.state IN ('cart', 'address', 'delivery')
+               AND o.email IS NOT NULL AND o.email != ''
+               AND EXISTS (SELECT 1 FROM line_items WHERE order_id = o.id)
+               AND o.updated_at <= ?1 AND o.updated_at >= ?2
+               AND COALESCE(cr.emails_sent, 0) < ?3
+               AND (cr.last_email_at IS NULL OR cr.last_email_at <= ?4)
+               AND NOT EXISTS (SELECT 1 FROM orders c WHERE LOWER(c.email) = LOWER(o.email) AND c.completed_at > o.updated_at)
+               ORDER BY o.updated_at ASC
+               LIMIT ?5"#
         ).map_err(|e| e.to_string())?;
 
-        let methods: Vec<ShippingMethod> = stmt.query_map([], |row| {
-            Ok(ShippingMethod {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                display_on: row.get(2)?,
-                tracking_url: row.get(3)?,
-                admin_name: row.get(4)?,
-                code: row.get(5)?,
-                carrier: row.get(6)?,
-                service_level: row.get(7)?,
-                cost: row.get(8)?,
-                active: row.get::<_, i64>(9)? == 1,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
+        let due = stmt.query_map(
+            (now - after_hours * HOUR_NS, now - CART_RECOVERY_MAX_AGE_DAYS * 24 * HOUR_NS, max_emails,
+             now - interval_hours * HOUR_NS, CART_RECOVERY_BATCH),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
 
-        Ok(methods)
-    })
-}
+        Ok(due)
+    })?;
 
-// ============================================
-// TAX RATES
-// ============================================
+    if due.is_empty() {
+        return Ok("No abandoned carts due".to_string());
+    }
 
-#[ic_cdk::query]
-fn get_tax_rates() -> Result<Vec<TaxRate>, String> {
-    with_connection(|conn| {
-        let mut stmt = conn.prepare(
-            r#"SELECT tr.id, tr.name, tr.amount, tr.zone_id, z.name as zone_name, 
-                      tr.tax_category_id, tc.name as category_name, tr.included_in_price, tr.show_rate_in_label
-               FROM tax_rates tr
-               LEFT JOIN zones z ON tr.zone_id = z.id
-               LEFT JOIN tax_categories tc ON tr.tax_category_id = tc.id
-               WHERE tr.deleted_at IS NULL"#
-        ).map_err(|e| e.to_string())?;
+    // Restore token and a possible promotion code per cart
+    let chunk_len = CART_RECOVERY_TOKEN_BYTES + CART_RECOVERY_CODE_LENGTH;
+    let bytes = random_bytes(due.len() * chunk_len).await?;
 
-        let rates: Vec<TaxRate> = stmt.query_map([], |row| {
-            Ok(TaxRate {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                amount: row.get(2)?,
-                zone_id: row.get(3)?,
-                zone_name: row.get(4)?,
-                tax_category_id: row.get(5)?,
-                tax_category_name: row.get(6)?,
-                included_in_price: row.get(7)?,
-                show_rate_in_label: row.get(8)?,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
+    let emails = with_connection(|conn| {
+        let now = now();
+        let (_, store_url) = feed_store(&conn)
+            .map_err(|_| "Set the store_url store setting to build cart restore links".to_string())?;
+        let max_emails = store_setting_i64(&conn, "cart_recovery_max_emails", 2).clamp(0, 5);
+        let promotion_id: Option<i64> = conn.query_row(
+            "SELECT id FROM promotions WHERE id = ?1 AND active = 1 AND per_code_usage_limit = 1",
+            (store_setting_i64(&conn, "cart_recovery_promotion_id", 0),),
+            |row| row.get(0)
+        ).ok();
 
-        Ok(rates)
-    })
-}
+        let mut emails = Vec::new();
+        for ((order_id, recovery_id), chunk) in due.iter().zip(bytes.chunks_exact(chunk_len)) {
+            // The cart may have been checked out while waiting for randomness
+            let cart: Option<(String, i64, String)> = conn.query_row(
+                r#"SELECT o.email, o.total, COALESCE(a.firstname, 'there') FROM orders o
+                   LEFT JOIN addresses a ON a.id = COALESCE(o.bill_address_id, o.ship_address_id)
+                   WHERE o.id = ?1 AND o.state IN ('cart', 'address', 'delivery')"#,
+                (order_id,),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            ).ok();
+            let (email, total, customer_name) = match cart {
+                Some(cart) => cart,
+                None => continue,
+            };
 
-#[ic_cdk::update]
-fn admin_create_tax_rate(input: CreateTaxRateInput) -> Result<i64, String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
-    with_connection(|conn| {
-        conn.execute(
-            r#"INSERT INTO tax_rates (name, amount, zone_id, tax_category_id, included_in_price, show_rate_in_label, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6, ?6)"#,
-            (
-                &input.name,
-                &input.amount,
-                &input.zone_id,
-                &input.tax_category_id,
-                &input.included_in_price.unwrap_or(false),
-                now(),
-            )
-        ).map_err(|e| e.to_string())?;
-        Ok(conn.last_insert_rowid())
-    })
-}
-
-#[ic_cdk::update]
-fn admin_update_tax_rate(id: i64, input: UpdateTaxRateInput) -> Result<(), String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
-    with_connection(|conn| {
-        if let Some(name) = input.name {
-            conn.execute("UPDATE tax_rates SET name = ?1, updated_at = ?2 WHERE id = ?3", (&name, now(), id))?;
-        }
-        if let Some(amount) = input.amount {
-            conn.execute("UPDATE tax_rates SET amount = ?1, updated_at = ?2 WHERE id = ?3", (&amount, now(), id))?;
-        }
-        if let Some(zone_id) = input.zone_id {
-            conn.execute("UPDATE tax_rates SET zone_id = ?1, updated_at = ?2 WHERE id = ?3", (zone_id, now(), id))?;
-        }
-        if let Some(tax_category_id) = input.tax_category_id {
-            conn.execute("UPDATE tax_rates SET tax_category_id = ?1, updated_at = ?2 WHERE id = ?3", (tax_category_id, now(), id))?;
-        }
-        if let Some(included_in_price) = input.included_in_price {
-            conn.execute("UPDATE tax_rates SET included_in_price = ?1, updated_at = ?2 WHERE id = ?3", (included_in_price, now(), id))?;
-        }
-        Ok(())
-    }).map_err(|e: ic_rusqlite::Error| e.to_string())
-}
+            let (token_bytes, code_bytes) = chunk.split_at(CART_RECOVERY_TOKEN_BYTES);
+            let recovery_id: i64 = match recovery_id {
+                Some(id) => *id,
+                None => conn.query_row(
+                    r#"INSERT INTO cart_recoveries (order_id, token, email, created_at, updated_at)
+                       VALUES (?1, ?2, ?3, ?4, ?4) RETURNING id"#,
+                    (order_id, hex::encode(token_bytes), &email, now),
+                    |row| row.get(0)
+                ).map_err(|e| e.to_string())?,
+            };
 
-#[ic_cdk::update]
-fn admin_delete_tax_rate(id: i64) -> Result<(), String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
-    with_connection(|conn| {
-        conn.execute("UPDATE tax_rates SET deleted_at = ?1 WHERE id = ?2", (now(), id))?;
-        Ok(())
-    }).map_err(|e: ic_rusqlite::Error| e.to_string())
-}
+            let (token, emails_sent, mut code_id): (String, i64, Option<i64>) = conn.query_row(
+                "SELECT token, emails_sent, promotion_code_id FROM cart_recoveries WHERE id = ?1",
+                (recovery_id,),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            ).map_err(|e| e.to_string())?;
+            let step = emails_sent + 1;
+
+            // Last email in the sequence carries the incentive
+            if let (Some(promotion_id), None, true) = (promotion_id, code_id, step == max_emails) {
+                let code: String = code_bytes.iter()
+                    .map(|b| PROMO_CODE_ALPHABET[(*b as usize) % PROMO_CODE_ALPHABET.len()] as char)
+                    .collect();
+                let exists: bool = conn.query_row(
+                    "SELECT 1 FROM promotion_codes WHERE value = ?1",
+                    (&code,),
+                    |_| Ok(true)
+                ).unwrap_or(false);
+                if !exists {
+                    code_id = Some(conn.query_row(
+                        "INSERT INTO promotion_codes (promotion_id, value, created_at, updated_at) VALUES (?1, ?2, ?3, ?3) RETURNING id",
+                        (promotion_id, &code, now),
+                        |row| row.get(0)
+                    ).map_err(|e| e.to_string())?);
+                }
+            }
 
-#[ic_cdk::query]
-fn get_zones() -> Result<Vec<Zone>, String> {
-    with_connection(|conn| {
-        let mut stmt = conn.prepare("SELECT id, name, description, zone_members_count FROM zones").map_err(|e| e.to_string())?;
-        let zone_rows: Vec<(i64, String, Option<String>, i64)> = stmt.query_map([], |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
+            conn.execute(
+                r#"UPDATE cart_recoveries SET emails_sent = ?1, last_email_at = ?2, promotion_code_id = ?3, email = ?4, updated_at = ?2
+                   WHERE id = ?5"#,
+                (step, now, code_id, &email, recovery_id)
+            ).map_err(|e| e.to_string())?;
+            let email_id: i64 = conn.query_row(
+                "INSERT INTO cart_recovery_emails (cart_recovery_id, step, sent_at) VALUES (?1, ?2, ?3) RETURNING id",
+                (recovery_id, step, now),
+                |row| row.get(0)
+            ).map_err(|e| e.to_string())?;
 
-        let mut zones = Vec::new();
-        for (id, name, description, member_count) in zone_rows {
-            // Get members for this zone
-            let mut member_stmt = conn.prepare(
-                "SELECT id, zoneable_type, zoneable_id FROM zone_members WHERE zone_id = ?1"
+            let mut stmt = conn.prepare(
+                r#"SELECT li.quantity, p.name, li.price FROM line_items li
+                   JOIN variants v ON v.id = li.variant_id
+                   JOIN products p ON p.id = v.product_id
+                   WHERE li.order_id = ?1 ORDER BY li.id"#
             ).map_err(|e| e.to_string())?;
-            let members: Vec<ZoneMember> = member_stmt.query_map([id], |row| {
-                Ok(ZoneMember {
-                    id: row.get(0)?,
-                    zoneable_type: row.get(1)?,
-                    zoneable_id: row.get(2)?,
-                })
+            let items_text = stmt.query_map((order_id,), |row| {
+                Ok(format!("{} x {} - ${:.2}", row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)? as f64 / 100.0))
             }).map_err(|e| e.to_string())?
-            .collect::<ic_rusqlite::Result<Vec<_>>>()
-            .map_err(|e| e.to_string())?;
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+            .join("\n");
 
-            zones.push(Zone {
-                id,
-                name,
-                description,
-                member_count,
-                members,
-            });
+            let code: Option<String> = code_id.and_then(|id| conn.query_row(
+                "SELECT value FROM promotion_codes WHERE id = ?1",
+                (id,),
+                |row| row.get(0)
+            ).ok());
+
+            emails.push((email_id, email, vec![
+                ("customer_name", customer_name),
+                ("cart_items", items_text),
+                ("cart_total", format!("${:.2}", total as f64 / 100.0)),
+                ("restore_url", format!("{}/cart/restore?token={}", store_url, token)),
+                ("promotion_message", code.as_ref().map(|c| format!("Use code {} at checkout.", c)).unwrap_or_default()),
+                ("promotion_code", code.unwrap_or_default()),
+            ]));
         }
-        Ok(zones)
-    })
-}
-
-#[ic_cdk::update]
-fn admin_create_zone(input: CreateZoneInput) -> Result<i64, String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
-    with_connection(|conn| {
-        let now = now();
-        conn.execute(
-            "INSERT INTO zones (name, description, zone_members_count, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
-            (&input.name, &input.description, input.members.len() as i64, now)
-        ).map_err(|e| e.to_string())?;
 
-        let zone_id = conn.last_insert_rowid();
+        Ok::<_, String>(emails)
+    })?;
 
-        // Insert members
-        for member in &input.members {
-            conn.execute(
-                "INSERT INTO zone_members (zone_id, zoneable_type, zoneable_id, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
-                (zone_id, &member.zoneable_type, &member.zoneable_id, now)
-            ).map_err(|e| e.to_string())?;
+    let mut sent = 0;
+    for (email_id, to, vars) in emails {
+        let error = match send_template_email("cart_abandoned", to, vars).await {
+            Ok(true) => {
+                sent += 1;
+                None
+            }
+            Ok(false) => Some("Email not configured or template disabled".to_string()),
+            Err(e) => Some(e),
+        };
+        if let Some(error) = error {
+            with_connection(|conn| {
+                conn.execute(
+                    "UPDATE cart_recovery_emails SET error = ?1 WHERE id = ?2",
+                    (error, email_id)
+                ).ok();
+            });
         }
+    }
 
-        Ok(zone_id)
-    })
+    Ok(format!("{} recovery emails sent", sent))
 }
 
+/// Reopen a cart from a recovery email link. Guest carts move to the caller's session
+/// (or account when signed in); account carts can only be restored by that account.
+/// Any other open cart of the caller is merged into the restored one.
 #[ic_cdk::update]
-fn admin_update_zone(id: i64, input: UpdateZoneInput) -> Result<(), String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
+fn restore_cart(token: String, session_id: Option<String>) -> Result<OrderDetail, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    if is_anonymous && session_id.is_none() {
+        return Err("Session ID required".to_string());
+    }
+
     with_connection(|conn| {
         let now = now();
 
-        if let Some(name) = input.name {
-            conn.execute("UPDATE zones SET name = ?1, updated_at = ?2 WHERE id = ?3", (&name, now, id))
-                .map_err(|e| e.to_string())?;
+        let (recovery_id, order_id, owner_id, state): (i64, i64, Option<i64>, String) = conn.query_row(
+            r#"SELECT cr.id, o.id, o.user_id, o.state FROM cart_recoveries cr
+               JOIN orders o ON o.id = cr.order_id
+               WHERE cr.token = ?1"#,
+            (token.trim(),),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        ).map_err(|_| "Invalid cart link".to_string())?;
+
+        if !matches!(state.as_str(), "cart" | "address" | "delivery") {
+            return Err("This cart is no longer available".to_string());
         }
-        if let Some(description) = input.description {
-            conn.execute("UPDATE zones SET description = ?1, updated_at = ?2 WHERE id = ?3", (&description, now, id))
-                .map_err(|e| e.to_string())?;
+        if let Some(owner_id) = owner_id {
+            if user_id != Some(owner_id) {
+                return Err("Sign in to restore this cart".to_string());
+            }
         }
 
-        // If members provided, replace all members
-        if let Some(members) = input.members {
-            // Delete existing members
-            conn.execute("DELETE FROM zone_members WHERE zone_id = ?1", [id])
-                .map_err(|e| e.to_string())?;
-
-            // Insert new members
-            for member in &members {
-                conn.execute(
-                    "INSERT INTO zone_members (zone_id, zoneable_type, zoneable_id, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
-                    (id, &member.zoneable_type, &member.zoneable_id, now)
-                ).map_err(|e| e.to_string())?;
-            }
+        let current_id: Option<i64> = if is_anonymous {
+            conn.query_row(
+                r#"SELECT id FROM orders
+                   WHERE guest_token = ?1 AND user_id IS NULL AND state IN ('cart', 'address', 'delivery') AND id != ?2
+                   ORDER BY id DESC LIMIT 1"#,
+                (&session_id, order_id),
+                |row| row.get(0)
+            ).ok()
+        } else {
+            conn.query_row(
+                r#"SELECT id FROM orders
+                   WHERE (user_id = ?1 OR user_principal = ?2) AND state IN ('cart', 'address', 'delivery') AND id != ?3
+                   ORDER BY updated_at DESC LIMIT 1"#,
+                (user_id, &caller_str, order_id),
+                |row| row.get(0)
+            ).ok()
+        };
 
-            // Update member count
-            conn.execute("UPDATE zones SET zone_members_count = ?1, updated_at = ?2 WHERE id = ?3",
-                (members.len() as i64, now, id))
+        if let Some(current_id) = current_id {
+            // The merged cart is gone, so its own recovery sequence ends
+            conn.execute(
+                "DELETE FROM cart_recovery_emails WHERE cart_recovery_id IN (SELECT id FROM cart_recoveries WHERE order_id = ?1)",
+                (current_id,)
+            ).map_err(|e| e.to_string())?;
+            conn.execute("DELETE FROM cart_recoveries WHERE order_id = ?1", (current_id,))
                 .map_err(|e| e.to_string())?;
+            merge_guest_order(&conn, current_id, order_id)?;
         }
 
-        Ok(())
-    })
-}
+        if is_anonymous {
+            conn.execute(
+                "UPDATE orders SET guest_token = ?1, updated_at = ?2 WHERE id = ?3",
+                (&session_id, now, order_id)
+            ).map_err(|e| e.to_string())?;
+        } else {
+            conn.execute(
+                "UPDATE orders SET user_id = ?1, user_principal = ?2, guest_token = NULL, updated_at = ?3 WHERE id = ?4",
+                (user_id, &caller_str, now, order_id)
+            ).map_err(|e| e.to_string())?;
+        }
 
-#[ic_cdk::update]
-fn admin_delete_zone(id: i64) -> Result<(), String> {
-    if !is_admin() { return Err("Unauthorized".to_string()); }
-    with_connection(|conn| {
-        // Delete members first
-        conn.execute("DELETE FROM zone_members WHERE zone_id = ?1", [id])
-            .map_err(|e| e.to_string())?;
-        // Delete zone
-        conn.execute("DELETE FROM zones WHERE id = ?1", [id])
-            .map_err(|e| e.to_string())?;
-        Ok(())
+        conn.execute(
+            "UPDATE cart_recoveries SET restored_at = COALESCE(restored_at, ?1), updated_at = ?1 WHERE id = ?2",
+            (now, recovery_id)
+        ).map_err(|e| e.to_string())?;
+
+        cap_line_items_to_stock(&conn, order_id)?;
+        recalculate_order(&conn, order_id)?;
+        get_order_detail(&conn, order_id)
     })
 }
 
+/// Carts with an email idle in cart/address/delivery for at least `hours` (default: the
+/// cart_recovery_after_hours setting), and recovery results for sequences started in the
+/// last `days`. An order counts as recovered when the emailed cart is later completed.
 #[ic_cdk::query]
-fn get_tax_categories() -> Result<Vec<TaxCategory>, String> {
+fn admin_get_abandoned_carts(params: AbandonedCartQuery) -> Result<AbandonedCartListResponse, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params.per_page.unwrap_or(20).clamp(1, 100);
+    let days = params.days.unwrap_or(30).clamp(1, 365);
+
     with_connection(|conn| {
-        let mut stmt = conn.prepare("SELECT id, name, description, is_default, tax_code FROM tax_categories").map_err(|e| e.to_string())?;
-        let items: Vec<TaxCategory> = stmt.query_map([], |row| {
-            Ok(TaxCategory {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                description: row.get(2)?,
-                is_default: row.get(3)?,
-                tax_code: row.get(4)?,
+        let now = now();
+        let hours = params.hours
+            .unwrap_or_else(|| store_setting_i64(&conn, "cart_recovery_after_hours", 4))
+            .max(0);
+        let idle_before = now - hours * HOUR_NS;
+
+        let filter = r#"o.state IN ('cart', 'address', 'delivery')
+            AND o.email IS NOT NULL AND o.email != ''
+            AND EXISTS (SELECT 1 FROM line_items WHERE order_id = o.id)
+            AND o.updated_at <= ?1"#;
+
+        let total_count: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM orders o WHERE {}", filter),
+            (idle_before,),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
+
+        let mut stmt = conn.prepare(&format!(
+            r#"SELECT o.id, o.number, o.email, o.state, o.user_id IS NOT NULL,
+               (SELECT COALESCE(SUM(quantity), 0) FROM line_items WHERE order_id = o.id),
+               o.total, o.updated_at, COALESCE(cr.emails_sent, 0), cr.last_email_at, cr.restored_at, pc.value
+               FROM orders o
+               LEFT JOIN cart_recoveries cr ON cr.order_id = o.id
+               LEFT JOIN promotion_codes pc ON pc.id = cr.promotion_code_id
+               WHERE {}
+               ORDER BY o.updated_at DESC
+               LIMIT ?2 OFFSET ?3"#,
+            filter
+        )).map_err(|e| e.to_string())?;
+
+        let carts = stmt.query_map((idle_before, per_page, (page - 1) * per_page), |row| {
+            Ok(AbandonedCart {
+                order_id: row.get(0)?,
+                number: row.get(1)?,
+                email: row.get(2)?,
+                state: row.get(3)?,
+                customer_type: if row.get::<_, bool>(4)? { "registered" } else { "guest" }.to_string(),
+                item_count: row.get(5)?,
+                total: row.get(6)?,
+                updated_at: row.get(7)?,
+                emails_sent: row.get(8)?,
+                last_email_at: row.get(9)?,
+                restored_at: row.get(10)?,
+                promotion_code: row.get(11)?,
             })
         }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
-        Ok(items)
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        let mut stats = conn.query_row(
+            r#"SELECT COUNT(*), COALESCE(SUM(cr.emails_sent), 0), COUNT(cr.restored_at),
+               COUNT(CASE WHEN o.completed_at IS NOT NULL AND o.state != 'canceled' THEN 1 END),
+               COALESCE(SUM(CASE WHEN o.completed_at IS NOT NULL AND o.state != 'canceled' THEN o.total END), 0),
+               COUNT(CASE WHEN o.completed_at IS NOT NULL AND o.state != 'canceled' AND EXISTS (
+                   SELECT 1 FROM order_promotions op WHERE op.order_id = o.id AND op.promotion_code_id = cr.promotion_code_id
+               ) THEN 1 END)
+               FROM cart_recoveries cr
+               JOIN orders o ON o.id = cr.order_id
+               WHERE cr.emails_sent > 0 AND cr.created_at >= ?1"#,
+            (now - days * 24 * HOUR_NS,),
+            |row| Ok(CartRecoveryStats {
+                carts_emailed: row.get(0)?,
+                emails_sent: row.get(1)?,
+                carts_restored: row.get(2)?,
+                orders_recovered: row.get(3)?,
+                revenue_recovered: row.get(4)?,
+                codes_redeemed: row.get(5)?,
+                recovery_rate: 0.0,
+            })
+        ).map_err(|e| e.to_string())?;
+        if stats.carts_emailed > 0 {
+            stats.recovery_rate = stats.orders_recovered as f64 / stats.carts_emailed as f64;
+        }
+
+        let total_pages = ((total_count as f64) / (per_page as f64)).ceil() as i64;
+
+        Ok(AbandonedCartListResponse {
+            carts,
+            total_count,
+            page,
+            per_page,
+            total_pages,
+            stats,
+        })
     })
 }
 
 // ============================================
-// HELPER FUNCTIONS
+// GIFT CARDS
 // ============================================
 
-fn recalculate_order(conn: &Connection, order_id: i64) -> Result<(), String> {
-    let now = now();
-
-    // 1. Recalculate Taxes
-    apply_tax_adjustments(conn, order_id).ok();
+const GIFT_CARD_CODE_LENGTH: usize = 16;
 
-    // 2. Calculate item total and count
-    let (item_total, item_count): (i64, i64) = conn.query_row(
-        "SELECT COALESCE(SUM(price * quantity), 0), COALESCE(SUM(quantity), 0) FROM line_items WHERE order_id = ?1",
-        (order_id,),
-        |row| Ok((row.get(0)?, row.get(1)?))
-    ).map_err(|e| e.to_string())?;
+// Codes are stored upper-case without separators, so "abcd-efgh ..." also matches
+fn normalize_gift_card_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
 
-    // 3. Sum adjustments
-    let adjustment_total: i64 = conn.query_row(
-        "SELECT COALESCE(SUM(amount), 0) FROM adjustments WHERE order_id = ?1",
+// Gift card balance applied to an order (pending or captured)
+fn gift_card_payment_total(conn: &Connection, order_id: i64) -> i64 {
+    conn.query_row(
+        r#"SELECT COALESCE(SUM(amount), 0) FROM payments
+           WHERE order_id = ?1 AND source_type = 'GiftCard' AND state IN ('checkout', 'completed')"#,
         (order_id,),
         |row| row.get(0)
-    ).unwrap_or(0);
+    ).unwrap_or(0)
+}
 
-    let promo_total: i64 = conn.query_row(
-        "SELECT COALESCE(SUM(amount), 0) FROM adjustments WHERE order_id = ?1 AND source_type = 'Promotion'",
-        (order_id,),
-        |row| row.get(0)
-    ).unwrap_or(0);
+// Amount left to pay after gift cards
+fn order_amount_due(conn: &Connection, order_id: i64) -> i64 {
+    let total: i64 = conn.query_row("SELECT total FROM orders WHERE id = ?1", (order_id,), |row| row.get(0)).unwrap_or(0);
+    (total - gift_card_payment_total(conn, order_id)).max(0)
+}
 
-    let tax_total: i64 = conn.query_row(
-        "SELECT COALESCE(SUM(amount), 0) FROM adjustments WHERE order_id = ?1 AND source_type = 'TaxRate'",
-        (order_id,),
-        |row| row.get(0)
-    ).unwrap_or(0);
+// Re-clamp applied gift cards to min(balance, remaining total) after the order total changes
+fn update_gift_card_payments(conn: &Connection, order_id: i64) -> Result<(), String> {
+    let now = now();
+    let total: i64 = conn.query_row("SELECT total FROM orders WHERE id = ?1", (order_id,), |row| row.get(0))
+        .map_err(|e| e.to_string())?;
 
-    // 4. Get shipment total
-    let shipment_total: i64 = conn.query_row(
-        "SELECT COALESCE(SUM(cost), 0) FROM shipments WHERE order_id = ?1",
-        (order_id,),
-        |row| row.get(0)
-    ).unwrap_or(0);
+    let mut stmt = conn.prepare(
+        r#"SELECT p.id, gc.balance, gc.state, gc.expires_at
+           FROM payments p
+           JOIN gift_cards gc ON gc.id = p.source_id
+           WHERE p.order_id = ?1 AND p.source_type = 'GiftCard' AND p.state = 'checkout'
+           ORDER BY p.id"#
+    ).map_err(|e| e.to_string())?;
 
-    let total = item_total + shipment_total + adjustment_total;
+    let applied: Vec<(i64, i64, String, Option<i64>)> = stmt.query_map((order_id,), |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    }).map_err(|e| e.to_string())?
+    .collect::<ic_rusqlite::Result<Vec<_>>>()
+    .map_err(|e| e.to_string())?;
+    drop(stmt);
 
-    conn.execute(
-        r#"UPDATE orders SET
-           item_total = ?1, item_count = ?2, shipment_total = ?3,
-           adjustment_total = ?4, promo_total = ?5, additional_tax_total = ?6,
-           total = ?7, updated_at = ?8
-           WHERE id = ?9"#,
-        (item_total, item_count, shipment_total, adjustment_total, promo_total, tax_total, total, now, order_id)
-    ).map_err(|e| e.to_string())?;
+    let mut remaining = total.max(0);
+    for (payment_id, balance, state, expires_at) in applied {
+        let usable = if state == "active" && expires_at.is_none_or(|e| now <= e) { balance } else { 0 };
+        let amount = usable.min(remaining);
+        remaining -= amount;
+
+        conn.execute(
+            "UPDATE payments SET amount = ?1, updated_at = ?2 WHERE id = ?3",
+            (amount, now, payment_id)
+        ).map_err(|e| e.to_string())?;
+    }
 
     Ok(())
 }
 
-fn apply_tax_adjustments(conn: &Connection, order_id: i64) -> Result<(), String> {
+// On order completion: capture applied gift cards and create the purchased ones.
+// Returns true when newly purchased gift cards are waiting for a code.
+fn complete_gift_cards(conn: &Connection, order_id: i64) -> Result<bool, String> {
     let now = now();
 
-    // Get shipping address state/country
-    let address: Option<(String, String)> = conn.query_row(
-        r#"SELECT country_code, state_name FROM addresses a
-           JOIN orders o ON o.ship_address_id = a.id
-           WHERE o.id = ?1"#,
-        (order_id,),
-        |row| Ok((row.get(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default()))
-    ).ok();
+    // Capture redemptions
+    let mut stmt = conn.prepare(
+        r#"SELECT id, amount, source_id FROM payments
+           WHERE order_id = ?1 AND source_type = 'GiftCard' AND state = 'checkout'"#
+    ).map_err(|e| e.to_string())?;
 
-    if let Some((country, state)) = address {
-        // Find matching zonemembers and then zones
-        let mut zone_stmt = conn.prepare(
-            r#"SELECT DISTINCT z.id FROM zones z
-               JOIN zone_members zm ON zm.zone_id = z.id
-               WHERE (zm.zoneable_type = 'Country' AND zm.zoneable_id = ?1)
-               OR (zm.zoneable_type = 'State' AND zm.zoneable_id = ?2)"#
+    let redemptions: Vec<(i64, i64, i64)> = stmt.query_map((order_id,), |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    }).map_err(|e| e.to_string())?
+    .collect::<ic_rusqlite::Result<Vec<_>>>()
+    .map_err(|e| e.to_string())?;
+    drop(stmt);
+
+    let mut shortfall = 0;
+    for (payment_id, amount, gift_card_id) in redemptions {
+        let (balance, state, expires_at): (i64, String, Option<i64>) = conn.query_row(
+            "SELECT balance, state, expires_at FROM gift_cards WHERE id = ?1",
+            (gift_card_id,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
         ).map_err(|e| e.to_string())?;
 
-        let zone_ids: Vec<i64> = zone_stmt.query_map((&country, &state), |row| row.get(0))
-            .map_err(|e| e.to_string())?
-            .collect::<ic_rusqlite::Result<Vec<i64>>>().map_err(|e| e.to_string())?;
-
-        // Clear existing tax adjustments
-        conn.execute("DELETE FROM adjustments WHERE order_id = ?1 AND source_type = 'TaxRate'", (order_id,)).ok();
-
-        if !zone_ids.is_empty() {
-            // Find tax rates for these zones using parameterized query
-            // Build placeholders for IN clause: ?1, ?2, ?3, etc.
-            let placeholders: String = zone_ids.iter().enumerate()
-                .map(|(i, _)| format!("?{}", i + 1))
-                .collect::<Vec<_>>()
-                .join(",");
+        let usable = if state == "active" && expires_at.is_none_or(|e| now <= e) { balance } else { 0 };
+        let captured = amount.min(usable);
+        shortfall += amount - captured;
 
-            let mut rate_stmt = conn.prepare(
-                &format!("SELECT id, name, amount FROM tax_rates WHERE zone_id IN ({})", placeholders)
+        if captured <= 0 {
+            conn.execute(
+                "UPDATE payments SET amount = 0, state = 'invalid', updated_at = ?1 WHERE id = ?2",
+                (now, payment_id)
             ).map_err(|e| e.to_string())?;
-
-            // Convert zone_ids to params slice for rusqlite
-            let params: Vec<&dyn ic_rusqlite::ToSql> = zone_ids.iter()
-                .map(|id| id as &dyn ic_rusqlite::ToSql)
-                .collect();
-
-            let rates = rate_stmt.query_map(params.as_slice(), |row| {
-                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, f64>(2)?))
-            }).map_err(|e| e.to_string())?;
-
-            let item_total: i64 = conn.query_row("SELECT item_total FROM orders WHERE id = ?1", (order_id,), |row| row.get(0)).unwrap_or(0);
-
-            for rate in rates {
-                let (rate_id, rate_name, amount) = rate.map_err(|e| e.to_string())?;
-                let tax_amount = (item_total as f64 * amount) as i64;
-                
-                if tax_amount != 0 {
-                    conn.execute(
-                        r#"INSERT INTO adjustments (source_type, source_id, adjustable_type, adjustable_id, order_id, amount, label, created_at, updated_at)
-                           VALUES ('TaxRate', ?1, 'Order', ?2, ?2, ?3, ?4, ?5, ?5)"#,
-                        (rate_id, order_id, tax_amount, format!("Tax: {}", rate_name), now)
-                    ).ok();
-                }
-            }
+            continue;
         }
-    }
 
-    Ok(())
-}
+        let balance_after = balance - captured;
+        conn.execute(
+            r#"UPDATE gift_cards SET balance = ?1,
+               state = CASE WHEN ?1 = 0 THEN 'depleted' ELSE state END,
+               updated_at = ?2 WHERE id = ?3"#,
+            (balance_after, now, gift_card_id)
+        ).map_err(|e| e.to_string())?;
 
-fn get_order_by_state(conn: &Connection, principal: &str, state: &str) -> Result<Option<OrderDetail>, String> {
-    let order_id: Option<i64> = conn.query_row(
-        "SELECT id FROM orders WHERE user_principal = ?1 AND state = ?2",
-        (principal, state),
-        |row| row.get(0)
-    ).ok();
+        conn.execute(
+            r#"INSERT INTO gift_card_transactions (gift_card_id, order_id, payment_id, action, amount, balance_after, created_at)
+               VALUES (?1, ?2, ?3, 'redeem', ?4, ?5, ?6)"#,
+            (gift_card_id, order_id, payment_id, -captured, balance_after, now)
+        ).map_err(|e| e.to_string())?;
 
-    match order_id {
-        Some(id) => Ok(Some(get_order_detail(conn, id)?)),
-        None => Ok(None),
+        conn.execute(
+            "UPDATE payments SET amount = ?1, state = 'completed', updated_at = ?2 WHERE id = ?3",
+            (captured, now, payment_id)
+        ).map_err(|e| e.to_string())?;
     }
-}
 
-fn get_order_detail(conn: &Connection, order_id: i64) -> Result<OrderDetail, String> {
-    let order: (i64, String, Option<String>, String, i64, i64, i64, i64, i64, Option<String>, Option<String>, Option<i64>, Option<i64>, Option<i64>, i64) = conn.query_row(
-        r#"SELECT id, number, email, state, item_total, shipment_total, total, item_count,
-           adjustment_total, payment_state, shipment_state,
-           ship_address_id, bill_address_id, completed_at, created_at
-           FROM orders WHERE id = ?1"#,
-        (order_id,),
-        |row| Ok((
-            row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?,
-            row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?, row.get(9)?,
-            row.get(10)?, row.get(11)?, row.get(12)?, row.get(13)?, row.get(14)?
-        ))
-    ).map_err(|e| e.to_string())?;
+    // A card was spent elsewhere between checkout and payment
+    if shortfall > 0 {
+        conn.execute(
+            "UPDATE orders SET payment_state = 'balance_due', updated_at = ?1 WHERE id = ?2",
+            (now, order_id)
+        ).map_err(|e| e.to_string())?;
+    }
 
-    // Get line items
-    let mut line_stmt = conn.prepare(
-        r#"SELECT li.id, li.variant_id, li.quantity, li.price, li.currency,
-           p.name as product_name, p.slug, v.sku,
-           COALESCE(
-               (SELECT attachment_url FROM assets WHERE viewable_type = 'Variant' AND viewable_id = li.variant_id LIMIT 1),
-               (SELECT attachment_url FROM assets WHERE viewable_type = 'Variant' AND viewable_id = (
-                   SELECT id FROM variants WHERE product_id = v.product_id AND is_master = 1 LIMIT 1
-               ) LIMIT 1)
-           ) as image_url
+    // Issue purchased gift cards, one per unit (idempotent per line item)
+    let mut stmt = conn.prepare(
+        r#"SELECT li.id, li.quantity,
+           (SELECT COUNT(*) FROM gift_cards gc WHERE gc.line_item_id = li.id)
            FROM line_items li
            JOIN variants v ON v.id = li.variant_id
            JOIN products p ON p.id = v.product_id
-           WHERE li.order_id = ?1"#
+           WHERE li.order_id = ?1 AND p.gift_card = 1"#
     ).map_err(|e| e.to_string())?;
 
-    let line_items: Vec<LineItemDetail> = line_stmt.query_map((order_id,), |row| {
-        let quantity: i64 = row.get(2)?;
-        let price: i64 = row.get(3)?;
-        Ok(LineItemDetail {
-            id: row.get(0)?,
-            variant_id: row.get(1)?,
-            quantity,
-            price,
-            total: quantity * price,
-            currency: row.get(4)?,
-            product_name: row.get(5)?,
-            product_slug: row.get(6)?,
-            variant_sku: row.get(7)?,
-            image_url: row.get(8)?,
-        })
+    let purchased: Vec<(i64, i64, i64)> = stmt.query_map((order_id,), |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
     }).map_err(|e| e.to_string())?
     .collect::<ic_rusqlite::Result<Vec<_>>>()
     .map_err(|e| e.to_string())?;
+    drop(stmt);
+
+    for (line_item_id, quantity, issued) in purchased {
+        for _ in issued..quantity {
+            // Recipient defaults to the order email
+            let (gift_card_id, amount): (i64, i64) = conn.query_row(
+                r#"INSERT INTO gift_cards (initial_amount, balance, currency, state, order_id, line_item_id,
+                   purchaser_email, recipient_email, recipient_name, message, created_at, updated_at)
+                   SELECT li.price, li.price, li.currency, 'pending', o.id, li.id,
+                   o.email, COALESCE(li.gift_card_recipient_email, o.email), li.gift_card_recipient_name, li.gift_card_message, ?2, ?2
+                   FROM line_items li JOIN orders o ON o.id = li.order_id
+                   WHERE li.id = ?1
+                   RETURNING id, initial_amount"#,
+                (line_item_id, now),
+                |row| Ok((row.get(0)?, row.get(1)?))
+            ).map_err(|e| e.to_string())?;
 
-    // Get shipping address
-    let ship_address: Option<AddressDetail> = if let Some(addr_id) = order.11 {
-        conn.query_row(
-            r#"SELECT id, firstname, lastname, address1, address2, city, state_name, zipcode, country_code, phone
-               FROM addresses WHERE id = ?1"#,
-            (addr_id,),
-            |row| Ok(AddressDetail {
-                id: row.get(0)?,
-                firstname: row.get(1)?,
-                lastname: row.get(2)?,
-                address1: row.get(3)?,
-                address2: row.get(4)?,
-                city: row.get(5)?,
-                state_name: row.get(6)?,
-                zipcode: row.get(7)?,
-                country_code: row.get(8)?,
-                phone: row.get(9)?,
-                is_default: false, // simplified
-            })
-        ).ok()
-    } else {
-        None
-    };
+            conn.execute(
+                r#"INSERT INTO gift_card_transactions (gift_card_id, order_id, action, amount, balance_after, created_at)
+                   VALUES (?1, ?2, 'issue', ?3, ?3, ?4)"#,
+                (gift_card_id, order_id, amount, now)
+            ).map_err(|e| e.to_string())?;
+        }
+    }
 
-    // Get shipment
-    let shipment: Option<ShipmentDetail> = conn.query_row(
-        r#"SELECT s.id, s.number, s.tracking, s.cost, s.state, s.shipped_at,
-           sm.id as method_id, sm.name as method_name
-           FROM shipments s
-           LEFT JOIN shipping_rates sr ON sr.shipment_id = s.id AND sr.selected = 1
-           LEFT JOIN shipping_methods sm ON sm.id = sr.shipping_method_id
-           WHERE s.order_id = ?1"#,
+    let pending: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM gift_cards WHERE order_id = ?1 AND state = 'pending'",
         (order_id,),
-        |row| Ok(ShipmentDetail {
-            id: row.get(0)?,
-            number: row.get(1)?,
-            tracking: row.get(2)?,
-            cost: row.get(3)?,
-            state: row.get(4)?,
-            shipped_at: row.get(5)?,
-            shipping_method_id: row.get(6)?,
-            shipping_method_name: row.get(7)?,
-        })
-    ).ok();
-
-    let adjustments = get_order_adjustments(conn, order_id).unwrap_or_default();
-    let tax_total: i64 = adjustments.iter().filter(|a| a.source_type.as_deref() == Some("TaxRate")).map(|a| a.amount).sum();
-    let promo_total: i64 = adjustments.iter().filter(|a| a.source_type.as_deref() == Some("Promotion")).map(|a| a.amount).sum();
+        |row| row.get(0)
+    ).unwrap_or(0);
 
-    Ok(OrderDetail {
-        id: order.0,
-        number: order.1,
-        email: order.2,
-        state: order.3,
-        item_total: order.4,
-        shipment_total: order.5,
-        tax_total,
-        promo_total,
-        total: order.6,
-        item_count: order.7,
-        adjustment_total: order.8,
-        payment_state: order.9,
-        shipment_state: order.10,
-        completed_at: order.13,
-        created_at: order.14,
-        line_items,
-        ship_address,
-        bill_address: None, // Simplified
-        shipment,
-        adjustments,
-        payments: get_order_payments(conn, order_id).unwrap_or_default(),
-    })
+    Ok(pending > 0)
 }
 
-fn get_order_adjustments(conn: &Connection, order_id: i64) -> Result<Vec<AdjustmentDetail>, String> {
-    let mut stmt = conn.prepare(
-        "SELECT id, label, amount, source_type, adjustable_type, included FROM adjustments WHERE order_id = ?1"
-    ).map_err(|e| e.to_string())?;
+// Assign codes to an order's pending gift cards and email them to the recipients.
+// Codes need raw_rand, so this runs after the (sync) completion paths.
+async fn activate_gift_cards(order_id: i64, resend: bool) -> Result<(), String> {
+    let pending: Vec<i64> = with_connection(|conn| {
+        let mut stmt = conn.prepare("SELECT id FROM gift_cards WHERE order_id = ?1 AND state = 'pending'")
+            .map_err(|e| e.to_string())?;
+        let ids = stmt.query_map((order_id,), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<i64>>>()
+            .map_err(|e| e.to_string())?;
+        Ok::<_, String>(ids)
+    })?;
 
-    let adjustments = stmt.query_map((order_id,), |row| {
-        Ok(AdjustmentDetail {
-            id: row.get(0)?,
-            label: row.get(1)?,
-            amount: row.get(2)?,
-            source_type: row.get(3)?,
-            adjustable_type: row.get(4)?,
-            included: row.get::<_, i64>(5)? == 1,
-        })
-    }).map_err(|e| e.to_string())?
-    .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+    if !pending.is_empty() {
+        // Extra bytes so a collision can be retried without another call
+        let bytes = random_bytes((pending.len() + 4) * GIFT_CARD_CODE_LENGTH).await?;
+
+        with_connection(|conn| {
+            let now = now();
+            let mut chunks = bytes.chunks_exact(GIFT_CARD_CODE_LENGTH);
+
+            for gift_card_id in &pending {
+                loop {
+                    let chunk = chunks.next().ok_or("Could not generate a unique gift card code")?;
+                    let code: String = chunk.iter()
+                        .map(|b| PROMO_CODE_ALPHABET[(*b as usize) % PROMO_CODE_ALPHABET.len()] as char)
+                        .collect();
+
+                    let exists: bool = conn.query_row(
+                        "SELECT 1 FROM gift_cards WHERE code = ?1",
+                        (&code,),
+                        |_| Ok(true)
+                    ).unwrap_or(false);
+                    if exists { continue; }
+
+                    // state guard: a concurrent activation may already have assigned a code
+                    conn.execute(
+                        "UPDATE gift_cards SET code = ?1, state = 'active', updated_at = ?2 WHERE id = ?3 AND state = 'pending'",
+                        (&code, now, gift_card_id)
+                    ).map_err(|e| e.to_string())?;
+                    break;
+                }
+            }
+            Ok::<(), String>(())
+        })?;
+    }
 
-    Ok(adjustments)
-}
+    // Email codes to recipients
+    let to_send: Vec<(i64, String, String, i64, String, String, String)> = with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            r#"SELECT gc.id, gc.code, gc.recipient_email, gc.initial_amount,
+               COALESCE(gc.recipient_name, 'there'), COALESCE(gc.message, ''),
+               COALESCE(a.firstname || ' ' || a.lastname, gc.purchaser_email, 'Someone')
+               FROM gift_cards gc
+               JOIN orders o ON o.id = gc.order_id
+               LEFT JOIN addresses a ON a.id = COALESCE(o.bill_address_id, o.ship_address_id)
+               WHERE gc.order_id = ?1 AND gc.state = 'active' AND gc.code IS NOT NULL
+               AND gc.recipient_email IS NOT NULL AND (gc.sent_at IS NULL OR ?2 = 1)"#
+        ).map_err(|e| e.to_string())?;
+        let rows = stmt.query_map((order_id, if resend { 1 } else { 0 }), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+        Ok::<_, String>(rows)
+    })?;
 
-fn get_order_payments(conn: &Connection, order_id: i64) -> Result<Vec<PaymentDetail>, String> {
-    let mut stmt = conn.prepare(
-        r#"SELECT p.id, p.amount, p.state, pm.name, p.created_at
-           FROM payments p
-           JOIN payment_methods pm ON pm.id = p.payment_method_id
-           WHERE p.order_id = ?1"#
-    ).map_err(|e| e.to_string())?;
+    for (gift_card_id, code, recipient_email, amount, recipient_name, message, sender_name) in to_send {
+        let vars = vec![
+            ("gift_card_code", code),
+            ("amount", format!("${:.2}", amount as f64 / 100.0)),
+            ("recipient_name", recipient_name),
+            ("sender_name", sender_name),
+            ("message", message),
+        ];
 
-    let payments = stmt.query_map((order_id,), |row| {
-        Ok(PaymentDetail {
-            id: row.get(0)?,
-            amount: row.get(1)?,
-            state: row.get(2)?,
-            payment_method_name: row.get(3)?,
-            created_at: row.get(4)?,
-        })
-    }).map_err(|e| e.to_string())?
-    .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        if send_template_email("gift_card", recipient_email, vars).await? {
+            with_connection(|conn| {
+                conn.execute(
+                    "UPDATE gift_cards SET sent_at = ?1, updated_at = ?1 WHERE id = ?2",
+                    (now(), gift_card_id)
+                ).ok();
+            });
+        }
+    }
 
-    Ok(payments)
+    Ok(())
+}
+
+// ic_cdk::spawn polls the task straight away and activation opens its own
+// connection, so this must only be called once with_connection has returned.
+// Calling it inside the completion closure traps on the nested borrow.
+fn spawn_gift_card_activation(order_id: i64) {
+    ic_cdk::spawn(async move {
+        let _ = activate_gift_cards(order_id, false).await;
+    });
+}
+
+/// Apply a gift card to the current checkout as partial (or full) payment
+#[ic_cdk::update]
+fn apply_gift_card(input: ApplyGiftCardInput, session_id: Option<String>) -> Result<OrderDetail, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    if is_anonymous && session_id.is_none() {
+        return Err("Session ID required".to_string());
+    }
+
+    let code = normalize_gift_card_code(&input.code);
+    if code.is_empty() {
+        return Err("Gift card code is required".to_string());
+    }
+
+    with_connection(|conn| {
+        let now = now();
+
+        let order_id: i64 = if is_anonymous {
+            let sess_id = session_id.as_ref().ok_or("Session ID required")?;
+            conn.query_row(
+                "SELECT id FROM orders WHERE guest_token = ?1 AND state IN ('cart', 'address', 'delivery', 'payment')",
+                (&sess_id,),
+                |row| row.get(0)
+            )
+        } else {
+            conn.query_row(
+                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state IN ('cart', 'address', 'delivery', 'payment')",
+                (user_id, &caller_str),
+                |row| row.get(0)
+            )
+        }.map_err(|_| "No active cart found".to_string())?;
+
+        let (gift_card_id, balance, state, expires_at): (i64, i64, String, Option<i64>) = conn.query_row(
+            "SELECT id, balance, state, expires_at FROM gift_cards WHERE code = ?1",
+            (&code,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        ).map_err(|_| "Invalid gift card code".to_string())?;
+
+        if state != "active" || balance <= 0 {
+            return Err("Gift card has no remaining balance".to_string());
+        }
+        if let Some(end) = expires_at {
+            if now > end { return Err("Gift card has expired".to_string()); }
+        }
+
+        let already_applied: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM payments WHERE order_id = ?1 AND source_type = 'GiftCard' AND source_id = ?2 AND state = 'checkout'",
+            (order_id, gift_card_id),
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        if already_applied > 0 {
+            return Err("Gift card already applied to this order".to_string());
+        }
+
+        let payment_method_id: i64 = conn.query_row(
+            "SELECT id FROM payment_methods WHERE type = 'GiftCard' AND active = 1 LIMIT 1",
+            [],
+            |row| row.get(0)
+        ).map_err(|_| "Gift card payments are not enabled".to_string())?;
+
+        // Amount is set by update_gift_card_payments during recalculation
+        conn.execute(
+            r#"INSERT INTO payments (order_id, payment_method_id, amount, state, source_type, source_id, created_at, updated_at)
+               VALUES (?1, ?2, 0, 'checkout', 'GiftCard', ?3, ?4, ?4)"#,
+            (order_id, payment_method_id, gift_card_id, now)
+        ).map_err(|e| e.to_string())?;
+
+        // The amount due changed; don't reuse a checkout session for the old amount
+        conn.execute(
+            "UPDATE payment_intents SET status = 'canceled', updated_at = ?1 WHERE order_id = ?2 AND status = 'checkout_session'",
+            (now, order_id)
+        ).ok();
+
+        recalculate_order(&conn, order_id)?;
+        get_order_detail(&conn, order_id)
+    })
+}
+
+#[ic_cdk::update]
+fn remove_gift_card(input: ApplyGiftCardInput, session_id: Option<String>) -> Result<OrderDetail, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    if is_anonymous && session_id.is_none() {
+        return Err("Session ID required".to_string());
+    }
+
+    let code = normalize_gift_card_code(&input.code);
+
+    with_connection(|conn| {
+        let now = now();
+
+        let order_id: i64 = if is_anonymous {
+            let sess_id = session_id.as_ref().ok_or("Session ID required")?;
+            conn.query_row(
+                "SELECT id FROM orders WHERE guest_token = ?1 AND state IN ('cart', 'address', 'delivery', 'payment')",
+                (&sess_id,),
+                |row| row.get(0)
+            )
+        } else {
+            conn.query_row(
+                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state IN ('cart', 'address', 'delivery', 'payment')",
+                (user_id, &caller_str),
+                |row| row.get(0)
+            )
+        }.map_err(|_| "No active cart found".to_string())?;
+
+        let removed = conn.execute(
+            r#"DELETE FROM payments WHERE order_id = ?1 AND source_type = 'GiftCard' AND state = 'checkout'
+               AND source_id = (SELECT id FROM gift_cards WHERE code = ?2)"#,
+            (order_id, &code)
+        ).map_err(|e| e.to_string())?;
+
+        if removed == 0 {
+            return Err("Gift card is not applied to this order".to_string());
+        }
+
+        conn.execute(
+            "UPDATE payment_intents SET status = 'canceled', updated_at = ?1 WHERE order_id = ?2 AND status = 'checkout_session'",
+            (now, order_id)
+        ).ok();
+
+        recalculate_order(&conn, order_id)?;
+        get_order_detail(&conn, order_id)
+    })
+}
+
+/// Set who receives the gift card bought on this line item (defaults to the order email)
+#[ic_cdk::update]
+fn set_gift_card_recipient(line_item_id: i64, input: GiftCardRecipientInput, session_id: Option<String>) -> Result<OrderDetail, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    if is_anonymous && session_id.is_none() {
+        return Err("Session ID required".to_string());
+    }
+
+    let email = input.email.trim().to_string();
+    if email.is_empty() || email.len() > 255 || !email.contains('@') {
+        return Err("A valid recipient email is required".to_string());
+    }
+    if input.name.as_ref().is_some_and(|n| n.len() > 100) {
+        return Err("Recipient name must be 100 characters or less".to_string());
+    }
+    if input.message.as_ref().is_some_and(|m| m.len() > 500) {
+        return Err("Message must be 500 characters or less".to_string());
+    }
+
+    with_connection(|conn| {
+        let now = now();
+
+        // Line item must be a gift card in the caller's open order
+        let order_id: i64 = conn.query_row(
+            r#"SELECT o.id FROM line_items li
+               JOIN orders o ON o.id = li.order_id
+               JOIN variants v ON v.id = li.variant_id
+               JOIN products p ON p.id = v.product_id
+               WHERE li.id = ?1 AND p.gift_card = 1
+               AND o.state IN ('cart', 'address', 'delivery', 'payment')
+               AND ((?2 = 1 AND o.guest_token = ?3) OR (?2 = 0 AND (o.user_id = ?4 OR o.user_principal = ?5)))"#,
+            (line_item_id, if is_anonymous { 1 } else { 0 }, &session_id, user_id, &caller_str),
+            |row| row.get(0)
+        ).map_err(|_| "Gift card item not found in your cart".to_string())?;
+
+        conn.execute(
+            r#"UPDATE line_items SET gift_card_recipient_email = ?1, gift_card_recipient_name = ?2,
+               gift_card_message = ?3, updated_at = ?4 WHERE id = ?5"#,
+            (&email, &input.name, &input.message, now, line_item_id)
+        ).map_err(|e| e.to_string())?;
+
+        get_order_detail(&conn, order_id)
+    })
 }
 
+/// Public balance lookup by code
 #[ic_cdk::query]
-fn get_payment_methods() -> Result<Vec<PaymentMethod>, String> {
+fn get_gift_card_balance(code: String) -> Result<GiftCardBalance, String> {
+    let code = normalize_gift_card_code(&code);
+    with_connection(|conn| {
+        conn.query_row(
+            "SELECT balance, currency, state, expires_at FROM gift_cards WHERE code = ?1",
+            (&code,),
+            |row| Ok(GiftCardBalance {
+                balance: row.get(0)?,
+                currency: row.get(1)?,
+                state: row.get(2)?,
+                expires_at: row.get(3)?,
+            })
+        ).map_err(|_| "Gift card not found".to_string())
+    })
+}
+
+#[ic_cdk::query]
+fn admin_get_gift_cards() -> Result<Vec<GiftCard>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
     with_connection(|conn| {
         let mut stmt = conn.prepare(
-            "SELECT id, name, type, description, active, auto_capture, position, test_mode, publishable_key, api_key
-             FROM payment_methods WHERE active = 1 AND available_to_users = 1 ORDER BY position ASC"
+            r#"SELECT gc.id, gc.code, gc.initial_amount, gc.balance, gc.currency, gc.state, o.number,
+               gc.purchaser_email, gc.recipient_email, gc.recipient_name, gc.expires_at, gc.sent_at, gc.created_at
+               FROM gift_cards gc
+               LEFT JOIN orders o ON o.id = gc.order_id
+               ORDER BY gc.created_at DESC, gc.id DESC"#
         ).map_err(|e| e.to_string())?;
 
-        let methods = stmt.query_map([], |row| {
-            let api_key: Option<String> = row.get(9)?;
-            let api_key_set = api_key.map(|k| !k.is_empty()).unwrap_or(false);
-            Ok(PaymentMethod {
+        let cards = stmt.query_map([], |row| {
+            Ok(GiftCard {
                 id: row.get(0)?,
-                name: row.get(1)?,
-                method_type: row.get(2)?,
-                description: row.get(3)?,
-                active: row.get::<_, i64>(4)? == 1,
-                auto_capture: row.get::<_, i64>(5)? == 1,
-                position: row.get(6)?,
-                available_to_users: true,
-                available_to_admin: true,
-                test_mode: Some(row.get::<_, i64>(7)? == 1),
-                api_key_set,
-                publishable_key: row.get(8)?,
+                code: row.get(1)?,
+                initial_amount: row.get(2)?,
+                balance: row.get(3)?,
+                currency: row.get(4)?,
+                state: row.get(5)?,
+                order_number: row.get(6)?,
+                purchaser_email: row.get(7)?,
+                recipient_email: row.get(8)?,
+                recipient_name: row.get(9)?,
+                expires_at: row.get(10)?,
+                sent_at: row.get(11)?,
+                created_at: row.get(12)?,
             })
         }).map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
 
-        Ok(methods)
+        Ok(cards)
     })
 }
 
+/// Balance ledger for one gift card
 #[ic_cdk::query]
-fn admin_get_payment_methods() -> Result<Vec<PaymentMethod>, String> {
+fn admin_get_gift_card_transactions(gift_card_id: i64) -> Result<Vec<GiftCardTransaction>, String> {
     if !is_admin() { return Err("Admin only".to_string()); }
+
     with_connection(|conn| {
         let mut stmt = conn.prepare(
-            "SELECT id, name, type, description, active, auto_capture, position, test_mode, publishable_key, api_key
-             FROM payment_methods ORDER BY position ASC"
+            r#"SELECT t.id, o.number, t.action, t.amount, t.balance_after, t.created_at
+               FROM gift_card_transactions t
+               LEFT JOIN orders o ON o.id = t.order_id
+               WHERE t.gift_card_id = ?1
+               ORDER BY t.id ASC"#
         ).map_err(|e| e.to_string())?;
 
-        let methods = stmt.query_map([], |row| {
-            let api_key: Option<String> = row.get(9)?;
-            let api_key_set = api_key.map(|k| !k.is_empty()).unwrap_or(false);
-            Ok(PaymentMethod {
+        let transactions = stmt.query_map((gift_card_id,), |row| {
+            Ok(GiftCardTransaction {
                 id: row.get(0)?,
-                name: row.get(1)?,
-                method_type: row.get(2)?,
-                description: row.get(3)?,
-                active: row.get::<_, i64>(4)? == 1,
-                auto_capture: row.get::<_, i64>(5)? == 1,
-                position: row.get(6)?,
-                available_to_users: true,
-                available_to_admin: true,
-                test_mode: Some(row.get::<_, i64>(7)? == 1),
-                api_key_set,
-                publishable_key: row.get(8)?,
+                order_number: row.get(1)?,
+                action: row.get(2)?,
+                amount: row.get(3)?,
+                balance_after: row.get(4)?,
+                created_at: row.get(5)?,
             })
         }).map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
 
-        Ok(methods)
+        Ok(transactions)
     })
 }
 
+/// Activate any pending gift cards on an order and (re)send their emails
 #[ic_cdk::update]
-fn admin_update_payment_method(id: i64, input: UpdatePaymentMethodInput) -> Result<(), String> {
+async fn admin_resend_gift_cards(order_id: i64) -> Result<(), String> {
     if !is_admin() { return Err("Admin only".to_string()); }
-    with_connection(|conn| {
-        let now = now();
-        if let Some(name) = input.name {
-            conn.execute("UPDATE payment_methods SET name = ?1, updated_at = ?2 WHERE id = ?3", (name, now, id)).ok();
-        }
-        if let Some(active) = input.active {
-            conn.execute("UPDATE payment_methods SET active = ?1, updated_at = ?2 WHERE id = ?3", (if active { 1 } else { 0 }, now, id)).ok();
-        }
-        if let Some(test_mode) = input.test_mode {
-            conn.execute("UPDATE payment_methods SET test_mode = ?1, updated_at = ?2 WHERE id = ?3", (if test_mode { 1 } else { 0 }, now, id)).ok();
-        }
-        if let Some(api_key) = input.api_key {
-            conn.execute("UPDATE payment_methods SET api_key = ?1, updated_at = ?2 WHERE id = ?3", (api_key, now, id)).ok();
-        }
-        if let Some(publishable_key) = input.publishable_key {
-            conn.execute("UPDATE payment_methods SET publishable_key = ?1, updated_at = ?2 WHERE id = ?3", (publishable_key, now, id)).ok();
-        }
-        if let Some(webhook_secret) = input.webhook_secret {
-            conn.execute("UPDATE payment_methods SET webhook_secret = ?1, updated_at = ?2 WHERE id = ?3", (webhook_secret, now, id)).ok();
-        }
-        Ok(())
-    })
+    activate_gift_cards(order_id, true).await
 }
 
 // ============================================
-// ADMIN: USERS
+// WISHLISTS
 // ============================================
 
-#[ic_cdk::query]
-fn admin_get_users(params: UserQueryParams) -> Result<UserListResponse, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+const MAX_WISHLISTS_PER_USER: i64 = 20;
+const MAX_WISHLIST_ITEMS: i64 = 200;
+const SAVED_FOR_LATER: &str = "Saved for later";
 
-    with_connection(|conn| {
-        let limit = params.per_page.unwrap_or(20);
-        let offset = (params.page.unwrap_or(1) - 1) * limit;
+// A wishlist with its items; the share token is only included for the owner
+fn load_wishlist(conn: &Connection, wishlist_id: i64, include_token: bool) -> Result<Wishlist, String> {
+    let (name, is_public, share_token, created_at, updated_at): (String, bool, Option<String>, i64, i64) = conn.query_row(
+        "SELECT name, is_public, share_token, created_at, updated_at FROM wishlists WHERE id = ?1",
+        (wishlist_id,),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+    ).map_err(|_| "Wishlist not found".to_string())?;
 
-        // Escape SQL LIKE wildcards to prevent query slowdown attacks
-        let search_pattern = params.q.as_ref().map(|q| {
-            let escaped = q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
-            format!("%{}%", escaped)
-        });
+    let mut stmt = conn.prepare(&format!(
+        r#"SELECT wi.id, v.id, p.id, p.name, p.slug, v.sku, COALESCE(pr.amount, 0),
+           COALESCE(
+               (SELECT attachment_url FROM assets WHERE viewable_type = 'Variant' AND viewable_id = v.id ORDER BY position LIMIT 1),
+               (SELECT a.attachment_url FROM assets a JOIN variants mv ON mv.id = a.viewable_id
+                WHERE a.viewable_type = 'Variant' AND mv.product_id = p.id AND mv.is_master = 1 ORDER BY a.position LIMIT 1)
+           ),
+           {} > 0,
+           v.deleted_at IS NULL AND p.deleted_at IS NULL,
+           wi.quantity, wi.notify_in_stock, wi.created_at
+           FROM wishlist_items wi
+           JOIN variants v ON v.id = wi.variant_id
+           JOIN products p ON p.id = v.product_id
+           LEFT JOIN prices pr ON pr.variant_id = v.id AND pr.deleted_at IS NULL
+           WHERE wi.wishlist_id = ?1
+           ORDER BY wi.created_at DESC, wi.id DESC"#,
+        available_stock_sql("v.id")
+    )).map_err(|e| e.to_string())?;
+
+    let items = stmt.query_map((wishlist_id,), |row| {
+        Ok(WishlistItem {
+            id: row.get(0)?,
+            variant_id: row.get(1)?,
+            product_id: row.get(2)?,
+            product_name: row.get(3)?,
+            product_slug: row.get(4)?,
+            sku: row.get(5)?,
+            price: row.get(6)?,
+            image_url: row.get(7)?,
+            in_stock: row.get(8)?,
+            available: row.get(9)?,
+            quantity: row.get(10)?,
+            notify_in_stock: row.get(11)?,
+            added_at: row.get(12)?,
+        })
+    }).map_err(|e| e.to_string())?
+    .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
 
-        let (query, count_query) = match (&search_pattern, &params.role) {
-            (Some(_), Some(_)) => (
-                format!("SELECT id, principal, email, role, created_at FROM users WHERE (principal LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\') AND role = ?2 ORDER BY created_at DESC LIMIT {} OFFSET {}", limit, offset),
-                "SELECT COUNT(*) FROM users WHERE (principal LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\') AND role = ?2".to_string()
-            ),
-            (Some(_), None) => (
-                format!("SELECT id, principal, email, role, created_at FROM users WHERE (principal LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\') ORDER BY created_at DESC LIMIT {} OFFSET {}", limit, offset),
-                "SELECT COUNT(*) FROM users WHERE (principal LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\')".to_string()
-            ),
-            (None, Some(_)) => (
-                format!("SELECT id, principal, email, role, created_at FROM users WHERE role = ?1 ORDER BY created_at DESC LIMIT {} OFFSET {}", limit, offset),
-                "SELECT COUNT(*) FROM users WHERE role = ?1".to_string()
-            ),
-            (None, None) => (
-                format!("SELECT id, principal, email, role, created_at FROM users ORDER BY created_at DESC LIMIT {} OFFSET {}", limit, offset),
-                "SELECT COUNT(*) FROM users".to_string()
-            ),
-        };
+    Ok(Wishlist {
+        id: wishlist_id,
+        name,
+        is_public,
+        share_token: if include_token { share_token } else { None },
+        items,
+        created_at,
+        updated_at,
+    })
+}
 
-        let total_count: i64 = match (&search_pattern, &params.role) {
-            (Some(pattern), Some(role)) => conn.query_row(&count_query, (pattern, role), |row| row.get(0)).unwrap_or(0),
-            (Some(pattern), None) => conn.query_row(&count_query, (pattern,), |row| row.get(0)).unwrap_or(0),
-            (None, Some(role)) => conn.query_row(&count_query, (role,), |row| row.get(0)).unwrap_or(0),
-            (None, None) => conn.query_row(&count_query, [], |row| row.get(0)).unwrap_or(0),
-        };
+fn require_own_wishlist(conn: &Connection, wishlist_id: i64, user_id: i64) -> Result<(), String> {
+    conn.query_row(
+        "SELECT 1 FROM wishlists WHERE id = ?1 AND user_id = ?2",
+        (wishlist_id, user_id),
+        |_| Ok(())
+    ).map_err(|_| "Wishlist not found".to_string())
+}
 
-        let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
+// Wishlist with the given name, created (private) when missing
+fn find_or_create_wishlist(conn: &Connection, user_id: i64, name: &str) -> Result<i64, String> {
+    if let Ok(id) = conn.query_row(
+        "SELECT id FROM wishlists WHERE user_id = ?1 AND name = ?2 ORDER BY id LIMIT 1",
+        (user_id, name),
+        |row| row.get(0)
+    ) {
+        return Ok(id);
+    }
 
-        // Execute query with appropriate parameters
-        let rows: Vec<(i64, String, Option<String>, String, i64)> = match (&search_pattern, &params.role) {
-            (Some(pattern), Some(role)) => {
-                stmt.query_map((pattern, role), |row| {
-                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
-                }).map_err(|e| e.to_string())?
-                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
-            },
-            (Some(pattern), None) => {
-                stmt.query_map((pattern,), |row| {
-                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
-                }).map_err(|e| e.to_string())?
-                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
-            },
-            (None, Some(role)) => {
-                stmt.query_map((role,), |row| {
-                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
-                }).map_err(|e| e.to_string())?
-                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
-            },
-            (None, None) => {
-                stmt.query_map([], |row| {
-                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
-                }).map_err(|e| e.to_string())?
-                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM wishlists WHERE user_id = ?1", (user_id,), |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    if count >= MAX_WISHLISTS_PER_USER {
+        return Err(format!("You can have at most {} wishlists", MAX_WISHLISTS_PER_USER));
+    }
+
+    let now = now();
+    conn.query_row(
+        "INSERT INTO wishlists (user_id, name, created_at, updated_at) VALUES (?1, ?2, ?3, ?3) RETURNING id",
+        (user_id, name, now),
+        |row| row.get(0)
+    ).map_err(|e| e.to_string())
+}
+
+// Add a variant to a wishlist, or update its quantity when it is already there
+fn upsert_wishlist_item(conn: &Connection, wishlist_id: i64, variant_id: i64, quantity: i64, notify_in_stock: Option<bool>) -> Result<(), String> {
+    let now = now();
+
+    conn.query_row(
+        "SELECT 1 FROM variants WHERE id = ?1 AND deleted_at IS NULL",
+        (variant_id,),
+        |_| Ok(())
+    ).map_err(|_| "Variant not found".to_string())?;
+
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM wishlist_items WHERE wishlist_id = ?1 AND variant_id != ?2",
+        (wishlist_id, variant_id),
+        |row| row.get(0)
+    ).map_err(|e| e.to_string())?;
+    if count >= MAX_WISHLIST_ITEMS {
+        return Err(format!("A wishlist can hold at most {} items", MAX_WISHLIST_ITEMS));
+    }
+
+    conn.execute(
+        r#"INSERT INTO wishlist_items (wishlist_id, variant_id, quantity, notify_in_stock, created_at, updated_at)
+           VALUES (?1, ?2, ?3, COALESCE(?4, 1), ?5, ?5)
+           ON CONFLICT(wishlist_id, variant_id) DO UPDATE SET
+           quantity = ?3, notify_in_stock = COALESCE(?4, notify_in_stock), updated_at = ?5"#,
+        (wishlist_id, variant_id, quantity, notify_in_stock, now)
+    ).map_err(|e| e.to_string())?;
+    conn.execute("UPDATE wishlists SET updated_at = ?1 WHERE id = ?2", (now, wishlist_id))
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+fn clean_wishlist_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > 60 {
+        return Err("Wishlist name must be 1 to 60 characters".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// The caller's wishlists with their items
+#[ic_cdk::query]
+fn get_wishlists() -> Result<Vec<Wishlist>, String> {
+    let user_id = get_current_user_id().ok_or("Sign in to use wishlists")?;
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare("SELECT id FROM wishlists WHERE user_id = ?1 ORDER BY id")
+            .map_err(|e| e.to_string())?;
+        let ids: Vec<i64> = stmt.query_map((user_id,), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        ids.into_iter().map(|id| load_wishlist(&conn, id, true)).collect()
+    })
+}
+
+/// A public wishlist by its share token (no login needed)
+#[ic_cdk::query]
+fn get_shared_wishlist(share_token: String) -> Result<Wishlist, String> {
+    with_connection(|conn| {
+        let id: i64 = conn.query_row(
+            "SELECT id FROM wishlists WHERE share_token = ?1 AND is_public = 1",
+            (share_token.trim(),),
+            |row| row.get(0)
+        ).map_err(|_| "Wishlist not found".to_string())?;
+
+        load_wishlist(&conn, id, false)
+    })
+}
+
+#[ic_cdk::update]
+async fn create_wishlist(input: CreateWishlistInput) -> Result<Wishlist, String> {
+    let user_id = get_current_user_id().ok_or("Sign in to use wishlists")?;
+    let name = clean_wishlist_name(&input.name)?;
+    let is_public = input.is_public.unwrap_or(false);
+
+    let share_token = if is_public { Some(hex::encode(random_bytes(16).await?)) } else { None };
+
+    with_connection(|conn| {
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM wishlists WHERE user_id = ?1", (user_id,), |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        if count >= MAX_WISHLISTS_PER_USER {
+            return Err(format!("You can have at most {} wishlists", MAX_WISHLISTS_PER_USER));
+        }
+
+        let now = now();
+        let id: i64 = conn.query_row(
+            r#"INSERT INTO wishlists (user_id, name, is_public, share_token, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?5) RETURNING id"#,
+            (user_id, &name, is_public, &share_token, now),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
+
+        load_wishlist(&conn, id, true)
+    })
+}
+
+/// Rename a wishlist or change its visibility. Making it public creates the share token
+/// once; making it private again disables the link without changing the token.
+#[ic_cdk::update]
+async fn update_wishlist(id: i64, input: UpdateWishlistInput) -> Result<Wishlist, String> {
+    let user_id = get_current_user_id().ok_or("Sign in to use wishlists")?;
+    let name = input.name.as_deref().map(clean_wishlist_name).transpose()?;
+
+    let has_token: bool = with_connection(|conn| {
+        require_own_wishlist(&conn, id, user_id)?;
+        conn.query_row("SELECT share_token IS NOT NULL FROM wishlists WHERE id = ?1", (id,), |row| row.get(0))
+            .map_err(|e| e.to_string())
+    })?;
+
+    let new_token = if input.is_public == Some(true) && !has_token {
+        Some(hex::encode(random_bytes(16).await?))
+    } else {
+        None
+    };
+
+    with_connection(|conn| {
+        // Re-checked after the await in case the list was deleted meanwhile
+        require_own_wishlist(&conn, id, user_id)?;
+        conn.execute(
+            r#"UPDATE wishlists SET name = COALESCE(?1, name), is_public = COALESCE(?2, is_public),
+               share_token = COALESCE(share_token, ?3), updated_at = ?4
+               WHERE id = ?5"#,
+            (&name, input.is_public, &new_token, now(), id)
+        ).map_err(|e| e.to_string())?;
+
+        load_wishlist(&conn, id, true)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_wishlist(id: i64) -> Result<(), String> {
+    let user_id = get_current_user_id().ok_or("Sign in to use wishlists")?;
+
+    with_connection(|conn| {
+        require_own_wishlist(&conn, id, user_id)?;
+        conn.execute("DELETE FROM wishlist_items WHERE wishlist_id = ?1", (id,))
+            .map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM wishlists WHERE id = ?1", (id,))
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    })
+}
+
+/// Add a variant to a wishlist (the caller's first list when none is given, created if needed)
+#[ic_cdk::update]
+fn add_to_wishlist(input: AddToWishlistInput) -> Result<Wishlist, String> {
+    let user_id = get_current_user_id().ok_or("Sign in to use wishlists")?;
+    let quantity = input.quantity.unwrap_or(1);
+    if !(1..=999).contains(&quantity) {
+        return Err("Quantity must be between 1 and 999".to_string());
+    }
+
+    with_connection(|conn| {
+        let wishlist_id = match input.wishlist_id {
+            Some(id) => {
+                require_own_wishlist(&conn, id, user_id)?;
+                id
+            }
+            None => match conn.query_row(
+                "SELECT id FROM wishlists WHERE user_id = ?1 AND name != ?2 ORDER BY id LIMIT 1",
+                (user_id, SAVED_FOR_LATER),
+                |row| row.get(0)
+            ) {
+                Ok(id) => id,
+                Err(_) => find_or_create_wishlist(&conn, user_id, "Wishlist")?,
             },
         };
 
-        let users: Vec<UserDetail> = rows.into_iter().map(|(user_id, principal, email, role, created_at)| {
-            // Get order count and total spent
-            let (order_count, total_spent): (i64, i64) = conn.query_row(
-                "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE user_id = ?1",
-                (user_id,),
-                |orow| Ok((orow.get(0)?, orow.get(1)?))
-            ).unwrap_or((0, 0));
+        upsert_wishlist_item(&conn, wishlist_id, input.variant_id, quantity, input.notify_in_stock)?;
+        load_wishlist(&conn, wishlist_id, true)
+    })
+}
 
-            UserDetail {
-                id: user_id,
-                principal,
-                email,
-                role: role.clone(),
-                roles: vec![role],
-                permissions: vec![],
-                created_at,
-                order_count,
-                total_spent,
-            }
-        }).collect();
+#[ic_cdk::update]
+fn remove_from_wishlist(wishlist_item_id: i64) -> Result<Wishlist, String> {
+    let user_id = get_current_user_id().ok_or("Sign in to use wishlists")?;
 
-        Ok(UserListResponse {
-            users,
-            total_count,
-            page: params.page.unwrap_or(1),
-            per_page: limit,
-            total_pages: (total_count as f64 / limit as f64).ceil() as i64,
-        })
+    with_connection(|conn| {
+        let wishlist_id: i64 = conn.query_row(
+            r#"SELECT wi.wishlist_id FROM wishlist_items wi
+               JOIN wishlists w ON w.id = wi.wishlist_id
+               WHERE wi.id = ?1 AND w.user_id = ?2"#,
+            (wishlist_item_id, user_id),
+            |row| row.get(0)
+        ).map_err(|_| "Wishlist item not found".to_string())?;
+
+        conn.execute("DELETE FROM wishlist_items WHERE id = ?1", (wishlist_item_id,))
+            .map_err(|e| e.to_string())?;
+        conn.execute("UPDATE wishlists SET updated_at = ?1 WHERE id = ?2", (now(), wishlist_id))
+            .map_err(|e| e.to_string())?;
+
+        load_wishlist(&conn, wishlist_id, true)
     })
 }
 
-#[ic_cdk::query]
-fn admin_get_customers(params: CustomerQueryParams) -> Result<CustomerListResponse, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+/// Move a wishlist item into the cart (its saved quantity unless one is given).
+/// The item stays on the list when keep_in_wishlist is true.
+#[ic_cdk::update]
+fn move_wishlist_item_to_cart(wishlist_item_id: i64, quantity: Option<i64>, keep_in_wishlist: Option<bool>) -> Result<OrderDetail, String> {
+    let user_id = get_current_user_id().ok_or("Sign in to use wishlists")?;
+
+    let (variant_id, saved_quantity): (i64, i64) = with_connection(|conn| {
+        conn.query_row(
+            r#"SELECT wi.variant_id, wi.quantity FROM wishlist_items wi
+               JOIN wishlists w ON w.id = wi.wishlist_id
+               WHERE wi.id = ?1 AND w.user_id = ?2"#,
+            (wishlist_item_id, user_id),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Wishlist item not found".to_string())
+    })?;
+
+    // add_to_cart checks price and stock; nothing is removed if it fails
+    let order = add_to_cart(variant_id, quantity.unwrap_or(saved_quantity), None)?;
+
+    if !keep_in_wishlist.unwrap_or(false) {
+        with_connection(|conn| {
+            conn.execute("DELETE FROM wishlist_items WHERE id = ?1", (wishlist_item_id,))
+                .map_err(|e| e.to_string())
+        })?;
+    }
+
+    Ok(order)
+}
+
+/// Move a cart line item to the caller's "Saved for later" list
+#[ic_cdk::update]
+fn save_for_later(line_item_id: i64) -> Result<OrderDetail, String> {
+    let caller_str = ic_cdk::api::caller().to_string();
+    let user_id = get_current_user_id().ok_or("Sign in to save items for later")?;
 
     with_connection(|conn| {
-        let limit = params.per_page.unwrap_or(50);
-        let page = params.page.unwrap_or(1);
-        let offset = (page - 1) * limit;
+        let (variant_id, quantity): (i64, i64) = conn.query_row(
+            r#"SELECT li.variant_id, li.quantity FROM line_items li
+               JOIN orders o ON o.id = li.order_id
+               WHERE li.id = ?1 AND (o.user_id = ?2 OR o.user_principal = ?3)
+               AND o.state IN ('cart', 'address', 'delivery')"#,
+            (line_item_id, user_id, &caller_str),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Line item not found".to_string())?;
 
-        // Escape SQL LIKE wildcards to prevent query slowdown attacks
-        let search_pattern = params.q.as_ref().map(|q| {
-            let escaped = q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
-            format!("%{}%", escaped)
+        let wishlist_id = find_or_create_wishlist(&conn, user_id, SAVED_FOR_LATER)?;
+        upsert_wishlist_item(&conn, wishlist_id, variant_id, quantity, None)
+    })?;
+
+    update_line_item(line_item_id, 0, None)
+}
+
+// ============================================
+// STOCK NOTIFICATIONS
+// ============================================
+
+// Queue back-in-stock emails for a variant that just became available again
+fn queue_back_in_stock(conn: &Connection, variant_id: i64) -> Result<(), String> {
+    let now = now();
+
+    // Explicit subscriptions first (guests included)
+    conn.execute(
+        r#"INSERT INTO stock_notifications (variant_id, user_id, email, source, created_at)
+           SELECT ?1, ss.user_id, ss.email, 'subscription', ?2 FROM stock_subscriptions ss
+           WHERE ss.variant_id = ?1 AND ss.state = 'active'
+           AND NOT EXISTS (SELECT 1 FROM stock_notifications sn
+                           WHERE sn.variant_id = ?1 AND sn.state = 'pending' AND LOWER(sn.email) = ss.email)"#,
+        (variant_id, now)
+    ).map_err(|e| e.to_string())?;
+
+    // Wishlist owners; users.email is often empty, so fall back to their latest order email
+    conn.execute(
+        r#"INSERT INTO stock_notifications (variant_id, user_id, email, source, created_at)
+           SELECT ?1, u.id, email, 'wishlist', ?2 FROM (
+               SELECT u.id,
+               COALESCE(NULLIF(u.email, ''), (SELECT o.email FROM orders o WHERE o.user_id = u.id AND o.email IS NOT NULL AND o.email != ''
+                                             ORDER BY o.id DESC LIMIT 1)) AS email
+               FROM users u
+               WHERE u.id IN (SELECT w.user_id FROM wishlist_items wi JOIN wishlists w ON w.id = wi.wishlist_id
+                              WHERE wi.variant_id = ?1 AND wi.notify_in_stock = 1)
+           ) u
+           WHERE email IS NOT NULL
+           AND NOT EXISTS (SELECT 1 FROM stock_notifications sn WHERE sn.variant_id = ?1 AND sn.state = 'pending'
+                           AND (sn.user_id = u.id OR LOWER(sn.email) = LOWER(u.email)))"#,
+        (variant_id, now)
+    ).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// Email queued notifications whose variant is (still) in stock
+async fn send_stock_notifications() -> Result<String, String> {
+    let pending = with_connection(|conn| {
+        let store_url: String = conn.query_row(
+            "SELECT value FROM store_settings WHERE key = 'store_url'",
+            [],
+            |row| row.get::<_, String>(0)
+        ).map(|url| url.trim().trim_end_matches('/').to_string()).unwrap_or_default();
+
+        let mut stmt = conn.prepare(&format!(
+            r#"SELECT sn.id, sn.variant_id, sn.email, p.name, p.slug, COALESCE(pr.amount, 0),
+               COALESCE((SELECT GROUP_CONCAT(ot.presentation || ': ' || ov.presentation, ', ')
+                         FROM option_values_variants ovv
+                         JOIN option_values ov ON ov.id = ovv.option_value_id
+                         JOIN option_types ot ON ot.id = ov.option_type_id
+                         WHERE ovv.variant_id = v.id), v.sku, ''),
+               (SELECT ss.token FROM stock_subscriptions ss
+                WHERE ss.variant_id = sn.variant_id AND ss.email = LOWER(sn.email) AND ss.state = 'active')
+               FROM stock_notifications sn
+               JOIN variants v ON v.id = sn.variant_id
+               JOIN products p ON p.id = v.product_id
+               LEFT JOIN prices pr ON pr.variant_id = v.id AND pr.deleted_at IS NULL
+               WHERE sn.state = 'pending' AND v.deleted_at IS NULL AND p.deleted_at IS NULL
+               AND {} > 0
+               ORDER BY sn.id
+               LIMIT 100"#,
+            available_stock_sql("v.id")
+        )).map_err(|e| e.to_string())?;
+
+        let rows = stmt.query_map([], |row| {
+            let slug: String = row.get(4)?;
+            // Wishlist alerts are turned off from the account's wishlists
+            let unsubscribe_url = match row.get::<_, Option<String>>(7)? {
+                Some(token) => format!("{}/back-in-stock/unsubscribe?token={}", store_url, token),
+                None => format!("{}/account", store_url),
+            };
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?, vec![
+                ("product_name", row.get::<_, String>(3)?),
+                ("variant_name", row.get::<_, String>(6)?),
+                ("price", format!("${:.2}", row.get::<_, i64>(5)? as f64 / 100.0)),
+                ("product_url", format!("{}/products/{}", store_url, url_encode(&slug))),
+                ("unsubscribe_url", unsubscribe_url),
+            ]))
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        Ok::<_, String>(rows)
+    })?;
+
+    let mut sent = 0;
+    for (id, variant_id, email, vars) in pending {
+        let (state, error) = match send_template_email("back_in_stock", email.clone(), vars).await {
+            Ok(true) => {
+                sent += 1;
+                ("sent", None)
+            }
+            Ok(false) => ("failed", Some("Email not configured or template disabled".to_string())),
+            Err(e) => ("failed", Some(e)),
+        };
+        with_connection(|conn| {
+            conn.execute(
+                "UPDATE stock_notifications SET state = ?1, error = ?2, sent_at = ?3 WHERE id = ?4",
+                (state, error, now(), id)
+            ).ok();
+            // A subscription is notified once; failed sends stay active for the next restock
+            if state == "sent" {
+                conn.execute(
+                    r#"UPDATE stock_subscriptions SET state = 'notified', notified_at = ?1, updated_at = ?1
+                       WHERE variant_id = ?2 AND email = LOWER(?3) AND state = 'active'"#,
+                    (now(), variant_id, &email)
+                ).ok();
+            }
         });
-        let has_search = search_pattern.is_some();
-        let customer_type_filter = params.customer_type.as_deref();
+    }
 
-        // Build the base WHERE clause based on customer_type filter
-        let type_clause = match customer_type_filter {
-            Some("registered") => " AND o.user_id IS NOT NULL",
-            Some("guest") => " AND o.user_id IS NULL",
-            _ => "",
+    Ok(format!("{} back-in-stock emails sent", sent))
+}
+
+const MAX_STOCK_SUBSCRIPTIONS_PER_EMAIL: i64 = 50;
+
+const STOCK_SUBSCRIPTION_SELECT: &str = r#"
+    SELECT ss.id, ss.variant_id, p.name, v.sku, ss.email, ss.state, ss.notified_at, ss.created_at
+    FROM stock_subscriptions ss
+    JOIN variants v ON v.id = ss.variant_id
+    JOIN products p ON p.id = v.product_id"#;
+
+fn stock_subscription_from_row(row: &ic_rusqlite::Row) -> ic_rusqlite::Result<StockSubscription> {
+    Ok(StockSubscription {
+        id: row.get(0)?,
+        variant_id: row.get(1)?,
+        product_name: row.get(2)?,
+        sku: row.get(3)?,
+        email: row.get(4)?,
+        state: row.get(5)?,
+        notified_at: row.get(6)?,
+        created_at: row.get(7)?,
+    })
+}
+
+/// Ask to be emailed when an out-of-stock variant is restocked. Guests must give an email;
+/// signed-in users default to their account (or latest order) email. Any other address
+/// has to confirm by email first (state 'pending'); an address gets at most one
+/// confirmation email a day.
+#[ic_cdk::update]
+async fn subscribe_back_in_stock(variant_id: i64, email: Option<String>) -> Result<i64, String> {
+    let caller = ic_cdk::api::caller();
+    let user_id = if caller != Principal::anonymous() { get_current_user_id() } else { None };
+    let email = email.map(|e| validate_email(&e)).transpose()?;
+    let token = hex::encode(random_bytes(16).await?);
+
+    let (id, confirmation) = with_connection(|conn| {
+        let now = now();
+
+        // The caller's own address needs no confirmation
+        let own_email: Option<String> = match user_id {
+            Some(uid) => conn.query_row(
+                r#"SELECT COALESCE(NULLIF(u.email, ''), (SELECT o.email FROM orders o WHERE o.user_id = u.id AND o.email IS NOT NULL AND o.email != ''
+                                                      ORDER BY o.id DESC LIMIT 1))
+                   FROM users u WHERE u.id = ?1"#,
+                (uid,),
+                |row| row.get::<_, Option<String>>(0)
+            ).map_err(|e| e.to_string())?.and_then(|e| validate_email(&e).ok()),
+            None => None,
+        };
+        let email = match email.or_else(|| own_email.clone()) {
+            Some(email) => email,
+            None => return Err("Email address is required".to_string()),
         };
+        let confirmed = own_email.as_deref() == Some(email.as_str());
+
+        let (product_name, variant_name): (String, String) = conn.query_row(
+            r#"SELECT p.name, COALESCE((SELECT GROUP_CONCAT(ot.presentation || ': ' || ov.presentation, ', ')
+                                        FROM option_values_variants ovv
+                                        JOIN option_values ov ON ov.id = ovv.option_value_id
+                                        JOIN option_types ot ON ot.id = ov.option_type_id
+                                        WHERE ovv.variant_id = v.id), v.sku, '')
+               FROM variants v JOIN products p ON p.id = v.product_id
+               WHERE v.id = ?1 AND v.deleted_at IS NULL AND p.deleted_at IS NULL"#,
+            (variant_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Variant not found".to_string())?;
 
-        // Build queries based on whether search is present
-        let (count_sql, data_sql) = if has_search {
+        if variant_stock(&conn, variant_id) > 0 {
+            return Err("This item is in stock".to_string());
+        }
+
+        if let Ok(id) = conn.query_row(
+            "SELECT id FROM stock_subscriptions WHERE variant_id = ?1 AND email = ?2 AND state IN ('active', 'pending')",
+            (variant_id, &email),
+            |row| row.get::<_, i64>(0)
+        ) {
+            return Ok((id, None));
+        }
+
+        let open: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM stock_subscriptions WHERE email = ?1 AND state IN ('active', 'pending')",
+            (&email,),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
+        if open >= MAX_STOCK_SUBSCRIPTIONS_PER_EMAIL {
+            return Err("Too many back-in-stock requests for this email".to_string());
+        }
+
+        let recently_asked = !confirmed && conn.query_row(
+            "SELECT 1 FROM stock_subscriptions WHERE email = ?1 AND confirmation_sent_at > ?2",
+            (&email, now - DAY_NS),
+            |_| Ok(())
+        ).is_ok();
+        let send_confirmation = !confirmed && !recently_asked;
+
+        let id: i64 = conn.query_row(
+            r#"INSERT INTO stock_subscriptions (variant_id, user_id, email, state, token, confirmation_sent_at, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7) RETURNING id"#,
             (
-                format!(
-                    "SELECT COUNT(DISTINCT o.email) FROM orders o WHERE o.email IS NOT NULL AND o.email != '' AND o.email LIKE ?1 ESCAPE '\\'{}",
-                    type_clause
-                ),
-                format!(
-                    r#"SELECT
-                        o.email,
-                        CASE WHEN MAX(o.user_id) IS NOT NULL THEN 'registered' ELSE 'guest' END as customer_type,
-                        MAX(u.principal) as principal,
-                        COUNT(*) as order_count,
-                        COALESCE(SUM(o.total), 0) as total_spent,
-                        MIN(o.created_at) as first_order_at,
-                        MAX(o.created_at) as last_order_at
-                       FROM orders o
-                       LEFT JOIN users u ON o.user_id = u.id
-                       WHERE o.email IS NOT NULL AND o.email != '' AND o.email LIKE ?1 ESCAPE '\\'{}
-                       GROUP BY o.email
-                       ORDER BY last_order_at DESC
-                       LIMIT {} OFFSET {}"#,
-                    type_clause, limit, offset
-                )
-            )
+                variant_id, user_id, &email, if confirmed { "active" } else { "pending" }, &token,
+                if send_confirmation { Some(now) } else { None }, now,
+            ),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
+
+        if !send_confirmation {
+            return Ok((id, None));
+        }
+        let store_url: String = conn.query_row(
+            "SELECT value FROM store_settings WHERE key = 'store_url'",
+            [],
+            |row| row.get::<_, String>(0)
+        ).map(|url| url.trim().trim_end_matches('/').to_string()).unwrap_or_default();
+        let vars = vec![
+            ("product_name", product_name),
+            ("variant_name", variant_name),
+            ("confirm_url", format!("{}/back-in-stock/confirm?token={}", store_url, token)),
+        ];
+        Ok((id, Some((email, vars))))
+    })?;
+
+    if let Some((email, vars)) = confirmation {
+        let _ = send_template_email("stock_subscription_confirm", email, vars).await;
+    }
+
+    Ok(id)
+}
+
+/// Confirm a back-in-stock request from the emailed link. Confirming proves the address,
+/// so every pending request for it becomes active.
+#[ic_cdk::update]
+fn confirm_stock_subscription(token: String) -> Result<(), String> {
+    with_connection(|conn| {
+        let (email, state): (String, String) = conn.query_row(
+            "SELECT email, state FROM stock_subscriptions WHERE token = ?1",
+            (token.trim(),),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Subscription not found".to_string())?;
+
+        if state == "canceled" {
+            return Err("This alert was canceled".to_string());
+        }
+        conn.execute(
+            "UPDATE stock_subscriptions SET state = 'active', updated_at = ?1 WHERE email = ?2 AND state = 'pending'",
+            (now(), &email)
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    })
+}
+
+/// Stop a back-in-stock alert from the link in its emails; no sign-in needed
+#[ic_cdk::update]
+fn unsubscribe_back_in_stock(token: String) -> Result<(), String> {
+    with_connection(|conn| {
+        conn.query_row(
+            "SELECT 1 FROM stock_subscriptions WHERE token = ?1",
+            (token.trim(),),
+            |_| Ok(())
+        ).map_err(|_| "Subscription not found".to_string())?;
+
+        conn.execute(
+            r#"UPDATE stock_subscriptions SET state = 'canceled', updated_at = ?1
+               WHERE token = ?2 AND state IN ('active', 'pending')"#,
+            (now(), token.trim())
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_my_stock_subscriptions() -> Result<Vec<StockSubscription>, String> {
+    let user_id = get_current_user_id().ok_or("Not logged in")?;
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE ss.user_id = ?1 AND ss.state IN ('active', 'pending') ORDER BY ss.created_at DESC", STOCK_SUBSCRIPTION_SELECT
+        )).map_err(|e| e.to_string())?;
+        let subscriptions = stmt.query_map((user_id,), stock_subscription_from_row)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        Ok(subscriptions)
+    })
+}
+
+#[ic_cdk::update]
+fn cancel_stock_subscription(id: i64) -> Result<(), String> {
+    let user_id = get_current_user_id().ok_or("Not logged in")?;
+
+    with_connection(|conn| {
+        let updated = conn.execute(
+            r#"UPDATE stock_subscriptions SET state = 'canceled', updated_at = ?1
+               WHERE id = ?2 AND user_id = ?3 AND state IN ('active', 'pending')"#,
+            (now(), id, user_id)
+        ).map_err(|e| e.to_string())?;
+        if updated == 0 {
+            return Err("Subscription not found".to_string());
+        }
+        Ok(())
+    })
+}
+
+/// Back-in-stock subscriptions, optionally filtered by state and variant
+#[ic_cdk::query]
+fn admin_get_stock_subscriptions(state: Option<String>, variant_id: Option<i64>) -> Result<Vec<StockSubscription>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(&format!(
+            r#"{} WHERE (?1 IS NULL OR ss.state = ?1) AND (?2 IS NULL OR ss.variant_id = ?2)
+               ORDER BY ss.created_at DESC LIMIT 500"#, STOCK_SUBSCRIPTION_SELECT
+        )).map_err(|e| e.to_string())?;
+        let subscriptions = stmt.query_map((state, variant_id), stock_subscription_from_row)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        Ok(subscriptions)
+    })
+}
+
+// ============================================
+// ADDRESS BOOK API
+// ============================================
+
+const ADDRESS_COLUMNS: &str =
+    "id, firstname, lastname, address1, address2, city, state_name, zipcode, country_code, phone, is_default, is_default_billing";
+
+fn address_from_row(row: &ic_rusqlite::Row) -> ic_rusqlite::Result<AddressDetail> {
+    Ok(AddressDetail {
+        id: row.get(0)?,
+        firstname: row.get(1)?,
+        lastname: row.get(2)?,
+        address1: row.get(3)?,
+        address2: row.get(4)?,
+        city: row.get(5)?,
+        state_name: row.get(6)?,
+        zipcode: row.get(7)?,
+        country_code: row.get(8)?,
+        phone: row.get(9)?,
+        is_default: row.get::<_, Option<i64>>(10)?.unwrap_or(0) == 1,
+        is_default_billing: row.get::<_, i64>(11)? == 1,
+    })
+}
+
+// Trimmed optional field; blank becomes NULL
+fn trim_opt(value: &Option<String>) -> Option<String> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(|v| v.to_string())
+}
+
+// Signed-in user owning the address book
+fn address_book_user() -> Result<i64, String> {
+    if ic_cdk::api::caller() == Principal::anonymous() {
+        return Err("Sign in to manage saved addresses".to_string());
+    }
+    get_current_user_id().ok_or_else(|| "User not found".to_string())
+}
+
+// Saved address identical to `addr`, ignoring case and surrounding whitespace
+fn find_saved_address(conn: &Connection, user_id: i64, addr: &AddressInput) -> Option<i64> {
+    conn.query_row(
+        r#"SELECT id FROM addresses
+           WHERE user_id = ?1 AND deleted_at IS NULL
+           AND LOWER(TRIM(firstname)) = LOWER(?2) AND LOWER(TRIM(lastname)) = LOWER(?3)
+           AND LOWER(TRIM(address1)) = LOWER(?4) AND LOWER(TRIM(COALESCE(address2, ''))) = LOWER(COALESCE(?5, ''))
+           AND LOWER(TRIM(city)) = LOWER(?6) AND LOWER(TRIM(COALESCE(state_name, ''))) = LOWER(COALESCE(?7, ''))
+           AND UPPER(TRIM(zipcode)) = UPPER(?8) AND UPPER(TRIM(country_code)) = ?9
+           AND TRIM(COALESCE(phone, '')) = COALESCE(?10, '')
+           ORDER BY id DESC LIMIT 1"#,
+        (
+            user_id,
+            addr.firstname.trim(),
+            addr.lastname.trim(),
+            addr.address1.trim(),
+            trim_opt(&addr.address2),
+            addr.city.trim(),
+            trim_opt(&addr.state_name),
+            addr.zipcode.trim(),
+            addr.country_code.as_deref().unwrap_or("US").trim().to_uppercase(),
+            trim_opt(&addr.phone),
+        ),
+        |row| row.get(0)
+    ).ok()
+}
+
+// Reuse an identical saved address or insert a new one. Guest addresses are never shared.
+fn find_or_create_address(conn: &Connection, user_id: Option<i64>, addr: &AddressInput) -> Result<i64, String> {
+    if let Some(id) = user_id.and_then(|uid| find_saved_address(conn, uid, addr)) {
+        return Ok(id);
+    }
+
+    conn.query_row(
+        r#"INSERT INTO addresses (user_id, firstname, lastname, address1, address2, city, state_name, zipcode, country_code, phone, created_at, updated_at)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?11) RETURNING id"#,
+        (
+            user_id,
+            addr.firstname.trim(),
+            addr.lastname.trim(),
+            addr.address1.trim(),
+            trim_opt(&addr.address2),
+            addr.city.trim(),
+            trim_opt(&addr.state_name),
+            addr.zipcode.trim(),
+            addr.country_code.as_deref().unwrap_or("US").trim().to_uppercase(),
+            trim_opt(&addr.phone),
+            now()
+        ),
+        |row| row.get(0)
+    ).map_err(|e| e.to_string())
+}
+
+// Make `address_id` the user's only default shipping (or billing) address
+fn mark_default_address(conn: &Connection, user_id: i64, address_id: i64, billing: bool) -> Result<(), String> {
+    let column = if billing { "is_default_billing" } else { "is_default" };
+    conn.execute(
+        &format!(
+            "UPDATE addresses SET {0} = CASE WHEN id = ?1 THEN 1 ELSE 0 END, updated_at = ?2 WHERE user_id = ?3 AND ({0} = 1 OR id = ?1)",
+            column
+        ),
+        (address_id, now(), user_id)
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Apply the input's default flags; the first saved address becomes the default for both
+fn apply_address_defaults(conn: &Connection, user_id: i64, address_id: i64, addr: &AddressInput) -> Result<(), String> {
+    let (has_ship_default, has_bill_default): (bool, bool) = conn.query_row(
+        r#"SELECT COALESCE(MAX(is_default = 1), 0), COALESCE(MAX(is_default_billing = 1), 0)
+           FROM addresses WHERE user_id = ?1 AND deleted_at IS NULL"#,
+        (user_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).map_err(|e| e.to_string())?;
+
+    if addr.is_default.unwrap_or(!has_ship_default) {
+        mark_default_address(conn, user_id, address_id, false)?;
+    }
+    if addr.is_default_billing.unwrap_or(!has_bill_default) {
+        mark_default_address(conn, user_id, address_id, true)?;
+    }
+    Ok(())
+}
+
+// Saved address owned by the user, not deleted
+fn get_user_address(conn: &Connection, user_id: i64, address_id: i64) -> Result<AddressDetail, String> {
+    conn.query_row(
+        &format!("SELECT {} FROM addresses WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NULL", ADDRESS_COLUMNS),
+        (address_id, user_id),
+        address_from_row
+    ).map_err(|_| "Address not found".to_string())
+}
+
+// Placed orders keep their addresses; open checkouts can follow edits
+fn address_used_by_placed_orders(conn: &Connection, address_id: i64) -> bool {
+    conn.query_row(
+        r#"SELECT COUNT(*) FROM orders
+           WHERE (ship_address_id = ?1 OR bill_address_id = ?1)
+           AND state NOT IN ('cart', 'address', 'delivery', 'payment')"#,
+        (address_id,),
+        |row| row.get::<_, i64>(0)
+    ).unwrap_or(0) > 0
+}
+
+// Remove a saved address, pointing open checkouts at `replacement_id`.
+// Rows still referenced by orders are soft-deleted.
+fn retire_address(conn: &Connection, address_id: i64, replacement_id: Option<i64>) -> Result<(), String> {
+    let now = now();
+
+    if let Some(new_id) = replacement_id {
+        conn.execute(
+            r#"UPDATE orders SET ship_address_id = CASE WHEN ship_address_id = ?1 THEN ?2 ELSE ship_address_id END,
+               bill_address_id = CASE WHEN bill_address_id = ?1 THEN ?2 ELSE bill_address_id END, updated_at = ?3
+               WHERE (ship_address_id = ?1 OR bill_address_id = ?1)
+               AND state IN ('cart', 'address', 'delivery', 'payment')"#,
+            (address_id, new_id, now)
+        ).map_err(|e| e.to_string())?;
+    }
+
+    // Subscriptions fall back to their source order's address when there is no replacement
+    conn.execute(
+        "UPDATE order_subscriptions SET ship_address_id = ?1, updated_at = ?2 WHERE ship_address_id = ?3",
+        (replacement_id, now, address_id)
+    ).map_err(|e| e.to_string())?;
+
+    let referenced: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM orders WHERE ship_address_id = ?1 OR bill_address_id = ?1",
+        (address_id,),
+        |row| row.get(0)
+    ).unwrap_or(0);
+
+    if referenced > 0 {
+        conn.execute(
+            "UPDATE addresses SET deleted_at = ?1, is_default = 0, is_default_billing = 0, updated_at = ?1 WHERE id = ?2",
+            (now, address_id)
+        ).map_err(|e| e.to_string())?;
+    } else {
+        conn.execute("DELETE FROM addresses WHERE id = ?1", (address_id,))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_my_addresses() -> Result<Vec<AddressDetail>, String> {
+    let user_id = address_book_user()?;
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(&format!(
+            r#"SELECT {} FROM addresses
+               WHERE user_id = ?1 AND deleted_at IS NULL
+               ORDER BY is_default DESC, is_default_billing DESC, updated_at DESC, id DESC"#,
+            ADDRESS_COLUMNS
+        )).map_err(|e| e.to_string())?;
+
+        let addresses = stmt.query_map((user_id,), address_from_row)
+            .map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+
+        Ok(addresses)
+    })
+}
+
+/// Save an address to the caller's address book (returns the existing one if identical)
+#[ic_cdk::update]
+fn create_address(input: AddressInput) -> Result<AddressDetail, String> {
+    validate_address(&input, "Address")?;
+    let user_id = address_book_user()?;
+
+    with_connection(|conn| {
+        let address_id = find_or_create_address(&conn, Some(user_id), &input)?;
+        apply_address_defaults(&conn, user_id, address_id, &input)?;
+        get_user_address(&conn, user_id, address_id)
+    })
+}
+
+/// Edit a saved address. If placed orders use it, a new row is saved and the
+/// old one is retired so order history is unchanged.
+#[ic_cdk::update]
+fn update_address(address_id: i64, input: AddressInput) -> Result<AddressDetail, String> {
+    validate_address(&input, "Address")?;
+    let user_id = address_book_user()?;
+
+    with_connection(|conn| {
+        let now = now();
+        let current = get_user_address(&conn, user_id, address_id)?;
+
+        let duplicate = find_saved_address(&conn, user_id, &input).filter(|id| *id != address_id);
+
+        let target_id = if let Some(existing_id) = duplicate {
+            // Edited into a copy of another saved address: merge into that one
+            retire_address(&conn, address_id, Some(existing_id))?;
+            existing_id
+        } else if address_used_by_placed_orders(&conn, address_id) {
+            let new_id = find_or_create_address(&conn, Some(user_id), &input)?;
+            retire_address(&conn, address_id, Some(new_id))?;
+            new_id
         } else {
-            (
-                format!(
-                    "SELECT COUNT(DISTINCT o.email) FROM orders o WHERE o.email IS NOT NULL AND o.email != ''{}",
-                    type_clause
-                ),
-                format!(
-                    r#"SELECT
-                        o.email,
-                        CASE WHEN MAX(o.user_id) IS NOT NULL THEN 'registered' ELSE 'guest' END as customer_type,
-                        MAX(u.principal) as principal,
-                        COUNT(*) as order_count,
-                        COALESCE(SUM(o.total), 0) as total_spent,
-                        MIN(o.created_at) as first_order_at,
-                        MAX(o.created_at) as last_order_at
-                       FROM orders o
-                       LEFT JOIN users u ON o.user_id = u.id
-                       WHERE o.email IS NOT NULL AND o.email != ''{}
-                       GROUP BY o.email
-                       ORDER BY last_order_at DESC
-                       LIMIT {} OFFSET {}"#,
-                    type_clause, limit, offset
+            conn.execute(
+                r#"UPDATE addresses SET firstname = ?1, lastname = ?2, address1 = ?3, address2 = ?4, city = ?5,
+                   state_name = ?6, zipcode = ?7, country_code = ?8, phone = ?9, updated_at = ?10
+                   WHERE id = ?11"#,
+                (
+                    input.firstname.trim(),
+                    input.lastname.trim(),
+                    input.address1.trim(),
+                    trim_opt(&input.address2),
+                    input.city.trim(),
+                    trim_opt(&input.state_name),
+                    input.zipcode.trim(),
+                    input.country_code.as_deref().unwrap_or("US").trim().to_uppercase(),
+                    trim_opt(&input.phone),
+                    now,
+                    address_id
                 )
+            ).map_err(|e| e.to_string())?;
+            address_id
+        };
+
+        // Keep the default flags unless the input changes them
+        if input.is_default.unwrap_or(current.is_default) {
+            mark_default_address(&conn, user_id, target_id, false)?;
+        } else if input.is_default == Some(false) {
+            conn.execute("UPDATE addresses SET is_default = 0 WHERE id = ?1", (target_id,))
+                .map_err(|e| e.to_string())?;
+        }
+        if input.is_default_billing.unwrap_or(current.is_default_billing) {
+            mark_default_address(&conn, user_id, target_id, true)?;
+        } else if input.is_default_billing == Some(false) {
+            conn.execute("UPDATE addresses SET is_default_billing = 0 WHERE id = ?1", (target_id,))
+                .map_err(|e| e.to_string())?;
+        }
+
+        get_user_address(&conn, user_id, target_id)
+    })
+}
+
+#[ic_cdk::update]
+fn delete_address(address_id: i64) -> Result<(), String> {
+    let user_id = address_book_user()?;
+
+    with_connection(|conn| {
+        get_user_address(&conn, user_id, address_id)?;
+        retire_address(&conn, address_id, None)
+    })
+}
+
+/// Set the default shipping or billing address (`address_type`: "shipping" or "billing")
+#[ic_cdk::update]
+fn set_default_address(address_id: i64, address_type: String) -> Result<(), String> {
+    let billing = match address_type.as_str() {
+        "shipping" => false,
+        "billing" => true,
+        _ => return Err("Address type must be 'shipping' or 'billing'".to_string()),
+    };
+    let user_id = address_book_user()?;
+
+    with_connection(|conn| {
+        get_user_address(&conn, user_id, address_id)?;
+        mark_default_address(&conn, user_id, address_id, billing)
+    })
+}
+
+// ============================================
+// CHECKOUT API
+// ============================================
+
+// Helper to validate address field lengths
+fn validate_address(addr: &AddressInput, label: &str) -> Result<(), String> {
+    const MAX_NAME_LEN: usize = 100;
+    const MAX_ADDRESS_LEN: usize = 255;
+    const MAX_CITY_LEN: usize = 100;
+    const MAX_STATE_LEN: usize = 100;
+    const MAX_ZIP_LEN: usize = 20;
+    const MAX_PHONE_LEN: usize = 30;
+
+    if addr.firstname.len() > MAX_NAME_LEN {
+        return Err(format!("{} first name too long (max {} chars)", label, MAX_NAME_LEN));
+    }
+    if addr.lastname.len() > MAX_NAME_LEN {
+        return Err(format!("{} last name too long (max {} chars)", label, MAX_NAME_LEN));
+    }
+    if addr.address1.len() > MAX_ADDRESS_LEN {
+        return Err(format!("{} address1 too long (max {} chars)", label, MAX_ADDRESS_LEN));
+    }
+    if let Some(ref a2) = addr.address2 {
+        if a2.len() > MAX_ADDRESS_LEN { return Err(format!("{} address2 too long (max {} chars)", label, MAX_ADDRESS_LEN)); }
+    }
+    if addr.city.len() > MAX_CITY_LEN {
+        return Err(format!("{} city too long (max {} chars)", label, MAX_CITY_LEN));
+    }
+    if let Some(ref s) = addr.state_name {
+        if s.len() > MAX_STATE_LEN { return Err(format!("{} state too long (max {} chars)", label, MAX_STATE_LEN)); }
+    }
+    if addr.zipcode.len() > MAX_ZIP_LEN {
+        return Err(format!("{} zipcode too long (max {} chars)", label, MAX_ZIP_LEN));
+    }
+    if let Some(ref p) = addr.phone {
+        if p.len() > MAX_PHONE_LEN { return Err(format!("{} phone too long (max {} chars)", label, MAX_PHONE_LEN)); }
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_order_address(input: SetAddressInput, session_id: Option<String>) -> Result<OrderDetail, String> {
+    // Validate email
+    if input.email.len() > 254 {
+        return Err("Email address too long (max 254 chars)".to_string());
+    }
+    // Validate address fields
+    if let Some(ref shipping) = input.shipping {
+        validate_address(shipping, "Shipping")?;
+    } else if input.ship_address_id.is_none() {
+        return Err("Shipping address is required".to_string());
+    }
+    if let Some(ref billing) = input.billing {
+        validate_address(billing, "Billing")?;
+    }
+
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    if (input.ship_address_id.is_some() || input.bill_address_id.is_some()) && user_id.is_none() {
+        return Err("Sign in to use saved addresses".to_string());
+    }
+
+    with_connection(|conn| {
+        let now = now();
+
+        // Get cart order
+        let order_id: i64 = if is_anonymous {
+             if let Some(sess_id) = session_id {
+                 conn.query_row(
+                    "SELECT id FROM orders WHERE guest_token = ?1 AND state IN ('cart', 'address')",
+                    (&sess_id,),
+                    |row| row.get(0)
+                ).map_err(|_| "No active cart found".to_string())?
+             } else {
+                 return Err("Session ID required for guest checkout".to_string());
+             }
+        } else {
+            conn.query_row(
+                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state IN ('cart', 'address')",
+                (user_id, &caller_str),
+                |row| row.get(0)
+            ).map_err(|_| "No active cart found".to_string())?
+        };
+
+        // Shipping address: saved address or new one (reused if identical to a saved one)
+        let ship_address_id: i64 = match (input.ship_address_id, &input.shipping) {
+            (Some(address_id), _) => get_user_address(&conn, user_id.unwrap_or(0), address_id)?.id,
+            (None, Some(shipping)) => {
+                let address_id = find_or_create_address(&conn, user_id, shipping)?;
+                if let Some(uid) = user_id {
+                    apply_address_defaults(&conn, uid, address_id, shipping)?;
+                }
+                address_id
+            }
+            (None, None) => return Err("Shipping address is required".to_string()),
+        };
+
+        // Use same for billing or create separate
+        let bill_address_id = if let Some(address_id) = input.bill_address_id {
+            get_user_address(&conn, user_id.unwrap_or(0), address_id)?.id
+        } else if input.use_shipping_for_billing.unwrap_or(true) {
+            ship_address_id
+        } else if let Some(billing) = input.billing {
+            let address_id = find_or_create_address(&conn, user_id, &billing)?;
+            if let Some(uid) = user_id {
+                apply_address_defaults(&conn, uid, address_id, &billing)?;
+            }
+            address_id
+        } else {
+            ship_address_id
+        };
+
+        // Update order with email and addresses
+        conn.execute(
+            r#"UPDATE orders SET
+               email = ?1, ship_address_id = ?2, bill_address_id = ?3,
+               state = 'address', updated_at = ?4
+               WHERE id = ?5"#,
+            (&input.email, ship_address_id, bill_address_id, now, order_id)
+        ).map_err(|e| e.to_string())?;
+
+        // Calculate taxes
+        calculate_taxes(&conn, order_id)?;
+
+        recalculate_order(&conn, order_id)?;
+        get_order_detail(&conn, order_id)
+    })
+}
+
+fn calculate_taxes(conn: &Connection, order_id: i64) -> Result<(), String> {
+    let now = now();
+
+    // Clear existing tax adjustments
+    conn.execute(
+        "DELETE FROM adjustments WHERE order_id = ?1 AND source_type = 'TaxRate'",
+        (order_id,)
+    ).map_err(|e| e.to_string())?;
+
+    // Get order shipping address country/state
+    let address: (String, Option<String>) = match conn.query_row(
+        r#"SELECT country_code, state_name FROM addresses
+           WHERE id = (SELECT ship_address_id FROM orders WHERE id = ?1)"#,
+        (order_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ) {
+        Ok(a) => a,
+        Err(_) => return Ok(()), // No address, no tax
+    };
+
+    let (country_code, state_name) = address;
+
+    // Find applicable tax rates
+    // This is a simplified version of Solidus zone matching
+    let mut stmt = conn.prepare(
+        r#"SELECT tr.id, tr.amount, tr.name, tr.included_in_price
+           FROM tax_rates tr
+           JOIN zones z ON z.id = tr.zone_id
+           JOIN zone_members zm ON zm.zone_id = z.id
+           WHERE tr.deleted_at IS NULL
+           AND (
+               (zm.zoneable_type = 'Country' AND zm.zoneable_id = ?1)
+               OR (zm.zoneable_type = 'State' AND zm.zoneable_id = ?2)
+           )"#
+    ).map_err(|e| e.to_string())?;
+
+    let tax_rates = stmt.query_map((&country_code, &state_name), |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, i64>(3)? == 1))
+    }).map_err(|e| e.to_string())?;
+
+    // For each line item, apply applicable tax rates
+    // Simplified: apply all matching rates to all line items
+    let mut line_items_stmt = conn.prepare(
+        "SELECT id, price, quantity FROM line_items WHERE order_id = ?1"
+    ).map_err(|e| e.to_string())?;
+
+    let line_items: Vec<(i64, i64, i64)> = line_items_stmt.query_map((order_id,), |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    }).map_err(|e| e.to_string())?.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+    for tax_rate in tax_rates {
+        let (rate_id, amount_rate, name, included) = tax_rate.map_err(|e| e.to_string())?;
+        let label = name.unwrap_or_else(|| format!("Tax ({:.1}%)", amount_rate * 100.0));
+
+        for (line_item_id, price, quantity) in &line_items {
+            let tax_amount = if included {
+                // price = total / (1 + rate)
+                // tax = total - (total / (1 + rate))
+                let total = price * quantity;
+                total - (total as f64 / (1.0 + amount_rate)).round() as i64
+            } else {
+                ((price * quantity) as f64 * amount_rate).round() as i64
+            };
+
+            if tax_amount != 0 {
+                conn.execute(
+                    r#"INSERT INTO adjustments (source_type, source_id, adjustable_type, adjustable_id, order_id, amount, label, included, created_at, updated_at)
+                       VALUES ('TaxRate', ?1, 'LineItem', ?2, ?3, ?4, ?5, ?6, ?7, ?7)"#,
+                    (rate_id, line_item_id, order_id, tax_amount, &label, if included { 1 } else { 0 }, now)
+                ).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn calculate_shipping_cost(conn: &Connection, shipping_method_id: i64, order_id: i64) -> Result<i64, String> {
+    // Basic calculation: base price from method + weight-based cost
+    let base_cost: i64 = match conn.query_row(
+        "SELECT base_cost FROM shipping_methods WHERE id = ?1",
+        (shipping_method_id,),
+        |row| row.get(0)
+    ) {
+        Ok(c) => c,
+        Err(_) => return Err("Shipping method not found".to_string()),
+    };
+
+    let total_weight: f64 = conn.query_row(
+        r#"SELECT COALESCE(SUM(v.weight * li.quantity), 0.0)
+           FROM line_items li
+           JOIN variants v ON v.id = li.variant_id
+           WHERE li.order_id = ?1"#,
+        (order_id,),
+        |row| row.get(0)
+    ).unwrap_or(0.0);
+
+    // Add $1 per unit of weight (simplified)
+    let weight_cost = (total_weight * 100.0) as i64;
+    
+    Ok(base_cost + weight_cost)
+}
+
+fn move_stock(conn: &Connection, variant_id: i64, stock_location_id: i64, quantity: i64, action: &str, originator_type: &str, originator_id: i64) -> Result<(), String> {
+    let now = now();
+
+    // Find stock item for the given location
+    let stock_item_id: i64 = match conn.query_row(
+        "SELECT id FROM stock_items WHERE variant_id = ?1 AND stock_location_id = ?2",
+        (variant_id, stock_location_id),
+        |row| row.get(0)
+    ) {
+        Ok(id) => id,
+        Err(_) => {
+            // Create stock item if it doesn't exist
+            conn.query_row(
+                "INSERT INTO stock_items (variant_id, stock_location_id, count_on_hand, backorderable, created_at, updated_at) VALUES (?1, ?2, 0, 1, ?3, ?3) RETURNING id",
+                (variant_id, stock_location_id, now),
+                |row| row.get(0)
+            ).map_err(|e| e.to_string())?
+        }
+    };
+
+    let available_before = variant_stock(conn, variant_id);
+
+    // Bundles made from this variant, with what they had available before the move
+    let bundles: Vec<(i64, i64)> = {
+        let mut stmt = conn.prepare("SELECT bundle_variant_id FROM bundle_components WHERE component_variant_id = ?1")
+            .map_err(|e| e.to_string())?;
+        let ids = stmt.query_map((variant_id,), |row| row.get::<_, i64>(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        ids.into_iter().map(|id| (id, variant_stock(conn, id))).collect()
+    };
+
+    // Update count on hand
+    conn.execute(
+        "UPDATE stock_items SET count_on_hand = count_on_hand + ?1, updated_at = ?2 WHERE id = ?3",
+        (quantity, now, stock_item_id)
+    ).map_err(|e| e.to_string())?;
+
+    // Back in stock: notify customers waiting on this variant
+    if available_before <= 0 && available_before + quantity > 0 {
+        queue_back_in_stock(conn, variant_id)?;
+    }
+    for (bundle_variant_id, bundle_before) in bundles {
+        if bundle_before <= 0 && variant_stock(conn, bundle_variant_id) > 0 {
+            queue_back_in_stock(conn, bundle_variant_id)?;
+        }
+    }
+
+    // Record movement
+    conn.execute(
+        r#"INSERT INTO stock_movements (stock_item_id, quantity, action, originator_type, originator_id, created_at, updated_at)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)"#,
+        (stock_item_id, quantity, action, originator_type, originator_id, now)
+    ).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// Take a completed order's units out of stock: a movement per variant (components for
+// bundle lines) and an inventory unit per item, keyed by the order's line item
+fn take_order_stock(conn: &Connection, order_id: i64) -> Result<(), String> {
+    let now = now();
+    let line_items: Vec<(i64, i64, i64)> = {
+        let mut stmt = conn.prepare(ORDER_STOCK_LINES_SQL).map_err(|e| e.to_string())?;
+
+        let result: Vec<(i64, i64, i64)> = stmt.query_map((order_id,), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+        result
+    };
+
+    for (variant_id, quantity, line_item_id) in line_items {
+        move_stock(conn, variant_id, 1, -quantity, "sold", "Order", order_id).ok();
+
+        // Create inventory units
+        for _ in 0..quantity {
+            conn.execute(
+                r#"INSERT INTO inventory_units (variant_id, shipment_id, line_item_id, state, pending, created_at, updated_at)
+                   VALUES (?1, (SELECT id FROM shipments WHERE order_id = ?2), ?3, 'on_hand', 0, ?4, ?4)"#,
+                (variant_id, order_id, line_item_id, now)
+            ).ok();
+        }
+    }
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn admin_get_stock_locations() -> Result<Vec<StockLocation>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let mut stmt = conn.prepare("SELECT id, name, code, active, address1, city, state_name, country_code, zipcode, is_default FROM stock_locations ORDER BY id ASC")
+            .map_err(|e| e.to_string())?;
+        let locations = stmt.query_map([], |row| {
+            Ok(StockLocation {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                code: row.get(2)?,
+                active: row.get::<_, i64>(3)? == 1,
+                address1: row.get(4)?,
+                address2: None, // Missing from query but expected by struct
+                city: row.get(5)?,
+                state_name: row.get(6)?,
+                zipcode: row.get(8)?,
+                country_code: row.get(7)?,
+                phone: None, // Missing from query
+                is_default: row.get::<_, i64>(9)? == 1,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        Ok(locations)
+    })
+}
+
+#[ic_cdk::update]
+fn admin_create_stock_location(input: CreateStockLocationInput) -> Result<i64, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let now = now();
+        let id: i64 = conn.query_row(
+            r#"INSERT INTO stock_locations (name, code, active, address1, city, state_name, country_code, zipcode, is_default, created_at, updated_at)
+               VALUES (?1, ?2, 1, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9) RETURNING id"#,
+            (&input.name, &input.code, &input.address1, &input.city, &input.state_name, &input.country_code, &input.zipcode, if input.is_default.unwrap_or(false) { 1 } else { 0 }, now),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
+        Ok(id)
+    })
+}
+
+#[ic_cdk::update]
+fn admin_update_stock_location(id: i64, input: UpdateStockLocationInput) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let now = now();
+        if let Some(name) = input.name {
+            conn.execute("UPDATE stock_locations SET name = ?1, updated_at = ?2 WHERE id = ?3", (name, now, id)).ok();
+        }
+        if let Some(code) = input.code {
+            conn.execute("UPDATE stock_locations SET code = ?1, updated_at = ?2 WHERE id = ?3", (code, now, id)).ok();
+        }
+        if let Some(active) = input.active {
+            conn.execute("UPDATE stock_locations SET active = ?1, updated_at = ?2 WHERE id = ?3", (if active { 1 } else { 0 }, now, id)).ok();
+        }
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn admin_delete_stock_location(id: i64) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        // Check if this is the default location
+        let is_default: bool = conn.query_row(
+            "SELECT is_default FROM stock_locations WHERE id = ?1",
+            [id],
+            |row| row.get(0)
+        ).unwrap_or(false);
+
+        if is_default {
+            return Err("Cannot delete the default stock location. Set another location as default first.".to_string());
+        }
+
+        // Check if there are stock items at this location
+        let stock_count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM stock_items WHERE stock_location_id = ?1 AND count_on_hand > 0",
+            [id],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        if stock_count > 0 {
+            return Err(format!("Cannot delete location with {} items in stock. Transfer or zero out stock first.", stock_count));
+        }
+
+        // Delete stock items (should be zero anyway)
+        conn.execute("DELETE FROM stock_items WHERE stock_location_id = ?1", [id]).ok();
+        // Delete the location
+        conn.execute("DELETE FROM stock_locations WHERE id = ?1", [id]).ok();
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn admin_get_stock_items(params: StockQueryParams) -> Result<StockListResponse, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let limit = params.per_page.unwrap_or(20);
+        let offset = (params.page.unwrap_or(1) - 1) * limit;
+
+        // Get low stock threshold from settings (default 10)
+        let threshold: i64 = if params.low_stock.unwrap_or(false) {
+            let threshold_str: String = conn.query_row(
+                "SELECT value FROM store_settings WHERE key = 'low_stock_threshold'",
+                [],
+                |row| row.get(0)
+            ).unwrap_or("10".to_string());
+            threshold_str.parse().unwrap_or(10)
+        } else {
+            0 // not used when not filtering
+        };
+
+        // Find stock items for a location or all (using parameterized queries)
+        let base_query = r#"SELECT si.id, v.id, v.sku, p.name, si.count_on_hand, si.backorderable
+                           FROM stock_items si
+                           JOIN variants v ON v.id = si.variant_id
+                           JOIN products p ON p.id = v.product_id
+                           WHERE si.deleted_at IS NULL"#;
+
+        let (items, total_count): (Vec<StockItem>, i64) = match (params.stock_location_id, params.low_stock.unwrap_or(false)) {
+            (Some(loc_id), true) => {
+                let query = format!("{} AND si.stock_location_id = ?1 AND si.count_on_hand <= ?2 ORDER BY si.count_on_hand ASC LIMIT ?3 OFFSET ?4", base_query);
+                let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
+                let items = stmt.query_map((loc_id, threshold, limit, offset), |row| {
+                    Ok(StockItem {
+                        id: row.get(0)?,
+                        stock_location_id: loc_id,
+                        stock_location_name: "Default".to_string(),
+                        variant_id: row.get(1)?,
+                        variant_sku: row.get(2)?,
+                        product_name: row.get(3)?,
+                        count_on_hand: row.get(4)?,
+                        backorderable: row.get::<_, i64>(5)? == 1,
+                    })
+                }).map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+                let count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM stock_items si WHERE si.deleted_at IS NULL AND si.stock_location_id = ?1 AND si.count_on_hand <= ?2",
+                    (loc_id, threshold), |row| row.get(0)
+                ).unwrap_or(0);
+                (items, count)
+            },
+            (Some(loc_id), false) => {
+                let query = format!("{} AND si.stock_location_id = ?1 ORDER BY si.count_on_hand ASC LIMIT ?2 OFFSET ?3", base_query);
+                let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
+                let items = stmt.query_map((loc_id, limit, offset), |row| {
+                    Ok(StockItem {
+                        id: row.get(0)?,
+                        stock_location_id: loc_id,
+                        stock_location_name: "Default".to_string(),
+                        variant_id: row.get(1)?,
+                        variant_sku: row.get(2)?,
+                        product_name: row.get(3)?,
+                        count_on_hand: row.get(4)?,
+                        backorderable: row.get::<_, i64>(5)? == 1,
+                    })
+                }).map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+                let count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM stock_items si WHERE si.deleted_at IS NULL AND si.stock_location_id = ?1",
+                    (loc_id,), |row| row.get(0)
+                ).unwrap_or(0);
+                (items, count)
+            },
+            (None, true) => {
+                let query = format!("{} AND si.count_on_hand <= ?1 ORDER BY si.count_on_hand ASC LIMIT ?2 OFFSET ?3", base_query);
+                let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
+                let items = stmt.query_map((threshold, limit, offset), |row| {
+                    Ok(StockItem {
+                        id: row.get(0)?,
+                        stock_location_id: params.stock_location_id.unwrap_or(1),
+                        stock_location_name: "Default".to_string(),
+                        variant_id: row.get(1)?,
+                        variant_sku: row.get(2)?,
+                        product_name: row.get(3)?,
+                        count_on_hand: row.get(4)?,
+                        backorderable: row.get::<_, i64>(5)? == 1,
+                    })
+                }).map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+                let count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM stock_items si WHERE si.deleted_at IS NULL AND si.count_on_hand <= ?1",
+                    (threshold,), |row| row.get(0)
+                ).unwrap_or(0);
+                (items, count)
+            },
+            (None, false) => {
+                let query = format!("{} ORDER BY si.count_on_hand ASC LIMIT ?1 OFFSET ?2", base_query);
+                let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
+                let items = stmt.query_map((limit, offset), |row| {
+                    Ok(StockItem {
+                        id: row.get(0)?,
+                        stock_location_id: params.stock_location_id.unwrap_or(1),
+                        stock_location_name: "Default".to_string(),
+                        variant_id: row.get(1)?,
+                        variant_sku: row.get(2)?,
+                        product_name: row.get(3)?,
+                        count_on_hand: row.get(4)?,
+                        backorderable: row.get::<_, i64>(5)? == 1,
+                    })
+                }).map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+                let count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM stock_items si WHERE si.deleted_at IS NULL",
+                    [], |row| row.get(0)
+                ).unwrap_or(0);
+                (items, count)
+            },
+        };
+
+        Ok(StockListResponse {
+            items,
+            total_count,
+            page: params.page.unwrap_or(1),
+            per_page: limit,
+            total_pages: (total_count as f64 / limit as f64).ceil() as i64,
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn admin_adjust_stock(input: StockAdjustmentInput) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        // Need to find variant_id and stock_location_id for this stock_item_id
+        let (variant_id, stock_location_id): (i64, i64) = conn.query_row(
+            "SELECT variant_id, stock_location_id FROM stock_items WHERE id = ?1",
+            (input.stock_item_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Stock item not found".to_string())?;
+
+        move_stock(&conn, variant_id, stock_location_id, input.quantity, "admin_adjustment", "Admin", 0)
+    })
+}
+
+#[ic_cdk::update]
+fn set_shipping_method(shipping_method_id: i64, session_id: Option<String>) -> Result<OrderDetail, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    with_connection(|conn| {
+        let now = now();
+
+        // Get order in address state (support guests)
+        let order_id: i64 = if is_anonymous {
+            if let Some(sess_id) = &session_id {
+                conn.query_row(
+                    "SELECT id FROM orders WHERE guest_token = ?1 AND state IN ('address', 'delivery')",
+                    (sess_id,),
+                    |row| row.get(0)
+                ).map_err(|_| "No order in address state".to_string())?
+            } else {
+                return Err("Session ID required for guest checkout".to_string());
+            }
+        } else {
+            conn.query_row(
+                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state IN ('address', 'delivery')",
+                (user_id, &caller_str),
+                |row| row.get(0)
+            ).map_err(|_| "No order in address state".to_string())?
+        };
+
+        // Verify shipping method exists, is active, and not deleted
+        let method_valid: bool = conn.query_row(
+            "SELECT 1 FROM shipping_methods WHERE id = ?1 AND active = 1 AND deleted_at IS NULL",
+            (shipping_method_id,),
+            |_| Ok(true)
+        ).unwrap_or(false);
+
+        if !method_valid {
+            return Err("Shipping method is not available".to_string());
+        }
+
+        // Get or create shipment
+        let shipment_id: i64 = match conn.query_row(
+            "SELECT id FROM shipments WHERE order_id = ?1",
+            (order_id,),
+            |row| row.get(0)
+        ) {
+            Ok(id) => id,
+            Err(_) => {
+                let number = generate_shipment_number();
+                conn.query_row(
+                    r#"INSERT INTO shipments (order_id, number, stock_location_id, state, created_at, updated_at)
+                       VALUES (?1, ?2, 1, 'pending', ?3, ?3) RETURNING id"#,
+                    (order_id, &number, now),
+                    |row| row.get(0)
+                ).map_err(|e| e.to_string())?
+            }
+        };
+
+        // Get shipping method cost
+        let cost: i64 = calculate_shipping_cost(&conn, shipping_method_id, order_id)?;
+
+        // Set shipping rate
+        conn.execute(
+            "DELETE FROM shipping_rates WHERE shipment_id = ?1",
+            (shipment_id,)
+        ).ok();
+
+        conn.execute(
+            r#"INSERT INTO shipping_rates (shipment_id, shipping_method_id, cost, selected, created_at, updated_at)
+               VALUES (?1, ?2, ?3, 1, ?4, ?4)"#,
+            (shipment_id, shipping_method_id, cost, now)
+        ).map_err(|e| e.to_string())?;
+
+        // Update shipment cost
+        conn.execute(
+            "UPDATE shipments SET cost = ?1, updated_at = ?2 WHERE id = ?3",
+            (cost, now, shipment_id)
+        ).map_err(|e| e.to_string())?;
+
+        // Move to delivery state
+        conn.execute(
+            "UPDATE orders SET state = 'delivery', shipment_total = ?1, updated_at = ?2 WHERE id = ?3",
+            (cost, now, order_id)
+        ).map_err(|e| e.to_string())?;
+
+        recalculate_order(&conn, order_id)?;
+        get_order_detail(&conn, order_id)
+    })
+}
+
+#[ic_cdk::update]
+fn complete_checkout(session_id: Option<String>) -> Result<OrderDetail, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    let (order_detail, needs_activation) = with_connection(|conn| {
+        let now = now();
+
+        // Get order ready for completion (support guests)
+        let order_id: i64 = if is_anonymous {
+            if let Some(sess_id) = &session_id {
+                conn.query_row(
+                    "SELECT id FROM orders WHERE guest_token = ?1 AND state = 'delivery'",
+                    (sess_id,),
+                    |row| row.get(0)
+                ).map_err(|_| "No order ready for checkout".to_string())?
+            } else {
+                return Err("Session ID required for guest checkout".to_string());
+            }
+        } else {
+            conn.query_row(
+                "SELECT id FROM orders WHERE (user_id = ?1 OR user_principal = ?2) AND state = 'delivery'",
+                (user_id, &caller_str),
+                |row| row.get(0)
+            ).map_err(|_| "No order ready for checkout".to_string())?
+        };
+
+        // Verify inventory is still available before completing (prevents race condition).
+        // Bundles count against their components, summed with any components bought on their own.
+        {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT s.variant_id, SUM(s.quantity), p.name, v.sku FROM ({}) s
+                 JOIN variants v ON s.variant_id = v.id
+                 JOIN products p ON v.product_id = p.id
+                 GROUP BY s.variant_id",
+                ORDER_STOCK_LINES_SQL
+            )).map_err(|e| e.to_string())?;
+
+            let items: Vec<(i64, i64, String, String)> = stmt.query_map((order_id,), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            }).map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+
+            for (variant_id, quantity, product_name, sku) in items {
+                let available: i64 = conn.query_row(
+                    "SELECT COALESCE(SUM(count_on_hand), 0) FROM stock_items WHERE variant_id = ?1 AND deleted_at IS NULL",
+                    (variant_id,),
+                    |row| row.get(0)
+                ).unwrap_or(0);
+
+                if available < quantity {
+                    return Err(format!(
+                        "Insufficient stock for {} (SKU: {}). Requested: {}, Available: {}",
+                        product_name, sku, quantity, available
+                    ));
+                }
+            }
+        }
+
+        // Create payment record for whatever gift cards don't cover
+        let amount_due = order_amount_due(&conn, order_id);
+        if amount_due > 0 {
+            let payment_method: (i64, String) = conn.query_row(
+                "SELECT id, name FROM payment_methods WHERE active = 1 AND type = 'IcpPayment' LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?))
+            ).map_err(|_| "No active payment method found".to_string())?;
+
+            conn.execute(
+                r#"INSERT INTO payments (order_id, payment_method_id, amount, state, created_at, updated_at)
+                   VALUES (?1, ?2, ?3, 'completed', ?4, ?4)"#,
+                (order_id, payment_method.0, amount_due, now)
+            ).map_err(|e| e.to_string())?;
+        }
+
+        // Decrease stock (components for bundle lines)
+        take_order_stock(&conn, order_id)?;
+
+        // Complete order
+        conn.execute(
+            r#"UPDATE orders SET
+               state = 'complete', payment_state = 'paid', shipment_state = 'ready',
+               completed_at = ?1, updated_at = ?1
+               WHERE id = ?2"#,
+            (now, order_id)
+        ).map_err(|e| e.to_string())?;
+
+        // Update shipment state
+        conn.execute(
+            "UPDATE shipments SET state = 'ready', updated_at = ?1 WHERE order_id = ?2",
+            (now, order_id)
+        ).ok();
+
+        let needs_activation = complete_gift_cards(&conn, order_id)?;
+
+        let mut detail = get_order_detail(&conn, order_id)?;
+
+        // Guests get a token to look the order up after the session is gone
+        if is_anonymous {
+            if let Some(sess_id) = &session_id {
+                detail.access_token = Some(order_access_token(sess_id, &detail.number));
+            }
+        }
+
+        Ok((detail, needs_activation))
+    })?;
+
+    if needs_activation {
+        spawn_gift_card_activation(order_detail.id);
+    }
+
+    // Send confirmation email (async, ignore errors so we don't fail the checkout)
+    let maybe_email = order_detail.email.clone();
+    let order_number = order_detail.number.clone();
+    let total = order_detail.total;
+
+    // Extract customer name from shipping address
+    let customer_name = order_detail.ship_address.as_ref()
+        .map(|addr| format!("{} {}", addr.firstname, addr.lastname))
+        .unwrap_or_else(|| "Customer".to_string());
+
+    // Format shipping address
+    let shipping_address = order_detail.ship_address.as_ref()
+        .map(|addr| {
+            let mut parts = vec![addr.address1.clone()];
+            if let Some(ref addr2) = addr.address2 {
+                if !addr2.is_empty() {
+                    parts.push(addr2.clone());
+                }
+            }
+            parts.push(format!("{}, {} {}", addr.city, addr.state_name.clone().unwrap_or_default(), addr.zipcode));
+            parts.push(addr.country_code.clone());
+            parts.join("\n")
+        })
+        .unwrap_or_default();
+
+    // Format line items for email
+    let items_text = order_detail.line_items.iter()
+        .map(|item| format!("{} x {} - ${:.2}", item.quantity, item.product_name, item.price as f64 / 100.0))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if let Some(email) = maybe_email {
+        ic_cdk::spawn(async move {
+            let _ = send_order_confirmation(email, order_number, total, customer_name, shipping_address, items_text).await;
+        });
+    }
+
+    Ok(order_detail)
+}
+
+// ============================================
+// ORDERS API
+// ============================================
+
+#[ic_cdk::query]
+fn get_my_orders() -> Result<Vec<OrderSummary>, String> {
+    let caller_str = ic_cdk::api::caller().to_string();
+    let user_id = get_current_user_id();
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            r#"SELECT id, number, state, total, item_count, payment_state, shipment_state, completed_at, created_at
+               FROM orders
+               WHERE (user_id = ?1 OR user_principal = ?2) AND state != 'cart'
+               ORDER BY created_at DESC"#
+        ).map_err(|e| e.to_string())?;
+
+        let orders: Vec<OrderSummary> = stmt.query_map((user_id, &caller_str), |row| {
+            Ok(OrderSummary {
+                id: row.get(0)?,
+                number: row.get(1)?,
+                state: row.get(2)?,
+                total: row.get(3)?,
+                item_count: row.get(4)?,
+                payment_state: row.get(5)?,
+                shipment_state: row.get(6)?,
+                completed_at: row.get(7)?,
+                created_at: row.get(8)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        Ok(orders)
+    })
+}
+
+#[ic_cdk::query]
+fn get_order(number: String) -> Result<OrderDetail, String> {
+    let caller_str = ic_cdk::api::caller().to_string();
+    let user_id = get_current_user_id();
+
+    with_connection(|conn| {
+        let order_id: i64 = conn.query_row(
+            r#"SELECT id FROM orders
+               WHERE number = ?1 AND (user_id = ?2 OR user_principal = ?3)"#,
+            (&number, user_id, &caller_str),
+            |row| row.get(0)
+        ).map_err(|_| "Order not found".to_string())?;
+
+        get_order_detail(&conn, order_id)
+    })
+}
+
+// ============================================
+// GUEST ORDER LOOKUP
+// ============================================
+
+const ORDER_LOOKUP_WINDOW: i64 = 3_600_000_000_000; // 1 hour in ns
+const ORDER_LOOKUP_MAX_FAILURES_PER_ORDER: i64 = 5;
+
+// Access token for a guest order: HMAC-SHA256 of the order number keyed by the
+// guest session token, which only the shopper's browser ever had
+fn order_access_token(guest_token: &str, number: &str) -> String {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(guest_token.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"order-access:");
+    mac.update(number.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Failed lookups within the current window
+fn order_lookup_failures(conn: &Connection, scope: &str, now: i64) -> i64 {
+    conn.query_row(
+        "SELECT failures FROM order_lookup_failures WHERE scope = ?1 AND window_start > ?2",
+        (scope, now - ORDER_LOOKUP_WINDOW),
+        |row| row.get(0)
+    ).unwrap_or(0)
+}
+
+fn record_order_lookup_failure(conn: &Connection, scope: &str, now: i64) {
+    conn.execute(
+        r#"INSERT INTO order_lookup_failures (scope, failures, window_start) VALUES (?1, 1, ?2)
+           ON CONFLICT(scope) DO UPDATE SET
+           failures = CASE WHEN window_start > ?3 THEN failures + 1 ELSE 1 END,
+           window_start = CASE WHEN window_start > ?3 THEN window_start ELSE ?2 END"#,
+        (scope, now, now - ORDER_LOOKUP_WINDOW)
+    ).ok();
+}
+
+// j***@example.com
+fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => format!("{}***@{}", local.chars().next().unwrap_or('*'), domain),
+        None => "***".to_string(),
+    }
+}
+
+// Strip personal details that the order number + email pair shouldn't reveal
+fn redact_order_detail(mut order: OrderDetail) -> OrderDetail {
+    order.email = order.email.as_deref().map(mask_email);
+    for address in [order.ship_address.as_mut(), order.bill_address.as_mut()].into_iter().flatten() {
+        address.lastname = address.lastname.chars().next().map(|c| format!("{}.", c)).unwrap_or_default();
+        address.address1 = String::new();
+        address.address2 = None;
+        address.phone = None;
+    }
+    order
+}
+
+/// Look up a placed order without signing in, by order number plus the order
+/// email or the access token returned by complete_checkout / record_stripe_payment.
+/// Returns a redacted OrderDetail. Failed attempts are rate limited per order number.
+#[ic_cdk::update]
+fn lookup_guest_order(input: GuestOrderLookupInput) -> Result<OrderDetail, String> {
+    let number = input.number.trim().to_uppercase();
+    if number.is_empty() || number.len() > 32 {
+        return Err("Order number is required".to_string());
+    }
+    if input.email.is_none() && input.access_token.is_none() {
+        return Err("Email or access token is required".to_string());
+    }
+
+    with_connection(|conn| {
+        let now = now();
+        let order_scope = format!("order:{}", number);
+
+        if order_lookup_failures(&conn, &order_scope, now) >= ORDER_LOOKUP_MAX_FAILURES_PER_ORDER {
+            return Err("Too many failed lookups, please try again later".to_string());
+        }
+
+        let order: Option<(i64, Option<String>, Option<String>)> = conn.query_row(
+            "SELECT id, email, guest_token FROM orders WHERE number = ?1 AND completed_at IS NOT NULL",
+            (&number,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).ok();
+
+        let matched = order.as_ref().and_then(|(order_id, email, guest_token)| {
+            let email_ok = match (&input.email, email) {
+                (Some(given), Some(stored)) => given.trim().eq_ignore_ascii_case(stored.trim()),
+                _ => false,
+            };
+            let token_ok = match (&input.access_token, guest_token) {
+                (Some(given), Some(guest_token)) => {
+                    let expected = order_access_token(guest_token, &number);
+                    // Constant-time comparison
+                    given.len() == expected.len()
+                        && given.as_bytes().iter().zip(expected.as_bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+                }
+                _ => false,
+            };
+            (email_ok || token_ok).then_some(*order_id)
+        });
+
+        // Same error whether the number or the email was wrong
+        let order_id = match matched {
+            Some(id) => id,
+            None => {
+                record_order_lookup_failure(&conn, &order_scope, now);
+                return Err("Order not found".to_string());
+            }
+        };
+
+        get_order_detail(&conn, order_id).map(redact_order_detail)
+    })
+}
+
+// ============================================
+// ADMIN: ORDERS
+// ============================================
+
+#[ic_cdk::query]
+fn admin_get_orders(params: OrderQueryParams) -> Result<OrderListResponse, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        // Always exclude 'cart' state orders (incomplete checkouts)
+        let mut conditions = vec!["state != 'cart'".to_string()];
+        let mut query_params: Vec<Box<dyn ic_rusqlite::ToSql>> = vec![];
+
+        if let Some(ref state) = params.state {
+            query_params.push(Box::new(state.clone()));
+            conditions.push(format!("state = ?{}", query_params.len()));
+        }
+
+        if let Some(ref payment_state) = params.payment_state {
+            query_params.push(Box::new(payment_state.clone()));
+            conditions.push(format!("payment_state = ?{}", query_params.len()));
+        }
+
+        let where_clause = format!("WHERE {}", conditions.join(" AND "));
+
+        // Count
+        let count_sql = format!("SELECT COUNT(*) FROM orders {}", where_clause);
+        let param_refs: Vec<&dyn ic_rusqlite::ToSql> = query_params.iter().map(|p| p.as_ref()).collect();
+        let total_count: i64 = conn.query_row(&count_sql, param_refs.as_slice(), |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+
+        let page = params.page.unwrap_or(1).max(1);
+        let per_page = params.per_page.unwrap_or(20).min(100);
+        let offset = (page - 1) * per_page;
+
+        let sql = format!(
+            r#"SELECT o.id, o.number, o.email, o.state, o.total, o.item_count, o.payment_state, o.shipment_state, s.tracking, o.completed_at, o.created_at
+               FROM orders o
+               LEFT JOIN shipments s ON s.order_id = o.id
+               {}
+               ORDER BY o.created_at DESC
+               LIMIT ?{} OFFSET ?{}"#,
+            where_clause.replace("state", "o.state").replace("payment_state", "o.payment_state"),
+            query_params.len() + 1,
+            query_params.len() + 2
+        );
+
+        query_params.push(Box::new(per_page));
+        query_params.push(Box::new(offset));
+
+        let param_refs: Vec<&dyn ic_rusqlite::ToSql> = query_params.iter().map(|p| p.as_ref()).collect();
+
+        let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+        let orders: Vec<AdminOrderSummary> = stmt.query_map(param_refs.as_slice(), |row| {
+            Ok(AdminOrderSummary {
+                id: row.get(0)?,
+                number: row.get(1)?,
+                email: row.get(2)?,
+                state: row.get(3)?,
+                total: row.get(4)?,
+                item_count: row.get(5)?,
+                payment_state: row.get(6)?,
+                shipment_state: row.get(7)?,
+                tracking_number: row.get(8)?,
+                completed_at: row.get(9)?,
+                created_at: row.get(10)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        let total_pages = ((total_count as f64) / (per_page as f64)).ceil() as i64;
+
+        Ok(OrderListResponse {
+            orders,
+            total_count,
+            page,
+            per_page,
+            total_pages,
+        })
+    })
+}
+
+// Check an admin order state change against the order's current state
+fn check_order_transition(conn: &Connection, order_id: i64, state: &str) -> Result<(), String> {
+    let current_state: String = conn.query_row(
+        "SELECT state FROM orders WHERE id = ?1",
+        (order_id,),
+        |row| row.get(0)
+    ).map_err(|_| "Order not found".to_string())?;
+
+    // Validate state transitions (prevent going backwards except for cancel/return)
+    let state_order = |s: &str| -> i32 {
+        match s {
+            "cart" => 0, "address" => 1, "delivery" => 2, "payment" => 3,
+            "confirm" => 4, "complete" => 5, "canceled" => 99, "returned" => 100, _ => -1
+        }
+    };
+
+    let current_order = state_order(&current_state);
+    let new_order = state_order(state);
+
+    // Allow: forward progress, cancel from any state, return from complete
+    if new_order < current_order && state != "canceled" && !(state == "returned" && current_state == "complete") {
+        return Err(format!("Cannot transition from '{}' to '{}'. Only forward transitions allowed (except cancel/return).", current_state, state));
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn admin_update_order_state(order_id: i64, state: String) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    // Validate state is a valid order state
+    const VALID_STATES: &[&str] = &["cart", "address", "delivery", "payment", "confirm", "complete", "canceled", "returned"];
+    if !VALID_STATES.contains(&state.as_str()) {
+        return Err(format!("Invalid order state '{}'. Valid states: {}", state, VALID_STATES.join(", ")));
+    }
+
+    // Validate before touching Stripe - a void can't be undone
+    with_connection(|conn| check_order_transition(&conn, order_id, &state))?;
+
+    // Release any card authorization before canceling
+    if state == "canceled" {
+        void_order_authorizations(order_id).await?;
+    }
+
+    with_connection(|conn| {
+        // The order may have moved on while Stripe was called
+        check_order_transition(&conn, order_id, &state)?;
+
+        let now = now();
+        conn.execute(
+            "UPDATE orders SET state = ?1, updated_at = ?2 WHERE id = ?3",
+            (&state, now, order_id)
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    })
+}
+
+// Only completed orders that haven't shipped (or been canceled) can be shipped
+fn check_order_shippable(conn: &Connection, order_id: i64) -> Result<(), String> {
+    let (state, shipment_state, open_shipments, shipments): (String, Option<String>, i64, i64) = conn.query_row(
+        r#"SELECT o.state, o.shipment_state,
+           (SELECT COUNT(*) FROM shipments WHERE order_id = o.id AND COALESCE(state, 'pending') NOT IN ('shipped', 'canceled')),
+           (SELECT COUNT(*) FROM shipments WHERE order_id = o.id)
+           FROM orders o WHERE o.id = ?1"#,
+        (order_id,),
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    ).map_err(|_| "Order not found".to_string())?;
+
+    if state != "complete" {
+        return Err(format!("Order is in state '{}' and cannot be shipped", state));
+    }
+    if shipment_state.as_deref() == Some("shipped") || (shipments > 0 && open_shipments == 0) {
+        return Err("Order has already been shipped or its shipments were canceled".to_string());
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn admin_ship_order(order_id: i64, tracking: Option<String>) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    // Validate before touching Stripe - a capture can't be undone
+    with_connection(|conn| check_order_shippable(&conn, order_id))?;
+
+    // Authorize-then-capture orders are charged for what ships
+    capture_order_payment(order_id, None).await?;
+
+    with_connection(|conn| {
+        let now = now();
+
+        // Update shipments still waiting to go out
+        conn.execute(
+            r#"UPDATE shipments SET state = 'shipped', tracking = ?1, shipped_at = ?2, updated_at = ?2
+               WHERE order_id = ?3 AND COALESCE(state, 'pending') NOT IN ('shipped', 'canceled')"#,
+            (&tracking, now, order_id)
+        ).map_err(|e| e.to_string())?;
+
+        // Update order
+        conn.execute(
+            "UPDATE orders SET shipment_state = 'shipped', updated_at = ?1 WHERE id = ?2",
+            (now, order_id)
+        ).map_err(|e| e.to_string())?;
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn admin_update_tracking(order_id: i64, tracking: Option<String>) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let now = now();
+
+        // Try to update tracking number on existing shipment
+        let rows_updated = conn.execute(
+            "UPDATE shipments SET tracking = ?1, updated_at = ?2 WHERE order_id = ?3",
+            (&tracking, now, order_id)
+        ).map_err(|e| e.to_string())?;
+
+        if rows_updated == 0 {
+            // No shipment exists - create one for this order
+            let number = format!("H{}", now / 1_000_000); // Generate shipment number
+            conn.execute(
+                r#"INSERT INTO shipments (order_id, number, stock_location_id, state, tracking, created_at, updated_at)
+                   VALUES (?1, ?2, 1, 'pending', ?3, ?4, ?4)"#,
+                (order_id, &number, &tracking, now)
+            ).map_err(|e| e.to_string())?;
+
+            // Also update order shipment_state if not already set
+            conn.execute(
+                "UPDATE orders SET shipment_state = COALESCE(shipment_state, 'pending'), updated_at = ?1 WHERE id = ?2",
+                (now, order_id)
+            ).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    })
+}
+
+// ============================================
+// ADMIN: PACKING SLIPS & SHIPPING LABELS
+// ============================================
+
+// Shipment and order fields a packing slip is built from
+struct PackingSlipShipment {
+    id: i64,
+    number: String,
+    state: String,
+    tracking: Option<String>,
+    label_url: Option<String>,
+    stock_location_id: Option<i64>,
+    order_id: i64,
+    order_number: String,
+    order_email: Option<String>,
+    ship_address_id: Option<i64>,
+    shipping_method_name: Option<String>,
+}
+
+// Build the packing slip for a shipment from its inventory units
+fn build_packing_slip(conn: &Connection, shipment_id: i64) -> Result<PackingSlip, String> {
+    let shipment = conn.query_row(
+        r#"SELECT s.id, s.number, COALESCE(s.state, 'pending'), s.tracking, s.label_url, s.stock_location_id,
+           o.id, o.number, o.email, o.ship_address_id, sm.name
+           FROM shipments s
+           JOIN orders o ON o.id = s.order_id
+           LEFT JOIN shipping_rates sr ON sr.shipment_id = s.id AND sr.selected = 1
+           LEFT JOIN shipping_methods sm ON sm.id = sr.shipping_method_id
+           WHERE s.id = ?1"#,
+        (shipment_id,),
+        |row| Ok(PackingSlipShipment {
+            id: row.get(0)?,
+            number: row.get(1)?,
+            state: row.get(2)?,
+            tracking: row.get(3)?,
+            label_url: row.get(4)?,
+            stock_location_id: row.get(5)?,
+            order_id: row.get(6)?,
+            order_number: row.get(7)?,
+            order_email: row.get(8)?,
+            ship_address_id: row.get(9)?,
+            shipping_method_name: row.get(10)?,
+        })
+    ).map_err(|_| "Shipment not found".to_string())?;
+
+    let ship_address: Option<AddressDetail> = match shipment.ship_address_id {
+        Some(addr_id) => conn.query_row(
+            r#"SELECT id, firstname, lastname, address1, address2, city, state_name, zipcode, country_code, phone, is_default, is_default_billing
+               FROM addresses WHERE id = ?1"#,
+            (addr_id,),
+            |row| Ok(AddressDetail {
+                id: row.get(0)?,
+                firstname: row.get(1)?,
+                lastname: row.get(2)?,
+                address1: row.get(3)?,
+                address2: row.get(4)?,
+                city: row.get(5)?,
+                state_name: row.get(6)?,
+                zipcode: row.get(7)?,
+                country_code: row.get(8)?,
+                phone: row.get(9)?,
+                is_default: row.get::<_, Option<i64>>(10)?.unwrap_or(0) == 1,
+                is_default_billing: row.get::<_, i64>(11)? == 1,
+            })
+        ).ok(),
+        None => None,
+    };
+
+    let stock_location: Option<StockLocation> = conn.query_row(
+        r#"SELECT id, name, code, address1, address2, city, state_name, zipcode, country_code, phone, active, is_default
+           FROM stock_locations WHERE id = COALESCE(?1, 1)"#,
+        (shipment.stock_location_id,),
+        |row| Ok(StockLocation {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            code: row.get(2)?,
+            address1: row.get(3)?,
+            address2: row.get(4)?,
+            city: row.get(5)?,
+            state_name: row.get(6)?,
+            zipcode: row.get(7)?,
+            country_code: row.get::<_, Option<String>>(8)?.unwrap_or_else(|| "US".to_string()),
+            phone: row.get(9)?,
+            active: row.get::<_, Option<i64>>(10)?.unwrap_or(1) == 1,
+            is_default: row.get::<_, Option<i64>>(11)?.unwrap_or(0) == 1,
+        })
+    ).ok();
+
+    // Group inventory units by variant / line item (returned units are not packed)
+    let mut stmt = conn.prepare(
+        r#"SELECT iu.variant_id, iu.line_item_id, v.sku, p.name, COUNT(*)
+           FROM inventory_units iu
+           JOIN variants v ON v.id = iu.variant_id
+           JOIN products p ON p.id = v.product_id
+           WHERE iu.shipment_id = ?1 AND iu.state != 'returned'
+           GROUP BY iu.variant_id, iu.line_item_id
+           ORDER BY p.name ASC"#
+    ).map_err(|e| e.to_string())?;
+
+    let mut lines: Vec<PackingSlipLine> = stmt.query_map((shipment_id,), |row| {
+        Ok(PackingSlipLine {
+            variant_id: row.get(0)?,
+            line_item_id: row.get(1)?,
+            sku: row.get(2)?,
+            product_name: row.get(3)?,
+            quantity: row.get(4)?,
+        })
+    }).map_err(|e| e.to_string())?
+    .collect::<ic_rusqlite::Result<Vec<_>>>()
+    .map_err(|e| e.to_string())?;
+
+    // Orders paid through the Stripe webhook have no inventory units - fall back to line items
+    if lines.is_empty() {
+        let mut li_stmt = conn.prepare(
+            r#"SELECT li.variant_id, li.id, v.sku, p.name, li.quantity
+               FROM line_items li
+               JOIN variants v ON v.id = li.variant_id
+               JOIN products p ON p.id = v.product_id
+               WHERE li.order_id = ?1
+               ORDER BY p.name ASC"#
+        ).map_err(|e| e.to_string())?;
+
+        lines = li_stmt.query_map((shipment.order_id,), |row| {
+            Ok(PackingSlipLine {
+                variant_id: row.get(0)?,
+                line_item_id: row.get(1)?,
+                sku: row.get(2)?,
+                product_name: row.get(3)?,
+                quantity: row.get(4)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    }
+
+    let total_quantity = lines.iter().map(|l| l.quantity).sum();
+
+    Ok(PackingSlip {
+        shipment_id: shipment.id,
+        shipment_number: shipment.number,
+        shipment_state: shipment.state,
+        tracking: shipment.tracking,
+        label_url: shipment.label_url,
+        order_number: shipment.order_number,
+        order_email: shipment.order_email,
+        shipping_method_name: shipment.shipping_method_name,
+        ship_address,
+        stock_location,
+        lines,
+        total_quantity,
+        generated_at: now(),
+    })
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn render_packing_slip_html(slip: &PackingSlip, store_name: &str) -> String {
+    let ship_to = match &slip.ship_address {
+        Some(a) => {
+            let mut parts = vec![
+                format!("{} {}", a.firstname, a.lastname),
+                a.address1.clone(),
+            ];
+            if let Some(a2) = a.address2.as_ref().filter(|s| !s.is_empty()) {
+                parts.push(a2.clone());
+            }
+            parts.push(format!("{}, {} {}", a.city, a.state_name.clone().unwrap_or_default(), a.zipcode));
+            parts.push(a.country_code.clone());
+            if let Some(phone) = a.phone.as_ref().filter(|s| !s.is_empty()) {
+                parts.push(phone.clone());
+            }
+            parts.iter().map(|p| html_escape(p)).collect::<Vec<_>>().join("<br>")
+        }
+        None => "No shipping address".to_string(),
+    };
+
+    let ship_from = match &slip.stock_location {
+        Some(l) => {
+            let mut parts = vec![l.name.clone()];
+            for part in [&l.address1, &l.address2].into_iter().flatten() {
+                if !part.is_empty() { parts.push(part.clone()); }
+            }
+            parts.push(format!(
+                "{}, {} {}",
+                l.city.clone().unwrap_or_default(),
+                l.state_name.clone().unwrap_or_default(),
+                l.zipcode.clone().unwrap_or_default()
+            ));
+            parts.push(l.country_code.clone());
+            parts.iter().map(|p| html_escape(p)).collect::<Vec<_>>().join("<br>")
+        }
+        None => String::new(),
+    };
+
+    let rows = slip.lines.iter()
+        .map(|l| format!(
+            "<tr><td>{}</td><td>{}</td><td class=\"qty\">{}</td><td class=\"check\">&#9744;</td></tr>",
+            html_escape(l.sku.as_deref().unwrap_or("-")),
+            html_escape(&l.product_name),
+            l.quantity
+        ))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Packing Slip {shipment}</title>
+<style>
+  body {{ font-family: Arial, sans-serif; color: #111; margin: 32px; }}
+  h1 {{ font-size: 22px; margin: 0 0 4px 0; }}
+  .meta {{ color: #555; font-size: 13px; margin-bottom: 24px; }}
+  .addresses {{ display: flex; gap: 48px; margin-bottom: 24px; }}
+  .addresses h2 {{ font-size: 12px; text-transform: uppercase; color: #555; margin: 0 0 6px 0; }}
+  table {{ width: 100%; border-collapse: collapse; font-size: 14px; }}
+  th, td {{ text-align: left; padding: 8px; border-bottom: 1px solid #ddd; }}
+  .qty, .check {{ text-align: center; width: 60px; }}
+  tfoot td {{ font-weight: bold; border-bottom: none; }}
+  @media print {{ body {{ margin: 0; }} }}
+</style>
+</head>
+<body>
+<h1>{store}</h1>
+<div class="meta">Packing Slip &middot; Order {order} &middot; Shipment {shipment}{method}{tracking}</div>
+<div class="addresses">
+  <div><h2>Ship From</h2>{ship_from}</div>
+  <div><h2>Ship To</h2>{ship_to}</div>
+</div>
+<table>
+<thead><tr><th>SKU</th><th>Item</th><th class="qty">Qty</th><th class="check">Packed</th></tr></thead>
+<tbody>
+{rows}
+</tbody>
+<tfoot><tr><td></td><td>Total items</td><td class="qty">{total}</td><td></td></tr></tfoot>
+</table>
+</body>
+</html>"#,
+        store = html_escape(store_name),
+        order = html_escape(&slip.order_number),
+        shipment = html_escape(&slip.shipment_number),
+        method = slip.shipping_method_name.as_ref()
+            .map(|m| format!(" &middot; {}", html_escape(m)))
+            .unwrap_or_default(),
+        tracking = slip.tracking.as_ref().filter(|t| !t.is_empty())
+            .map(|t| format!(" &middot; Tracking {}", html_escape(t)))
+            .unwrap_or_default(),
+        ship_from = ship_from,
+        ship_to = ship_to,
+        rows = rows,
+        total = slip.total_quantity,
+    )
+}
+
+#[ic_cdk::query]
+fn admin_get_packing_slip(shipment_id: i64) -> Result<PackingSlip, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| build_packing_slip(&conn, shipment_id))
+}
+
+/// Render a packing slip as a printable HTML document ("html") or as JSON ("json")
+#[ic_cdk::query]
+fn admin_render_packing_slip(shipment_id: i64, format: String) -> Result<String, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let slip = build_packing_slip(&conn, shipment_id)?;
+        match format.as_str() {
+            "html" => {
+                let store_name: String = conn.query_row(
+                    "SELECT value FROM store_settings WHERE key = 'store_name'",
+                    [],
+                    |row| row.get(0)
+                ).unwrap_or_else(|_| "Canister Shop".to_string());
+                Ok(render_packing_slip_html(&slip, &store_name))
+            }
+            "json" => serde_json::to_string_pretty(&slip).map_err(|e| e.to_string()),
+            _ => Err(format!("Unsupported format '{}'. Use 'html' or 'json'.", format)),
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_carrier_settings() -> Result<CarrierSettings, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        conn.query_row(
+            "SELECT provider, api_url, api_key, label_format, active FROM carrier_settings WHERE id = 1",
+            [],
+            |row| {
+                let api_key: String = row.get(2)?;
+                // Mask API key - never return actual value
+                let masked_key = if api_key.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}...{}", &api_key[..4.min(api_key.len())], "****")
+                };
+
+                Ok(CarrierSettings {
+                    provider: row.get(0)?,
+                    api_url: row.get(1)?,
+                    api_key: masked_key,
+                    label_format: row.get(3)?,
+                    active: row.get::<_, i64>(4)? == 1,
+                })
+            }
+        ).map_err(|e| e.to_string())
+    })
+}
+
+#[ic_cdk::update]
+fn update_carrier_settings(input: UpdateCarrierSettingsInput) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    if !input.api_url.is_empty() && !input.api_url.starts_with("https://") {
+        return Err("Carrier API URL must use https".to_string());
+    }
+    with_connection(|conn| {
+        let now = now();
+        // Keep the stored key when the masked value (or nothing) is sent back
+        let keep_key = input.api_key.is_empty() || input.api_key.ends_with("****");
+        conn.execute(
+            r#"UPDATE carrier_settings SET provider = ?1, api_url = ?2,
+               api_key = CASE WHEN ?3 = 1 THEN api_key ELSE ?4 END,
+               label_format = ?5, active = ?6, updated_at = ?7 WHERE id = 1"#,
+            (&input.provider, &input.api_url, if keep_key { 1 } else { 0 }, &input.api_key,
+             &input.label_format, if input.active { 1 } else { 0 }, now)
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    })
+}
+
+/// Purchase a shipping label through the configured carrier API
+/// and store the returned tracking number on the shipment
+#[ic_cdk::update]
+async fn admin_purchase_shipping_label(shipment_id: i64) -> Result<ShippingLabel, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    // Step 1: Load carrier settings, packing slip and parcel details
+    let (settings, slip, carrier, service_level, total_weight) = with_connection(|conn| {
+        let settings = conn.query_row(
+            "SELECT provider, api_url, api_key, label_format, active FROM carrier_settings WHERE id = 1",
+            [],
+            |row| Ok(CarrierSettings {
+                provider: row.get(0)?,
+                api_url: row.get(1)?,
+                api_key: row.get(2)?,
+                label_format: row.get(3)?,
+                active: row.get::<_, i64>(4)? == 1,
+            })
+        ).map_err(|_| "Carrier label API not configured".to_string())?;
+
+        if !settings.active || settings.api_url.is_empty() {
+            return Err("Carrier label API not configured".to_string());
+        }
+
+        let slip = build_packing_slip(&conn, shipment_id)?;
+
+        if slip.shipment_state == "shipped" || slip.shipment_state == "canceled" {
+            return Err(format!("Shipment is '{}' and cannot be labeled", slip.shipment_state));
+        }
+        if slip.label_url.is_some() {
+            return Err("Shipment already has a shipping label".to_string());
+        }
+        if slip.ship_address.is_none() {
+            return Err("Order has no shipping address".to_string());
+        }
+
+        let (carrier, service_level): (Option<String>, Option<String>) = conn.query_row(
+            r#"SELECT sm.carrier, sm.service_level FROM shipping_rates sr
+               JOIN shipping_methods sm ON sm.id = sr.shipping_method_id
+               WHERE sr.shipment_id = ?1 AND sr.selected = 1"#,
+            (shipment_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap_or((None, None));
+
+        let total_weight: f64 = slip.lines.iter()
+            .map(|l| {
+                let weight: f64 = conn.query_row(
+                    "SELECT COALESCE(weight, 0.0) FROM variants WHERE id = ?1",
+                    (l.variant_id,),
+                    |row| row.get(0)
+                ).unwrap_or(0.0);
+                weight * l.quantity as f64
+            })
+            .sum();
+
+        Ok((settings, slip, carrier, service_level, total_weight))
+    })?;
+
+    // Step 2: Request the label from the carrier
+    let ship_to = slip.ship_address.as_ref().map(|a| serde_json::json!({
+        "name": format!("{} {}", a.firstname, a.lastname),
+        "address1": a.address1,
+        "address2": a.address2,
+        "city": a.city,
+        "state": a.state_name,
+        "zipcode": a.zipcode,
+        "country_code": a.country_code,
+        "phone": a.phone,
+    }));
+    let ship_from = slip.stock_location.as_ref().map(|l| serde_json::json!({
+        "name": l.name,
+        "address1": l.address1,
+        "address2": l.address2,
+        "city": l.city,
+        "state": l.state_name,
+        "zipcode": l.zipcode,
+        "country_code": l.country_code,
+        "phone": l.phone,
+    }));
+
+    let json_body = serde_json::json!({
+        "reference": slip.shipment_number,
+        "order_number": slip.order_number,
+        "provider": settings.provider,
+        "carrier": carrier,
+        "service_level": service_level,
+        "label_format": settings.label_format,
+        "ship_from": ship_from,
+        "ship_to": ship_to,
+        "parcel": {
+            "weight": total_weight,
+            "item_count": slip.total_quantity,
+        },
+        "items": slip.lines.iter().map(|l| serde_json::json!({
+            "sku": l.sku,
+            "description": l.product_name,
+            "quantity": l.quantity,
+        })).collect::<Vec<_>>(),
+    }).to_string();
+
+    let request = CanisterHttpRequestArgument {
+        url: settings.api_url.clone(),
+        method: HttpMethod::POST,
+        body: Some(json_body.into_bytes()),
+        max_response_bytes: Some(10000),
+        transform: None,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "Authorization".to_string(), value: format!("Bearer {}", settings.api_key) },
+            // Replicas may repeat the outcall - make sure only one label is bought
+            HttpHeader { name: "Idempotency-Key".to_string(), value: format!("label_{}", slip.shipment_number) },
+        ],
+    };
+
+    let (response,) = http_request(request, 2_000_000_000).await.map_err(|(code, msg)| {
+        format!("HTTP request failed: {:?} - {}", code, msg)
+    })?;
+
+    let body_text = String::from_utf8_lossy(&response.body);
+    if response.status != 200u64 && response.status != 201u64 {
+        return Err(format!("Carrier API error ({}): {}", response.status, &body_text[..body_text.len().min(200)]));
+    }
+
+    let tracking = extract_json_string(&body_text, "tracking_number")
+        .or_else(|| extract_json_string(&body_text, "tracking"))
+        .ok_or_else(|| format!("Could not parse tracking number from carrier response: {}", &body_text[..body_text.len().min(200)]))?;
+    let label_url = extract_json_string(&body_text, "label_url");
+
+    // Step 3: Store tracking and label on the shipment
+    with_connection(|conn| {
+        conn.execute(
+            "UPDATE shipments SET tracking = ?1, label_url = ?2, updated_at = ?3 WHERE id = ?4",
+            (&tracking, &label_url, now(), shipment_id)
+        ).map_err(|e| e.to_string())
+    })?;
+
+    Ok(ShippingLabel {
+        shipment_id,
+        tracking,
+        label_url,
+        carrier,
+        service_level,
+    })
+}
+
+// ============================================
+// ADMIN: ANALYTICS
+// ============================================
+
+#[ic_cdk::query]
+fn get_dashboard_stats() -> Result<DashboardStats, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let total_revenue: i64 = conn.query_row(
+            "SELECT COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete'",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        let total_orders: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM orders WHERE state != 'cart'",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        let pending_orders: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM orders WHERE shipment_state IN ('pending', 'ready')",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        // Count unique customers by email (includes both registered and guest)
+        let total_customers: i64 = conn.query_row(
+            "SELECT COUNT(DISTINCT email) FROM orders WHERE email IS NOT NULL AND email != ''",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        // Fetch low stock threshold from settings (default 10)
+        let threshold_str: String = conn.query_row(
+            "SELECT value FROM store_settings WHERE key = 'low_stock_threshold'",
+            [],
+            |row| row.get(0)
+        ).unwrap_or("10".to_string());
+        let threshold: i64 = threshold_str.parse().unwrap_or(10);
+
+        let low_stock_count: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM stock_items WHERE count_on_hand <= {} AND deleted_at IS NULL", threshold),
+            [],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+
+        let total_products: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM products WHERE deleted_at IS NULL",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        Ok(DashboardStats {
+            total_revenue,
+            total_orders,
+            pending_orders,
+            total_customers,
+            low_stock_count,
+            total_products,
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_revenue_stats() -> Result<RevenueData, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let now_ns = now();
+        // Convert nanoseconds to seconds for date calculations
+        let now_secs = now_ns / 1_000_000_000;
+        let day_secs: i64 = 86400;
+        let week_secs: i64 = day_secs * 7;
+        let month_secs: i64 = day_secs * 30;
+
+        // Start of today (midnight)
+        let start_of_today_secs = (now_secs / day_secs) * day_secs;
+        let start_of_today_ns = start_of_today_secs * 1_000_000_000;
+
+        // Start of this week (7 days ago)
+        let start_of_week_ns = (now_secs - week_secs) * 1_000_000_000;
+
+        // Start of this month (30 days ago)
+        let start_of_month_ns = (now_secs - month_secs) * 1_000_000_000;
+
+        // Total revenue & orders (completed only)
+        let total_revenue: i64 = conn.query_row(
+            "SELECT COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete'",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        let total_orders: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM orders WHERE state = 'complete'",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(0);
+
+        let average_order_value = if total_orders > 0 {
+            total_revenue / total_orders
+        } else {
+            0
+        };
+
+        // Today's stats
+        let (orders_today, revenue_today): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete' AND completed_at >= ?1",
+            (start_of_today_ns,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap_or((0, 0));
+
+        // This week's stats
+        let (orders_this_week, revenue_this_week): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete' AND completed_at >= ?1",
+            (start_of_week_ns,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap_or((0, 0));
+
+        // This month's stats
+        let (orders_this_month, revenue_this_month): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE state = 'complete' AND completed_at >= ?1",
+            (start_of_month_ns,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap_or((0, 0));
+
+        // Top selling products (by revenue)
+        let mut top_stmt = conn.prepare(
+            r#"SELECT p.id, p.name, SUM(li.quantity) as qty_sold, SUM(li.quantity * li.price) as revenue
+               FROM line_items li
+               JOIN orders o ON o.id = li.order_id
+               JOIN variants v ON v.id = li.variant_id
+               JOIN products p ON p.id = v.product_id
+               WHERE o.state = 'complete'
+               GROUP BY p.id
+               ORDER BY revenue DESC
+               LIMIT 5"#
+        ).map_err(|e| e.to_string())?;
+
+        let top_products: Vec<TopProduct> = top_stmt.query_map([], |row| {
+            Ok(TopProduct {
+                product_id: row.get(0)?,
+                product_name: row.get(1)?,
+                quantity_sold: row.get(2)?,
+                revenue: row.get(3)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        // Recent orders (last 10 completed)
+        let mut recent_stmt = conn.prepare(
+            r#"SELECT id, number, total, completed_at, email
+               FROM orders
+               WHERE state = 'complete'
+               ORDER BY completed_at DESC
+               LIMIT 10"#
+        ).map_err(|e| e.to_string())?;
+
+        let recent_orders: Vec<RecentOrderSummary> = recent_stmt.query_map([], |row| {
+            Ok(RecentOrderSummary {
+                id: row.get(0)?,
+                number: row.get(1)?,
+                total: row.get(2)?,
+                created_at: row.get::<_, Option<i64>>(3)?.unwrap_or(0),
+                email: row.get(4)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        Ok(RevenueData {
+            stats: RevenueStats {
+                total_revenue,
+                total_orders,
+                average_order_value,
+                orders_today,
+                revenue_today,
+                orders_this_week,
+                revenue_this_week,
+                orders_this_month,
+                revenue_this_month,
+            },
+            top_products,
+            recent_orders,
+        })
+    })
+}
+
+// Offset in seconds for "UTC", "+05:30", "-0800", "+5" or "UTC-08:00". Named zones with
+// daylight saving rules aren't supported (no tz database in the canister).
+fn parse_utc_offset(timezone: &str) -> Result<i64, String> {
+    let tz = timezone.trim();
+    let tz = tz.strip_prefix("UTC").or_else(|| tz.strip_prefix("GMT")).unwrap_or(tz);
+    if tz.is_empty() || tz == "Z" {
+        return Ok(0);
+    }
+
+    let invalid = || format!("Unsupported timezone '{}' (use a UTC offset such as +05:30)", timezone);
+    let (sign, rest) = match tz.as_bytes()[0] {
+        b'+' => (1, &tz[1..]),
+        b'-' => (-1, &tz[1..]),
+        _ => return Err(invalid()),
+    };
+    if !rest.is_ascii() {
+        return Err(invalid());
+    }
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let hours: i64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i64 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 14 || minutes >= 60 {
+        return Err(invalid());
+    }
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+// Requested timezone, else the store's `timezone` setting, with its UTC offset in seconds
+fn report_timezone(conn: &Connection, timezone: Option<String>) -> Result<(String, i64), String> {
+    let timezone = match timezone {
+        Some(tz) => tz,
+        None => conn.query_row(
+            "SELECT value FROM store_settings WHERE key = 'timezone'",
+            [],
+            |row| row.get::<_, Option<String>>(0)
+        ).ok().flatten().unwrap_or_else(|| "UTC".to_string()),
+    };
+    let offset = parse_utc_offset(&timezone)?;
+    Ok((timezone, offset))
+}
+
+// "YYYY-MM-DD" to days since 1970-01-01
+fn parse_date(date: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid date '{}' (expected YYYY-MM-DD)", date);
+    let parts: Vec<&str> = date.trim().split('-').collect();
+    if parts.len() != 3 {
+        return Err(invalid());
+    }
+    let year: i64 = parts[0].parse().map_err(|_| invalid())?;
+    let month: i64 = parts[1].parse().map_err(|_| invalid())?;
+    let day: i64 = parts[2].parse().map_err(|_| invalid())?;
+
+    // Keep the date math and nanosecond timestamps (which end in 2262) from overflowing
+    if !(1900..=2200).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+
+    // Round-trip rejects dates like 2024-02-30
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        return Err(invalid());
+    }
+    Ok(days)
+}
+
+fn format_date(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// First day of the month `months` after the month containing `days`
+fn add_months(days: i64, months: i64) -> i64 {
+    let (year, month, _) = civil_from_days(days);
+    let index = year * 12 + (month - 1) + months;
+    days_from_civil(index.div_euclid(12), index.rem_euclid(12) + 1, 1)
+}
+
+// Most buckets one report may return
+const SALES_REPORT_MAX_BUCKETS: usize = 400;
+
+// Bucket start days (local calendar) covering [first, last], plus the end of the last bucket
+fn sales_buckets(period: &str, first: i64, last: i64) -> Result<Vec<i64>, String> {
+    let mut starts = Vec::new();
+    let mut day = match period {
+        "day" => first,
+        // ISO weeks start on Monday; 1970-01-01 was a Thursday
+        "week" => first - (first + 3).rem_euclid(7),
+        "month" => add_months(first, 0),
+        _ => return Err("Period must be day, week or month".to_string()),
+    };
+
+    while day <= last {
+        starts.push(day);
+        if starts.len() > SALES_REPORT_MAX_BUCKETS {
+            return Err(format!("Date range too long (at most {} {}s)", SALES_REPORT_MAX_BUCKETS, period));
+        }
+        day = match period {
+            "day" => day + 1,
+            "week" => day + 7,
+            _ => add_months(day, 1),
+        };
+    }
+    starts.push(day);
+    Ok(starts)
+}
+
+// Sales per bucket; `bounds` are bucket edges in ns (one more than the number of buckets)
+fn sales_data_points(conn: &Connection, bounds: &[i64], labels: &[String]) -> Result<Vec<SalesDataPoint>, String> {
+    let mut points: Vec<SalesDataPoint> = labels.iter().map(|label| SalesDataPoint {
+        date: label.clone(),
+        revenue: 0,
+        orders: 0,
+        units: 0,
+        discounts: 0,
+        tax: 0,
+        shipping: 0,
+        refunds: 0,
+    }).collect();
+    let (start, end) = (bounds[0], bounds[bounds.len() - 1]);
+    let bucket = |ts: i64| bounds.partition_point(|&b| b <= ts) - 1;
+
+    // Placed orders; returned orders still count as sales (their refunds are listed separately)
+    let mut stmt = conn.prepare(
+        r#"SELECT completed_at, total, COALESCE(item_count, 0), promo_total,
+           included_tax_total + additional_tax_total, shipment_total
+           FROM orders
+           WHERE state IN ('complete', 'returned') AND completed_at >= ?1 AND completed_at < ?2"#
+    ).map_err(|e| e.to_string())?;
+    let mut rows = stmt.query((start, end)).map_err(|e| e.to_string())?;
+    while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+        let point = &mut points[bucket(row.get(0).map_err(|e| e.to_string())?)];
+        point.orders += 1;
+        point.revenue += row.get::<_, i64>(1).map_err(|e| e.to_string())?;
+        point.units += row.get::<_, i64>(2).map_err(|e| e.to_string())?;
+        // Promotion adjustments are negative
+        point.discounts -= row.get::<_, i64>(3).map_err(|e| e.to_string())?;
+        point.tax += row.get::<_, i64>(4).map_err(|e| e.to_string())?;
+        point.shipping += row.get::<_, i64>(5).map_err(|e| e.to_string())?;
+    }
+    drop(rows);
+    drop(stmt);
+
+    // Refunds fall in the bucket they were issued in
+    let mut stmt = conn.prepare(
+        "SELECT created_at, amount FROM refunds WHERE state = 'succeeded' AND created_at >= ?1 AND created_at < ?2"
+    ).map_err(|e| e.to_string())?;
+    let mut rows = stmt.query((start, end)).map_err(|e| e.to_string())?;
+    while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+        points[bucket(row.get(0).map_err(|e| e.to_string())?)].refunds += row.get::<_, i64>(1).map_err(|e| e.to_string())?;
+    }
+
+    Ok(points)
+}
+
+fn sales_totals(points: &[SalesDataPoint]) -> SalesTotals {
+    let mut totals = SalesTotals {
+        revenue: 0,
+        orders: 0,
+        units: 0,
+        discounts: 0,
+        tax: 0,
+        shipping: 0,
+        refunds: 0,
+        average_order_value: 0,
+    };
+    for p in points {
+        totals.revenue += p.revenue;
+        totals.orders += p.orders;
+        totals.units += p.units;
+        totals.discounts += p.discounts;
+        totals.tax += p.tax;
+        totals.shipping += p.shipping;
+        totals.refunds += p.refunds;
+    }
+    if totals.orders > 0 {
+        totals.average_order_value = totals.revenue / totals.orders;
+    }
+    totals
+}
+
+/// Sales by day, week (Monday start) or month between two dates (YYYY-MM-DD, inclusive),
+/// bucketed on calendar boundaries in `timezone` (a UTC offset; defaults to the store's
+/// `timezone` setting). The range is widened to whole buckets and compared with the
+/// same number of buckets immediately before it.
+#[ic_cdk::query]
+fn admin_get_sales_report(period: String, from: Option<String>, to: Option<String>, timezone: Option<String>) -> Result<SalesReport, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let (timezone, offset) = report_timezone(&conn, timezone)?;
+        let today = (now() / 1_000_000_000 + offset).div_euclid(86_400);
+
+        let last = match to {
+            Some(ref d) => parse_date(d)?,
+            None => today,
+        };
+        let first = match from {
+            Some(ref d) => parse_date(d)?,
+            // Default: last 30 days, 12 weeks or 12 months
+            None => match period.as_str() {
+                "week" => last - 7 * 11,
+                "month" => add_months(last, -11),
+                _ => last - 29,
+            },
+        };
+        if first > last {
+            return Err("'from' must not be after 'to'".to_string());
+        }
+
+        let starts = sales_buckets(&period, first, last)?;
+        let count = starts.len() as i64 - 1;
+        let previous_first = match period.as_str() {
+            "month" => add_months(starts[0], -count),
+            _ => starts[0] - (starts[count as usize] - starts[0]),
+        };
+        let previous_starts = sales_buckets(&period, previous_first, starts[0] - 1)?;
+
+        let label = |day: i64| -> String {
+            if period == "month" { format_date(day)[..7].to_string() } else { format_date(day) }
+        };
+        // Local midnight to UTC nanoseconds
+        let to_ns = |day: i64| (day * 86_400 - offset) * 1_000_000_000;
+
+        let bounds: Vec<i64> = starts.iter().map(|&d| to_ns(d)).collect();
+        let labels: Vec<String> = starts[..starts.len() - 1].iter().map(|&d| label(d)).collect();
+        let data = sales_data_points(&conn, &bounds, &labels)?;
+
+        let previous_bounds: Vec<i64> = previous_starts.iter().map(|&d| to_ns(d)).collect();
+        let previous_labels: Vec<String> = previous_starts[..previous_starts.len() - 1].iter().map(|&d| label(d)).collect();
+        let previous_data = sales_data_points(&conn, &previous_bounds, &previous_labels)?;
+
+        Ok(SalesReport {
+            period: period.clone(),
+            timezone: timezone.clone(),
+            from: format_date(starts[0]),
+            to: format_date(starts[starts.len() - 1] - 1),
+            totals: sales_totals(&data),
+            data,
+            previous_from: format_date(previous_starts[0]),
+            previous_to: format_date(previous_starts[previous_starts.len() - 1] - 1),
+            previous_totals: sales_totals(&previous_data),
+            previous_data,
+        })
+    })
+}
+
+// ============================================
+// ADMIN: FINANCIAL EXPORTS
+// ============================================
+
+const EXPORT_DEFAULT_LIMIT: i64 = 500;
+const EXPORT_MAX_LIMIT: i64 = 2000;
+
+// SQL for one export. Parameters: ?1/?2 = range start/end (ns, end exclusive), ?3/?4 = cursor
+// keys, ?5 = limit. The first `keys` columns are the cursor and are left out of the output.
+// Orders, line items, tax and shipping are dated by order completion; payments and refunds
+// by when they were made.
+fn export_query(report: &str) -> Option<(usize, &'static str)> {
+    let sql = match report {
+        "orders" => r#"
+            SELECT o.id,
+                   o.number AS order_number, o.completed_at, o.state, o.payment_state, o.email, o.currency,
+                   o.item_total, -o.promo_total AS discount_total, o.shipment_total AS shipping_total,
+                   o.included_tax_total + o.additional_tax_total AS tax_total,
+                   o.included_tax_total, o.additional_tax_total, o.adjustment_total, o.total,
+                   COALESCE((SELECT SUM(amount) FROM payments WHERE order_id = o.id AND state = 'completed'), 0) AS paid_total,
+                   COALESCE((SELECT SUM(r.amount) FROM refunds r JOIN payments p ON p.id = r.payment_id
+                             WHERE p.order_id = o.id AND r.state = 'succeeded'), 0) AS refunded_total,
+                   a.state_name AS ship_state, a.zipcode AS ship_zipcode, a.country_code AS ship_country
+            FROM orders o
+            LEFT JOIN addresses a ON a.id = o.ship_address_id
+            WHERE o.completed_at >= ?1 AND o.completed_at < ?2 AND o.id > ?3
+            ORDER BY o.id LIMIT ?5"#,
+        "line_items" => r#"
+            SELECT li.id,
+                   o.number AS order_number, o.completed_at, li.id AS line_item_id, v.sku, p.name AS product_name,
+                   li.quantity, li.price AS unit_price, li.quantity * li.price AS amount,
+                   -COALESCE((SELECT SUM(amount) FROM adjustments WHERE adjustable_type = 'LineItem' AND adjustable_id = li.id AND source_type = 'Promotion'), 0) AS discount,
+                   COALESCE((SELECT SUM(amount) FROM adjustments WHERE adjustable_type = 'LineItem' AND adjustable_id = li.id AND source_type = 'TaxRate'), 0) AS tax,
+                   COALESCE(li.currency, o.currency) AS currency
+            FROM line_items li
+            JOIN orders o ON o.id = li.order_id
+            LEFT JOIN variants v ON v.id = li.variant_id
+            LEFT JOIN products p ON p.id = v.product_id
+            WHERE o.completed_at >= ?1 AND o.completed_at < ?2 AND li.id > ?3
+            ORDER BY li.id LIMIT ?5"#,
+        "payments" => r#"
+            SELECT p.id,
+                   o.number AS order_number, p.created_at, p.id AS payment_id, pm.name AS payment_method, p.source_type,
+                   p.state, p.amount, o.currency,
+                   COALESCE((SELECT SUM(amount) FROM refunds WHERE payment_id = p.id AND state = 'succeeded'), 0) AS refunded,
+                   COALESCE(p.stripe_payment_intent_id, p.response_code) AS reference
+            FROM payments p
+            JOIN orders o ON o.id = p.order_id
+            LEFT JOIN payment_methods pm ON pm.id = p.payment_method_id
+            WHERE p.created_at >= ?1 AND p.created_at < ?2 AND p.id > ?3
+            ORDER BY p.id LIMIT ?5"#,
+        "refunds" => r#"
+            SELECT r.id,
+                   o.number AS order_number, r.created_at, r.id AS refund_id, r.payment_id, r.amount, o.currency,
+                   r.state, rr.name AS reason, r.transaction_id AS reference, r.failure_reason
+            FROM refunds r
+            JOIN payments p ON p.id = r.payment_id
+            JOIN orders o ON o.id = p.order_id
+            LEFT JOIN refund_reasons rr ON rr.id = r.refund_reason_id
+            WHERE r.created_at >= ?1 AND r.created_at < ?2 AND r.id > ?3
+            ORDER BY r.id LIMIT ?5"#,
+        // One row per order and tax rate
+        "tax" => r#"
+            SELECT a.order_id, COALESCE(a.source_id, 0),
+                   o.number AS order_number, o.completed_at, z.name AS zone, tr.name AS tax_rate, tr.amount AS rate,
+                   tc.name AS tax_category, MAX(COALESCE(a.included, 0)) AS included_in_price,
+                   SUM(a.amount) AS tax_amount, o.currency,
+                   ad.state_name AS ship_state, ad.zipcode AS ship_zipcode, ad.country_code AS ship_country
+            FROM adjustments a
+            JOIN orders o ON o.id = a.order_id
+            LEFT JOIN tax_rates tr ON tr.id = a.source_id
+            LEFT JOIN zones z ON z.id = tr.zone_id
+            LEFT JOIN tax_categories tc ON tc.id = tr.tax_category_id
+            LEFT JOIN addresses ad ON ad.id = o.ship_address_id
+            WHERE a.source_type = 'TaxRate' AND o.completed_at >= ?1 AND o.completed_at < ?2
+            AND (a.order_id, COALESCE(a.source_id, 0)) > (?3, ?4)
+            GROUP BY a.order_id, COALESCE(a.source_id, 0)
+            ORDER BY a.order_id, COALESCE(a.source_id, 0) LIMIT ?5"#,
+        // One row per tax rate (jurisdiction) over the whole range
+        "tax_summary" => r#"
+            SELECT COALESCE(a.source_id, 0),
+                   z.name AS zone, tr.name AS tax_rate, tr.amount AS rate, tc.name AS tax_category,
+                   MAX(COALESCE(a.included, 0)) AS included_in_price,
+                   COUNT(DISTINCT a.order_id) AS order_count, SUM(a.amount) AS tax_amount,
+                   GROUP_CONCAT(DISTINCT o.currency) AS currency
+            FROM adjustments a
+            JOIN orders o ON o.id = a.order_id
+            LEFT JOIN tax_rates tr ON tr.id = a.source_id
+            LEFT JOIN zones z ON z.id = tr.zone_id
+            LEFT JOIN tax_categories tc ON tc.id = tr.tax_category_id
+            WHERE a.source_type = 'TaxRate' AND o.completed_at >= ?1 AND o.completed_at < ?2
+            AND COALESCE(a.source_id, 0) > ?3
+            GROUP BY COALESCE(a.source_id, 0)
+            ORDER BY COALESCE(a.source_id, 0) LIMIT ?5"#,
+        "shipping" => r#"
+            SELECT s.id,
+                   o.number AS order_number, o.completed_at, s.number AS shipment_number,
+                   (SELECT sm.name FROM shipping_rates sr JOIN shipping_methods sm ON sm.id = sr.shipping_method_id
+                    WHERE sr.shipment_id = s.id AND sr.selected = 1 LIMIT 1) AS shipping_method,
+                   s.state, s.cost, o.currency, s.shipped_at, s.tracking,
+                   ad.state_name AS ship_state, ad.zipcode AS ship_zipcode, ad.country_code AS ship_country
+            FROM shipments s
+            JOIN orders o ON o.id = s.order_id
+            LEFT JOIN addresses ad ON ad.id = o.ship_address_id
+            WHERE o.completed_at >= ?1 AND o.completed_at < ?2 AND s.id > ?3
+            ORDER BY s.id LIMIT ?5"#,
+        _ => return None,
+    };
+    Some((if report == "tax" { 2 } else { 1 }, sql))
+}
+
+// Export cell as JSON; `*_at` timestamps become ISO 8601
+fn export_value(column: &str, value: ic_rusqlite::types::ValueRef) -> serde_json::Value {
+    use ic_rusqlite::types::ValueRef;
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(n) if column.ends_with("_at") => serde_json::Value::String(format_iso_datetime(n)),
+        ValueRef::Integer(n) => serde_json::Value::from(n),
+        ValueRef::Real(f) => serde_json::Value::from(f),
+        ValueRef::Text(t) => serde_json::Value::String(String::from_utf8_lossy(t).into_owned()),
+        ValueRef::Blob(b) => serde_json::Value::String(hex::encode(b)),
+    }
+}
+
+/// Export accounting data for a date range as CSV or JSON, in cursor-paginated chunks.
+/// report: orders, line_items, payments, refunds, tax (per order and tax rate), tax_summary
+/// (per tax rate and zone) or shipping.
+/// Amounts are in cents. Pass `next_cursor` back until it comes back empty; the CSV
+/// header is only on the first chunk, so chunks can be appended to one file.
+#[ic_cdk::query]
+fn admin_export_financials(input: FinancialExportInput) -> Result<FinancialExportChunk, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    let (keys, sql) = export_query(&input.report)
+        .ok_or("Unknown report (expected orders, line_items, payments, refunds, tax, tax_summary or shipping)")?;
+    if input.format != "csv" && input.format != "json" {
+        return Err("Format must be csv or json".to_string());
+    }
+    let limit = input.limit.unwrap_or(EXPORT_DEFAULT_LIMIT).clamp(1, EXPORT_MAX_LIMIT);
+
+    // Cursor is the last row's key(s), "id" or "order_id:tax_rate_id"
+    let cursor: Vec<i64> = match input.cursor.as_deref() {
+        None | Some("") => vec![0; keys],
+        Some(c) => c.split(':').map(|k| k.parse::<i64>()).collect::<Result<Vec<_>, _>>()
+            .ok().filter(|k| k.len() == keys)
+            .ok_or("Invalid cursor")?,
+    };
+
+    with_connection(|conn| {
+        // Whole days in the store timezone, `to` inclusive
+        let (_, offset) = report_timezone(&conn, input.timezone.clone())?;
+        let first = parse_date(&input.from)?;
+        let last = parse_date(&input.to)?;
+        if first > last {
+            return Err("'from' must not be after 'to'".to_string());
+        }
+        let start = (first * 86_400 - offset) * 1_000_000_000;
+        let end = ((last + 1) * 86_400 - offset) * 1_000_000_000;
+
+        let mut stmt = conn.prepare(sql).map_err(|e| e.to_string())?;
+        let columns: Vec<String> = stmt.column_names().iter().skip(keys).map(|c| c.to_string()).collect();
+
+        let mut rows = stmt.query((start, end, cursor[0], cursor.get(1).copied().unwrap_or(0), limit))
+            .map_err(|e| e.to_string())?;
+        let mut records: Vec<Vec<serde_json::Value>> = Vec::new();
+        let mut last_key: Vec<i64> = Vec::new();
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            last_key = (0..keys).map(|i| row.get::<_, i64>(i)).collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())?;
+            let mut record = Vec::with_capacity(columns.len());
+            for (i, column) in columns.iter().enumerate() {
+                record.push(export_value(column, row.get_ref(keys + i).map_err(|e| e.to_string())?));
+            }
+            records.push(record);
+        }
+
+        let row_count = records.len() as i64;
+        // A full chunk may have more behind it
+        let next_cursor = if row_count == limit {
+            Some(last_key.iter().map(|k| k.to_string()).collect::<Vec<_>>().join(":"))
+        } else {
+            None
+        };
+
+        let data = if input.format == "csv" {
+            let mut csv = String::new();
+            if input.cursor.as_deref().unwrap_or("").is_empty() {
+                csv.push_str(&columns.join(","));
+                csv.push('\n');
+            }
+            for record in &records {
+                let fields: Vec<String> = record.iter().map(|v| match v {
+                    serde_json::Value::Null => String::new(),
+                    serde_json::Value::String(s) => csv_field(s),
+                    other => other.to_string(),
+                }).collect();
+                csv.push_str(&fields.join(","));
+                csv.push('\n');
+            }
+            csv
+        } else {
+            let objects: Vec<serde_json::Value> = records.into_iter().map(|record| {
+                serde_json::Value::Object(columns.iter().cloned().zip(record).collect())
+            }).collect();
+            serde_json::to_string(&objects).map_err(|e| e.to_string())?
+        };
+
+        Ok(FinancialExportChunk {
+            report: input.report.clone(),
+            format: input.format.clone(),
+            data,
+            row_count,
+            next_cursor,
+        })
+    })
+}
+
+// ============================================
+// ADMIN: CUSTOMER ANALYTICS
+// ============================================
+
+// Customers are identified by lower-cased email across placed orders (guest and registered)
+
+// Per-customer totals before scoring
+struct CustomerAggregate {
+    email: String,
+    registered: bool,
+    first_order_at: i64,
+    last_order_at: i64,
+    order_count: i64,
+    gross: i64,
+    refunded: i64,
+}
+
+fn customer_aggregates(conn: &Connection) -> Result<Vec<CustomerAggregate>, String> {
+    let mut stmt = conn.prepare(
+        r#"SELECT LOWER(o.email), MAX(o.user_id IS NOT NULL), MIN(o.completed_at), MAX(o.completed_at), COUNT(*), SUM(o.total),
+           COALESCE(SUM((SELECT SUM(r.amount) FROM refunds r JOIN payments p ON p.id = r.payment_id
+                         WHERE p.order_id = o.id AND r.state = 'succeeded')), 0)
+           FROM orders o
+           WHERE o.state IN ('complete', 'returned') AND o.completed_at IS NOT NULL
+           AND o.email IS NOT NULL AND o.email != ''
+           GROUP BY LOWER(o.email)"#
+    ).map_err(|e| e.to_string())?;
+
+    let customers = stmt.query_map([], |row| {
+        Ok(CustomerAggregate {
+            email: row.get(0)?,
+            registered: row.get(1)?,
+            first_order_at: row.get(2)?,
+            last_order_at: row.get(3)?,
+            order_count: row.get(4)?,
+            gross: row.get(5)?,
+            refunded: row.get(6)?,
+        })
+    }).map_err(|e| e.to_string())?
+    .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+    Ok(customers)
+}
+
+// Quintile score (1-5) of each value; equal values share a score
+fn quintile_scores(values: &[i64]) -> Vec<i64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    let n = sorted.len() as i64;
+    values.iter().map(|v| {
+        let rank = sorted.partition_point(|x| x < v) as i64;
+        1 + rank * 5 / n.max(1)
+    }).collect()
+}
+
+// Segment name for recency / frequency scores
+fn rfm_segment(recency: i64, frequency: i64) -> &'static str {
+    match (recency, frequency) {
+        (4..=5, 4..=5) => "champions",
+        (_, 4..=5) if recency >= 3 => "loyal",
+        (4..=5, 1) => "new",
+        (4..=5, _) => "potential_loyalist",
+        (1..=2, 4..=5) => "cant_lose",
+        (1..=2, 3) => "at_risk",
+        (1..=2, _) => "hibernating",
+        _ => "needs_attention",
+    }
+}
+
+// Taxon with the most units bought by a customer
+fn favourite_taxon(conn: &Connection, email: &str) -> Option<String> {
+    conn.query_row(
+        r#"SELECT t.name FROM line_items li
+           JOIN orders o ON o.id = li.order_id
+           JOIN variants v ON v.id = li.variant_id
+           JOIN products_taxons pt ON pt.product_id = v.product_id
+           JOIN taxons t ON t.id = pt.taxon_id
+           WHERE LOWER(o.email) = ?1 AND o.state IN ('complete', 'returned') AND o.completed_at IS NOT NULL
+           GROUP BY t.id
+           ORDER BY SUM(li.quantity) DESC, t.name ASC
+           LIMIT 1"#,
+        (email,),
+        |row| row.get(0)
+    ).ok()
+}
+
+/// Lifetime value, order history and RFM scores per customer.
+/// sort: ltv (default), orders, aov, recent, first; segment filters by RFM segment.
+/// Scores are quintiles across all customers (5 = most recent / frequent / valuable).
+#[ic_cdk::query]
+fn admin_get_customer_metrics(params: CustomerMetricsQuery) -> Result<CustomerMetricsResponse, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let customers = customer_aggregates(&conn)?;
+
+        let recency = quintile_scores(&customers.iter().map(|c| c.last_order_at).collect::<Vec<_>>());
+        let frequency = quintile_scores(&customers.iter().map(|c| c.order_count).collect::<Vec<_>>());
+        let monetary = quintile_scores(&customers.iter().map(|c| c.gross - c.refunded).collect::<Vec<_>>());
+
+        let mut metrics: Vec<CustomerMetrics> = customers.into_iter().enumerate().map(|(i, c)| {
+            let lifetime_value = c.gross - c.refunded;
+            CustomerMetrics {
+                email: c.email,
+                customer_type: if c.registered { "registered" } else { "guest" }.to_string(),
+                first_order_at: c.first_order_at,
+                last_order_at: c.last_order_at,
+                order_count: c.order_count,
+                lifetime_value,
+                average_order_value: lifetime_value / c.order_count.max(1),
+                refunded: c.refunded,
+                refund_rate: if c.gross > 0 { c.refunded as f64 / c.gross as f64 } else { 0.0 },
+                favourite_taxon: None,
+                recency_score: recency[i],
+                frequency_score: frequency[i],
+                monetary_score: monetary[i],
+                rfm_segment: rfm_segment(recency[i], frequency[i]).to_string(),
+            }
+        }).collect();
+
+        // Segment sizes over every customer, before filtering
+        let mut segments: Vec<RfmSegmentCount> = Vec::new();
+        for m in &metrics {
+            match segments.iter_mut().find(|s| s.segment == m.rfm_segment) {
+                Some(s) => {
+                    s.customers += 1;
+                    s.lifetime_value += m.lifetime_value;
+                }
+                None => segments.push(RfmSegmentCount {
+                    segment: m.rfm_segment.clone(),
+                    customers: 1,
+                    lifetime_value: m.lifetime_value,
+                }),
+            }
+        }
+        segments.sort_by(|a, b| b.customers.cmp(&a.customers).then(a.segment.cmp(&b.segment)));
+
+        if let Some(ref segment) = params.segment {
+            metrics.retain(|m| &m.rfm_segment == segment);
+        }
+        if let Some(ref q) = params.q {
+            let q = q.trim().to_lowercase();
+            metrics.retain(|m| m.email.contains(&q));
+        }
+
+        match params.sort.as_deref() {
+            Some("orders") => metrics.sort_by_key(|m| std::cmp::Reverse(m.order_count)),
+            Some("aov") => metrics.sort_by_key(|m| std::cmp::Reverse(m.average_order_value)),
+            Some("recent") => metrics.sort_by_key(|m| std::cmp::Reverse(m.last_order_at)),
+            Some("first") => metrics.sort_by_key(|m| std::cmp::Reverse(m.first_order_at)),
+            _ => metrics.sort_by_key(|m| std::cmp::Reverse(m.lifetime_value)),
+        }
+
+        let total_count = metrics.len() as i64;
+        let page = params.page.unwrap_or(1).max(1);
+        let per_page = params.per_page.unwrap_or(50).clamp(1, 200);
+        let mut customers: Vec<CustomerMetrics> = metrics.into_iter()
+            .skip(((page - 1) * per_page) as usize)
+            .take(per_page as usize)
+            .collect();
+        for c in &mut customers {
+            c.favourite_taxon = favourite_taxon(&conn, &c.email);
+        }
+
+        let total_pages = ((total_count as f64) / (per_page as f64)).ceil() as i64;
+
+        Ok(CustomerMetricsResponse {
+            customers,
+            total_count,
+            page,
+            per_page,
+            total_pages,
+            segments,
+        })
+    })
+}
+
+/// Monthly acquisition cohorts (by first order) with repeat-purchase retention:
+/// retained[k] is how many of the cohort ordered again k months after their first month
+/// (retained[0] is the cohort size). Covers the last `months` cohorts (default 12, max 36).
+#[ic_cdk::query]
+fn admin_get_customer_cohorts(months: Option<i64>, timezone: Option<String>) -> Result<Vec<CustomerCohort>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    let months = months.unwrap_or(12).clamp(1, 36);
+
+    with_connection(|conn| {
+        let (_, offset) = report_timezone(&conn, timezone)?;
+        // Months since year 0 in the store timezone
+        let month_index = |ns: i64| -> i64 {
+            let (year, month, _) = civil_from_days((ns / 1_000_000_000 + offset).div_euclid(86_400));
+            year * 12 + month - 1
+        };
+        let current = month_index(now());
+        let first_cohort = current - months + 1;
+
+        let mut stmt = conn.prepare(
+            r#"SELECT LOWER(email), completed_at FROM orders
+               WHERE state IN ('complete', 'returned') AND completed_at IS NOT NULL
+               AND email IS NOT NULL AND email != ''
+               ORDER BY LOWER(email), completed_at"#
+        ).map_err(|e| e.to_string())?;
+        let orders: Vec<(String, i64)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        let mut cohorts: Vec<CustomerCohort> = (first_cohort..=current).map(|m| CustomerCohort {
+            cohort: format!("{:04}-{:02}", m.div_euclid(12), m.rem_euclid(12) + 1),
+            customers: 0,
+            retained: vec![0; (current - m + 1) as usize],
+            retention_rates: Vec::new(),
+        }).collect();
+
+        // Orders are grouped by customer, oldest first
+        let mut i = 0;
+        while i < orders.len() {
+            let email = &orders[i].0;
+            let acquired = month_index(orders[i].1);
+            let mut active: Vec<i64> = Vec::new();
+            while i < orders.len() && &orders[i].0 == email {
+                let k = month_index(orders[i].1) - acquired;
+                if active.last() != Some(&k) {
+                    active.push(k);
+                }
+                i += 1;
+            }
+
+            if acquired < first_cohort {
+                continue;
+            }
+            let cohort = &mut cohorts[(acquired - first_cohort) as usize];
+            cohort.customers += 1;
+            for k in active {
+                if let Some(count) = cohort.retained.get_mut(k as usize) {
+                    *count += 1;
+                }
+            }
+        }
+
+        for cohort in &mut cohorts {
+            let size = cohort.customers.max(1) as f64;
+            cohort.retention_rates = cohort.retained.iter().map(|&n| n as f64 / size).collect();
+        }
+
+        Ok(cohorts)
+    })
+}
+
+// ============================================
+// SHIPPING METHODS
+// ============================================
+
+#[ic_cdk::query]
+fn get_shipping_methods() -> Result<Vec<ShippingMethod>, String> {
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            r#"SELECT id, name, display_on, tracking_url, admin_name, code, carrier, service_level, base_cost, active
+               FROM shipping_methods WHERE deleted_at IS NULL AND active = 1
+               ORDER BY id ASC"#
+        ).map_err(|e| e.to_string())?;
+
+        let methods: Vec<ShippingMethod> = stmt.query_map([], |row| {
+            Ok(ShippingMethod {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                display_on: row.get(2)?,
+                tracking_url: row.get(3)?,
+                admin_name: row.get(4)?,
+                code: row.get(5)?,
+                carrier: row.get(6)?,
+                service_level: row.get(7)?,
+                cost: row.get(8)?,
+                active: row.get::<_, i64>(9)? == 1,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        Ok(methods)
+    })
+}
+
+#[ic_cdk::update]
+fn admin_create_shipping_method(input: CreateShippingMethodInput) -> Result<i64, String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        let active: i64 = if input.active.unwrap_or(true) { 1 } else { 0 };
+        conn.execute(
+            r#"INSERT INTO shipping_methods (name, admin_name, code, carrier, service_level, tracking_url, display_on, base_cost, active, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)"#,
+            (
+                &input.name,
+                &input.admin_name,
+                &input.code,
+                &input.carrier,
+                &input.service_level,
+                &input.tracking_url,
+                &input.display_on,
+                &input.base_cost,
+                active,
+                now(),
+            )
+        ).map_err(|e| e.to_string())?;
+        Ok(conn.last_insert_rowid())
+    })
+}
+
+#[ic_cdk::update]
+fn admin_update_shipping_method(id: i64, input: UpdateShippingMethodInput) -> Result<(), String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        if let Some(name) = input.name {
+            conn.execute("UPDATE shipping_methods SET name = ?1, updated_at = ?2 WHERE id = ?3", (&name, now(), id))?;
+        }
+        if let Some(admin_name) = input.admin_name {
+            conn.execute("UPDATE shipping_methods SET admin_name = ?1, updated_at = ?2 WHERE id = ?3", (&admin_name, now(), id))?;
+        }
+        if let Some(code) = input.code {
+            conn.execute("UPDATE shipping_methods SET code = ?1, updated_at = ?2 WHERE id = ?3", (&code, now(), id))?;
+        }
+        if let Some(carrier) = input.carrier {
+            conn.execute("UPDATE shipping_methods SET carrier = ?1, updated_at = ?2 WHERE id = ?3", (&carrier, now(), id))?;
+        }
+        if let Some(service_level) = input.service_level {
+            conn.execute("UPDATE shipping_methods SET service_level = ?1, updated_at = ?2 WHERE id = ?3", (&service_level, now(), id))?;
+        }
+        if let Some(tracking_url) = input.tracking_url {
+            conn.execute("UPDATE shipping_methods SET tracking_url = ?1, updated_at = ?2 WHERE id = ?3", (&tracking_url, now(), id))?;
+        }
+        if let Some(display_on) = input.display_on {
+            conn.execute("UPDATE shipping_methods SET display_on = ?1, updated_at = ?2 WHERE id = ?3", (&display_on, now(), id))?;
+        }
+        if let Some(base_cost) = input.base_cost {
+            conn.execute("UPDATE shipping_methods SET base_cost = ?1, updated_at = ?2 WHERE id = ?3", (base_cost, now(), id))?;
+        }
+        if let Some(active) = input.active {
+            let active_int: i64 = if active { 1 } else { 0 };
+            conn.execute("UPDATE shipping_methods SET active = ?1, updated_at = ?2 WHERE id = ?3", (active_int, now(), id))?;
+        }
+        Ok(())
+    }).map_err(|e: ic_rusqlite::Error| e.to_string())
+}
+
+#[ic_cdk::update]
+fn admin_delete_shipping_method(id: i64) -> Result<(), String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        conn.execute("UPDATE shipping_methods SET deleted_at = ?1 WHERE id = ?2", (now(), id))?;
+        Ok(())
+    }).map_err(|e: ic_rusqlite::Error| e.to_string())
+}
+
+#[ic_cdk::query]
+fn admin_get_shipping_methods() -> Result<Vec<ShippingMethod>, String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            r#"SELECT id, name, display_on, tracking_url, admin_name, code, carrier, service_level, base_cost, active
+               FROM shipping_methods WHERE deleted_at IS NULL
+               ORDER BY id ASC"#
+        ).map_err(|e| e.to_string())?;
+
+        let methods: Vec<ShippingMethod> = stmt.query_map([], |row| {
+            Ok(ShippingMethod {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                display_on: row.get(2)?,
+                tracking_url: row.get(3)?,
+                admin_name: row.get(4)?,
+                code: row.get(5)?,
+                carrier: row.get(6)?,
+                service_level: row.get(7)?,
+                cost: row.get(8)?,
+                active: row.get::<_, i64>(9)? == 1,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        Ok(methods)
+    })
+}
+
+// ============================================
+// TAX RATES
+// ============================================
+
+#[ic_cdk::query]
+fn get_tax_rates() -> Result<Vec<TaxRate>, String> {
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            r#"SELECT tr.id, tr.name, tr.amount, tr.zone_id, z.name as zone_name, 
+                      tr.tax_category_id, tc.name as category_name, tr.included_in_price, tr.show_rate_in_label
+               FROM tax_rates tr
+               LEFT JOIN zones z ON tr.zone_id = z.id
+               LEFT JOIN tax_categories tc ON tr.tax_category_id = tc.id
+               WHERE tr.deleted_at IS NULL"#
+        ).map_err(|e| e.to_string())?;
+
+        let rates: Vec<TaxRate> = stmt.query_map([], |row| {
+            Ok(TaxRate {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                amount: row.get(2)?,
+                zone_id: row.get(3)?,
+                zone_name: row.get(4)?,
+                tax_category_id: row.get(5)?,
+                tax_category_name: row.get(6)?,
+                included_in_price: row.get(7)?,
+                show_rate_in_label: row.get(8)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        Ok(rates)
+    })
+}
+
+#[ic_cdk::update]
+fn admin_create_tax_rate(input: CreateTaxRateInput) -> Result<i64, String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        conn.execute(
+            r#"INSERT INTO tax_rates (name, amount, zone_id, tax_category_id, included_in_price, show_rate_in_label, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6, ?6)"#,
+            (
+                &input.name,
+                &input.amount,
+                &input.zone_id,
+                &input.tax_category_id,
+                &input.included_in_price.unwrap_or(false),
+                now(),
+            )
+        ).map_err(|e| e.to_string())?;
+        Ok(conn.last_insert_rowid())
+    })
+}
+
+#[ic_cdk::update]
+fn admin_update_tax_rate(id: i64, input: UpdateTaxRateInput) -> Result<(), String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        if let Some(name) = input.name {
+            conn.execute("UPDATE tax_rates SET name = ?1, updated_at = ?2 WHERE id = ?3", (&name, now(), id))?;
+        }
+        if let Some(amount) = input.amount {
+            conn.execute("UPDATE tax_rates SET amount = ?1, updated_at = ?2 WHERE id = ?3", (&amount, now(), id))?;
+        }
+        if let Some(zone_id) = input.zone_id {
+            conn.execute("UPDATE tax_rates SET zone_id = ?1, updated_at = ?2 WHERE id = ?3", (zone_id, now(), id))?;
+        }
+        if let Some(tax_category_id) = input.tax_category_id {
+            conn.execute("UPDATE tax_rates SET tax_category_id = ?1, updated_at = ?2 WHERE id = ?3", (tax_category_id, now(), id))?;
+        }
+        if let Some(included_in_price) = input.included_in_price {
+            conn.execute("UPDATE tax_rates SET included_in_price = ?1, updated_at = ?2 WHERE id = ?3", (included_in_price, now(), id))?;
+        }
+        Ok(())
+    }).map_err(|e: ic_rusqlite::Error| e.to_string())
+}
+
+#[ic_cdk::update]
+fn admin_delete_tax_rate(id: i64) -> Result<(), String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        conn.execute("UPDATE tax_rates SET deleted_at = ?1 WHERE id = ?2", (now(), id))?;
+        Ok(())
+    }).map_err(|e: ic_rusqlite::Error| e.to_string())
+}
+
+#[ic_cdk::query]
+fn get_zones() -> Result<Vec<Zone>, String> {
+    with_connection(|conn| {
+        let mut stmt = conn.prepare("SELECT id, name, description, zone_members_count FROM zones").map_err(|e| e.to_string())?;
+        let zone_rows: Vec<(i64, String, Option<String>, i64)> = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        let mut zones = Vec::new();
+        for (id, name, description, member_count) in zone_rows {
+            // Get members for this zone
+            let mut member_stmt = conn.prepare(
+                "SELECT id, zoneable_type, zoneable_id FROM zone_members WHERE zone_id = ?1"
+            ).map_err(|e| e.to_string())?;
+            let members: Vec<ZoneMember> = member_stmt.query_map([id], |row| {
+                Ok(ZoneMember {
+                    id: row.get(0)?,
+                    zoneable_type: row.get(1)?,
+                    zoneable_id: row.get(2)?,
+                })
+            }).map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+
+            zones.push(Zone {
+                id,
+                name,
+                description,
+                member_count,
+                members,
+            });
+        }
+        Ok(zones)
+    })
+}
+
+#[ic_cdk::update]
+fn admin_create_zone(input: CreateZoneInput) -> Result<i64, String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        let now = now();
+        conn.execute(
+            "INSERT INTO zones (name, description, zone_members_count, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+            (&input.name, &input.description, input.members.len() as i64, now)
+        ).map_err(|e| e.to_string())?;
+
+        let zone_id = conn.last_insert_rowid();
+
+        // Insert members
+        for member in &input.members {
+            conn.execute(
+                "INSERT INTO zone_members (zone_id, zoneable_type, zoneable_id, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+                (zone_id, &member.zoneable_type, &member.zoneable_id, now)
+            ).map_err(|e| e.to_string())?;
+        }
+
+        Ok(zone_id)
+    })
+}
+
+#[ic_cdk::update]
+fn admin_update_zone(id: i64, input: UpdateZoneInput) -> Result<(), String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        let now = now();
+
+        if let Some(name) = input.name {
+            conn.execute("UPDATE zones SET name = ?1, updated_at = ?2 WHERE id = ?3", (&name, now, id))
+                .map_err(|e| e.to_string())?;
+        }
+        if let Some(description) = input.description {
+            conn.execute("UPDATE zones SET description = ?1, updated_at = ?2 WHERE id = ?3", (&description, now, id))
+                .map_err(|e| e.to_string())?;
+        }
+
+        // If members provided, replace all members
+        if let Some(members) = input.members {
+            // Delete existing members
+            conn.execute("DELETE FROM zone_members WHERE zone_id = ?1", [id])
+                .map_err(|e| e.to_string())?;
+
+            // Insert new members
+            for member in &members {
+                conn.execute(
+                    "INSERT INTO zone_members (zone_id, zoneable_type, zoneable_id, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+                    (id, &member.zoneable_type, &member.zoneable_id, now)
+                ).map_err(|e| e.to_string())?;
+            }
+
+            // Update member count
+            conn.execute("UPDATE zones SET zone_members_count = ?1, updated_at = ?2 WHERE id = ?3",
+                (members.len() as i64, now, id))
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn admin_delete_zone(id: i64) -> Result<(), String> {
+    if !is_admin() { return Err("Unauthorized".to_string()); }
+    with_connection(|conn| {
+        // Delete members first
+        conn.execute("DELETE FROM zone_members WHERE zone_id = ?1", [id])
+            .map_err(|e| e.to_string())?;
+        // Delete zone
+        conn.execute("DELETE FROM zones WHERE id = ?1", [id])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_tax_categories() -> Result<Vec<TaxCategory>, String> {
+    with_connection(|conn| {
+        let mut stmt = conn.prepare("SELECT id, name, description, is_default, tax_code FROM tax_categories").map_err(|e| e.to_string())?;
+        let items: Vec<TaxCategory> = stmt.query_map([], |row| {
+            Ok(TaxCategory {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                description: row.get(2)?,
+                is_default: row.get(3)?,
+                tax_code: row.get(4)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+        Ok(items)
+    })
+}
+
+// ============================================
+// HELPER FUNCTIONS
+// ============================================
+
+fn recalculate_order(conn: &Connection, order_id: i64) -> Result<(), String> {
+    let now = now();
+
+    // 1. Recalculate Taxes
+    apply_tax_adjustments(conn, order_id).ok();
+
+    // 1b. Re-evaluate promotions against the current cart
+    apply_promotions(conn, order_id)?;
+
+    // 2. Calculate item total and count
+    let (item_total, item_count): (i64, i64) = conn.query_row(
+        "SELECT COALESCE(SUM(price * quantity), 0), COALESCE(SUM(quantity), 0) FROM line_items WHERE order_id = ?1",
+        (order_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).map_err(|e| e.to_string())?;
+
+    // 3. Sum adjustments
+    let adjustment_total: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM adjustments WHERE order_id = ?1",
+        (order_id,),
+        |row| row.get(0)
+    ).unwrap_or(0);
+
+    let promo_total: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM adjustments WHERE order_id = ?1 AND source_type = 'Promotion'",
+        (order_id,),
+        |row| row.get(0)
+    ).unwrap_or(0);
+
+    let tax_total: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM adjustments WHERE order_id = ?1 AND source_type = 'TaxRate'",
+        (order_id,),
+        |row| row.get(0)
+    ).unwrap_or(0);
+
+    // 4. Get shipment total
+    let shipment_total: i64 = conn.query_row(
+        "SELECT COALESCE(SUM(cost), 0) FROM shipments WHERE order_id = ?1",
+        (order_id,),
+        |row| row.get(0)
+    ).unwrap_or(0);
+
+    let total = item_total + shipment_total + adjustment_total;
+
+    conn.execute(
+        r#"UPDATE orders SET
+           item_total = ?1, item_count = ?2, shipment_total = ?3,
+           adjustment_total = ?4, promo_total = ?5, additional_tax_total = ?6,
+           total = ?7, updated_at = ?8
+           WHERE id = ?9"#,
+        (item_total, item_count, shipment_total, adjustment_total, promo_total, tax_total, total, now, order_id)
+    ).map_err(|e| e.to_string())?;
+
+    // 5. Re-clamp applied gift cards to the new total
+    update_gift_card_payments(conn, order_id)?;
+
+    Ok(())
+}
+
+fn apply_tax_adjustments(conn: &Connection, order_id: i64) -> Result<(), String> {
+    let now = now();
+
+    // Get shipping address state/country
+    let address: Option<(String, String)> = conn.query_row(
+        r#"SELECT country_code, state_name FROM addresses a
+           JOIN orders o ON o.ship_address_id = a.id
+           WHERE o.id = ?1"#,
+        (order_id,),
+        |row| Ok((row.get(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default()))
+    ).ok();
+
+    if let Some((country, state)) = address {
+        // Find matching zonemembers and then zones
+        let mut zone_stmt = conn.prepare(
+            r#"SELECT DISTINCT z.id FROM zones z
+               JOIN zone_members zm ON zm.zone_id = z.id
+               WHERE (zm.zoneable_type = 'Country' AND zm.zoneable_id = ?1)
+               OR (zm.zoneable_type = 'State' AND zm.zoneable_id = ?2)"#
+        ).map_err(|e| e.to_string())?;
+
+        let zone_ids: Vec<i64> = zone_stmt.query_map((&country, &state), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<i64>>>().map_err(|e| e.to_string())?;
+
+        // Clear existing tax adjustments
+        conn.execute("DELETE FROM adjustments WHERE order_id = ?1 AND source_type = 'TaxRate'", (order_id,)).ok();
+
+        if !zone_ids.is_empty() {
+            // Find tax rates for these zones using parameterized query
+            // Build placeholders for IN clause: ?1, ?2, ?3, etc.
+            let placeholders: String = zone_ids.iter().enumerate()
+                .map(|(i, _)| format!("?{}", i + 1))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            let mut rate_stmt = conn.prepare(
+                &format!("SELECT id, name, amount FROM tax_rates WHERE zone_id IN ({})", placeholders)
+            ).map_err(|e| e.to_string())?;
+
+            // Convert zone_ids to params slice for rusqlite
+            let params: Vec<&dyn ic_rusqlite::ToSql> = zone_ids.iter()
+                .map(|id| id as &dyn ic_rusqlite::ToSql)
+                .collect();
+
+            let rates = rate_stmt.query_map(params.as_slice(), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, f64>(2)?))
+            }).map_err(|e| e.to_string())?;
+
+            let item_total: i64 = conn.query_row("SELECT item_total FROM orders WHERE id = ?1", (order_id,), |row| row.get(0)).unwrap_or(0);
+
+            for rate in rates {
+                let (rate_id, rate_name, amount) = rate.map_err(|e| e.to_string())?;
+                let tax_amount = (item_total as f64 * amount) as i64;
+                
+                if tax_amount != 0 {
+                    conn.execute(
+                        r#"INSERT INTO adjustments (source_type, source_id, adjustable_type, adjustable_id, order_id, amount, label, created_at, updated_at)
+                           VALUES ('TaxRate', ?1, 'Order', ?2, ?2, ?3, ?4, ?5, ?5)"#,
+                        (rate_id, order_id, tax_amount, format!("Tax: {}", rate_name), now)
+                    ).ok();
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_order_by_state(conn: &Connection, principal: &str, state: &str) -> Result<Option<OrderDetail>, String> {
+    let order_id: Option<i64> = conn.query_row(
+        "SELECT id FROM orders WHERE user_principal = ?1 AND state = ?2",
+        (principal, state),
+        |row| row.get(0)
+    ).ok();
+
+    match order_id {
+        Some(id) => Ok(Some(get_order_detail(conn, id)?)),
+        None => Ok(None),
+    }
+}
+
+fn get_order_detail(conn: &Connection, order_id: i64) -> Result<OrderDetail, String> {
+    let order: (i64, String, Option<String>, String, i64, i64, i64, i64, i64, Option<String>, Option<String>, Option<i64>, Option<i64>, Option<i64>, i64) = conn.query_row(
+        r#"SELECT id, number, email, state, item_total, shipment_total, total, item_count,
+           adjustment_total, payment_state, shipment_state,
+           ship_address_id, bill_address_id, completed_at, created_at
+           FROM orders WHERE id = ?1"#,
+        (order_id,),
+        |row| Ok((
+            row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?,
+            row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?, row.get(9)?,
+            row.get(10)?, row.get(11)?, row.get(12)?, row.get(13)?, row.get(14)?
+        ))
+    ).map_err(|e| e.to_string())?;
+
+    // Get line items
+    let mut line_stmt = conn.prepare(
+        r#"SELECT li.id, li.variant_id, li.quantity, li.price, li.currency,
+           p.name as product_name, p.slug, v.sku,
+           COALESCE(
+               (SELECT attachment_url FROM assets WHERE viewable_type = 'Variant' AND viewable_id = li.variant_id LIMIT 1),
+               (SELECT attachment_url FROM assets WHERE viewable_type = 'Variant' AND viewable_id = (
+                   SELECT id FROM variants WHERE product_id = v.product_id AND is_master = 1 LIMIT 1
+               ) LIMIT 1)
+           ) as image_url
+           FROM line_items li
+           JOIN variants v ON v.id = li.variant_id
+           JOIN products p ON p.id = v.product_id
+           WHERE li.order_id = ?1"#
+    ).map_err(|e| e.to_string())?;
+
+    let line_items: Vec<LineItemDetail> = line_stmt.query_map((order_id,), |row| {
+        let quantity: i64 = row.get(2)?;
+        let price: i64 = row.get(3)?;
+        Ok(LineItemDetail {
+            id: row.get(0)?,
+            variant_id: row.get(1)?,
+            quantity,
+            price,
+            total: quantity * price,
+            currency: row.get(4)?,
+            product_name: row.get(5)?,
+            product_slug: row.get(6)?,
+            variant_sku: row.get(7)?,
+            image_url: row.get(8)?,
+        })
+    }).map_err(|e| e.to_string())?
+    .collect::<ic_rusqlite::Result<Vec<_>>>()
+    .map_err(|e| e.to_string())?;
+
+    // Get shipping address
+    let ship_address: Option<AddressDetail> = if let Some(addr_id) = order.11 {
+        conn.query_row(
+            r#"SELECT id, firstname, lastname, address1, address2, city, state_name, zipcode, country_code, phone,
+               is_default, is_default_billing
+               FROM addresses WHERE id = ?1"#,
+            (addr_id,),
+            |row| Ok(AddressDetail {
+                id: row.get(0)?,
+                firstname: row.get(1)?,
+                lastname: row.get(2)?,
+                address1: row.get(3)?,
+                address2: row.get(4)?,
+                city: row.get(5)?,
+                state_name: row.get(6)?,
+                zipcode: row.get(7)?,
+                country_code: row.get(8)?,
+                phone: row.get(9)?,
+                is_default: row.get::<_, Option<i64>>(10)?.unwrap_or(0) == 1,
+                is_default_billing: row.get::<_, i64>(11)? == 1,
+            })
+        ).ok()
+    } else {
+        None
+    };
+
+    // Get shipment
+    let shipment: Option<ShipmentDetail> = conn.query_row(
+        r#"SELECT s.id, s.number, s.tracking, s.cost, s.state, s.shipped_at,
+           sm.id as method_id, sm.name as method_name
+           FROM shipments s
+           LEFT JOIN shipping_rates sr ON sr.shipment_id = s.id AND sr.selected = 1
+           LEFT JOIN shipping_methods sm ON sm.id = sr.shipping_method_id
+           WHERE s.order_id = ?1"#,
+        (order_id,),
+        |row| Ok(ShipmentDetail {
+            id: row.get(0)?,
+            number: row.get(1)?,
+            tracking: row.get(2)?,
+            cost: row.get(3)?,
+            state: row.get(4)?,
+            shipped_at: row.get(5)?,
+            shipping_method_id: row.get(6)?,
+            shipping_method_name: row.get(7)?,
+        })
+    ).ok();
+
+    let adjustments = get_order_adjustments(conn, order_id).unwrap_or_default();
+    let tax_total: i64 = adjustments.iter().filter(|a| a.source_type.as_deref() == Some("TaxRate")).map(|a| a.amount).sum();
+    let promo_total: i64 = adjustments.iter().filter(|a| a.source_type.as_deref() == Some("Promotion")).map(|a| a.amount).sum();
+    let gift_card_total = gift_card_payment_total(conn, order_id);
+
+    Ok(OrderDetail {
+        id: order.0,
+        number: order.1,
+        email: order.2,
+        state: order.3,
+        item_total: order.4,
+        shipment_total: order.5,
+        tax_total,
+        promo_total,
+        total: order.6,
+        item_count: order.7,
+        adjustment_total: order.8,
+        payment_state: order.9,
+        shipment_state: order.10,
+        completed_at: order.13,
+        created_at: order.14,
+        line_items,
+        ship_address,
+        bill_address: None, // Simplified
+        shipment,
+        adjustments,
+        payments: get_order_payments(conn, order_id).unwrap_or_default(),
+        gift_card_total,
+        amount_due: (order.6 - gift_card_total).max(0),
+        disputed: conn.query_row("SELECT disputed = 1 FROM orders WHERE id = ?1", (order_id,), |row| row.get(0)).unwrap_or(false),
+        access_token: None,
+    })
+}
+
+fn get_order_adjustments(conn: &Connection, order_id: i64) -> Result<Vec<AdjustmentDetail>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT id, label, amount, source_type, adjustable_type, included FROM adjustments WHERE order_id = ?1"
+    ).map_err(|e| e.to_string())?;
+
+    let adjustments = stmt.query_map((order_id,), |row| {
+        Ok(AdjustmentDetail {
+            id: row.get(0)?,
+            label: row.get(1)?,
+            amount: row.get(2)?,
+            source_type: row.get(3)?,
+            adjustable_type: row.get(4)?,
+            included: row.get::<_, i64>(5)? == 1,
+        })
+    }).map_err(|e| e.to_string())?
+    .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+    Ok(adjustments)
+}
+
+fn get_order_payments(conn: &Connection, order_id: i64) -> Result<Vec<PaymentDetail>, String> {
+    let mut stmt = conn.prepare(
+        r#"SELECT p.id, p.amount, p.state, pm.name, p.authorization_expires_at, p.created_at
+           FROM payments p
+           JOIN payment_methods pm ON pm.id = p.payment_method_id
+           WHERE p.order_id = ?1"#
+    ).map_err(|e| e.to_string())?;
+
+    let payments = stmt.query_map((order_id,), |row| {
+        Ok(PaymentDetail {
+            id: row.get(0)?,
+            amount: row.get(1)?,
+            state: row.get(2)?,
+            payment_method_name: row.get(3)?,
+            authorization_expires_at: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    }).map_err(|e| e.to_string())?
+    .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+    Ok(payments)
+}
+
+#[ic_cdk::query]
+fn get_payment_methods() -> Result<Vec<PaymentMethod>, String> {
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT id, name, type, description, active, auto_capture, position, test_mode, publishable_key, api_key
+             FROM payment_methods WHERE active = 1 AND available_to_users = 1 ORDER BY position ASC"
+        ).map_err(|e| e.to_string())?;
+
+        let methods = stmt.query_map([], |row| {
+            let api_key: Option<String> = row.get(9)?;
+            let api_key_set = api_key.map(|k| !k.is_empty()).unwrap_or(false);
+            Ok(PaymentMethod {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                method_type: row.get(2)?,
+                description: row.get(3)?,
+                active: row.get::<_, i64>(4)? == 1,
+                auto_capture: row.get::<_, i64>(5)? == 1,
+                position: row.get(6)?,
+                available_to_users: true,
+                available_to_admin: true,
+                test_mode: Some(row.get::<_, i64>(7)? == 1),
+                api_key_set,
+                publishable_key: row.get(8)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        Ok(methods)
+    })
+}
+
+#[ic_cdk::query]
+fn admin_get_payment_methods() -> Result<Vec<PaymentMethod>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT id, name, type, description, active, auto_capture, position, test_mode, publishable_key, api_key
+             FROM payment_methods ORDER BY position ASC"
+        ).map_err(|e| e.to_string())?;
+
+        let methods = stmt.query_map([], |row| {
+            let api_key: Option<String> = row.get(9)?;
+            let api_key_set = api_key.map(|k| !k.is_empty()).unwrap_or(false);
+            Ok(PaymentMethod {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                method_type: row.get(2)?,
+                description: row.get(3)?,
+                active: row.get::<_, i64>(4)? == 1,
+                auto_capture: row.get::<_, i64>(5)? == 1,
+                position: row.get(6)?,
+                available_to_users: true,
+                available_to_admin: true,
+                test_mode: Some(row.get::<_, i64>(7)? == 1),
+                api_key_set,
+                publishable_key: row.get(8)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        Ok(methods)
+    })
+}
+
+#[ic_cdk::update]
+fn admin_update_payment_method(id: i64, input: UpdatePaymentMethodInput) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+    with_connection(|conn| {
+        let now = now();
+        if let Some(name) = input.name {
+            conn.execute("UPDATE payment_methods SET name = ?1, updated_at = ?2 WHERE id = ?3", (name, now, id)).ok();
+        }
+        if let Some(active) = input.active {
+            conn.execute("UPDATE payment_methods SET active = ?1, updated_at = ?2 WHERE id = ?3", (if active { 1 } else { 0 }, now, id)).ok();
+        }
+        if let Some(auto_capture) = input.auto_capture {
+            conn.execute("UPDATE payment_methods SET auto_capture = ?1, updated_at = ?2 WHERE id = ?3", (if auto_capture { 1 } else { 0 }, now, id)).ok();
+        }
+        if let Some(test_mode) = input.test_mode {
+            conn.execute("UPDATE payment_methods SET test_mode = ?1, updated_at = ?2 WHERE id = ?3", (if test_mode { 1 } else { 0 }, now, id)).ok();
+        }
+        if let Some(api_key) = input.api_key {
+            conn.execute("UPDATE payment_methods SET api_key = ?1, updated_at = ?2 WHERE id = ?3", (api_key, now, id)).ok();
+        }
+        if let Some(publishable_key) = input.publishable_key {
+            conn.execute("UPDATE payment_methods SET publishable_key = ?1, updated_at = ?2 WHERE id = ?3", (publishable_key, now, id)).ok();
+        }
+        if let Some(webhook_secret) = input.webhook_secret {
+            conn.execute("UPDATE payment_methods SET webhook_secret = ?1, updated_at = ?2 WHERE id = ?3", (webhook_secret, now, id)).ok();
+        }
+        Ok(())
+    })
+}
+
+// ============================================
+// ADMIN: USERS
+// ============================================
+
+#[ic_cdk::query]
+fn admin_get_users(params: UserQueryParams) -> Result<UserListResponse, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let limit = params.per_page.unwrap_or(20);
+        let offset = (params.page.unwrap_or(1) - 1) * limit;
+
+        // Escape SQL LIKE wildcards to prevent query slowdown attacks
+        let search_pattern = params.q.as_ref().map(|q| {
+            let escaped = q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+
+        let (query, count_query) = match (&search_pattern, &params.role) {
+            (Some(_), Some(_)) => (
+                format!("SELECT id, principal, email, role, created_at FROM users WHERE (principal LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\') AND role = ?2 ORDER BY created_at DESC LIMIT {} OFFSET {}", limit, offset),
+                "SELECT COUNT(*) FROM users WHERE (principal LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\') AND role = ?2".to_string()
+            ),
+            (Some(_), None) => (
+                format!("SELECT id, principal, email, role, created_at FROM users WHERE (principal LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\') ORDER BY created_at DESC LIMIT {} OFFSET {}", limit, offset),
+                "SELECT COUNT(*) FROM users WHERE (principal LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\')".to_string()
+            ),
+            (None, Some(_)) => (
+                format!("SELECT id, principal, email, role, created_at FROM users WHERE role = ?1 ORDER BY created_at DESC LIMIT {} OFFSET {}", limit, offset),
+                "SELECT COUNT(*) FROM users WHERE role = ?1".to_string()
+            ),
+            (None, None) => (
+                format!("SELECT id, principal, email, role, created_at FROM users ORDER BY created_at DESC LIMIT {} OFFSET {}", limit, offset),
+                "SELECT COUNT(*) FROM users".to_string()
+            ),
+        };
+
+        let total_count: i64 = match (&search_pattern, &params.role) {
+            (Some(pattern), Some(role)) => conn.query_row(&count_query, (pattern, role), |row| row.get(0)).unwrap_or(0),
+            (Some(pattern), None) => conn.query_row(&count_query, (pattern,), |row| row.get(0)).unwrap_or(0),
+            (None, Some(role)) => conn.query_row(&count_query, (role,), |row| row.get(0)).unwrap_or(0),
+            (None, None) => conn.query_row(&count_query, [], |row| row.get(0)).unwrap_or(0),
+        };
+
+        let mut stmt = conn.prepare(&query).map_err(|e| e.to_string())?;
+
+        // Execute query with appropriate parameters
+        let rows: Vec<(i64, String, Option<String>, String, i64)> = match (&search_pattern, &params.role) {
+            (Some(pattern), Some(role)) => {
+                stmt.query_map((pattern, role), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+                }).map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+            },
+            (Some(pattern), None) => {
+                stmt.query_map((pattern,), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+                }).map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+            },
+            (None, Some(role)) => {
+                stmt.query_map((role,), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+                }).map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+            },
+            (None, None) => {
+                stmt.query_map([], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+                }).map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+            },
+        };
+
+        let users: Vec<UserDetail> = rows.into_iter().map(|(user_id, principal, email, role, created_at)| {
+            // Get order count and total spent
+            let (order_count, total_spent): (i64, i64) = conn.query_row(
+                "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE user_id = ?1",
+                (user_id,),
+                |orow| Ok((orow.get(0)?, orow.get(1)?))
+            ).unwrap_or((0, 0));
+
+            UserDetail {
+                id: user_id,
+                principal,
+                email,
+                role: role.clone(),
+                roles: vec![role],
+                permissions: vec![],
+                created_at,
+                order_count,
+                total_spent,
+            }
+        }).collect();
+
+        Ok(UserListResponse {
+            users,
+            total_count,
+            page: params.page.unwrap_or(1),
+            per_page: limit,
+            total_pages: (total_count as f64 / limit as f64).ceil() as i64,
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn admin_get_customers(params: CustomerQueryParams) -> Result<CustomerListResponse, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let limit = params.per_page.unwrap_or(50);
+        let page = params.page.unwrap_or(1);
+        let offset = (page - 1) * limit;
+
+        // Escape SQL LIKE wildcards to prevent query slowdown attacks
+        let search_pattern = params.q.as_ref().map(|q| {
+            let escaped = q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+        let has_search = search_pattern.is_some();
+        let customer_type_filter = params.customer_type.as_deref();
+
+        // Build the base WHERE clause based on customer_type filter
+        let type_clause = match customer_type_filter {
+            Some("registered") => " AND o.user_id IS NOT NULL",
+            Some("guest") => " AND o.user_id IS NULL",
+            _ => "",
+        };
+
+        // Build queries based on whether search is present
+        let (count_sql, data_sql) = if has_search {
+            (
+                format!(
+                    "SELECT COUNT(DISTINCT o.email) FROM orders o WHERE o.email IS NOT NULL AND o.email != '' AND o.email LIKE ?1 ESCAPE '\\'{}",
+                    type_clause
+                ),
+                format!(
+                    r#"SELECT
+                        o.email,
+                        CASE WHEN MAX(o.user_id) IS NOT NULL THEN 'registered' ELSE 'guest' END as customer_type,
+                        MAX(u.principal) as principal,
+                        COUNT(*) as order_count,
+                        COALESCE(SUM(o.total), 0) as total_spent,
+                        MIN(o.created_at) as first_order_at,
+                        MAX(o.created_at) as last_order_at
+                       FROM orders o
+                       LEFT JOIN users u ON o.user_id = u.id
+                       WHERE o.email IS NOT NULL AND o.email != '' AND o.email LIKE ?1 ESCAPE '\\'{}
+                       GROUP BY o.email
+                       ORDER BY last_order_at DESC
+                       LIMIT {} OFFSET {}"#,
+                    type_clause, limit, offset
+                )
+            )
+        } else {
+            (
+                format!(
+                    "SELECT COUNT(DISTINCT o.email) FROM orders o WHERE o.email IS NOT NULL AND o.email != ''{}",
+                    type_clause
+                ),
+                format!(
+                    r#"SELECT
+                        o.email,
+                        CASE WHEN MAX(o.user_id) IS NOT NULL THEN 'registered' ELSE 'guest' END as customer_type,
+                        MAX(u.principal) as principal,
+                        COUNT(*) as order_count,
+                        COALESCE(SUM(o.total), 0) as total_spent,
+                        MIN(o.created_at) as first_order_at,
+                        MAX(o.created_at) as last_order_at
+                       FROM orders o
+                       LEFT JOIN users u ON o.user_id = u.id
+                       WHERE o.email IS NOT NULL AND o.email != ''{}
+                       GROUP BY o.email
+                       ORDER BY last_order_at DESC
+                       LIMIT {} OFFSET {}"#,
+                    type_clause, limit, offset
+                )
+            )
+        };
+
+        // Execute count query with proper parameters
+        let total_count: i64 = if let Some(ref pattern) = search_pattern {
+            conn.query_row(&count_sql, (pattern,), |row| row.get(0)).unwrap_or(0)
+        } else {
+            conn.query_row(&count_sql, [], |row| row.get(0)).unwrap_or(0)
+        };
+
+        // Execute data query with proper parameters
+        let mut stmt = conn.prepare(&data_sql).map_err(|e| e.to_string())?;
+        let mut id_counter = offset;
+
+        let customers: Vec<CustomerSummary> = if let Some(ref pattern) = search_pattern {
+            stmt.query_map((pattern,), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, i64>(5)?,
+                    row.get::<_, i64>(6)?,
+                ))
+            }).map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+        } else {
+            stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, i64>(5)?,
+                    row.get::<_, i64>(6)?,
+                ))
+            }).map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+        }.into_iter().map(|(email, customer_type, principal, order_count, total_spent, first_order_at, last_order_at)| {
+            id_counter += 1;
+            CustomerSummary {
+                id: id_counter,
+                email,
+                customer_type,
+                principal,
+                order_count,
+                total_spent,
+                first_order_at,
+                last_order_at,
+            }
+        }).collect();
+
+        Ok(CustomerListResponse {
+            customers,
+            total_count,
+            page,
+            per_page: limit,
+            total_pages: (total_count as f64 / limit as f64).ceil() as i64,
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn admin_get_user(id: i64) -> Result<UserDetail, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let (order_count, total_spent): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE user_id = ?1",
+            (id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap_or((0, 0));
+
+        let mut user = conn.query_row(
+            "SELECT id, principal, email, role, created_at FROM users WHERE id = ?1",
+            (id,),
+            |row| Ok(UserDetail {
+                id: row.get(0)?,
+                principal: row.get(1)?,
+                email: row.get(2)?,
+                role: row.get(3)?,
+                roles: vec![],
+                permissions: vec![],
+                created_at: row.get(4)?,
+                order_count,
+                total_spent,
+            })
+        ).map_err(|_| "User not found".to_string())?;
+
+        // Get roles
+        let mut stmt = conn.prepare(
+            "SELECT r.name FROM roles r JOIN role_users ru ON ru.role_id = r.id WHERE ru.user_id = ?1"
+        ).map_err(|e| e.to_string())?;
+        
+        user.roles = stmt.query_map((id,), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<String>>>().map_err(|e| e.to_string())?;
+
+        // Get permissions
+        let mut stmt = conn.prepare(
+            r#"SELECT DISTINCT ps.name 
+               FROM permission_sets ps
+               JOIN role_permission_sets rps ON rps.permission_set_id = ps.id
+               JOIN role_users ru ON ru.role_id = rps.role_id
+               WHERE ru.user_id = ?1"#
+        ).map_err(|e| e.to_string())?;
+
+        user.permissions = stmt.query_map((id,), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<ic_rusqlite::Result<Vec<String>>>().map_err(|e| e.to_string())?;
+
+        Ok(user)
+    })
+}
+
+#[ic_cdk::update]
+fn admin_update_user(id: i64, input: UpdateUserInput) -> Result<(), String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let now = now();
+
+        if let Some(email) = input.email {
+            conn.execute("UPDATE users SET email = ?1, updated_at = ?2 WHERE id = ?3", (&email, now, id))
+                .map_err(|e| e.to_string())?;
+        }
+
+        if let Some(role) = input.role {
+            conn.execute("UPDATE users SET role = ?1, updated_at = ?2 WHERE id = ?3", (&role, now, id))
+                .map_err(|e| e.to_string())?;
+        }
+
+        if let Some(roles) = input.roles {
+            // Clear existing roles
+            conn.execute("DELETE FROM role_users WHERE user_id = ?1", (id,))
+                .map_err(|e| e.to_string())?;
+            
+            // Add new roles
+            for role_name in roles {
+                let role_id: i64 = conn.query_row(
+                    "SELECT id FROM roles WHERE name = ?1",
+                    (&role_name,),
+                    |row| row.get(0)
+                ).map_err(|_| format!("Role {} not found", role_name))?;
+
+                conn.execute(
+                    "INSERT INTO role_users (role_id, user_id, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
+                    (role_id, id, now)
+                ).map_err(|e| e.to_string())?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_refund_reasons() -> Result<Vec<RefundReason>, String> {
+    with_connection(|conn| {
+        let mut stmt = conn.prepare("SELECT id, name, active FROM refund_reasons WHERE active = 1")
+            .map_err(|e| e.to_string())?;
+        
+        let reasons = stmt.query_map([], |row| {
+            Ok(RefundReason {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                active: row.get::<_, i64>(2)? == 1,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        
+        Ok(reasons)
+    })
+}
+
+// Refunded so far; failed and canceled Stripe refunds don't count
+fn refunded_amount(conn: &Connection, payment_id: i64) -> i64 {
+    conn.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM refunds WHERE payment_id = ?1 AND state NOT IN ('failed', 'canceled')",
+        (payment_id,),
+        |row| row.get(0)
+    ).unwrap_or(0)
+}
+
+// Money taken back through lost disputes (chargebacks)
+fn lost_dispute_amount(conn: &Connection, payment_id: i64) -> i64 {
+    conn.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM disputes WHERE payment_id = ?1 AND status = 'lost'",
+        (payment_id,),
+        |row| row.get(0)
+    ).unwrap_or(0)
+}
+
+// Re-derive payment and order payment_state from the payment's refunds and lost disputes
+fn update_refunded_payment_state(conn: &Connection, payment_id: i64) -> Result<(), String> {
+    let now = now();
+
+    let (payment_amount, order_id): (i64, i64) = conn.query_row(
+        "SELECT amount, order_id FROM payments WHERE id = ?1",
+        (payment_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).map_err(|_| "Payment not found".to_string())?;
+
+    let total_refunded = refunded_amount(conn, payment_id) + lost_dispute_amount(conn, payment_id);
+
+    if total_refunded >= payment_amount {
+        conn.execute(
+            "UPDATE orders SET payment_state = 'void', updated_at = ?1 WHERE id = ?2",
+            (now, order_id)
+        ).ok();
+        conn.execute(
+            "UPDATE payments SET state = 'void', updated_at = ?1 WHERE id = ?2",
+            (now, payment_id)
+        ).ok();
+    } else if total_refunded > 0 {
+        conn.execute(
+            "UPDATE orders SET payment_state = 'credit_owed', updated_at = ?1 WHERE id = ?2",
+            (now, order_id)
+        ).ok();
+        conn.execute(
+            "UPDATE payments SET state = 'completed', updated_at = ?1 WHERE id = ?2 AND state = 'void'",
+            (now, payment_id)
+        ).ok();
+    } else {
+        // Every refund failed: the payment stands as it was
+        conn.execute(
+            "UPDATE payments SET state = 'completed', updated_at = ?1 WHERE id = ?2 AND state = 'void'",
+            (now, payment_id)
+        ).ok();
+        conn.execute(
+            "UPDATE orders SET payment_state = 'paid', updated_at = ?1 WHERE id = ?2 AND payment_state IN ('void', 'credit_owed')",
+            (now, order_id)
+        ).ok();
+    }
+
+    Ok(())
+}
+
+/// Refund a payment, in full or in part. Stripe payments are refunded through the
+/// Stripe API; other payment methods are only recorded.
+#[ic_cdk::update]
+async fn admin_create_refund(input: CreateRefundInput) -> Result<i64, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    if input.amount <= 0 {
+        return Err("Refund amount must be positive".to_string());
+    }
+
+    // Record the refund before calling Stripe so concurrent refunds can't exceed the payment
+    let (refund_id, stripe_refund) = with_connection(|conn| {
+        let now = now();
+
+        // Verify payment
+        let (payment_amount, stripe_intent_id, payment_state): (i64, Option<String>, Option<String>) = conn.query_row(
+            "SELECT amount, stripe_payment_intent_id, state FROM payments WHERE id = ?1",
+            (input.payment_id,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).map_err(|_| "Payment not found".to_string())?;
+
+        if payment_state.as_deref() == Some("pending") && stripe_intent_id.is_some() {
+            return Err("Payment is only authorized; cancel the order to release it".to_string());
+        }
+
+        let refundable = payment_amount - refunded_amount(&conn, input.payment_id) - lost_dispute_amount(&conn, input.payment_id);
+        if input.amount > refundable {
+            return Err(format!("Refund amount cannot exceed the remaining payment amount ({})", refundable));
+        }
+
+        let stripe_refund = match stripe_intent_id.filter(|id| !id.is_empty()) {
+            Some(intent_id) => Some((intent_id, stripe_credentials(&conn)?.1)),
+            None => None,
+        };
+
+        // Create refund
+        let refund_id: i64 = conn.query_row(
+            r#"INSERT INTO refunds (payment_id, amount, refund_reason_id, state, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?5) RETURNING id"#,
+            (input.payment_id, input.amount, input.reason_id, if stripe_refund.is_some() { "pending" } else { "succeeded" }, now),
+            |row| row.get(0)
+        ).map_err(|e| e.to_string())?;
+
+        if stripe_refund.is_none() {
+            update_refunded_payment_state(&conn, input.payment_id)?;
+        }
+
+        Ok((refund_id, stripe_refund))
+    })?;
+
+    if let Some((intent_id, api_key)) = stripe_refund {
+        match create_stripe_refund(&api_key, input.payment_id, refund_id, &intent_id, input.amount).await {
+            Ok(refund) => {
+                with_connection(|conn| sync_stripe_refund(&conn, &refund))?;
+            }
+            Err(e) => {
+                // The webhook corrects this if Stripe did process the refund
+                with_connection(|conn| {
+                    conn.execute(
+                        "UPDATE refunds SET state = 'failed', failure_reason = ?1, updated_at = ?2 WHERE id = ?3",
+                        (&e, now(), refund_id)
+                    ).ok();
+                });
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(refund_id)
+}
+
+// POST /v1/refunds for a local refund row; the idempotency key is the row id
+async fn create_stripe_refund(api_key: &str, payment_id: i64, refund_id: i64, intent_id: &str, amount: i64) -> Result<serde_json::Value, String> {
+    // Checkout Session payments were recorded under the session id; refund its payment intent
+    let intent_id = if intent_id.starts_with("cs_") {
+        let session = stripe_request(api_key, HttpMethod::GET, &format!("/v1/checkout/sessions/{}", intent_id), None, None).await?;
+        let session_intent = session["payment_intent"].as_str()
+            .ok_or("Checkout session has no payment intent")?
+            .to_string();
+        with_connection(|conn| {
+            conn.execute(
+                "UPDATE payments SET stripe_payment_intent_id = ?1, updated_at = ?2 WHERE id = ?3",
+                (&session_intent, now(), payment_id)
+            ).ok();
+        });
+        session_intent
+    } else {
+        intent_id.to_string()
+    };
+
+    let body = format!(
+        "payment_intent={}&amount={}&metadata[refund_id]={}",
+        url_encode(&intent_id), amount, refund_id
+    );
+    stripe_request(api_key, HttpMethod::POST, "/v1/refunds", Some(body), Some(format!("refund_{}", refund_id))).await
+}
+
+// Apply a Stripe refund object to the refunds table. Refunds made in the Stripe
+// dashboard are added; returns false if the refund isn't for one of our payments.
+fn sync_stripe_refund(conn: &Connection, refund: &serde_json::Value) -> Result<bool, String> {
+    let now = now();
+    let transaction_id = refund["id"].as_str().ok_or("Missing refund ID")?;
+    let amount = refund["amount"].as_i64().ok_or("Missing refund amount")?;
+    let status = refund["status"].as_str().unwrap_or("pending");
+    let failure_reason = refund["failure_reason"].as_str();
+
+    // Match on the Stripe id, or on the refund_id we sent as metadata
+    let existing: Option<(i64, i64)> = conn.query_row(
+        "SELECT id, payment_id FROM refunds WHERE transaction_id = ?1",
+        (transaction_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).ok().or_else(|| {
+        let local_id = refund["metadata"]["refund_id"].as_str()?.parse::<i64>().ok()?;
+        conn.query_row(
+            "SELECT id, payment_id FROM refunds WHERE id = ?1 AND transaction_id IS NULL",
+            (local_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).ok()
+    });
+
+    let payment_id = if let Some((id, payment_id)) = existing {
+        conn.execute(
+            r#"UPDATE refunds SET transaction_id = ?1, amount = ?2, state = ?3, failure_reason = ?4, updated_at = ?5
+               WHERE id = ?6"#,
+            (transaction_id, amount, status, failure_reason, now, id)
+        ).map_err(|e| e.to_string())?;
+        payment_id
+    } else {
+        let payment_id: Option<i64> = refund["payment_intent"].as_str().and_then(|intent_id| {
+            conn.query_row(
+                "SELECT id FROM payments WHERE stripe_payment_intent_id = ?1 ORDER BY id LIMIT 1",
+                (intent_id,),
+                |row| row.get(0)
+            ).ok()
+        });
+        let payment_id = match payment_id {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        conn.execute(
+            r#"INSERT INTO refunds (payment_id, amount, transaction_id, state, failure_reason, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)"#,
+            (payment_id, amount, transaction_id, status, failure_reason, now)
+        ).map_err(|e| e.to_string())?;
+        payment_id
+    };
+
+    update_refunded_payment_state(conn, payment_id)?;
+    Ok(true)
+}
+
+/// Refunds recorded against an order's payments
+#[ic_cdk::query]
+fn admin_get_order_refunds(order_id: i64) -> Result<Vec<Refund>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            r#"SELECT r.id, r.payment_id, o.number, r.amount, rr.name, r.state, r.transaction_id, r.failure_reason, r.created_at
+               FROM refunds r
+               JOIN payments p ON p.id = r.payment_id
+               JOIN orders o ON o.id = p.order_id
+               LEFT JOIN refund_reasons rr ON rr.id = r.refund_reason_id
+               WHERE p.order_id = ?1
+               ORDER BY r.created_at ASC, r.id ASC"#
+        ).map_err(|e| e.to_string())?;
+
+        let refunds = stmt.query_map((order_id,), |row| {
+            Ok(Refund {
+                id: row.get(0)?,
+                payment_id: row.get(1)?,
+                order_number: row.get(2)?,
+                amount: row.get(3)?,
+                reason: row.get(4)?,
+                state: row.get(5)?,
+                transaction_id: row.get(6)?,
+                failure_reason: row.get(7)?,
+                created_at: row.get(8)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        Ok(refunds)
+    })
+}
+
+// ============================================
+// PAYMENT CAPTURE
+// ============================================
+
+// Card authorizations lapse after 7 days if not captured
+const STRIPE_AUTHORIZATION_TTL: i64 = 7 * 24 * 3_600_000_000_000;
+
+// Mark an authorized Stripe payment as captured; false if the intent has no pending payment
+fn capture_authorized_payment(conn: &Connection, intent_id: &str, amount: i64) -> Result<bool, String> {
+    let now = now();
+
+    let payment: Option<(i64, i64)> = conn.query_row(
+        "SELECT id, order_id FROM payments WHERE stripe_payment_intent_id = ?1 AND state = 'pending'",
+        (intent_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).ok();
+    let (payment_id, order_id) = match payment {
+        Some(p) => p,
+        None => return Ok(false),
+    };
+
+    conn.execute(
+        "UPDATE payments SET state = 'completed', amount = ?1, authorization_expires_at = NULL, updated_at = ?2 WHERE id = ?3",
+        (amount, now, payment_id)
+    ).map_err(|e| e.to_string())?;
+    conn.execute(
+        "UPDATE payment_intents SET status = 'succeeded', updated_at = ?1 WHERE stripe_intent_id = ?2",
+        (now, intent_id)
+    ).ok();
+
+    // Capturing less than the order total leaves a balance
+    let (total, paid): (i64, i64) = conn.query_row(
+        r#"SELECT o.total, COALESCE((SELECT SUM(amount) FROM payments WHERE order_id = o.id AND state = 'completed'), 0)
+           FROM orders o WHERE o.id = ?1"#,
+        (order_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).map_err(|e| e.to_string())?;
+
+    conn.execute(
+        "UPDATE orders SET payment_state = ?1, updated_at = ?2 WHERE id = ?3 AND payment_state = 'pending'",
+        (if paid >= total { "paid" } else { "balance_due" }, now, order_id)
+    ).map_err(|e| e.to_string())?;
+
+    Ok(true)
+}
+
+// Authorization released, by us or by Stripe when it expired
+fn void_authorized_payment(conn: &Connection, intent_id: &str) -> Result<(), String> {
+    let now = now();
+
+    conn.execute(
+        r#"UPDATE orders SET payment_state = 'void', updated_at = ?1
+           WHERE payment_state = 'pending'
+           AND id IN (SELECT order_id FROM payments WHERE stripe_payment_intent_id = ?2 AND state = 'pending')"#,
+        (now, intent_id)
+    ).map_err(|e| e.to_string())?;
+    conn.execute(
+        "UPDATE payments SET state = 'void', authorization_expires_at = NULL, updated_at = ?1 WHERE stripe_payment_intent_id = ?2 AND state = 'pending'",
+        (now, intent_id)
+    ).map_err(|e| e.to_string())?;
+    conn.execute(
+        "UPDATE payment_intents SET status = 'canceled', updated_at = ?1 WHERE stripe_intent_id = ?2",
+        (now, intent_id)
+    ).ok();
+
+    Ok(())
+}
+
+// Capture the order's pending Stripe authorization. Without an amount this captures what
+// the order still owes (capped at the authorization); Stripe releases the remainder.
+// Returns None if the order has nothing to capture.
+async fn capture_order_payment(order_id: i64, amount: Option<i64>) -> Result<Option<i64>, String> {
+    let pending = with_connection(|conn| {
+        let authorization: Option<(i64, i64, String)> = conn.query_row(
+            r#"SELECT id, amount, stripe_payment_intent_id FROM payments
+               WHERE order_id = ?1 AND state = 'pending' AND stripe_payment_intent_id IS NOT NULL
+               ORDER BY id DESC LIMIT 1"#,
+            (order_id,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).ok();
+
+        match authorization {
+            Some((payment_id, authorized, intent_id)) => {
+                let (_, api_key) = stripe_credentials(&conn)?;
+                let capture_amount = amount.unwrap_or_else(|| order_amount_due(&conn, order_id).min(authorized));
+                Ok(Some((payment_id, intent_id, authorized, capture_amount, api_key)))
+            }
+            None => Ok::<_, String>(None),
+        }
+    })?;
+
+    let (payment_id, intent_id, authorized, capture_amount, api_key) = match pending {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+
+    if capture_amount <= 0 || capture_amount > authorized {
+        return Err(format!("Capture amount must be between 1 and the authorized amount ({})", authorized));
+    }
+
+    let intent = stripe_request(
+        &api_key,
+        HttpMethod::POST,
+        &format!("/v1/payment_intents/{}/capture", intent_id),
+        Some(format!("amount_to_capture={}", capture_amount)),
+        Some(format!("capture_{}", payment_id)),
+    ).await?;
+    let captured = intent["amount_received"].as_i64().unwrap_or(capture_amount);
+
+    with_connection(|conn| capture_authorized_payment(&conn, &intent_id, captured))?;
+    Ok(Some(captured))
+}
+
+// Release the order's pending Stripe authorizations
+async fn void_order_authorizations(order_id: i64) -> Result<(), String> {
+    let (api_key, intent_ids) = with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT stripe_payment_intent_id FROM payments WHERE order_id = ?1 AND state = 'pending' AND stripe_payment_intent_id IS NOT NULL"
+        ).map_err(|e| e.to_string())?;
+        let intent_ids: Vec<String> = stmt.query_map((order_id,), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        if intent_ids.is_empty() {
+            return Ok((String::new(), intent_ids));
+        }
+        Ok::<_, String>((stripe_credentials(&conn)?.1, intent_ids))
+    })?;
+
+    for intent_id in intent_ids {
+        stripe_request(
+            &api_key,
+            HttpMethod::POST,
+            &format!("/v1/payment_intents/{}/cancel", intent_id),
+            None,
+            Some(format!("void_{}", intent_id)),
+        ).await?;
+        with_connection(|conn| void_authorized_payment(&conn, &intent_id))?;
+    }
+
+    Ok(())
+}
+
+/// Capture an order's authorized Stripe payment (defaults to what the order owes).
+/// Returns the amount captured.
+#[ic_cdk::update]
+async fn admin_capture_payment(order_id: i64, amount: Option<i64>) -> Result<i64, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    capture_order_payment(order_id, amount).await?
+        .ok_or_else(|| "Order has no authorized payment to capture".to_string())
+}
+
+/// Authorized payments awaiting capture, soonest to expire first
+#[ic_cdk::query]
+fn admin_get_pending_authorizations() -> Result<Vec<PendingAuthorization>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            r#"SELECT p.id, p.order_id, o.number, p.amount, p.created_at, p.authorization_expires_at
+               FROM payments p
+               JOIN orders o ON o.id = p.order_id
+               WHERE p.state = 'pending' AND p.stripe_payment_intent_id IS NOT NULL
+               ORDER BY p.authorization_expires_at ASC"#
+        ).map_err(|e| e.to_string())?;
+
+        let authorizations = stmt.query_map([], |row| {
+            Ok(PendingAuthorization {
+                payment_id: row.get(0)?,
+                order_id: row.get(1)?,
+                order_number: row.get(2)?,
+                amount: row.get(3)?,
+                authorized_at: row.get(4)?,
+                expires_at: row.get(5)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        Ok(authorizations)
+    })
+}
+
+// ============================================
+// DISPUTES
+// ============================================
+
+// Where store notifications go: the notification address, else the sender address
+fn store_notification_email(conn: &Connection) -> Option<String> {
+    conn.query_row(
+        "SELECT COALESCE(NULLIF(TRIM(notification_email), ''), sender_email) FROM email_settings WHERE id = 1",
+        [],
+        |row| row.get(0)
+    ).ok()
+}
+
+// Upsert a Stripe dispute and apply it to its order. Returns the local dispute id when the
+// store should be told (dispute opened or just closed).
+fn sync_stripe_dispute(conn: &Connection, dispute: &serde_json::Value) -> Result<Option<i64>, String> {
+    let now = now();
+    let stripe_dispute_id = dispute["id"].as_str().ok_or("Missing dispute ID")?;
+    let amount = dispute["amount"].as_i64().ok_or("Missing dispute amount")?;
+    let status = dispute["status"].as_str().unwrap_or("needs_response");
+    let reason = dispute["reason"].as_str();
+    let currency = dispute["currency"].as_str().unwrap_or("usd").to_uppercase();
+    let closed = matches!(status, "won" | "lost" | "warning_closed");
+    // Stripe sends seconds; 0 or null once no evidence is due
+    let evidence_due_by = dispute["evidence_details"]["due_by"].as_i64()
+        .filter(|s| *s > 0)
+        .map(|s| s * 1_000_000_000);
+
+    let payment: Option<(i64, i64)> = dispute["payment_intent"].as_str().and_then(|intent_id| {
+        conn.query_row(
+            "SELECT id, order_id FROM payments WHERE stripe_payment_intent_id = ?1 ORDER BY id LIMIT 1",
+            (intent_id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).ok()
+    });
+
+    let existing: Option<(i64, bool)> = conn.query_row(
+        "SELECT id, closed_at IS NOT NULL FROM disputes WHERE stripe_dispute_id = ?1",
+        (stripe_dispute_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).ok();
+
+    let notify = match existing {
+        Some((id, was_closed)) => {
+            conn.execute(
+                r#"UPDATE disputes SET amount = ?1, status = ?2, reason = ?3, evidence_due_by = ?4,
+                   closed_at = CASE WHEN ?5 THEN COALESCE(closed_at, ?6) ELSE NULL END, updated_at = ?6
+                   WHERE id = ?7"#,
+                (amount, status, reason, evidence_due_by, closed, now, id)
+            ).map_err(|e| e.to_string())?;
+            if closed && !was_closed { Some(id) } else { None }
+        }
+        None => {
+            let id: i64 = conn.query_row(
+                r#"INSERT INTO disputes (stripe_dispute_id, stripe_charge_id, payment_id, order_id, amount, currency,
+                       reason, status, evidence_due_by, closed_at, created_at, updated_at)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?11) RETURNING id"#,
+                (
+                    stripe_dispute_id, dispute["charge"].as_str(), payment.map(|p| p.0), payment.map(|p| p.1),
+                    amount, &currency, reason, status, evidence_due_by,
+                    if closed { Some(now) } else { None }, now,
+                ),
+                |row| row.get(0)
+            ).map_err(|e| e.to_string())?;
+            Some(id)
+        }
+    };
+
+    // Flag the order; its payment_state follows the dispute
+    if let Some((payment_id, order_id)) = payment {
+        conn.execute(
+            "UPDATE orders SET disputed = 1, updated_at = ?1 WHERE id = ?2",
+            (now, order_id)
+        ).map_err(|e| e.to_string())?;
+
+        match status {
+            "won" | "warning_closed" => {
+                conn.execute(
+                    "UPDATE orders SET payment_state = 'paid', updated_at = ?1 WHERE id = ?2 AND payment_state = 'disputed'",
+                    (now, order_id)
+                ).map_err(|e| e.to_string())?;
+                if refunded_amount(conn, payment_id) > 0 {
+                    update_refunded_payment_state(conn, payment_id)?;
+                }
+            }
+            // A lost dispute takes its amount back like a refund: void in full, credit_owed in part
+            "lost" => update_refunded_payment_state(conn, payment_id)?,
+            _ => {
+                conn.execute(
+                    "UPDATE orders SET payment_state = 'disputed', updated_at = ?1 WHERE id = ?2",
+                    (now, order_id)
+                ).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    Ok(notify)
+}
+
+// Email the store about a dispute
+async fn send_dispute_notification(dispute_id: i64) -> Result<bool, String> {
+    let message = with_connection(|conn| {
+        let to = store_notification_email(&conn)?;
+        conn.query_row(
+            r#"SELECT d.stripe_dispute_id, COALESCE(o.number, 'unknown'), d.amount, COALESCE(d.reason, 'unknown'),
+               d.status, d.evidence_due_by
+               FROM disputes d
+               LEFT JOIN orders o ON o.id = d.order_id
+               WHERE d.id = ?1"#,
+            (dispute_id,),
+            |row| Ok(vec![
+                ("dispute_id", row.get::<_, String>(0)?),
+                ("order_number", row.get::<_, String>(1)?),
+                ("amount", format!("${:.2}", row.get::<_, i64>(2)? as f64 / 100.0)),
+                ("reason", row.get::<_, String>(3)?.replace('_', " ")),
+                ("status", row.get::<_, String>(4)?.replace('_', " ")),
+                ("evidence_due_by", row.get::<_, Option<i64>>(5)?.map(format_utc_datetime).unwrap_or_else(|| "n/a".to_string())),
+            ])
+        ).ok().map(|vars| (to, vars))
+    });
+
+    match message {
+        Some((to, vars)) => send_template_email("dispute", to, vars).await,
+        None => Ok(false),
+    }
+}
+
+/// Disputes, open ones first by evidence deadline. `status` filters by Stripe status,
+/// or "open" / "closed".
+#[ic_cdk::query]
+fn admin_get_disputes(status: Option<String>) -> Result<Vec<Dispute>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let filter = match status.as_deref() {
+            None => "1 = 1",
+            Some("open") => "d.closed_at IS NULL",
+            Some("closed") => "d.closed_at IS NOT NULL",
+            Some(_) => "d.status = ?1",
+        };
+        let mut stmt = conn.prepare(&format!(
+            r#"SELECT d.id, d.stripe_dispute_id, d.payment_id, d.order_id, o.number, d.amount, d.currency,
+               d.reason, d.status, d.evidence_due_by, d.closed_at, d.created_at
+               FROM disputes d
+               LEFT JOIN orders o ON o.id = d.order_id
+               WHERE {}
+               ORDER BY d.closed_at IS NOT NULL, d.evidence_due_by IS NULL, d.evidence_due_by ASC, d.created_at DESC"#,
+            filter
+        )).map_err(|e| e.to_string())?;
+
+        let map_row = |row: &ic_rusqlite::Row| -> ic_rusqlite::Result<Dispute> {
+            Ok(Dispute {
+                id: row.get(0)?,
+                stripe_dispute_id: row.get(1)?,
+                payment_id: row.get(2)?,
+                order_id: row.get(3)?,
+                order_number: row.get(4)?,
+                amount: row.get(5)?,
+                currency: row.get(6)?,
+                reason: row.get(7)?,
+                status: row.get(8)?,
+                evidence_due_by: row.get(9)?,
+                closed_at: row.get(10)?,
+                created_at: row.get(11)?,
+            })
+        };
+
+        let disputes = if filter.contains("?1") {
+            stmt.query_map((status.unwrap_or_default(),), map_row)
+        } else {
+            stmt.query_map([], map_row)
+        }.map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        Ok(disputes)
+    })
+}
+
+// ============================================
+// STRIPE INTEGRATION
+// ============================================
+
+/// Simple payment intent creation - just takes an amount in cents
+/// This is the simpler approach used for single-page checkout
+#[ic_cdk::update]
+async fn create_payment_intent(amount: i64) -> Result<String, String> {
+    // Get Stripe API key from database
+    let api_key = with_connection(|conn| {
+        conn.query_row(
+            "SELECT api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
+            [],
+            |row| row.get::<_, Option<String>>(0)
+        ).map_err(|_| "Stripe payment method not configured or active".to_string())?
+        .ok_or("Stripe API key not configured".to_string())
+    })?;
+
+    // Make HTTP outcall to Stripe API
+    let body = format!(
+        "amount={}&currency=usd&automatic_payment_methods[enabled]=true",
+        amount
+    );
+
+    let request = CanisterHttpRequestArgument {
+        url: "https://api.stripe.com/v1/payment_intents".to_string(),
+        max_response_bytes: Some(10000),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Authorization".to_string(),
+                value: format!("Bearer {}", api_key),
+            },
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/x-www-form-urlencoded".to_string(),
+            },
+        ],
+        body: Some(body.into_bytes()),
+        transform: None,
+    };
+
+    let cycles: u128 = 2_000_000_000;
+    let (response,) = http_request(request, cycles).await.map_err(|(code, msg)| {
+        format!("HTTP request failed: {:?} - {}", code, msg)
+    })?;
+
+    if response.status != 200u64 {
+        let body_text = String::from_utf8_lossy(&response.body);
+        return Err(format!("Stripe API error ({}): {}", response.status, body_text));
+    }
+
+    // Parse client_secret from response
+    let body_text = String::from_utf8_lossy(&response.body);
+    let client_secret = extract_json_string(&body_text, "client_secret")
+        .ok_or_else(|| format!("Could not parse client_secret from Stripe response: {}", &body_text[..body_text.len().min(200)]))?;
+
+    Ok(client_secret)
+}
+
+#[ic_cdk::update]
+async fn create_stripe_payment_intent(order_id: i64, session_id: Option<String>, save_card: Option<bool>) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+    // Cards can only be saved for signed-in users (they hang off the user's Stripe customer)
+    let save_card = save_card.unwrap_or(false) && user_id.is_some();
+    // Check admin BEFORE entering with_connection to avoid nested borrow
+    let is_admin_user = is_admin();
+
+    // Step 1: Get order details, check for existing intent, and get Stripe API key
+    let (total, payment_method_id, api_key, existing_intent) = with_connection(|conn| {
+        // Verify order exists and belongs to the caller (or guest with session) or they are admin
+        let (_total, state, _order_number): (i64, String, String) = if is_anonymous {
+            if let Some(sess_id) = &session_id {
+                conn.query_row(
+                    "SELECT total, state, number FROM orders WHERE id = ?1 AND guest_token = ?2",
+                    (order_id, sess_id),
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                ).map_err(|_| "Order not found or access denied".to_string())?
+            } else {
+                return Err("Session ID required for guest checkout".to_string());
+            }
+        } else {
+            conn.query_row(
+                "SELECT total, state, number FROM orders WHERE id = ?1 AND (user_principal = ?2 OR user_id = ?3 OR 1=?4)",
+                (order_id, &caller_str, user_id, if is_admin_user { 1 } else { 0 }),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            ).map_err(|_| "Order not found or access denied".to_string())?
+        };
+
+        if state != "cart" && state != "address" && state != "delivery" && state != "payment" {
+            return Err(format!("Order is in state '{}' and cannot be paid", state));
+        }
+
+        // Charge only what gift cards don't cover
+        let total = order_amount_due(&conn, order_id);
+
+        // IDEMPOTENCY: Check if we already have a valid PaymentIntent for this order
+        // If so, return it instead of creating a new one
+        let existing: Option<(String, i64, bool)> = conn.query_row(
+            r#"SELECT client_secret, amount, save_card FROM payment_intents
+               WHERE order_id = ?1 AND status NOT IN ('succeeded', 'canceled')
+               ORDER BY created_at DESC LIMIT 1"#,
+            (order_id,),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? == 1))
+        ).ok();
+
+        // If existing intent has same amount (and card saving choice), reuse it
+        if let Some((client_secret, intent_amount, intent_saves_card)) = existing {
+            if intent_amount == total && intent_saves_card == save_card {
+                // Return existing intent - no need to create new one
+                return Ok((total, 0i64, String::new(), Some(client_secret)));
+            }
+            // Amount changed - we'll need to create a new intent
+            // (Could also update the existing intent via Stripe API, but creating new is safer)
+        }
+
+        // Find the active Stripe payment method and get API key
+        let (payment_method_id, api_key): (i64, Option<String>) = conn.query_row(
+            "SELECT id, api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Stripe payment method not configured or active".to_string())?;
+
+        let api_key = api_key.ok_or("Stripe API key not configured")?;
+
+        Ok((total, payment_method_id, api_key, None))
+    })?;
+
+    // If we found an existing valid intent, return it immediately
+    if let Some(client_secret) = existing_intent {
+        return Ok(client_secret);
+    }
+
+    // Validate amount is positive (Stripe requires minimum 50 cents for USD)
+    if total <= 0 {
+        return Err("Order total must be positive".to_string());
+    }
+    if total < 50 {
+        return Err("Order total must be at least $0.50 USD for Stripe payments".to_string());
+    }
+
+    // Generate idempotency key based on order_id and amount
+    // This ensures Stripe won't create duplicates if our call is retried
+    let mut idempotency_key = format!("order_{}_amount_{}", order_id, total);
+
+    // Step 2: Make HTTP outcall to Stripe API with idempotency key
+    let mut body = format!(
+        "amount={}&currency=usd&automatic_payment_methods[enabled]=true&metadata[order_id]={}",
+        total, order_id
+    );
+
+    // Attach the card to the user's Stripe customer once the payment succeeds
+    if let (true, Some(uid)) = (save_card, user_id) {
+        let customer_id = ensure_stripe_customer(&api_key, uid).await?;
+        body.push_str(&format!("&customer={}&setup_future_usage=off_session", url_encode(&customer_id)));
+        idempotency_key.push_str("_save");
+    }
+
+    // Authorize only; the payment is captured when the order ships
+    if !with_connection(|conn| stripe_auto_capture(&conn)) {
+        body.push_str("&capture_method=manual");
+        idempotency_key.push_str("_manual");
+    }
+
+    let request = CanisterHttpRequestArgument {
+        url: "https://api.stripe.com/v1/payment_intents".to_string(),
+        max_response_bytes: Some(10000),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Authorization".to_string(),
+                value: format!("Bearer {}", api_key),
+            },
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/x-www-form-urlencoded".to_string(),
+            },
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: idempotency_key,
+            },
+        ],
+        body: Some(body.into_bytes()),
+        transform: None,
+    };
+
+    // HTTP outcalls require cycles (about 2 billion)
+    let cycles: u128 = 2_000_000_000;
+    let (response,) = http_request(request, cycles).await.map_err(|(code, msg)| {
+        format!("HTTP request failed: {:?} - {}", code, msg)
+    })?;
+
+    if response.status != 200u64 {
+        let body_text = String::from_utf8_lossy(&response.body);
+        return Err(format!("Stripe API error ({}): {}", response.status, body_text));
+    }
+
+    // Step 3: Parse the response to extract client_secret and id
+    let body_text = String::from_utf8_lossy(&response.body);
+    let (intent_id, client_secret) = parse_stripe_payment_intent(&body_text)?;
+
+    // Step 4: Store the payment intent in database
+    // Use INSERT OR REPLACE to handle race conditions
+    with_connection(|conn| {
+        let now = now();
+        conn.execute(
+            r#"INSERT OR REPLACE INTO payment_intents (order_id, payment_method_id, stripe_intent_id, client_secret, amount, status, save_card, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, 'requires_payment_method', ?6, ?7, ?7)"#,
+            (order_id, payment_method_id, &intent_id, &client_secret, total, save_card as i64, now)
+        ).map_err(|e| e.to_string())?;
+
+        Ok(client_secret)
+    })
+}
+
+// Helper function to parse Stripe PaymentIntent response using serde_json
+fn parse_stripe_payment_intent(json: &str) -> Result<(String, String), String> {
+    let parsed: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| format!("Failed to parse Stripe response as JSON: {}", e))?;
+
+    let id = parsed.get("id")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("Missing 'id' in Stripe response: {}", &json[..json.len().min(500)]))?
+        .to_string();
+
+    let client_secret = parsed.get("client_secret")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("Missing 'client_secret' in Stripe response: {}", &json[..json.len().min(500)]))?
+        .to_string();
+
+    Ok((id, client_secret))
+}
+
+// Helper to extract a string value from JSON using serde_json
+fn extract_json_string(json: &str, key: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(json)
+        .ok()
+        .and_then(|v| v.get(key)?.as_str().map(|s| s.to_string()))
+}
+
+// URL encode helper for Stripe API
+fn url_encode(text: &str) -> String {
+    let mut result = String::new();
+    for c in text.chars() {
+        match c {
+            ' ' => result.push_str("%20"),
+            '&' => result.push_str("%26"),
+            '=' => result.push_str("%3D"),
+            '?' => result.push_str("%3F"),
+            '#' => result.push_str("%23"),
+            '+' => result.push_str("%2B"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+// Active Stripe payment method id and secret key
+fn stripe_credentials(conn: &Connection) -> Result<(i64, String), String> {
+    let (payment_method_id, api_key): (i64, Option<String>) = conn.query_row(
+        "SELECT id, api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).map_err(|_| "Stripe payment method not configured or active".to_string())?;
+
+    Ok((payment_method_id, api_key.ok_or("Stripe API key not configured")?))
+}
+
+// Stripe payments are captured at checkout unless the merchant turned auto_capture off
+fn stripe_auto_capture(conn: &Connection) -> bool {
+    conn.query_row(
+        "SELECT COALESCE(auto_capture, 1) FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
+        [],
+        |row| row.get::<_, i64>(0)
+    ).map(|v| v == 1).unwrap_or(true)
+}
+
+// Call the Stripe API with a form-encoded body and parse the JSON response.
+// Stripe errors come back as Err with Stripe's own message.
+async fn stripe_request(
+    api_key: &str,
+    method: HttpMethod,
+    path: &str,
+    body: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let mut headers = vec![
+        HttpHeader {
+            name: "Authorization".to_string(),
+            value: format!("Bearer {}", api_key),
+        },
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/x-www-form-urlencoded".to_string(),
+        },
+    ];
+    if let Some(key) = idempotency_key {
+        headers.push(HttpHeader { name: "Idempotency-Key".to_string(), value: key });
+    }
+
+    let request = CanisterHttpRequestArgument {
+        url: format!("https://api.stripe.com{}", path),
+        max_response_bytes: Some(20000),
+        method,
+        headers,
+        body: body.map(|b| b.into_bytes()),
+        transform: None,
+    };
+
+    let (response,) = http_request(request, 2_000_000_000).await.map_err(|(code, msg)| {
+        format!("HTTP request failed: {:?} - {}", code, msg)
+    })?;
+
+    let json: serde_json::Value = serde_json::from_slice(&response.body)
+        .map_err(|_| format!("Invalid response from Stripe (status {})", response.status))?;
+
+    if response.status != 200u64 {
+        let message = json["error"]["message"].as_str().unwrap_or("unknown error");
+        return Err(format!("Stripe API error ({}): {}", response.status, message));
+    }
+
+    Ok(json)
+}
+
+// Whether a stripe_request error came from Stripe itself, so the request is settled. A failed
+// outcall may still have reached Stripe and must be retried with the same idempotency key.
+fn stripe_responded(error: &str) -> bool {
+    error.starts_with("Stripe API error")
+}
+
+/// Create a Stripe Checkout Session and return the redirect URL
+/// This is the recommended approach for a single-page checkout experience
+#[ic_cdk::update]
+async fn create_stripe_checkout_session(
+    order_id: i64,
+    success_url: String,
+    cancel_url: String,
+    session_id: Option<String>
+) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+    // Check admin BEFORE entering with_connection to avoid nested borrow
+    let is_admin_user = is_admin();
+
+    // Step 1: Get order details, check for existing session, and get Stripe API key
+    let (order_number, line_items, api_key, email, existing_session) = with_connection(|conn| {
+        // Verify order exists and get details
+        let (order_number, state, email, _total): (String, String, Option<String>, i64) = if is_anonymous {
+            if let Some(sess_id) = &session_id {
+                conn.query_row(
+                    "SELECT number, state, email, total FROM orders WHERE id = ?1 AND guest_token = ?2",
+                    (order_id, sess_id),
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                ).map_err(|_| "Order not found or access denied".to_string())?
+            } else {
+                return Err("Session ID required for guest checkout".to_string());
+            }
+        } else {
+            conn.query_row(
+                "SELECT number, state, email, total FROM orders WHERE id = ?1 AND (user_principal = ?2 OR user_id = ?3 OR 1=?4)",
+                (order_id, &caller_str, user_id, if is_admin_user { 1 } else { 0 }),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            ).map_err(|_| "Order not found or access denied".to_string())?
+        };
+
+        if state != "cart" && state != "address" && state != "delivery" && state != "payment" {
+            return Err(format!("Order is in state '{}' and cannot be paid", state));
+        }
+
+        // IDEMPOTENCY: Check if we already have a valid checkout session for this order
+        let existing: Option<String> = conn.query_row(
+            r#"SELECT client_secret FROM payment_intents
+               WHERE order_id = ?1 AND status = 'checkout_session'
+               ORDER BY created_at DESC LIMIT 1"#,
+            (order_id,),
+            |row| row.get(0)
+        ).ok();
+
+        // If we have an existing checkout session URL, return it
+        if let Some(url) = existing {
+            if !url.is_empty() {
+                return Ok((order_number, vec![], String::new(), email, Some(url)));
+            }
+        }
+
+        // Get line items
+        let mut stmt = conn.prepare(
+            r#"SELECT li.quantity, li.price, p.name
+               FROM line_items li
+               JOIN variants v ON v.id = li.variant_id
+               JOIN products p ON p.id = v.product_id
+               WHERE li.order_id = ?1"#
+        ).map_err(|e| e.to_string())?;
+
+        let mut items: Vec<(i64, i64, String)> = stmt.query_map((order_id,), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        }).map_err(|e| e.to_string())?
+        .collect::<ic_rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+        // With gift cards applied, charge the remaining balance as a single line
+        if gift_card_payment_total(&conn, order_id) > 0 && !items.is_empty() {
+            items = vec![(1, order_amount_due(&conn, order_id), format!("Order {}", order_number))];
+        }
+
+        // Get Stripe API key
+        let api_key: Option<String> = conn.query_row(
+            "SELECT api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
+            [],
+            |row| row.get(0)
+        ).map_err(|_| "Stripe payment method not configured or active".to_string())?;
+
+        let api_key = api_key.ok_or("Stripe API key not configured")?;
+
+        Ok((order_number, items, api_key, email, None))
+    })?;
+
+    // If we found an existing valid checkout session, return it immediately
+    if let Some(checkout_url) = existing_session {
+        return Ok(checkout_url);
+    }
+
+    // Validate we have line items and all prices are positive
+    if line_items.is_empty() {
+        return Err("Order has no items".to_string());
+    }
+    for (quantity, price, name) in &line_items {
+        if *price <= 0 {
+            return Err(format!("Invalid price for item '{}': must be positive", name));
+        }
+        if *quantity <= 0 {
+            return Err(format!("Invalid quantity for item '{}': must be positive", name));
+        }
+        // Stripe minimum is 50 cents per line item
+        if *price < 50 {
+            return Err(format!("Price for '{}' must be at least $0.50 USD", name));
+        }
+    }
+
+    // Step 2: Build the request body for Stripe Checkout Session
+    let mut body_parts: Vec<String> = vec![
+        "mode=payment".to_string(),
+        format!("success_url={}", url_encode(&success_url)),
+        format!("cancel_url={}", url_encode(&cancel_url)),
+        format!("client_reference_id={}", order_number),
+    ];
+
+    // Add customer email if available
+    if let Some(email_addr) = email {
+        body_parts.push(format!("customer_email={}", url_encode(&email_addr)));
+    }
+
+    // Authorize only; the order id lets the amount_capturable_updated webhook find the order
+    let manual_capture = !with_connection(|conn| stripe_auto_capture(&conn));
+    if manual_capture {
+        body_parts.push("payment_intent_data[capture_method]=manual".to_string());
+        body_parts.push(format!("payment_intent_data[metadata][order_id]={}", order_id));
+    }
+
+    // Add line items
+    for (i, (quantity, price, name)) in line_items.iter().enumerate() {
+        let prefix = format!("line_items[{}]", i);
+        body_parts.push(format!("{}[price_data][currency]=usd", prefix));
+        body_parts.push(format!("{}[price_data][unit_amount]={}", prefix, price));
+        body_parts.push(format!("{}[price_data][product_data][name]={}", prefix, url_encode(name)));
+        body_parts.push(format!("{}[quantity]={}", prefix, quantity));
+    }
+
+    let body = body_parts.join("&");
+
+    // Generate idempotency key based on order_number and amount
+    // This ensures Stripe won't create duplicate checkout sessions if our call is retried
+    let amount: i64 = line_items.iter().map(|(quantity, price, _)| quantity * price).sum();
+    let idempotency_key = format!("checkout_{}_{}{}", order_number, amount, if manual_capture { "_manual" } else { "" });
+
+    // Step 3: Make HTTP outcall to Stripe API with idempotency key
+    let request = CanisterHttpRequestArgument {
+        url: "https://api.stripe.com/v1/checkout/sessions".to_string(),
+        max_response_bytes: Some(15000),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Authorization".to_string(),
+                value: format!("Bearer {}", api_key),
+            },
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/x-www-form-urlencoded".to_string(),
+            },
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: idempotency_key,
+            },
+        ],
+        body: Some(body.into_bytes()),
+        transform: None,
+    };
+
+    let cycles: u128 = 2_000_000_000;
+    let (response,) = http_request(request, cycles).await.map_err(|(code, msg)| {
+        format!("HTTP request failed: {:?} - {}", code, msg)
+    })?;
+
+    if response.status != 200u64 {
+        let body_text = String::from_utf8_lossy(&response.body);
+        return Err(format!("Stripe API error ({}): {}", response.status, body_text));
+    }
+
+    // Step 4: Parse the response to get the checkout URL
+    let body_text = String::from_utf8_lossy(&response.body);
+    let checkout_url = extract_json_string(&body_text, "url")
+        .ok_or("Could not parse checkout URL from Stripe response")?;
+    
+    let session_stripe_id = extract_json_string(&body_text, "id")
+        .ok_or("Could not parse session ID from Stripe response")?;
+
+    // Step 5: Store the checkout session in database
+    with_connection(|conn| {
+        let now = now();
+        let payment_method_id: i64 = conn.query_row(
+            "SELECT id FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(1);
+
+        conn.execute(
+            r#"INSERT INTO payment_intents (order_id, payment_method_id, stripe_intent_id, client_secret, amount, status, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, 0, 'checkout_session', ?5, ?5)"#,
+            (order_id, payment_method_id, &session_stripe_id, &checkout_url, now)
+        ).ok(); // Ignore errors if already exists
+
+        // Move order to payment state
+        conn.execute(
+            "UPDATE orders SET state = 'payment', updated_at = ?1 WHERE id = ?2",
+            (now, order_id)
+        ).ok();
+
+        Ok(checkout_url)
+    })
+}
+
+/// Verify and record a Stripe payment by checking with Stripe API
+/// This is secure for mainnet - we verify the payment status directly with Stripe.
+/// Guests get the order's access token back for lookup_guest_order, also when the
+/// webhook already completed the order.
+#[ic_cdk::update]
+async fn record_stripe_payment(order_id: i64, payment_intent_id: String, _status: String, session_id: Option<String>) -> Result<Option<String>, String> {
+    // Step 1: Get Stripe API key from database
+    let api_key: String = with_connection(|conn| {
+        conn.query_row(
+            "SELECT api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 AND api_key IS NOT NULL LIMIT 1",
+            [],
+            |row| row.get(0)
+        ).map_err(|_| "Stripe API key not configured".to_string())
+    })?;
+
+    // Step 2: Verify order ownership
+    let caller = ic_cdk::api::caller();
+    let caller_str = caller.to_string();
+    let is_anonymous = caller == Principal::anonymous();
+    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+
+    let order_check = with_connection(|conn| {
+        let order_owner_check: bool = if is_anonymous {
+            if let Some(ref sess_id) = session_id {
+                conn.query_row(
+                    "SELECT 1 FROM orders WHERE id = ?1 AND guest_token = ?2",
+                    (order_id, sess_id),
+                    |_| Ok(true)
+                ).unwrap_or(false)
+            } else {
+                false
+            }
+        } else {
+            conn.query_row(
+                "SELECT 1 FROM orders WHERE id = ?1 AND (user_id = ?2 OR user_principal = ?3)",
+                (order_id, user_id, &caller_str),
+                |_| Ok(true)
+            ).unwrap_or(false)
+        };
+
+        if !order_owner_check && !is_admin() {
+            return Err("Unauthorized: Order does not belong to you".to_string());
+        }
+
+        // Verify order exists and get expected amount (total less gift cards)
+        let order_number: String = conn.query_row(
+            "SELECT number FROM orders WHERE id = ?1",
+            (order_id,),
+            |row| row.get(0)
+        ).map_err(|_| "Order not found".to_string())?;
+
+        // Guests look the order up later with a token derived from their session
+        let access_token = if is_anonymous && order_owner_check {
+            session_id.as_deref().map(|sess_id| order_access_token(sess_id, &order_number))
+        } else {
+            None
+        };
+
+        Ok((order_amount_due(&conn, order_id), order_number, access_token))
+    })?;
+
+    let (expected_amount, order_number, access_token) = order_check;
+
+    // Step 3: Verify payment with Stripe API
+    // Determine if this is a checkout session or payment intent
+    let is_session = payment_intent_id.starts_with("cs_");
+    let stripe_url = if is_session {
+        format!("https://api.stripe.com/v1/checkout/sessions/{}?expand[]=payment_intent", payment_intent_id)
+    } else {
+        format!("https://api.stripe.com/v1/payment_intents/{}", payment_intent_id)
+    };
+
+    let request_headers = vec![
+        HttpHeader { name: "Authorization".to_string(), value: format!("Bearer {}", api_key) },
+    ];
+
+    let request = CanisterHttpRequestArgument {
+        url: stripe_url,
+        method: HttpMethod::GET,
+        headers: request_headers,
+        body: None,
+        max_response_bytes: Some(10000),
+        transform: None,
+    };
+
+    let (response,) = http_request(request, 2_000_000_000).await.map_err(|(code, msg)| {
+        format!("HTTP request failed: {:?} - {}", code, msg)
+    })?;
+
+    if response.status != 200u64 {
+        return Err(format!("Stripe API error: status {}", response.status));
+    }
+
+    let body_str = String::from_utf8(response.body)
+        .map_err(|_| "Invalid response from Stripe")?;
+
+    let stripe_data: serde_json::Value = serde_json::from_str(&body_str)
+        .map_err(|_| "Failed to parse Stripe response")?;
+
+    // Step 4: Verify payment status and amount
+    // With manual capture an authorized payment counts; authorized_intent is the intent to capture later
+    let (verified_status, verified_amount, metadata_order_number, authorized_intent) = if is_session {
+        // For checkout sessions
+        let status = stripe_data["payment_status"].as_str().unwrap_or("");
+        let amount = stripe_data["amount_total"].as_i64().unwrap_or(0);
+        let meta_order = stripe_data["metadata"]["order_number"].as_str().map(|s| s.to_string());
+        let intent = &stripe_data["payment_intent"];
+        let authorized_intent = if status != "paid" && intent["status"].as_str() == Some("requires_capture") {
+            intent["id"].as_str().map(|s| s.to_string())
+        } else {
+            None
+        };
+        (status == "paid" || authorized_intent.is_some(), amount, meta_order, authorized_intent)
+    } else {
+        // For payment intents
+        let status = stripe_data["status"].as_str().unwrap_or("");
+        let amount = stripe_data["amount"].as_i64().unwrap_or(0);
+        let meta_order = stripe_data["metadata"]["order_number"].as_str().map(|s| s.to_string());
+        let authorized_intent = if status == "requires_capture" { Some(payment_intent_id.clone()) } else { None };
+        (status == "succeeded" || authorized_intent.is_some(), amount, meta_order, authorized_intent)
+    };
+
+    // Verify amount matches (within 1 cent tolerance for rounding)
+    if (verified_amount - expected_amount).abs() > 1 {
+        return Err(format!("Amount mismatch: expected {}, got {}", expected_amount, verified_amount));
+    }
+
+    // Verify order number matches if present in metadata
+    if let Some(ref meta_num) = metadata_order_number {
+        if meta_num != &order_number {
+            return Err("Order number mismatch".to_string());
+        }
+    }
+
+    if !verified_status {
+        return Err("Payment not completed".to_string());
+    }
+
+    // Step 5: Payment verified! Now complete the order
+    let needs_activation = with_connection(|conn| {
+        let now = now();
+
+        // Check if already processed (an authorized order is 'pending')
+        let already_paid: bool = conn.query_row(
+            "SELECT payment_state IN ('paid', 'pending') FROM orders WHERE id = ?1",
+            (order_id,),
+            |row| row.get(0)
+        ).unwrap_or(false);
+
+        if already_paid {
+            return Ok::<_, String>(false); // Already processed, nothing to do
+        }
+
+        let authorized = authorized_intent.is_some();
+
+        // Find payment method
+        let payment_method_id: i64 = conn.query_row(
+            "SELECT id FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
+            [],
+            |row| row.get(0)
+        ).map_err(|_| "Stripe payment method not configured".to_string())?;
+
+        // Update the intent status
+        conn.execute(
+            "UPDATE payment_intents SET status = ?1, updated_at = ?2 WHERE stripe_intent_id = ?3",
+            (if authorized { "requires_capture" } else { "succeeded" }, now, &payment_intent_id)
+        ).ok();
+
+        // Record the payment; an authorization is recorded against the intent that will be captured
+        conn.execute(
+            r#"INSERT OR IGNORE INTO payments (order_id, payment_method_id, amount, state, response_code, stripe_payment_intent_id, payment_source_id, authorization_expires_at, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?5, (SELECT payment_source_id FROM payment_intents WHERE stripe_intent_id = ?6), ?7, ?8, ?8)"#,
+            (
+                order_id, payment_method_id, expected_amount,
+                if authorized { "pending" } else { "completed" },
+                authorized_intent.as_ref().unwrap_or(&payment_intent_id), &payment_intent_id,
+                if authorized { Some(now + STRIPE_AUTHORIZATION_TTL) } else { None }, now,
             )
-        };
+        ).ok();
 
-        // Execute count query with proper parameters
-        let total_count: i64 = if let Some(ref pattern) = search_pattern {
-            conn.query_row(&count_sql, (pattern,), |row| row.get(0)).unwrap_or(0)
-        } else {
-            conn.query_row(&count_sql, [], |row| row.get(0)).unwrap_or(0)
-        };
+        // Finalize order
+        conn.execute(
+            "UPDATE orders SET payment_state = ?1, state = 'complete', completed_at = ?2, updated_at = ?2 WHERE id = ?3",
+            (if authorized { "pending" } else { "paid" }, now, order_id)
+        ).ok();
 
-        // Execute data query with proper parameters
-        let mut stmt = conn.prepare(&data_sql).map_err(|e| e.to_string())?;
-        let mut id_counter = offset;
+        let needs_activation = complete_gift_cards(&conn, order_id)?;
 
-        let customers: Vec<CustomerSummary> = if let Some(ref pattern) = search_pattern {
-            stmt.query_map((pattern,), |row| {
-                Ok((
-                    row.get::<_, String>(0)?,
-                    row.get::<_, String>(1)?,
-                    row.get::<_, Option<String>>(2)?,
-                    row.get::<_, i64>(3)?,
-                    row.get::<_, i64>(4)?,
-                    row.get::<_, i64>(5)?,
-                    row.get::<_, i64>(6)?,
-                ))
-            }).map_err(|e| e.to_string())?
-            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
-        } else {
-            stmt.query_map([], |row| {
-                Ok((
-                    row.get::<_, String>(0)?,
-                    row.get::<_, String>(1)?,
-                    row.get::<_, Option<String>>(2)?,
-                    row.get::<_, i64>(3)?,
-                    row.get::<_, i64>(4)?,
-                    row.get::<_, i64>(5)?,
-                    row.get::<_, i64>(6)?,
-                ))
-            }).map_err(|e| e.to_string())?
-            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
-        }.into_iter().map(|(email, customer_type, principal, order_count, total_spent, first_order_at, last_order_at)| {
-            id_counter += 1;
-            CustomerSummary {
-                id: id_counter,
-                email,
-                customer_type,
-                principal,
-                order_count,
-                total_spent,
-                first_order_at,
-                last_order_at,
-            }
-        }).collect();
+        // Decrease stock (components for bundle lines)
+        take_order_stock(&conn, order_id)?;
 
-        Ok(CustomerListResponse {
-            customers,
-            total_count,
-            page,
-            per_page: limit,
-            total_pages: (total_count as f64 / limit as f64).ceil() as i64,
-        })
-    })
+        Ok(needs_activation)
+    })?;
+
+    if needs_activation {
+        spawn_gift_card_activation(order_id);
+    }
+
+    // Keep the card if the buyer asked to save it
+    if !is_session && !stripe_data["setup_future_usage"].is_null() {
+        if let (Some(customer), Some(pm)) = (stripe_data["customer"].as_str(), stripe_data["payment_method"].as_str()) {
+            let _ = save_payment_source(customer.to_string(), pm.to_string()).await;
+        }
+    }
+
+    // Step 6: Send confirmation email (async, after db transaction)
+    let email_data = with_connection(|conn| {
+        conn.query_row(
+            r#"SELECT o.email, o.number, o.total,
+               COALESCE(a.firstname || ' ' || a.lastname, 'Customer'),
+               COALESCE(a.address1 || CHAR(10) || a.city || ', ' || COALESCE(a.state_name, '') || ' ' || a.zipcode, ''),
+               GROUP_CONCAT(li.quantity || ' x ' || p.name || ' - $' || printf('%.2f', v.price / 100.0), CHAR(10))
+               FROM orders o
+               LEFT JOIN addresses a ON a.id = o.ship_address_id
+               LEFT JOIN line_items li ON li.order_id = o.id
+               LEFT JOIN variants v ON v.id = li.variant_id
+               LEFT JOIN products p ON p.id = v.product_id
+               WHERE o.id = ?1
+               GROUP BY o.id"#,
+            (order_id,),
+            |row| Ok((
+                row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+            ))
+        ).ok()
+    });
+
+    if let Some((email, order_number, total, customer_name, shipping_address, items_text)) = email_data {
+        if !email.is_empty() {
+            let _ = send_order_confirmation(email, order_number, total, customer_name, shipping_address, items_text).await;
+        }
+    }
+
+    Ok(access_token)
 }
 
-#[ic_cdk::query]
-fn admin_get_user(id: i64) -> Result<UserDetail, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+// ============================================
+// SAVED CARDS
+// ============================================
 
-    with_connection(|conn| {
-        let (order_count, total_spent): (i64, i64) = conn.query_row(
-            "SELECT COUNT(*), COALESCE(SUM(total), 0) FROM orders WHERE user_id = ?1",
-            (id,),
+fn saved_card_user() -> Result<i64, String> {
+    if ic_cdk::api::caller() == Principal::anonymous() {
+        return Err("Sign in to use saved cards".to_string());
+    }
+    get_current_user_id().ok_or_else(|| "User not found".to_string())
+}
+
+// The user's Stripe customer id, creating the customer on first use
+async fn ensure_stripe_customer(api_key: &str, user_id: i64) -> Result<String, String> {
+    let (existing, email): (Option<String>, Option<String>) = with_connection(|conn| {
+        conn.query_row(
+            "SELECT stripe_customer_id, email FROM users WHERE id = ?1",
+            (user_id,),
             |row| Ok((row.get(0)?, row.get(1)?))
-        ).unwrap_or((0, 0));
+        ).map_err(|_| "User not found".to_string())
+    })?;
 
-        let mut user = conn.query_row(
-            "SELECT id, principal, email, role, created_at FROM users WHERE id = ?1",
-            (id,),
-            |row| Ok(UserDetail {
-                id: row.get(0)?,
-                principal: row.get(1)?,
-                email: row.get(2)?,
-                role: row.get(3)?,
-                roles: vec![],
-                permissions: vec![],
-                created_at: row.get(4)?,
-                order_count,
-                total_spent,
-            })
-        ).map_err(|_| "User not found".to_string())?;
+    if let Some(customer_id) = existing {
+        return Ok(customer_id);
+    }
 
-        // Get roles
-        let mut stmt = conn.prepare(
-            "SELECT r.name FROM roles r JOIN role_users ru ON ru.role_id = r.id WHERE ru.user_id = ?1"
-        ).map_err(|e| e.to_string())?;
-        
-        user.roles = stmt.query_map((id,), |row| row.get(0))
-            .map_err(|e| e.to_string())?
-            .collect::<ic_rusqlite::Result<Vec<String>>>().map_err(|e| e.to_string())?;
+    let mut body = format!("metadata[user_id]={}", user_id);
+    if let Some(email) = email.filter(|e| !e.is_empty()) {
+        body.push_str(&format!("&email={}", url_encode(&email)));
+    }
 
-        // Get permissions
-        let mut stmt = conn.prepare(
-            r#"SELECT DISTINCT ps.name 
-               FROM permission_sets ps
-               JOIN role_permission_sets rps ON rps.permission_set_id = ps.id
-               JOIN role_users ru ON ru.role_id = rps.role_id
-               WHERE ru.user_id = ?1"#
-        ).map_err(|e| e.to_string())?;
+    // Keyed by user so concurrent checkouts can't create two customers
+    let customer = stripe_request(
+        api_key, HttpMethod::POST, "/v1/customers", Some(body), Some(format!("customer_user_{}", user_id))
+    ).await?;
+    let customer_id = customer["id"].as_str()
+        .ok_or("Missing 'id' in Stripe customer response")?
+        .to_string();
 
-        user.permissions = stmt.query_map((id,), |row| row.get(0))
-            .map_err(|e| e.to_string())?
-            .collect::<ic_rusqlite::Result<Vec<String>>>().map_err(|e| e.to_string())?;
+    with_connection(|conn| {
+        conn.execute(
+            "UPDATE users SET stripe_customer_id = ?1, updated_at = ?2 WHERE id = ?3 AND stripe_customer_id IS NULL",
+            (&customer_id, now(), user_id)
+        ).map_err(|e| e.to_string())
+    })?;
 
-        Ok(user)
-    })
+    Ok(customer_id)
 }
 
-#[ic_cdk::update]
-fn admin_update_user(id: i64, input: UpdateUserInput) -> Result<(), String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+// Record the card Stripe attached to a customer after a setup_future_usage payment
+async fn save_payment_source(customer_id: String, stripe_pm_id: String) -> Result<(), String> {
+    let (payment_method_id, api_key, user_id, known) = with_connection(|conn| {
+        let (payment_method_id, api_key) = stripe_credentials(&conn)?;
+        let user_id: i64 = conn.query_row(
+            "SELECT id FROM users WHERE stripe_customer_id = ?1",
+            (&customer_id,),
+            |row| row.get(0)
+        ).map_err(|_| format!("No user for Stripe customer {}", customer_id))?;
+        let known = conn.query_row(
+            "SELECT 1 FROM payment_sources WHERE stripe_payment_method_id = ?1",
+            (&stripe_pm_id,),
+            |_| Ok(())
+        ).is_ok();
+        Ok::<_, String>((payment_method_id, api_key, user_id, known))
+    })?;
 
-    with_connection(|conn| {
+    if known {
+        return Ok(());
+    }
+
+    let pm = stripe_request(&api_key, HttpMethod::GET, &format!("/v1/payment_methods/{}", stripe_pm_id), None, None).await?;
+    let card = &pm["card"];
+    if card.is_null() {
+        return Ok(()); // only cards are offered as saved sources
+    }
+    let fingerprint = card["fingerprint"].as_str().map(|s| s.to_string());
+
+    let duplicate = with_connection(|conn| {
         let now = now();
 
-        if let Some(email) = input.email {
-            conn.execute("UPDATE users SET email = ?1, updated_at = ?2 WHERE id = ?3", (&email, now, id))
-                .map_err(|e| e.to_string())?;
+        // Saved meanwhile by the webhook or record_stripe_payment
+        if conn.query_row(
+            "SELECT 1 FROM payment_sources WHERE stripe_payment_method_id = ?1",
+            (&stripe_pm_id,),
+            |_| Ok(())
+        ).is_ok() {
+            return Ok(false);
         }
 
-        if let Some(role) = input.role {
-            conn.execute("UPDATE users SET role = ?1, updated_at = ?2 WHERE id = ?3", (&role, now, id))
-                .map_err(|e| e.to_string())?;
+        // The same card saved again shows up once; keep the row we already have
+        if let Some(fp) = &fingerprint {
+            if conn.query_row(
+                "SELECT 1 FROM payment_sources WHERE user_id = ?1 AND card_fingerprint = ?2 AND deleted_at IS NULL",
+                (user_id, fp),
+                |_| Ok(())
+            ).is_ok() {
+                return Ok(true);
+            }
         }
 
-        if let Some(roles) = input.roles {
-            // Clear existing roles
-            conn.execute("DELETE FROM role_users WHERE user_id = ?1", (id,))
-                .map_err(|e| e.to_string())?;
-            
-            // Add new roles
-            for role_name in roles {
-                let role_id: i64 = conn.query_row(
-                    "SELECT id FROM roles WHERE name = ?1",
-                    (&role_name,),
-                    |row| row.get(0)
-                ).map_err(|_| format!("Role {} not found", role_name))?;
+        conn.execute(
+            r#"INSERT INTO payment_sources (payment_method_id, user_id, stripe_payment_method_id, stripe_customer_id,
+                   card_brand, card_last4, card_exp_month, card_exp_year, card_fingerprint, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)"#,
+            (
+                payment_method_id, user_id, &stripe_pm_id, &customer_id,
+                card["brand"].as_str(), card["last4"].as_str(),
+                card["exp_month"].as_i64(), card["exp_year"].as_i64(),
+                &fingerprint, now,
+            )
+        ).map_err(|e| e.to_string())?;
 
-                conn.execute(
-                    "INSERT INTO role_users (role_id, user_id, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)",
-                    (role_id, id, now)
-                ).map_err(|e| e.to_string())?;
-            }
-        }
+        Ok::<_, String>(false)
+    })?;
 
-        Ok(())
-    })
-}
+    if duplicate {
+        let _ = stripe_request(&api_key, HttpMethod::POST, &format!("/v1/payment_methods/{}/detach", stripe_pm_id), None, None).await;
+    }
 
-#[ic_cdk::query]
-fn get_refund_reasons() -> Result<Vec<RefundReason>, String> {
-    with_connection(|conn| {
-        let mut stmt = conn.prepare("SELECT id, name, active FROM refund_reasons WHERE active = 1")
-            .map_err(|e| e.to_string())?;
-        
-        let reasons = stmt.query_map([], |row| {
-            Ok(RefundReason {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                active: row.get::<_, i64>(2)? == 1,
-            })
-        }).map_err(|e| e.to_string())?
-        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
-        
-        Ok(reasons)
-    })
+    Ok(())
 }
 
-#[ic_cdk::update]
-fn admin_create_refund(input: CreateRefundInput) -> Result<i64, String> {
-    if !is_admin() { return Err("Admin only".to_string()); }
+/// List the caller's saved cards
+#[ic_cdk::query]
+fn get_my_payment_sources() -> Result<Vec<SavedCard>, String> {
+    let user_id = saved_card_user()?;
 
     with_connection(|conn| {
-        let now = now();
-
-        // Verify payment
-        let (payment_amount, order_id): (i64, i64) = conn.query_row(
-            "SELECT amount, order_id FROM payments WHERE id = ?1",
-            (input.payment_id,),
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).map_err(|_| "Payment not found".to_string())?;
-
-        if input.amount > payment_amount {
-            return Err("Refund amount cannot exceed payment amount".to_string());
-        }
-
-        // Create refund
-        let refund_id: i64 = conn.query_row(
-            r#"INSERT INTO refunds (payment_id, amount, refund_reason_id, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, ?4) RETURNING id"#,
-            (input.payment_id, input.amount, input.reason_id, now),
-            |row| row.get(0)
+        let mut stmt = conn.prepare(
+            r#"SELECT id, card_brand, card_last4, card_exp_month, card_exp_year, created_at
+               FROM payment_sources
+               WHERE user_id = ?1 AND deleted_at IS NULL AND stripe_payment_method_id IS NOT NULL
+               ORDER BY created_at DESC"#
         ).map_err(|e| e.to_string())?;
 
-        // Update order payment state if fully refunded
-        let total_refunded: i64 = conn.query_row(
-            "SELECT COALESCE(SUM(amount), 0) FROM refunds WHERE payment_id = ?1",
-            (input.payment_id,),
-            |row| row.get(0)
-        ).unwrap_or(0);
-
-        if total_refunded >= payment_amount {
-            conn.execute(
-                "UPDATE orders SET payment_state = 'void', updated_at = ?1 WHERE id = ?2",
-                (now, order_id)
-            ).ok();
-            conn.execute(
-                "UPDATE payments SET state = 'void', updated_at = ?1 WHERE id = ?2",
-                (now, input.payment_id)
-            ).ok();
-        } else {
-             conn.execute(
-                "UPDATE orders SET payment_state = 'credit_owed', updated_at = ?1 WHERE id = ?2",
-                (now, order_id)
-            ).ok();
-        }
-        
-        Ok(refund_id)
+        let cards = stmt.query_map((user_id,), |row| {
+            Ok(SavedCard {
+                id: row.get(0)?,
+                card_brand: row.get(1)?,
+                card_last4: row.get(2)?,
+                card_exp_month: row.get(3)?,
+                card_exp_year: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        Ok(cards)
     })
 }
 
-// ============================================
-// STRIPE INTEGRATION
-// ============================================
-
-/// Simple payment intent creation - just takes an amount in cents
-/// This is the simpler approach used for single-page checkout
+/// Remove a saved card: detach it from the Stripe customer and hide it.
+/// The row is kept because past payments reference it.
 #[ic_cdk::update]
-async fn create_payment_intent(amount: i64) -> Result<String, String> {
-    // Get Stripe API key from database
-    let api_key = with_connection(|conn| {
-        conn.query_row(
-            "SELECT api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
-            [],
-            |row| row.get::<_, Option<String>>(0)
-        ).map_err(|_| "Stripe payment method not configured or active".to_string())?
-        .ok_or("Stripe API key not configured".to_string())
-    })?;
-
-    // Make HTTP outcall to Stripe API
-    let body = format!(
-        "amount={}&currency=usd&automatic_payment_methods[enabled]=true",
-        amount
-    );
-
-    let request = CanisterHttpRequestArgument {
-        url: "https://api.stripe.com/v1/payment_intents".to_string(),
-        max_response_bytes: Some(10000),
-        method: HttpMethod::POST,
-        headers: vec![
-            HttpHeader {
-                name: "Authorization".to_string(),
-                value: format!("Bearer {}", api_key),
-            },
-            HttpHeader {
-                name: "Content-Type".to_string(),
-                value: "application/x-www-form-urlencoded".to_string(),
-            },
-        ],
-        body: Some(body.into_bytes()),
-        transform: None,
-    };
+async fn remove_payment_source(payment_source_id: i64) -> Result<(), String> {
+    let user_id = saved_card_user()?;
 
-    let cycles: u128 = 2_000_000_000;
-    let (response,) = http_request(request, cycles).await.map_err(|(code, msg)| {
-        format!("HTTP request failed: {:?} - {}", code, msg)
+    let (api_key, stripe_pm_id) = with_connection(|conn| {
+        let stripe_pm_id: Option<String> = conn.query_row(
+            "SELECT stripe_payment_method_id FROM payment_sources WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NULL",
+            (payment_source_id, user_id),
+            |row| row.get(0)
+        ).map_err(|_| "Saved card not found".to_string())?;
+        let (_, api_key) = stripe_credentials(&conn)?;
+        Ok::<_, String>((api_key, stripe_pm_id))
     })?;
 
-    if response.status != 200u64 {
-        let body_text = String::from_utf8_lossy(&response.body);
-        return Err(format!("Stripe API error ({}): {}", response.status, body_text));
+    if let Some(pm) = stripe_pm_id {
+        stripe_request(&api_key, HttpMethod::POST, &format!("/v1/payment_methods/{}/detach", pm), None, None).await?;
     }
 
-    // Parse client_secret from response
-    let body_text = String::from_utf8_lossy(&response.body);
-    let client_secret = extract_json_string(&body_text, "client_secret")
-        .ok_or_else(|| format!("Could not parse client_secret from Stripe response: {}", &body_text[..body_text.len().min(200)]))?;
-
-    Ok(client_secret)
+    with_connection(|conn| {
+        let now = now();
+        conn.execute(
+            "UPDATE payment_sources SET deleted_at = ?1, updated_at = ?1 WHERE id = ?2",
+            (now, payment_source_id)
+        ).map_err(|e| e.to_string())?;
+        Ok(())
+    })
 }
 
+/// Pay an order with a saved card. If the bank asks for authentication the status is
+/// 'requires_action' and client_secret is returned for the frontend to confirm, after
+/// which record_stripe_payment (or the webhook) completes the order as usual.
 #[ic_cdk::update]
-async fn create_stripe_payment_intent(order_id: i64, session_id: Option<String>) -> Result<String, String> {
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
-    let user_id = if !is_anonymous { get_current_user_id() } else { None };
-    // Check admin BEFORE entering with_connection to avoid nested borrow
-    let is_admin_user = is_admin();
+async fn pay_with_saved_card(order_id: i64, payment_source_id: i64) -> Result<SavedCardPayment, String> {
+    let user_id = saved_card_user()?;
+    let caller_str = ic_cdk::api::caller().to_string();
 
-    // Step 1: Get order details, check for existing intent, and get Stripe API key
-    let (total, payment_method_id, api_key, existing_intent) = with_connection(|conn| {
-        // Verify order exists and belongs to the caller (or guest with session) or they are admin
-        let (total, state, _order_number): (i64, String, String) = if is_anonymous {
-            if let Some(sess_id) = &session_id {
-                conn.query_row(
-                    "SELECT total, state, number FROM orders WHERE id = ?1 AND guest_token = ?2",
-                    (order_id, sess_id),
-                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-                ).map_err(|_| "Order not found or access denied".to_string())?
-            } else {
-                return Err("Session ID required for guest checkout".to_string());
-            }
-        } else {
-            conn.query_row(
-                "SELECT total, state, number FROM orders WHERE id = ?1 AND (user_principal = ?2 OR user_id = ?3 OR 1=?4)",
-                (order_id, &caller_str, user_id, if is_admin_user { 1 } else { 0 }),
-                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-            ).map_err(|_| "Order not found or access denied".to_string())?
-        };
+    let (payment_method_id, api_key, amount, stripe_pm_id, customer_id, attempts) = with_connection(|conn| {
+        let (state, has_address, attempts): (String, bool, i64) = conn.query_row(
+            r#"SELECT state, ship_address_id IS NOT NULL, saved_card_attempts FROM orders
+               WHERE id = ?1 AND (user_id = ?2 OR user_principal = ?3)"#,
+            (order_id, user_id, &caller_str),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).map_err(|_| "Order not found or access denied".to_string())?;
 
-        if state != "cart" && state != "address" && state != "delivery" && state != "payment" {
+        // Same as checkout: address and shipping have to be chosen first
+        if state != "delivery" && state != "payment" && state != "confirm" {
             return Err(format!("Order is in state '{}' and cannot be paid", state));
         }
-
-        // IDEMPOTENCY: Check if we already have a valid PaymentIntent for this order
-        // If so, return it instead of creating a new one
-        let existing: Option<(String, i64)> = conn.query_row(
-            r#"SELECT client_secret, amount FROM payment_intents
-               WHERE order_id = ?1 AND status NOT IN ('succeeded', 'canceled')
-               ORDER BY created_at DESC LIMIT 1"#,
-            (order_id,),
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).ok();
-
-        // If existing intent has same amount, reuse it
-        if let Some((client_secret, intent_amount)) = existing {
-            if intent_amount == total {
-                // Return existing intent - no need to create new one
-                return Ok((total, 0i64, String::new(), Some(client_secret)));
-            }
-            // Amount changed - we'll need to create a new intent
-            // (Could also update the existing intent via Stripe API, but creating new is safer)
+        if !has_address {
+            return Err("Order has no shipping address".to_string());
         }
 
-        // Find the active Stripe payment method and get API key
-        let (payment_method_id, api_key): (i64, Option<String>) = conn.query_row(
-            "SELECT id, api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
-            [],
+        let (stripe_pm_id, customer_id): (String, String) = conn.query_row(
+            r#"SELECT stripe_payment_method_id, stripe_customer_id FROM payment_sources
+               WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NULL
+               AND stripe_payment_method_id IS NOT NULL AND stripe_customer_id IS NOT NULL"#,
+            (payment_source_id, user_id),
             |row| Ok((row.get(0)?, row.get(1)?))
-        ).map_err(|_| "Stripe payment method not configured or active".to_string())?;
+        ).map_err(|_| "Saved card not found".to_string())?;
 
-        let api_key = api_key.ok_or("Stripe API key not configured")?;
+        let (payment_method_id, api_key) = stripe_credentials(&conn)?;
 
-        Ok((total, payment_method_id, api_key, None))
+        Ok((payment_method_id, api_key, order_amount_due(&conn, order_id), stripe_pm_id, customer_id, attempts))
     })?;
 
-    // If we found an existing valid intent, return it immediately
-    if let Some(client_secret) = existing_intent {
-        return Ok(client_secret);
-    }
-
-    // Validate amount is positive (Stripe requires minimum 50 cents for USD)
-    if total <= 0 {
-        return Err("Order total must be positive".to_string());
-    }
-    if total < 50 {
+    if amount < 50 {
         return Err("Order total must be at least $0.50 USD for Stripe payments".to_string());
     }
 
-    // Generate idempotency key based on order_id and amount
-    // This ensures Stripe won't create duplicates if our call is retried
-    let idempotency_key = format!("order_{}_amount_{}", order_id, total);
-
-    // Step 2: Make HTTP outcall to Stripe API with idempotency key
-    let body = format!(
-        "amount={}&currency=usd&automatic_payment_methods[enabled]=true&metadata[order_id]={}",
-        total, order_id
+    let mut body = format!(
+        "amount={}&currency=usd&customer={}&payment_method={}&payment_method_types[]=card&confirm=true&metadata[order_id]={}",
+        amount, url_encode(&customer_id), url_encode(&stripe_pm_id), order_id
     );
+    if !with_connection(|conn| stripe_auto_capture(&conn)) {
+        body.push_str("&capture_method=manual");
+    }
+    // Retrying after a lost response reuses the key; a settled attempt (declined, or waiting
+    // on authentication) moves the counter on so the card can be tried again
+    let idempotency_key = format!("order_{}_source_{}_attempt_{}", order_id, payment_source_id, attempts);
+    let next_attempt = || with_connection(|conn| {
+        conn.execute(
+            "UPDATE orders SET saved_card_attempts = ?1 + 1, updated_at = ?2 WHERE id = ?3 AND saved_card_attempts = ?1",
+            (attempts, now(), order_id)
+        ).map_err(|e| e.to_string())
+    });
 
-    let request = CanisterHttpRequestArgument {
-        url: "https://api.stripe.com/v1/payment_intents".to_string(),
-        max_response_bytes: Some(10000),
-        method: HttpMethod::POST,
-        headers: vec![
-            HttpHeader {
-                name: "Authorization".to_string(),
-                value: format!("Bearer {}", api_key),
-            },
-            HttpHeader {
-                name: "Content-Type".to_string(),
-                value: "application/x-www-form-urlencoded".to_string(),
-            },
-            HttpHeader {
-                name: "Idempotency-Key".to_string(),
-                value: idempotency_key,
-            },
-        ],
-        body: Some(body.into_bytes()),
-        transform: None,
+    let intent = match stripe_request(&api_key, HttpMethod::POST, "/v1/payment_intents", Some(body), Some(idempotency_key)).await {
+        Ok(intent) => intent,
+        Err(e) => {
+            if stripe_responded(&e) {
+                next_attempt()?;
+            }
+            return Err(e);
+        }
     };
+    let intent_id = intent["id"].as_str()
+        .ok_or("Missing 'id' in Stripe response")?
+        .to_string();
+    let status = intent["status"].as_str().unwrap_or("").to_string();
+    let client_secret = intent["client_secret"].as_str().map(|s| s.to_string());
 
-    // HTTP outcalls require cycles (about 2 billion)
-    let cycles: u128 = 2_000_000_000;
-    let (response,) = http_request(request, cycles).await.map_err(|(code, msg)| {
-        format!("HTTP request failed: {:?} - {}", code, msg)
-    })?;
-
-    if response.status != 200u64 {
-        let body_text = String::from_utf8_lossy(&response.body);
-        return Err(format!("Stripe API error ({}): {}", response.status, body_text));
+    if status != "succeeded" && status != "requires_capture" {
+        next_attempt()?;
     }
 
-    // Step 3: Parse the response to extract client_secret and id
-    let body_text = String::from_utf8_lossy(&response.body);
-    let (intent_id, client_secret) = parse_stripe_payment_intent(&body_text)?;
-
-    // Step 4: Store the payment intent in database
-    // Use INSERT OR REPLACE to handle race conditions
     with_connection(|conn| {
         let now = now();
         conn.execute(
-            r#"INSERT OR REPLACE INTO payment_intents (order_id, payment_method_id, stripe_intent_id, client_secret, amount, status, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, ?5, 'requires_payment_method', ?6, ?6)"#,
-            (order_id, payment_method_id, &intent_id, &client_secret, total, now)
-        ).map_err(|e| e.to_string())?;
+            r#"INSERT OR REPLACE INTO payment_intents (order_id, payment_method_id, stripe_intent_id, client_secret, amount, status, payment_source_id, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)"#,
+            (order_id, payment_method_id, &intent_id, &client_secret, amount, &status, payment_source_id, now)
+        ).map_err(|e| e.to_string())
+    })?;
 
-        Ok(client_secret)
+    if status == "succeeded" || status == "requires_capture" {
+        process_successful_payment(&intent_id, amount, Some(order_id), status == "succeeded")?;
+    }
+
+    Ok(SavedCardPayment {
+        payment_intent_id: intent_id,
+        client_secret: if status == "requires_action" { client_secret } else { None },
+        status,
     })
 }
 
-// Helper function to parse Stripe PaymentIntent response using serde_json
-fn parse_stripe_payment_intent(json: &str) -> Result<(String, String), String> {
-    let parsed: serde_json::Value = serde_json::from_str(json)
-        .map_err(|e| format!("Failed to parse Stripe response as JSON: {}", e))?;
-
-    let id = parsed.get("id")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| format!("Missing 'id' in Stripe response: {}", &json[..json.len().min(500)]))?
-        .to_string();
+// ============================================
+// SUBSCRIPTION ORDERS
+// ============================================
 
-    let client_secret = parsed.get("client_secret")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| format!("Missing 'client_secret' in Stripe response: {}", &json[..json.len().min(500)]))?
-        .to_string();
+const DAY_NS: i64 = 24 * HOUR_NS;
+
+const ORDER_SUBSCRIPTION_SELECT: &str = r#"
+    SELECT s.id, s.user_id, s.variant_id, p.name, v.sku, s.quantity, COALESCE(pr.amount, 0), s.interval_days,
+    s.next_run_at, s.payment_source_id, ps.card_brand, ps.card_last4, s.ship_address_id, s.shipping_method_id,
+    s.state, s.failed_attempts, s.last_error, lo.number, s.canceled_at, s.created_at
+    FROM order_subscriptions s
+    JOIN variants v ON v.id = s.variant_id
+    JOIN products p ON p.id = v.product_id
+    LEFT JOIN prices pr ON pr.variant_id = v.id AND pr.deleted_at IS NULL
+    LEFT JOIN payment_sources ps ON ps.id = s.payment_source_id
+    LEFT JOIN orders lo ON lo.id = s.last_order_id"#;
+
+fn order_subscription_from_row(row: &ic_rusqlite::Row) -> ic_rusqlite::Result<OrderSubscription> {
+    Ok(OrderSubscription {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        variant_id: row.get(2)?,
+        product_name: row.get(3)?,
+        sku: row.get(4)?,
+        quantity: row.get(5)?,
+        price: row.get(6)?,
+        interval_days: row.get(7)?,
+        next_run_at: row.get(8)?,
+        payment_source_id: row.get(9)?,
+        card_brand: row.get(10)?,
+        card_last4: row.get(11)?,
+        ship_address_id: row.get(12)?,
+        shipping_method_id: row.get(13)?,
+        state: row.get(14)?,
+        failed_attempts: row.get(15)?,
+        last_error: row.get(16)?,
+        last_order_number: row.get(17)?,
+        canceled_at: row.get(18)?,
+        created_at: row.get(19)?,
+    })
+}
 
-    Ok((id, client_secret))
+fn load_order_subscription(conn: &Connection, id: i64) -> Result<OrderSubscription, String> {
+    conn.query_row(
+        &format!("{} WHERE s.id = ?1", ORDER_SUBSCRIPTION_SELECT),
+        (id,),
+        order_subscription_from_row
+    ).map_err(|_| "Subscription not found".to_string())
 }
 
-// Helper to extract a string value from JSON using serde_json
-fn extract_json_string(json: &str, key: &str) -> Option<String> {
-    serde_json::from_str::<serde_json::Value>(json)
-        .ok()
-        .and_then(|v| v.get(key)?.as_str().map(|s| s.to_string()))
+// State of the caller's subscription
+fn own_order_subscription(conn: &Connection, id: i64, user_id: i64) -> Result<String, String> {
+    conn.query_row(
+        "SELECT state FROM order_subscriptions WHERE id = ?1 AND user_id = ?2",
+        (id, user_id),
+        |row| row.get(0)
+    ).map_err(|_| "Subscription not found".to_string())
 }
 
-// URL encode helper for Stripe API
-fn url_encode(text: &str) -> String {
-    let mut result = String::new();
-    for c in text.chars() {
-        match c {
-            ' ' => result.push_str("%20"),
-            '&' => result.push_str("%26"),
-            '=' => result.push_str("%3D"),
-            '?' => result.push_str("%3F"),
-            '#' => result.push_str("%23"),
-            '+' => result.push_str("%2B"),
-            _ => result.push(c),
-        }
+fn validate_subscription_schedule(interval_days: i64, quantity: i64) -> Result<(), String> {
+    if !(1..=365).contains(&interval_days) {
+        return Err("Interval must be between 1 and 365 days".to_string());
     }
-    result
+    if !(1..=999).contains(&quantity) {
+        return Err("Quantity must be between 1 and 999".to_string());
+    }
+    Ok(())
 }
 
-/// Create a Stripe Checkout Session and return the redirect URL
-/// This is the recommended approach for a single-page checkout experience
-#[ic_cdk::update]
-async fn create_stripe_checkout_session(
-    order_id: i64,
-    success_url: String,
-    cancel_url: String,
-    session_id: Option<String>
-) -> Result<String, String> {
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
-    let user_id = if !is_anonymous { get_current_user_id() } else { None };
-    // Check admin BEFORE entering with_connection to avoid nested borrow
-    let is_admin_user = is_admin();
+// Saved card usable for off-session charges
+fn check_subscription_card(conn: &Connection, user_id: i64, payment_source_id: i64) -> Result<(), String> {
+    conn.query_row(
+        r#"SELECT 1 FROM payment_sources
+           WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NULL
+           AND stripe_payment_method_id IS NOT NULL AND stripe_customer_id IS NOT NULL"#,
+        (payment_source_id, user_id),
+        |_| Ok(())
+    ).map_err(|_| "Saved card not found".to_string())
+}
 
-    // Step 1: Get order details, check for existing session, and get Stripe API key
-    let (order_number, line_items, api_key, email, existing_session) = with_connection(|conn| {
-        // Verify order exists and get details
-        let (order_number, state, email, _total): (String, String, Option<String>, i64) = if is_anonymous {
-            if let Some(sess_id) = &session_id {
-                conn.query_row(
-                    "SELECT number, state, email, total FROM orders WHERE id = ?1 AND guest_token = ?2",
-                    (order_id, sess_id),
-                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-                ).map_err(|_| "Order not found or access denied".to_string())?
-            } else {
-                return Err("Session ID required for guest checkout".to_string());
-            }
-        } else {
-            conn.query_row(
-                "SELECT number, state, email, total FROM orders WHERE id = ?1 AND (user_principal = ?2 OR user_id = ?3 OR 1=?4)",
-                (order_id, &caller_str, user_id, if is_admin_user { 1 } else { 0 }),
-                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-            ).map_err(|_| "Order not found or access denied".to_string())?
-        };
+// Cancel an order placed for a subscription that was never paid
+fn cancel_pending_subscription_order(conn: &Connection, subscription_id: i64) -> Result<(), String> {
+    let now = now();
+    conn.execute(
+        r#"UPDATE orders SET state = 'canceled', updated_at = ?1
+           WHERE id = (SELECT pending_order_id FROM order_subscriptions WHERE id = ?2) AND state = 'delivery'"#,
+        (now, subscription_id)
+    ).map_err(|e| e.to_string())?;
+    conn.execute(
+        "UPDATE order_subscriptions SET pending_order_id = NULL, updated_at = ?1 WHERE id = ?2",
+        (now, subscription_id)
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
 
-        if state != "cart" && state != "address" && state != "delivery" && state != "payment" {
-            return Err(format!("Order is in state '{}' and cannot be paid", state));
+/// Turn a line item from one of the caller's placed orders into a subscription. The
+/// first recurring order is placed after one interval unless start_at is given.
+#[ic_cdk::update]
+fn create_order_subscription(input: CreateOrderSubscriptionInput) -> Result<OrderSubscription, String> {
+    let user_id = saved_card_user()?;
+
+    with_connection(|conn| {
+        let now = now();
+
+        let (order_id, variant_id, line_quantity, is_gift_card): (i64, i64, i64, bool) = conn.query_row(
+            r#"SELECT o.id, li.variant_id, li.quantity, p.gift_card = 1 FROM line_items li
+               JOIN orders o ON o.id = li.order_id
+               JOIN variants v ON v.id = li.variant_id
+               JOIN products p ON p.id = v.product_id
+               WHERE li.id = ?1 AND o.user_id = ?2 AND o.state != 'canceled' AND o.completed_at IS NOT NULL"#,
+            (input.line_item_id, user_id),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        ).map_err(|_| "Line item not found".to_string())?;
+
+        if is_gift_card {
+            return Err("Gift cards cannot be ordered on a subscription".to_string());
         }
 
-        // IDEMPOTENCY: Check if we already have a valid checkout session for this order
-        let existing: Option<String> = conn.query_row(
-            r#"SELECT client_secret FROM payment_intents
-               WHERE order_id = ?1 AND status = 'checkout_session'
-               ORDER BY created_at DESC LIMIT 1"#,
-            (order_id,),
-            |row| row.get(0)
-        ).ok();
+        let quantity = input.quantity.unwrap_or(line_quantity);
+        validate_subscription_schedule(input.interval_days, quantity)?;
+        check_subscription_card(&conn, user_id, input.payment_source_id)?;
 
-        // If we have an existing checkout session URL, return it
-        if let Some(url) = existing {
-            if !url.is_empty() {
-                return Ok((order_number, vec![], String::new(), email, Some(url)));
-            }
+        let next_run_at = match input.start_at {
+            Some(at) if at <= now => return Err("Start date must be in the future".to_string()),
+            Some(at) => at,
+            None => now + input.interval_days * DAY_NS,
+        };
+
+        if conn.query_row(
+            "SELECT 1 FROM order_subscriptions WHERE user_id = ?1 AND variant_id = ?2 AND state != 'canceled'",
+            (user_id, variant_id),
+            |_| Ok(())
+        ).is_ok() {
+            return Err("You already have a subscription for this item".to_string());
         }
 
-        // Get line items
-        let mut stmt = conn.prepare(
-            r#"SELECT li.quantity, li.price, p.name
-               FROM line_items li
-               JOIN variants v ON v.id = li.variant_id
-               JOIN products p ON p.id = v.product_id
-               WHERE li.order_id = ?1"#
+        let id: i64 = conn.query_row(
+            r#"INSERT INTO order_subscriptions (user_id, variant_id, quantity, interval_days, next_run_at,
+                   payment_source_id, source_order_id, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8) RETURNING id"#,
+            (user_id, variant_id, quantity, input.interval_days, next_run_at, input.payment_source_id, order_id, now),
+            |row| row.get(0)
         ).map_err(|e| e.to_string())?;
 
-        let items: Vec<(i64, i64, String)> = stmt.query_map((order_id,), |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-        }).map_err(|e| e.to_string())?
-        .collect::<ic_rusqlite::Result<Vec<_>>>()
-        .map_err(|e| e.to_string())?;
+        load_order_subscription(&conn, id)
+    })
+}
 
-        // Get Stripe API key
-        let api_key: Option<String> = conn.query_row(
-            "SELECT api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
-            [],
-            |row| row.get(0)
-        ).map_err(|_| "Stripe payment method not configured or active".to_string())?;
+#[ic_cdk::query]
+fn get_my_order_subscriptions() -> Result<Vec<OrderSubscription>, String> {
+    let user_id = saved_card_user()?;
 
-        let api_key = api_key.ok_or("Stripe API key not configured")?;
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE s.user_id = ?1 ORDER BY s.state = 'canceled', s.next_run_at", ORDER_SUBSCRIPTION_SELECT
+        )).map_err(|e| e.to_string())?;
+        let subscriptions = stmt.query_map((user_id,), order_subscription_from_row)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        Ok(subscriptions)
+    })
+}
 
-        Ok((order_number, items, api_key, email, None))
-    })?;
+/// Change quantity, interval, card, address, shipping method or the next run date.
+/// A new card on a past_due subscription reactivates it and retries on the next run.
+#[ic_cdk::update]
+fn update_order_subscription(id: i64, input: UpdateOrderSubscriptionInput) -> Result<OrderSubscription, String> {
+    let user_id = saved_card_user()?;
 
-    // If we found an existing valid checkout session, return it immediately
-    if let Some(checkout_url) = existing_session {
-        return Ok(checkout_url);
-    }
+    with_connection(|conn| {
+        let now = now();
+        let state = own_order_subscription(&conn, id, user_id)?;
+        if state == "canceled" {
+            return Err("Subscription is canceled".to_string());
+        }
 
-    // Validate we have line items and all prices are positive
-    if line_items.is_empty() {
-        return Err("Order has no items".to_string());
-    }
-    for (quantity, price, name) in &line_items {
-        if *price <= 0 {
-            return Err(format!("Invalid price for item '{}': must be positive", name));
+        let (quantity, interval_days): (i64, i64) = conn.query_row(
+            "SELECT quantity, interval_days FROM order_subscriptions WHERE id = ?1",
+            (id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|e| e.to_string())?;
+        validate_subscription_schedule(input.interval_days.unwrap_or(interval_days), input.quantity.unwrap_or(quantity))?;
+
+        if let Some(payment_source_id) = input.payment_source_id {
+            check_subscription_card(&conn, user_id, payment_source_id)?;
         }
-        if *quantity <= 0 {
-            return Err(format!("Invalid quantity for item '{}': must be positive", name));
+        if let Some(address_id) = input.ship_address_id {
+            get_user_address(&conn, user_id, address_id)?;
         }
-        // Stripe minimum is 50 cents per line item
-        if *price < 50 {
-            return Err(format!("Price for '{}' must be at least $0.50 USD", name));
+        if let Some(method_id) = input.shipping_method_id {
+            conn.query_row(
+                "SELECT 1 FROM shipping_methods WHERE id = ?1 AND active = 1 AND deleted_at IS NULL",
+                (method_id,),
+                |_| Ok(())
+            ).map_err(|_| "Shipping method is not available".to_string())?;
+        }
+        if input.next_run_at.is_some_and(|at| at <= now) {
+            return Err("Next order date must be in the future".to_string());
         }
-    }
 
-    // Step 2: Build the request body for Stripe Checkout Session
-    let mut body_parts: Vec<String> = vec![
-        "mode=payment".to_string(),
-        format!("success_url={}", url_encode(&success_url)),
-        format!("cancel_url={}", url_encode(&cancel_url)),
-        format!("client_reference_id={}", order_number),
-    ];
+        // An unpaid order no longer matches the new quantity, address or shipping
+        if input.quantity.is_some() || input.ship_address_id.is_some() || input.shipping_method_id.is_some() {
+            cancel_pending_subscription_order(&conn, id)?;
+        }
 
-    // Add customer email if available
-    if let Some(email_addr) = email {
-        body_parts.push(format!("customer_email={}", url_encode(&email_addr)));
-    }
+        conn.execute(
+            r#"UPDATE order_subscriptions SET
+               quantity = COALESCE(?1, quantity), interval_days = COALESCE(?2, interval_days),
+               payment_source_id = COALESCE(?3, payment_source_id), ship_address_id = COALESCE(?4, ship_address_id),
+               shipping_method_id = COALESCE(?5, shipping_method_id), next_run_at = COALESCE(?6, next_run_at),
+               updated_at = ?7
+               WHERE id = ?8"#,
+            (input.quantity, input.interval_days, input.payment_source_id, input.ship_address_id,
+             input.shipping_method_id, input.next_run_at, now, id)
+        ).map_err(|e| e.to_string())?;
 
-    // Add line items
-    for (i, (quantity, price, name)) in line_items.iter().enumerate() {
-        let prefix = format!("line_items[{}]", i);
-        body_parts.push(format!("{}[price_data][currency]=usd", prefix));
-        body_parts.push(format!("{}[price_data][unit_amount]={}", prefix, price));
-        body_parts.push(format!("{}[price_data][product_data][name]={}", prefix, url_encode(name)));
-        body_parts.push(format!("{}[quantity]={}", prefix, quantity));
-    }
+        if state == "past_due" && input.payment_source_id.is_some() {
+            conn.execute(
+                r#"UPDATE order_subscriptions SET state = 'active', failed_attempts = 0, last_error = NULL,
+                   next_run_at = MAX(next_run_at, ?1), updated_at = ?1
+                   WHERE id = ?2"#,
+                (now, id)
+            ).map_err(|e| e.to_string())?;
+        }
 
-    let body = body_parts.join("&");
+        load_order_subscription(&conn, id)
+    })
+}
 
-    // Generate idempotency key based on order_number
-    // This ensures Stripe won't create duplicate checkout sessions if our call is retried
-    let idempotency_key = format!("checkout_{}", order_number);
+#[ic_cdk::update]
+fn pause_order_subscription(id: i64) -> Result<OrderSubscription, String> {
+    let user_id = saved_card_user()?;
 
-    // Step 3: Make HTTP outcall to Stripe API with idempotency key
-    let request = CanisterHttpRequestArgument {
-        url: "https://api.stripe.com/v1/checkout/sessions".to_string(),
-        max_response_bytes: Some(15000),
-        method: HttpMethod::POST,
-        headers: vec![
-            HttpHeader {
-                name: "Authorization".to_string(),
-                value: format!("Bearer {}", api_key),
-            },
-            HttpHeader {
-                name: "Content-Type".to_string(),
-                value: "application/x-www-form-urlencoded".to_string(),
-            },
-            HttpHeader {
-                name: "Idempotency-Key".to_string(),
-                value: idempotency_key,
-            },
-        ],
-        body: Some(body.into_bytes()),
-        transform: None,
-    };
+    with_connection(|conn| {
+        if own_order_subscription(&conn, id, user_id)? != "active" {
+            return Err("Only active subscriptions can be paused".to_string());
+        }
+        conn.execute(
+            "UPDATE order_subscriptions SET state = 'paused', updated_at = ?1 WHERE id = ?2",
+            (now(), id)
+        ).map_err(|e| e.to_string())?;
+        load_order_subscription(&conn, id)
+    })
+}
 
-    let cycles: u128 = 2_000_000_000;
-    let (response,) = http_request(request, cycles).await.map_err(|(code, msg)| {
-        format!("HTTP request failed: {:?} - {}", code, msg)
-    })?;
+/// Resume a paused or past_due subscription. An overdue next run happens on the next job run.
+#[ic_cdk::update]
+fn resume_order_subscription(id: i64) -> Result<OrderSubscription, String> {
+    let user_id = saved_card_user()?;
 
-    if response.status != 200u64 {
-        let body_text = String::from_utf8_lossy(&response.body);
-        return Err(format!("Stripe API error ({}): {}", response.status, body_text));
-    }
+    with_connection(|conn| {
+        let state = own_order_subscription(&conn, id, user_id)?;
+        if state != "paused" && state != "past_due" {
+            return Err("Only paused or past due subscriptions can be resumed".to_string());
+        }
+        let now = now();
+        conn.execute(
+            r#"UPDATE order_subscriptions SET state = 'active', failed_attempts = 0, last_error = NULL,
+               next_run_at = MAX(next_run_at, ?1), updated_at = ?1
+               WHERE id = ?2"#,
+            (now, id)
+        ).map_err(|e| e.to_string())?;
+        load_order_subscription(&conn, id)
+    })
+}
 
-    // Step 4: Parse the response to get the checkout URL
-    let body_text = String::from_utf8_lossy(&response.body);
-    let checkout_url = extract_json_string(&body_text, "url")
-        .ok_or("Could not parse checkout URL from Stripe response")?;
-    
-    let session_stripe_id = extract_json_string(&body_text, "id")
-        .ok_or("Could not parse session ID from Stripe response")?;
+/// Skip the next order: the next run moves one interval later
+#[ic_cdk::update]
+fn skip_order_subscription(id: i64) -> Result<OrderSubscription, String> {
+    let user_id = saved_card_user()?;
 
-    // Step 5: Store the checkout session in database
     with_connection(|conn| {
+        let state = own_order_subscription(&conn, id, user_id)?;
+        if state != "active" && state != "paused" {
+            return Err("Only active or paused subscriptions can be skipped".to_string());
+        }
         let now = now();
-        let payment_method_id: i64 = conn.query_row(
-            "SELECT id FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
-            [],
-            |row| row.get(0)
-        ).unwrap_or(1);
-
+        cancel_pending_subscription_order(&conn, id)?;
         conn.execute(
-            r#"INSERT INTO payment_intents (order_id, payment_method_id, stripe_intent_id, client_secret, amount, status, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, 0, 'checkout_session', ?5, ?5)"#,
-            (order_id, payment_method_id, &session_stripe_id, &checkout_url, now)
-        ).ok(); // Ignore errors if already exists
-
-        // Move order to payment state
+            r#"UPDATE order_subscriptions SET next_run_at = MAX(next_run_at, ?1) + interval_days * ?2, updated_at = ?1
+               WHERE id = ?3"#,
+            (now, DAY_NS, id)
+        ).map_err(|e| e.to_string())?;
         conn.execute(
-            "UPDATE orders SET state = 'payment', updated_at = ?1 WHERE id = ?2",
-            (now, order_id)
-        ).ok();
-
-        Ok(checkout_url)
+            "INSERT INTO order_subscription_runs (subscription_id, state, created_at) VALUES (?1, 'skipped', ?2)",
+            (id, now)
+        ).map_err(|e| e.to_string())?;
+        load_order_subscription(&conn, id)
     })
 }
 
-/// Verify and record a Stripe payment by checking with Stripe API
-/// This is secure for mainnet - we verify the payment status directly with Stripe
 #[ic_cdk::update]
-async fn record_stripe_payment(order_id: i64, payment_intent_id: String, _status: String, session_id: Option<String>) -> Result<(), String> {
-    // Step 1: Get Stripe API key from database
-    let api_key: String = with_connection(|conn| {
-        conn.query_row(
-            "SELECT api_key FROM payment_methods WHERE type = 'stripe' AND active = 1 AND api_key IS NOT NULL LIMIT 1",
-            [],
-            |row| row.get(0)
-        ).map_err(|_| "Stripe API key not configured".to_string())
-    })?;
+fn cancel_order_subscription(id: i64) -> Result<OrderSubscription, String> {
+    let user_id = saved_card_user()?;
 
-    // Step 2: Verify order ownership
-    let caller = ic_cdk::api::caller();
-    let caller_str = caller.to_string();
-    let is_anonymous = caller == Principal::anonymous();
-    let user_id = if !is_anonymous { get_current_user_id() } else { None };
+    with_connection(|conn| {
+        if own_order_subscription(&conn, id, user_id)? == "canceled" {
+            return Err("Subscription is already canceled".to_string());
+        }
+        let now = now();
+        cancel_pending_subscription_order(&conn, id)?;
+        conn.execute(
+            "UPDATE order_subscriptions SET state = 'canceled', canceled_at = ?1, updated_at = ?1 WHERE id = ?2",
+            (now, id)
+        ).map_err(|e| e.to_string())?;
+        load_order_subscription(&conn, id)
+    })
+}
 
-    let order_check = with_connection(|conn| {
-        let order_owner_check: bool = if is_anonymous {
-            if let Some(ref sess_id) = session_id {
-                conn.query_row(
-                    "SELECT 1 FROM orders WHERE id = ?1 AND guest_token = ?2",
-                    (order_id, sess_id),
-                    |_| Ok(true)
-                ).unwrap_or(false)
-            } else {
-                false
-            }
-        } else {
-            conn.query_row(
-                "SELECT 1 FROM orders WHERE id = ?1 AND (user_id = ?2 OR user_principal = ?3)",
-                (order_id, user_id, &caller_str),
-                |_| Ok(true)
-            ).unwrap_or(false)
-        };
+#[ic_cdk::query]
+fn admin_get_order_subscriptions(state: Option<String>) -> Result<Vec<OrderSubscription>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
 
-        if !order_owner_check && !is_admin() {
-            return Err("Unauthorized: Order does not belong to you".to_string());
-        }
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE (?1 IS NULL OR s.state = ?1) ORDER BY s.next_run_at LIMIT 500", ORDER_SUBSCRIPTION_SELECT
+        )).map_err(|e| e.to_string())?;
+        let subscriptions = stmt.query_map((state,), order_subscription_from_row)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        Ok(subscriptions)
+    })
+}
+
+/// Charge attempts and skipped cycles of a subscription, newest first
+#[ic_cdk::query]
+fn admin_get_order_subscription_runs(subscription_id: i64) -> Result<Vec<OrderSubscriptionRun>, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            r#"SELECT r.id, r.subscription_id, o.number, r.state, r.amount, r.error, r.created_at
+               FROM order_subscription_runs r
+               LEFT JOIN orders o ON o.id = r.order_id
+               WHERE r.subscription_id = ?1
+               ORDER BY r.id DESC"#
+        ).map_err(|e| e.to_string())?;
+        let runs = stmt.query_map((subscription_id,), |row| {
+            Ok(OrderSubscriptionRun {
+                id: row.get(0)?,
+                subscription_id: row.get(1)?,
+                order_number: row.get(2)?,
+                state: row.get(3)?,
+                amount: row.get(4)?,
+                error: row.get(5)?,
+                created_at: row.get(6)?,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        Ok(runs)
+    })
+}
 
-        // Verify order exists and get expected amount
-        let order_data: (i64, String) = conn.query_row(
-            "SELECT total, number FROM orders WHERE id = ?1",
-            (order_id,),
-            |row| Ok((row.get(0)?, row.get(1)?))
-        ).map_err(|_| "Order not found".to_string())?;
+// Everything needed to charge one subscription order off-session
+struct SubscriptionCharge {
+    order_id: i64,
+    amount: i64,
+    payment_source_id: i64,
+    stripe_pm_id: String,
+    customer_id: String,
+    payment_method_id: i64,
+    api_key: String,
+    attempt: i64,  // failed charges of this order so far, part of the idempotency key
+}
 
-        Ok(order_data)
-    })?;
+// What happened to the order placed for a subscription before this run
+enum PendingOrder {
+    Charge(SubscriptionCharge),
+    AlreadyPaid(i64),  // completed meanwhile (webhook, or the customer paid it)
+    Retry(String),     // out of stock or a payment still processing; not a dunning failure
+    CardMissing,       // the saved card was removed; the customer has to pick another
+}
 
-    let (expected_amount, order_number) = order_check;
+// Order for this cycle: the unpaid order from an earlier attempt, or a new one built from
+// the subscription and its source order (addresses, email, shipping method)
+fn prepare_subscription_order(conn: &Connection, subscription_id: i64) -> Result<PendingOrder, String> {
+    let now = now();
 
-    // Step 3: Verify payment with Stripe API
-    // Determine if this is a checkout session or payment intent
-    let is_session = payment_intent_id.starts_with("cs_");
-    let stripe_url = if is_session {
-        format!("https://api.stripe.com/v1/checkout/sessions/{}", payment_intent_id)
-    } else {
-        format!("https://api.stripe.com/v1/payment_intents/{}", payment_intent_id)
-    };
+    let (user_id, variant_id, quantity, payment_source_id, ship_address_id, shipping_method_id, source_order_id, pending_order_id) = conn.query_row(
+        r#"SELECT user_id, variant_id, quantity, payment_source_id, ship_address_id, shipping_method_id,
+           source_order_id, pending_order_id
+           FROM order_subscriptions WHERE id = ?1"#,
+        (subscription_id,),
+        |row| Ok((
+            row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?, row.get::<_, i64>(3)?,
+            row.get::<_, Option<i64>>(4)?, row.get::<_, Option<i64>>(5)?, row.get::<_, i64>(6)?, row.get::<_, Option<i64>>(7)?,
+        ))
+    ).map_err(|_| "Subscription not found".to_string())?;
 
-    let request_headers = vec![
-        HttpHeader { name: "Authorization".to_string(), value: format!("Bearer {}", api_key) },
-    ];
+    let pending_state: Option<String> = pending_order_id.and_then(|id| conn.query_row(
+        "SELECT state FROM orders WHERE id = ?1",
+        (id,),
+        |row| row.get(0)
+    ).ok());
 
-    let request = CanisterHttpRequestArgument {
-        url: stripe_url,
-        method: HttpMethod::GET,
-        headers: request_headers,
-        body: None,
-        max_response_bytes: Some(10000),
-        transform: None,
-    };
+    if let (Some(id), Some("complete")) = (pending_order_id, pending_state.as_deref()) {
+        return Ok(PendingOrder::AlreadyPaid(id));
+    }
 
-    let (response,) = http_request(request, 2_000_000_000).await.map_err(|(code, msg)| {
-        format!("HTTP request failed: {:?} - {}", code, msg)
-    })?;
+    // Never charge an order twice while an earlier charge is still going through
+    if pending_state.as_deref() == Some("delivery") && conn.query_row(
+        "SELECT 1 FROM payment_intents WHERE order_id = ?1 AND status IN ('processing', 'succeeded', 'requires_capture')",
+        (pending_order_id,),
+        |_| Ok(())
+    ).is_ok() {
+        return Ok(PendingOrder::Retry("Waiting for the previous payment to complete".to_string()));
+    }
 
-    if response.status != 200u64 {
-        return Err(format!("Stripe API error: status {}", response.status));
+    let available = variant_stock(conn, variant_id);
+    if available < quantity {
+        return Ok(PendingOrder::Retry(format!("Out of stock. Requested: {}, Available: {}", quantity, available)));
     }
 
-    let body_str = String::from_utf8(response.body)
-        .map_err(|_| "Invalid response from Stripe")?;
+    let Ok((stripe_pm_id, customer_id)) = conn.query_row(
+        r#"SELECT stripe_payment_method_id, stripe_customer_id FROM payment_sources
+           WHERE id = ?1 AND user_id = ?2 AND deleted_at IS NULL
+           AND stripe_payment_method_id IS NOT NULL AND stripe_customer_id IS NOT NULL"#,
+        (payment_source_id, user_id),
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    ) else {
+        return Ok(PendingOrder::CardMissing);
+    };
+    let (payment_method_id, api_key) = stripe_credentials(conn)?;
 
-    let stripe_data: serde_json::Value = serde_json::from_str(&body_str)
-        .map_err(|_| "Failed to parse Stripe response")?;
+    let order_id = match (pending_order_id, pending_state.as_deref()) {
+        (Some(id), Some("delivery")) => id,
+        _ => {
+            let (source_ship, source_bill, source_email, source_method) = conn.query_row(
+                r#"SELECT o.ship_address_id, o.bill_address_id, o.email,
+                   (SELECT sr.shipping_method_id FROM shipments sh JOIN shipping_rates sr ON sr.shipment_id = sh.id
+                    WHERE sh.order_id = o.id AND sr.selected = 1 LIMIT 1)
+                   FROM orders o WHERE o.id = ?1"#,
+                (source_order_id,),
+                |row| Ok((
+                    row.get::<_, Option<i64>>(0)?, row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, Option<String>>(2)?, row.get::<_, Option<i64>>(3)?,
+                ))
+            ).unwrap_or((None, None, None, None));
 
-    // Step 4: Verify payment status and amount
-    let (verified_status, verified_amount, metadata_order_number) = if is_session {
-        // For checkout sessions
-        let status = stripe_data["payment_status"].as_str().unwrap_or("");
-        let amount = stripe_data["amount_total"].as_i64().unwrap_or(0);
-        let meta_order = stripe_data["metadata"]["order_number"].as_str().map(|s| s.to_string());
-        (status == "paid", amount, meta_order)
-    } else {
-        // For payment intents
-        let status = stripe_data["status"].as_str().unwrap_or("");
-        let amount = stripe_data["amount"].as_i64().unwrap_or(0);
-        let meta_order = stripe_data["metadata"]["order_number"].as_str().map(|s| s.to_string());
-        (status == "succeeded", amount, meta_order)
-    };
+            let ship = ship_address_id.or(source_ship).ok_or("No shipping address for the subscription")?;
+            let bill = if ship_address_id.is_some() { ship } else { source_bill.unwrap_or(ship) };
+            let method = shipping_method_id.or(source_method).ok_or("No shipping method for the subscription")?;
 
-    // Verify amount matches (within 1 cent tolerance for rounding)
-    if (verified_amount - expected_amount).abs() > 1 {
-        return Err(format!("Amount mismatch: expected {}, got {}", expected_amount, verified_amount));
-    }
+            let (principal, user_email): (String, Option<String>) = conn.query_row(
+                "SELECT principal, email FROM users WHERE id = ?1",
+                (user_id,),
+                |row| Ok((row.get(0)?, row.get(1)?))
+            ).map_err(|_| "User not found".to_string())?;
+            let email = source_email.or(user_email.filter(|e| !e.is_empty()))
+                .ok_or("No email address for the subscription")?;
+
+            let (price, currency): (i64, String) = conn.query_row(
+                r#"SELECT pr.amount, pr.currency FROM prices pr
+                   JOIN variants v ON v.id = pr.variant_id
+                   WHERE pr.variant_id = ?1 AND pr.deleted_at IS NULL AND v.deleted_at IS NULL"#,
+                (variant_id,),
+                |row| Ok((row.get(0)?, row.get(1)?))
+            ).map_err(|_| "Variant not found or has no price".to_string())?;
+
+            // Numbers are time based; the subscription id keeps orders placed in one run apart
+            let number = format!("{}S{}", generate_order_number(), subscription_id);
+            let order_id: i64 = conn.query_row(
+                r#"INSERT INTO orders (number, user_id, user_principal, email, ship_address_id, bill_address_id, state, created_at, updated_at)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, 'delivery', ?7, ?7) RETURNING id"#,
+                (&number, user_id, &principal, &email, ship, bill, now),
+                |row| row.get(0)
+            ).map_err(|e| e.to_string())?;
 
-    // Verify order number matches if present in metadata
-    if let Some(ref meta_num) = metadata_order_number {
-        if meta_num != &order_number {
-            return Err("Order number mismatch".to_string());
-        }
-    }
+            conn.execute(
+                r#"INSERT INTO line_items (order_id, variant_id, quantity, price, currency, created_at, updated_at)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)"#,
+                (order_id, variant_id, quantity, price, &currency, now)
+            ).map_err(|e| e.to_string())?;
 
-    if !verified_status {
-        return Err("Payment not completed".to_string());
-    }
+            let shipment_id: i64 = conn.query_row(
+                r#"INSERT INTO shipments (order_id, number, stock_location_id, state, created_at, updated_at)
+                   VALUES (?1, ?2, 1, 'pending', ?3, ?3) RETURNING id"#,
+                (order_id, format!("{}S{}", generate_shipment_number(), subscription_id), now),
+                |row| row.get(0)
+            ).map_err(|e| e.to_string())?;
+            let cost = calculate_shipping_cost(conn, method, order_id)?;
+            conn.execute(
+                r#"INSERT INTO shipping_rates (shipment_id, shipping_method_id, cost, selected, created_at, updated_at)
+                   VALUES (?1, ?2, ?3, 1, ?4, ?4)"#,
+                (shipment_id, method, cost, now)
+            ).map_err(|e| e.to_string())?;
+            conn.execute("UPDATE shipments SET cost = ?1 WHERE id = ?2", (cost, shipment_id))
+                .map_err(|e| e.to_string())?;
 
-    // Step 5: Payment verified! Now complete the order
-    with_connection(|conn| {
-        let now = now();
+            // Subscribe & save
+            let discount_percent = store_setting_i64(conn, "subscription_discount_percent", 0).clamp(0, 100);
+            if discount_percent > 0 {
+                conn.execute(
+                    r#"INSERT INTO adjustments (source_type, source_id, adjustable_type, adjustable_id, order_id, amount, label, created_at, updated_at)
+                       VALUES ('Subscription', ?1, 'Order', ?2, ?2, ?3, ?4, ?5, ?5)"#,
+                    (subscription_id, order_id, -(price * quantity * discount_percent / 100),
+                     format!("Subscribe & save ({}%)", discount_percent), now)
+                ).map_err(|e| e.to_string())?;
+            }
 
-        // Check if already processed
-        let already_paid: bool = conn.query_row(
-            "SELECT payment_state = 'paid' FROM orders WHERE id = ?1",
-            (order_id,),
-            |row| row.get(0)
-        ).unwrap_or(false);
+            conn.execute(
+                "UPDATE order_subscriptions SET pending_order_id = ?1, updated_at = ?2 WHERE id = ?3",
+                (order_id, now, subscription_id)
+            ).map_err(|e| e.to_string())?;
 
-        if already_paid {
-            return Ok::<(), String>(()); // Already processed, nothing to do
+            order_id
         }
+    };
 
-        // Find payment method
-        let payment_method_id: i64 = conn.query_row(
-            "SELECT id FROM payment_methods WHERE type = 'stripe' AND active = 1 LIMIT 1",
-            [],
-            |row| row.get(0)
-        ).map_err(|_| "Stripe payment method not configured".to_string())?;
+    recalculate_order(conn, order_id)?;
+    let amount = order_amount_due(conn, order_id);
+    if amount < 50 {
+        return Err("Order total must be at least $0.50 USD for Stripe payments".to_string());
+    }
 
-        // Update the intent status
-        conn.execute(
-            "UPDATE payment_intents SET status = 'succeeded', updated_at = ?1 WHERE stripe_intent_id = ?2",
-            (now, &payment_intent_id)
-        ).ok();
+    // Counted from the run log rather than failed_attempts, which is reset on resume
+    let attempt: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM order_subscription_runs WHERE subscription_id = ?1 AND order_id = ?2 AND state = 'failed'",
+        (subscription_id, order_id),
+        |row| row.get(0)
+    ).map_err(|e| e.to_string())?;
 
-        // Record the payment
-        conn.execute(
-            r#"INSERT OR IGNORE INTO payments (order_id, payment_method_id, amount, state, response_code, stripe_payment_intent_id, created_at, updated_at)
-               VALUES (?1, ?2, ?3, 'completed', ?4, ?4, ?5, ?5)"#,
-            (order_id, payment_method_id, expected_amount, &payment_intent_id, now)
-        ).ok();
+    Ok(PendingOrder::Charge(SubscriptionCharge {
+        order_id, amount, payment_source_id, stripe_pm_id, customer_id, payment_method_id, api_key, attempt,
+    }))
+}
+
+// Paid: schedule the next cycle one interval after this one (or from now if far behind)
+fn complete_subscription_cycle(conn: &Connection, subscription_id: i64, order_id: i64, amount: i64) -> Result<(), String> {
+    let now = now();
+    conn.execute(
+        r#"UPDATE order_subscriptions SET last_order_id = ?1, pending_order_id = NULL, failed_attempts = 0, last_error = NULL,
+           next_run_at = MAX(next_run_at, ?3) + interval_days * ?2, updated_at = ?3
+           WHERE id = ?4"#,
+        (order_id, DAY_NS, now, subscription_id)
+    ).map_err(|e| e.to_string())?;
+    conn.execute(
+        r#"INSERT INTO order_subscription_runs (subscription_id, order_id, state, amount, created_at)
+           VALUES (?1, ?2, 'paid', ?3, ?4)"#,
+        (subscription_id, order_id, amount, now)
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Dunning: retry after subscription_retry_hours, and go past_due (cancelling the unpaid
+// order) after subscription_max_payment_attempts failures. The customer is emailed each time.
+async fn fail_subscription_cycle(subscription_id: i64, order_id: Option<i64>, amount: i64, error: &str) {
+    let notice = with_connection(|conn| {
+        let now = now();
+        let retry_hours = store_setting_i64(&conn, "subscription_retry_hours", 24).max(1);
+        let max_attempts = store_setting_i64(&conn, "subscription_max_payment_attempts", 3).max(1);
 
-        // Finalize order
         conn.execute(
-            "UPDATE orders SET payment_state = 'paid', state = 'complete', completed_at = ?1, updated_at = ?1 WHERE id = ?2",
-            (now, order_id)
+            r#"INSERT INTO order_subscription_runs (subscription_id, order_id, state, amount, error, created_at)
+               VALUES (?1, ?2, 'failed', ?3, ?4, ?5)"#,
+            (subscription_id, order_id, amount, error, now)
         ).ok();
 
-        // Decrement stock for each line item
-        let mut stmt = conn.prepare(
-            "SELECT variant_id, quantity FROM line_items WHERE order_id = ?1"
+        let attempts: i64 = conn.query_row(
+            r#"UPDATE order_subscriptions SET failed_attempts = failed_attempts + 1, last_error = ?1,
+               next_run_at = ?2, updated_at = ?3
+               WHERE id = ?4 RETURNING failed_attempts"#,
+            (error, now + retry_hours * HOUR_NS, now, subscription_id),
+            |row| row.get(0)
         ).map_err(|e| e.to_string())?;
 
-        let line_items: Vec<(i64, i64)> = stmt.query_map((order_id,), |row| Ok((row.get(0)?, row.get(1)?)))
-            .map_err(|e| e.to_string())?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| e.to_string())?;
-        drop(stmt);
-
-        for (variant_id, quantity) in line_items {
+        let retry_message = if attempts >= max_attempts {
+            cancel_pending_subscription_order(&conn, subscription_id)?;
             conn.execute(
-                "UPDATE stock_items SET count_on_hand = count_on_hand - ?1, updated_at = ?2 WHERE variant_id = ?3",
-                (quantity, now, variant_id)
-            ).ok();
-        }
+                "UPDATE order_subscriptions SET state = 'past_due', updated_at = ?1 WHERE id = ?2",
+                (now, subscription_id)
+            ).map_err(|e| e.to_string())?;
+            "Your subscription is on hold until you update your payment method.".to_string()
+        } else {
+            format!("We will try again in {} hours ({} of {} attempts made).", retry_hours, attempts, max_attempts)
+        };
 
-        Ok(())
-    })?;
+        let store_url: String = conn.query_row(
+            "SELECT value FROM store_settings WHERE key = 'store_url'",
+            [],
+            |row| row.get::<_, String>(0)
+        ).map(|url| url.trim().trim_end_matches('/').to_string()).unwrap_or_default();
 
-    // Step 6: Send confirmation email (async, after db transaction)
-    let email_data = with_connection(|conn| {
         conn.query_row(
-            r#"SELECT o.email, o.number, o.total,
-               COALESCE(a.firstname || ' ' || a.lastname, 'Customer'),
-               COALESCE(a.address1 || CHAR(10) || a.city || ', ' || COALESCE(a.state_name, '') || ' ' || a.zipcode, ''),
-               GROUP_CONCAT(li.quantity || ' x ' || p.name || ' - $' || printf('%.2f', v.price / 100.0), CHAR(10))
-               FROM orders o
+            r#"SELECT COALESCE(o.email, NULLIF(u.email, '')), p.name, s.quantity, o.number, o.total,
+               COALESCE(a.firstname, 'there')
+               FROM order_subscriptions s
+               JOIN users u ON u.id = s.user_id
+               JOIN variants v ON v.id = s.variant_id
+               JOIN products p ON p.id = v.product_id
+               LEFT JOIN orders o ON o.id = ?2
                LEFT JOIN addresses a ON a.id = o.ship_address_id
-               LEFT JOIN line_items li ON li.order_id = o.id
-               LEFT JOIN variants v ON v.id = li.variant_id
-               LEFT JOIN products p ON p.id = v.product_id
-               WHERE o.id = ?1
-               GROUP BY o.id"#,
-            (order_id,),
-            |row| Ok((
-                row.get::<_, Option<String>>(0)?.unwrap_or_default(),
-                row.get::<_, String>(1)?,
-                row.get::<_, i64>(2)?,
-                row.get::<_, String>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, Option<String>>(5)?.unwrap_or_default(),
-            ))
-        ).ok()
+               WHERE s.id = ?1"#,
+            (subscription_id, order_id),
+            |row| {
+                let email: Option<String> = row.get(0)?;
+                let vars = vec![
+                    ("customer_name", row.get::<_, String>(5)?),
+                    ("product_name", row.get::<_, String>(1)?),
+                    ("quantity", row.get::<_, i64>(2)?.to_string()),
+                    ("order_number", row.get::<_, Option<String>>(3)?.unwrap_or_default()),
+                    ("order_total", format!("${:.2}", row.get::<_, Option<i64>>(4)?.unwrap_or(amount) as f64 / 100.0)),
+                    ("error", error.to_string()),
+                    ("retry_message", retry_message),
+                    ("manage_url", format!("{}/account", store_url)),
+                ];
+                Ok(email.map(|email| (email, vars)))
+            }
+        ).map_err(|e| e.to_string())
     });
 
-    if let Some((email, order_number, total, customer_name, shipping_address, items_text)) = email_data {
-        if !email.is_empty() {
-            let _ = send_order_confirmation(email, order_number, total, customer_name, shipping_address, items_text).await;
-        }
+    if let Ok(Some((email, vars))) = notice {
+        let _ = send_template_email("subscription_payment_failed", email, vars).await;
     }
+}
 
+// Try again after subscription_retry_hours without counting a failed attempt
+fn retry_subscription_later(conn: &Connection, subscription_id: i64, reason: &str) -> Result<(), String> {
+    let now = now();
+    let retry_hours = store_setting_i64(conn, "subscription_retry_hours", 24).max(1);
+    conn.execute(
+        "UPDATE order_subscriptions SET last_error = ?1, next_run_at = ?2, updated_at = ?3 WHERE id = ?4",
+        (reason, now + retry_hours * HOUR_NS, now, subscription_id)
+    ).map_err(|e| e.to_string())?;
     Ok(())
 }
 
+// Place and charge one due subscription; Ok(true) when an order was paid
+async fn charge_order_subscription(subscription_id: i64) -> Result<bool, String> {
+    let charge = match with_connection(|conn| prepare_subscription_order(&conn, subscription_id)) {
+        Ok(PendingOrder::Charge(charge)) => charge,
+        Ok(PendingOrder::AlreadyPaid(order_id)) => {
+            with_connection(|conn| complete_subscription_cycle(&conn, subscription_id, order_id, order_amount_due(&conn, order_id)))?;
+            return Ok(true);
+        }
+        Ok(PendingOrder::Retry(reason)) => {
+            with_connection(|conn| retry_subscription_later(&conn, subscription_id, &reason))?;
+            return Err(reason);
+        }
+        Ok(PendingOrder::CardMissing) => {
+            let error = "Saved card not found".to_string();
+            fail_subscription_cycle(subscription_id, None, 0, &error).await;
+            return Err(error);
+        }
+        // Store setup problems (shipping, prices, minimum amount) aren't the customer's card;
+        // keep retrying with the error on the subscription for the admin to fix
+        Err(error) => {
+            with_connection(|conn| retry_subscription_later(&conn, subscription_id, &error))?;
+            return Err(error);
+        }
+    };
+
+    let mut body = format!(
+        "amount={}&currency=usd&customer={}&payment_method={}&payment_method_types[]=card&confirm=true&off_session=true&metadata[order_id]={}&metadata[subscription_id]={}",
+        charge.amount, url_encode(&charge.customer_id), url_encode(&charge.stripe_pm_id), charge.order_id, subscription_id
+    );
+    if !with_connection(|conn| stripe_auto_capture(&conn)) {
+        body.push_str("&capture_method=manual");
+    }
+    // One key per failed attempt: a retry after a decline is a new charge, while a retry
+    // after a lost response gets Stripe's answer to the original request
+    let idempotency_key = format!("subscription_{}_order_{}_attempt_{}", subscription_id, charge.order_id, charge.attempt);
+
+    let intent = match stripe_request(&charge.api_key, HttpMethod::POST, "/v1/payment_intents", Some(body), Some(idempotency_key)).await {
+        Ok(intent) => intent,
+        // Stripe may have charged the card; don't count it against the customer
+        Err(error) if !stripe_responded(&error) => {
+            with_connection(|conn| retry_subscription_later(&conn, subscription_id, &error))?;
+            return Err(error);
+        }
+        Err(error) => {
+            fail_subscription_cycle(subscription_id, Some(charge.order_id), charge.amount, &error).await;
+            return Err(error);
+        }
+    };
+    let intent_id = intent["id"].as_str().unwrap_or("").to_string();
+    let status = intent["status"].as_str().unwrap_or("").to_string();
+
+    with_connection(|conn| {
+        let now = now();
+        conn.execute(
+            r#"INSERT OR REPLACE INTO payment_intents (order_id, payment_method_id, stripe_intent_id, client_secret, amount, status, payment_source_id, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)"#,
+            (charge.order_id, charge.payment_method_id, &intent_id, intent["client_secret"].as_str(), charge.amount, &status, charge.payment_source_id, now)
+        ).map_err(|e| e.to_string())
+    })?;
+
+    match status.as_str() {
+        "succeeded" | "requires_capture" => {
+            process_successful_payment(&intent_id, charge.amount, Some(charge.order_id), status == "succeeded")?;
+            with_connection(|conn| complete_subscription_cycle(&conn, subscription_id, charge.order_id, charge.amount))?;
+            Ok(true)
+        }
+        // The webhook completes the order; a later run picks that up
+        "processing" => {
+            with_connection(|conn| retry_subscription_later(&conn, subscription_id, "Payment processing"))?;
+            Ok(false)
+        }
+        _ => {
+            let error = if status == "requires_action" {
+                "Your bank asked to confirm the payment. Pay the order from your account to continue.".to_string()
+            } else {
+                format!("Payment {}", status.replace('_', " "))
+            };
+            fail_subscription_cycle(subscription_id, Some(charge.order_id), charge.amount, &error).await;
+            Err(error)
+        }
+    }
+}
+
+// Scheduled job: place and charge every subscription that is due
+async fn run_order_subscriptions() -> Result<String, String> {
+    let due: Vec<i64> = with_connection(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT id FROM order_subscriptions WHERE state = 'active' AND next_run_at <= ?1 ORDER BY next_run_at LIMIT 25"
+        ).map_err(|e| e.to_string())?;
+        let due = stmt.query_map((now(),), |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+        Ok::<_, String>(due)
+    })?;
+
+    let (mut paid, mut failed) = (0, 0);
+    for id in due {
+        match charge_order_subscription(id).await {
+            Ok(true) => paid += 1,
+            Ok(false) => {}
+            Err(_) => failed += 1,
+        }
+    }
+
+    Ok(format!("{} subscription orders paid, {} failed", paid, failed))
+}
+
 // ============================================
 // STRIPE WEBHOOK - Production Payment Verification
 // ============================================
@@ -4592,7 +11402,7 @@ fn verify_stripe_signature(payload: &str, signature_header: &str, webhook_secret
 }
 
 /// Handle Stripe webhook events
-/// This endpoint verifies the signature and processes payment events
+/// This endpoint verifies the signature, stores the event and processes it
 #[ic_cdk::update]
 fn handle_stripe_webhook(input: StripeWebhookInput) -> Result<String, String> {
     // Step 1: Get webhook secret from database
@@ -4611,10 +11421,23 @@ fn handle_stripe_webhook(input: StripeWebhookInput) -> Result<String, String> {
     let event: serde_json::Value = serde_json::from_str(&input.payload)
         .map_err(|e| format!("Failed to parse webhook payload: {}", e))?;
 
+    // Step 4: Store it; Stripe redelivers events, so skip ones already processed
+    let (event_row_id, processed) = with_connection(|conn| store_stripe_event(&conn, &event, &input.payload, "webhook"))?;
+    if processed {
+        return Ok(format!("Duplicate event ignored: {}", event["id"].as_str().unwrap_or("")));
+    }
+
+    // Step 5: Handle the event. An error makes Stripe retry, which re-runs it.
+    let result = process_stripe_event(&event);
+    record_stripe_event_result(event_row_id, &result)?;
+    result
+}
+
+// Apply a verified Stripe event
+fn process_stripe_event(event: &serde_json::Value) -> Result<String, String> {
     let event_type = event["type"].as_str()
         .ok_or("Missing event type")?;
 
-    // Step 4: Handle the event
     match event_type {
         "payment_intent.succeeded" => {
             let payment_intent = &event["data"]["object"];
@@ -4625,7 +11448,18 @@ fn handle_stripe_webhook(input: StripeWebhookInput) -> Result<String, String> {
             let order_id = payment_intent["metadata"]["order_id"].as_str()
                 .and_then(|s| s.parse::<i64>().ok());
 
-            process_successful_payment(intent_id, amount, order_id)?;
+            process_successful_payment(intent_id, amount, order_id, true)?;
+
+            // Buyer asked to save the card: Stripe has attached it to their customer
+            if !payment_intent["setup_future_usage"].is_null() {
+                if let (Some(customer), Some(pm)) = (payment_intent["customer"].as_str(), payment_intent["payment_method"].as_str()) {
+                    let (customer, pm) = (customer.to_string(), pm.to_string());
+                    ic_cdk::spawn(async move {
+                        let _ = save_payment_source(customer, pm).await;
+                    });
+                }
+            }
+
             Ok(format!("Processed payment_intent.succeeded: {}", intent_id))
         },
         "checkout.session.completed" => {
@@ -4643,6 +11477,66 @@ fn handle_stripe_webhook(input: StripeWebhookInput) -> Result<String, String> {
                 Ok(format!("Checkout session {} not yet paid: {}", session_id, payment_status))
             }
         },
+        "charge.refunded" => {
+            // Older API versions embed the charge's refunds; newer ones send refund.* events instead
+            let charge = &event["data"]["object"];
+            let refunds = charge["refunds"]["data"].as_array().cloned().unwrap_or_default();
+            let synced = with_connection(|conn| {
+                let mut synced = 0;
+                for refund in &refunds {
+                    if sync_stripe_refund(&conn, refund)? {
+                        synced += 1;
+                    }
+                }
+                Ok::<_, String>(synced)
+            })?;
+            Ok(format!("Processed charge.refunded: {} ({} refunds)", charge["id"].as_str().unwrap_or(""), synced))
+        },
+        "charge.refund.updated" | "refund.created" | "refund.updated" | "refund.failed" => {
+            let refund = &event["data"]["object"];
+            let refund_id = refund["id"].as_str().ok_or("Missing refund ID")?;
+
+            if with_connection(|conn| sync_stripe_refund(&conn, refund))? {
+                Ok(format!("Processed {}: {}", event_type, refund_id))
+            } else {
+                Ok(format!("Ignored {} for unknown payment: {}", event_type, refund_id))
+            }
+        },
+        "payment_intent.amount_capturable_updated" => {
+            // Manual capture: the card is authorized, so complete the order without charging it yet
+            let payment_intent = &event["data"]["object"];
+            let intent_id = payment_intent["id"].as_str()
+                .ok_or("Missing payment intent ID")?;
+            let amount = payment_intent["amount_capturable"].as_i64()
+                .ok_or("Missing amount")?;
+            let order_id = payment_intent["metadata"]["order_id"].as_str()
+                .and_then(|s| s.parse::<i64>().ok());
+
+            if amount > 0 {
+                process_successful_payment(intent_id, amount, order_id, false)?;
+            }
+            Ok(format!("Processed payment_intent.amount_capturable_updated: {}", intent_id))
+        },
+        "payment_intent.canceled" => {
+            // Voided by us, or the authorization expired before capture
+            let payment_intent = &event["data"]["object"];
+            let intent_id = payment_intent["id"].as_str()
+                .ok_or("Missing payment intent ID")?;
+
+            with_connection(|conn| void_authorized_payment(&conn, intent_id))?;
+            Ok(format!("Processed payment_intent.canceled: {}", intent_id))
+        },
+        "charge.dispute.created" | "charge.dispute.updated" | "charge.dispute.closed" => {
+            let dispute = &event["data"]["object"];
+            let dispute_id = dispute["id"].as_str().ok_or("Missing dispute ID")?;
+
+            if let Some(local_id) = with_connection(|conn| sync_stripe_dispute(&conn, dispute))? {
+                ic_cdk::spawn(async move {
+                    let _ = send_dispute_notification(local_id).await;
+                });
+            }
+            Ok(format!("Processed {}: {}", event_type, dispute_id))
+        },
         "payment_intent.payment_failed" => {
             let payment_intent = &event["data"]["object"];
             let intent_id = payment_intent["id"].as_str()
@@ -4658,11 +11552,26 @@ fn handle_stripe_webhook(input: StripeWebhookInput) -> Result<String, String> {
     }
 }
 
-/// Process a successful payment from webhook
-fn process_successful_payment(intent_id: &str, amount: i64, order_id_meta: Option<i64>) -> Result<(), String> {
-    with_connection(|conn| {
+/// Process a successful payment from webhook.
+/// `captured` is false when the card was only authorized (auto_capture off).
+fn process_successful_payment(intent_id: &str, amount: i64, order_id_meta: Option<i64>, captured: bool) -> Result<(), String> {
+    let completed = with_connection(|conn| {
         let now = now();
 
+        // Capture of an earlier authorization: the order is already complete
+        if captured && capture_authorized_payment(&conn, intent_id, amount)? {
+            return Ok::<_, String>(None);
+        }
+
+        // Already recorded (e.g. the succeeded event for a capture we made ourselves)
+        if conn.query_row(
+            "SELECT 1 FROM payments WHERE stripe_payment_intent_id = ?1",
+            (intent_id,),
+            |_| Ok(())
+        ).is_ok() {
+            return Ok(None);
+        }
+
         // Find order by intent_id in payment_intents table, or by metadata order_id
         let order_id: i64 = if let Some(oid) = order_id_meta {
             oid
@@ -4674,21 +11583,21 @@ fn process_successful_payment(intent_id: &str, amount: i64, order_id_meta: Optio
             ).map_err(|_| format!("Order not found for payment intent: {}", intent_id))?
         };
 
-        // Check if already processed (idempotency)
+        // Check if already processed (idempotency); an authorized order is 'pending'
         let already_paid: bool = conn.query_row(
-            "SELECT payment_state = 'paid' FROM orders WHERE id = ?1",
+            "SELECT payment_state IN ('paid', 'pending') FROM orders WHERE id = ?1",
             (order_id,),
             |row| row.get(0)
         ).unwrap_or(false);
 
         if already_paid {
-            return Ok(()); // Already processed, skip
+            return Ok(None); // Already processed, skip
         }
 
         // Update payment intent status
         conn.execute(
-            "UPDATE payment_intents SET status = 'succeeded', updated_at = ?1 WHERE stripe_intent_id = ?2",
-            (now, intent_id)
+            "UPDATE payment_intents SET status = ?1, updated_at = ?2 WHERE stripe_intent_id = ?3",
+            (if captured { "succeeded" } else { "requires_capture" }, now, intent_id)
         ).ok();
 
         // Get payment method ID
@@ -4698,36 +11607,27 @@ fn process_successful_payment(intent_id: &str, amount: i64, order_id_meta: Optio
             |row| row.get(0)
         ).unwrap_or(1);
 
-        // Record payment
+        // Record payment ('pending' = authorized, awaiting capture)
         conn.execute(
-            r#"INSERT OR IGNORE INTO payments (order_id, payment_method_id, amount, state, response_code, stripe_payment_intent_id, created_at, updated_at)
-               VALUES (?1, ?2, ?3, 'completed', ?4, ?4, ?5, ?5)"#,
-            (order_id, payment_method_id, amount, intent_id, now)
+            r#"INSERT OR IGNORE INTO payments (order_id, payment_method_id, amount, state, response_code, stripe_payment_intent_id, payment_source_id, authorization_expires_at, created_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?5, (SELECT payment_source_id FROM payment_intents WHERE stripe_intent_id = ?5), ?6, ?7, ?7)"#,
+            (
+                order_id, payment_method_id, amount,
+                if captured { "completed" } else { "pending" }, intent_id,
+                if captured { None } else { Some(now + STRIPE_AUTHORIZATION_TTL) }, now,
+            )
         ).ok();
 
         // Finalize order
         conn.execute(
-            "UPDATE orders SET payment_state = 'paid', state = 'complete', completed_at = ?1, updated_at = ?1 WHERE id = ?2",
-            (now, order_id)
+            "UPDATE orders SET payment_state = ?1, state = 'complete', completed_at = ?2, updated_at = ?2 WHERE id = ?3",
+            (if captured { "paid" } else { "pending" }, now, order_id)
         ).ok();
 
-        // Decrement stock
-        let mut stmt = conn.prepare(
-            "SELECT variant_id, quantity FROM line_items WHERE order_id = ?1"
-        ).map_err(|e| e.to_string())?;
-
-        let line_items: Vec<(i64, i64)> = stmt.query_map((order_id,), |row| Ok((row.get(0)?, row.get(1)?)))
-            .map_err(|e| e.to_string())?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| e.to_string())?;
-        drop(stmt);
+        let needs_activation = complete_gift_cards(&conn, order_id)?;
 
-        for (variant_id, quantity) in line_items {
-            conn.execute(
-                "UPDATE stock_items SET count_on_hand = count_on_hand - ?1, updated_at = ?2 WHERE variant_id = ?3",
-                (quantity, now, variant_id)
-            ).ok();
-        }
+        // Decrease stock (components for bundle lines)
+        take_order_stock(&conn, order_id)?;
 
         // Get order details for email
         let email_data: Option<(String, String, i64, String, String, String)> = conn.query_row(
@@ -4753,6 +11653,14 @@ fn process_successful_payment(intent_id: &str, amount: i64, order_id_meta: Optio
             ))
         ).ok();
 
+        Ok(Some((order_id, needs_activation, email_data)))
+    })?;
+
+    if let Some((order_id, needs_activation, email_data)) = completed {
+        if needs_activation {
+            spawn_gift_card_activation(order_id);
+        }
+
         if let Some((email, order_number, total, customer_name, shipping_address, items_text)) = email_data {
             if !email.is_empty() {
                 ic_cdk::spawn(async move {
@@ -4760,14 +11668,14 @@ fn process_successful_payment(intent_id: &str, amount: i64, order_id_meta: Optio
                 });
             }
         }
+    }
 
-        Ok(())
-    })
+    Ok(())
 }
 
 /// Process a completed checkout session
 fn process_checkout_session_completed(session_id: &str, order_number: Option<&str>) -> Result<(), String> {
-    with_connection(|conn| {
+    let completed = with_connection(|conn| {
         let now = now();
 
         // Find order by session_id or order_number
@@ -4793,15 +11701,11 @@ fn process_checkout_session_completed(session_id: &str, order_number: Option<&st
         ).unwrap_or(false);
 
         if already_paid {
-            return Ok(());
+            return Ok::<_, String>(None);
         }
 
-        // Get order total
-        let amount: i64 = conn.query_row(
-            "SELECT total FROM orders WHERE id = ?1",
-            (order_id,),
-            |row| row.get(0)
-        ).unwrap_or(0);
+        // Amount charged by Stripe (total less gift cards)
+        let amount = order_amount_due(&conn, order_id);
 
         // Update checkout session status
         conn.execute(
@@ -4818,8 +11722,8 @@ fn process_checkout_session_completed(session_id: &str, order_number: Option<&st
 
         // Record payment
         conn.execute(
-            r#"INSERT OR IGNORE INTO payments (order_id, payment_method_id, amount, state, response_code, stripe_payment_intent_id, created_at, updated_at)
-               VALUES (?1, ?2, ?3, 'completed', ?4, ?4, ?5, ?5)"#,
+            r#"INSERT OR IGNORE INTO payments (order_id, payment_method_id, amount, state, response_code, stripe_payment_intent_id, payment_source_id, created_at, updated_at)
+               VALUES (?1, ?2, ?3, 'completed', ?4, ?4, (SELECT payment_source_id FROM payment_intents WHERE stripe_intent_id = ?4), ?5, ?5)"#,
             (order_id, payment_method_id, amount, session_id, now)
         ).ok();
 
@@ -4829,23 +11733,10 @@ fn process_checkout_session_completed(session_id: &str, order_number: Option<&st
             (now, order_id)
         ).ok();
 
-        // Decrement stock
-        let mut stmt = conn.prepare(
-            "SELECT variant_id, quantity FROM line_items WHERE order_id = ?1"
-        ).map_err(|e| e.to_string())?;
-
-        let line_items: Vec<(i64, i64)> = stmt.query_map((order_id,), |row| Ok((row.get(0)?, row.get(1)?)))
-            .map_err(|e| e.to_string())?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| e.to_string())?;
-        drop(stmt);
+        let needs_activation = complete_gift_cards(&conn, order_id)?;
 
-        for (variant_id, quantity) in line_items {
-            conn.execute(
-                "UPDATE stock_items SET count_on_hand = count_on_hand - ?1, updated_at = ?2 WHERE variant_id = ?3",
-                (quantity, now, variant_id)
-            ).ok();
-        }
+        // Decrease stock (components for bundle lines)
+        take_order_stock(&conn, order_id)?;
 
         // Get order details for email
         let email_data: Option<(String, String, i64, String, String, String)> = conn.query_row(
@@ -4871,6 +11762,14 @@ fn process_checkout_session_completed(session_id: &str, order_number: Option<&st
             ))
         ).ok();
 
+        Ok(Some((order_id, needs_activation, email_data)))
+    })?;
+
+    if let Some((order_id, needs_activation, email_data)) = completed {
+        if needs_activation {
+            spawn_gift_card_activation(order_id);
+        }
+
         if let Some((email, order_number, total, customer_name, shipping_address, items_text)) = email_data {
             if !email.is_empty() {
                 ic_cdk::spawn(async move {
@@ -4878,9 +11777,9 @@ fn process_checkout_session_completed(session_id: &str, order_number: Option<&st
                 });
             }
         }
+    }
 
-        Ok(())
-    })
+    Ok(())
 }
 
 /// Process a failed payment from webhook
@@ -4898,6 +11797,321 @@ fn process_failed_payment(intent_id: &str) -> Result<(), String> {
     })
 }
 
+// ============================================
+// STRIPE WEBHOOK EVENTS
+// ============================================
+
+// Stripe keeps events for 30 days; with nothing processed yet, reconcile looks back this far (seconds)
+const STRIPE_RECONCILE_LOOKBACK: i64 = 3 * 24 * 3600;
+
+// Upper bound on event pages fetched per reconciliation run
+const STRIPE_RECONCILE_MAX_PAGES: usize = 10;
+
+// Reconciliation progress (store_settings, seconds): every event created before the cursor
+// has been checked. A window with more events than one run can fetch is cut down to end at
+// the oldest event fetched, so the oldest events are always reconciled first.
+const STRIPE_RECONCILE_CURSOR: &str = "stripe_reconcile_cursor";
+const STRIPE_RECONCILE_WINDOW_END: &str = "stripe_reconcile_window_end";
+
+// Event types process_stripe_event acts on (the rest are acknowledged and ignored)
+const STRIPE_EVENT_TYPES: &[&str] = &[
+    "payment_intent.succeeded",
+    "payment_intent.amount_capturable_updated",
+    "payment_intent.canceled",
+    "payment_intent.payment_failed",
+    "checkout.session.completed",
+    "charge.refunded",
+    "charge.refund.updated",
+    "refund.created",
+    "refund.updated",
+    "refund.failed",
+    "charge.dispute.created",
+    "charge.dispute.updated",
+    "charge.dispute.closed",
+];
+
+// Store a verified event (deduplicated by Stripe's event id).
+// Returns the row id and whether the event was already processed.
+fn store_stripe_event(conn: &Connection, event: &serde_json::Value, payload: &str, source: &str) -> Result<(i64, bool), String> {
+    let event_id = event["id"].as_str().ok_or("Missing event ID")?;
+    let event_type = event["type"].as_str().ok_or("Missing event type")?;
+    let now = now();
+
+    conn.execute(
+        r#"INSERT OR IGNORE INTO stripe_webhook_events (stripe_event_id, event_type, payload, stripe_created_at, source, created_at, updated_at)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)"#,
+        (event_id, event_type, payload, event["created"].as_i64(), source, now)
+    ).map_err(|e| e.to_string())?;
+
+    conn.query_row(
+        "SELECT id, COALESCE(processed, 0) FROM stripe_webhook_events WHERE stripe_event_id = ?1",
+        (event_id,),
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).map_err(|e| e.to_string())
+}
+
+// Save the outcome of one processing attempt
+fn record_stripe_event_result(event_row_id: i64, result: &Result<String, String>) -> Result<(), String> {
+    with_connection(|conn| {
+        let now = now();
+        let (processed, message, error) = match result {
+            Ok(message) => (true, Some(message.as_str()), None),
+            Err(error) => (false, None, Some(error.as_str())),
+        };
+
+        conn.execute(
+            r#"UPDATE stripe_webhook_events
+               SET processed = ?1, result = ?2, error = ?3, attempts = attempts + 1,
+                   processed_at = CASE WHEN ?1 THEN ?4 ELSE processed_at END, updated_at = ?4
+               WHERE id = ?5"#,
+            (processed, message, error, now, event_row_id)
+        ).map_err(|e| e.to_string())?;
+
+        Ok(())
+    })
+}
+
+fn set_store_setting(conn: &Connection, key: &str, value: &str) -> Result<(), String> {
+    conn.execute(
+        r#"INSERT INTO store_settings (key, value, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)
+           ON CONFLICT(key) DO UPDATE SET value = ?2, updated_at = ?3"#,
+        (key, value, now())
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Fetch events Stripe created since the reconcile cursor and apply any we never received.
+// Returns the number of events that were missing.
+async fn reconcile_stripe_events() -> Result<i64, String> {
+    let now_secs = now() / 1_000_000_000;
+
+    let (api_key, since, window_end) = with_connection(|conn| {
+        let (_, api_key) = stripe_credentials(&conn)?;
+        // Before the cursor was stored, carry on from the newest processed event
+        let last_processed: Option<i64> = conn.query_row(
+            "SELECT MAX(stripe_created_at) FROM stripe_webhook_events WHERE processed = 1",
+            [],
+            |row| row.get(0)
+        ).unwrap_or(None);
+        let cursor = store_setting_i64(&conn, STRIPE_RECONCILE_CURSOR, last_processed.unwrap_or(now_secs - STRIPE_RECONCILE_LOOKBACK));
+        Ok::<_, String>((api_key, cursor, store_setting_i64(&conn, STRIPE_RECONCILE_WINDOW_END, 0)))
+    })?;
+
+    // Every replica must get the same list, so leave out the last minute's events
+    let mut until = now_secs - 60;
+    if window_end > since {
+        until = until.min(window_end);
+    }
+    if until <= since {
+        return Ok(0);
+    }
+    let types: String = STRIPE_EVENT_TYPES.iter().map(|t| format!("&types[]={}", t)).collect();
+
+    let mut events: Vec<serde_json::Value> = Vec::new();
+    let mut starting_after: Option<String> = None;
+    let mut complete = false;
+    for _ in 0..STRIPE_RECONCILE_MAX_PAGES {
+        let mut path = format!("/v1/events?limit=100&created[gte]={}&created[lt]={}{}", since, until, types);
+        if let Some(ref id) = starting_after {
+            path.push_str(&format!("&starting_after={}", id));
+        }
+
+        let page = stripe_request(&api_key, HttpMethod::GET, &path, None, None).await?;
+        let data = page["data"].as_array().cloned().unwrap_or_default();
+        starting_after = data.last().and_then(|e| e["id"].as_str()).map(String::from);
+        events.extend(data);
+
+        if !page["has_more"].as_bool().unwrap_or(false) || starting_after.is_none() {
+            complete = true;
+            break;
+        }
+    }
+
+    // Stripe lists newest first, so a window cut short at the page cap is missing its oldest
+    // events. Leave the cursor where it is and fetch only up to the oldest one next run.
+    if !complete {
+        let oldest = events.last().and_then(|e| e["created"].as_i64()).unwrap_or(until);
+        if oldest + 1 >= until {
+            return Err(format!("More than {} Stripe events in one second, cannot reconcile", STRIPE_RECONCILE_MAX_PAGES * 100));
+        }
+        with_connection(|conn| set_store_setting(&conn, STRIPE_RECONCILE_WINDOW_END, &(oldest + 1).to_string()))?;
+        return Ok(0);
+    }
+
+    // Apply them in the order they happened
+    events.reverse();
+
+    let mut missed = 0;
+    for event in &events {
+        let event_id = event["id"].as_str().unwrap_or_default();
+        let known = with_connection(|conn| conn.query_row(
+            "SELECT 1 FROM stripe_webhook_events WHERE stripe_event_id = ?1",
+            (event_id,),
+            |_| Ok(())
+        ).is_ok());
+        if known {
+            continue;
+        }
+
+        let (event_row_id, _) = with_connection(|conn| store_stripe_event(&conn, event, &event.to_string(), "reconcile"))?;
+        let result = process_stripe_event(event);
+        record_stripe_event_result(event_row_id, &result)?;
+        missed += 1;
+    }
+
+    // The whole window is reconciled
+    with_connection(|conn| {
+        set_store_setting(&conn, STRIPE_RECONCILE_CURSOR, &until.to_string())?;
+        set_store_setting(&conn, STRIPE_RECONCILE_WINDOW_END, "0")
+    })?;
+
+    Ok(missed)
+}
+
+/// List stored Stripe events, newest first.
+/// status: "processed", "failed" (errored, awaiting replay) or "pending"
+#[ic_cdk::query]
+fn admin_get_webhook_events(params: WebhookEventQueryParams) -> Result<WebhookEventListResponse, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        let mut conditions = vec!["1 = 1".to_string()];
+        let mut query_params: Vec<Box<dyn ic_rusqlite::ToSql>> = vec![];
+
+        match params.status.as_deref() {
+            Some("processed") => conditions.push("processed = 1".to_string()),
+            Some("failed") => conditions.push("COALESCE(processed, 0) = 0 AND error IS NOT NULL".to_string()),
+            Some("pending") => conditions.push("COALESCE(processed, 0) = 0 AND error IS NULL".to_string()),
+            Some(other) => return Err(format!("Unknown status: {}", other)),
+            None => {}
+        }
+
+        if let Some(ref event_type) = params.event_type {
+            query_params.push(Box::new(event_type.clone()));
+            conditions.push(format!("event_type = ?{}", query_params.len()));
+        }
+
+        let where_clause = format!("WHERE {}", conditions.join(" AND "));
+
+        let count_sql = format!("SELECT COUNT(*) FROM stripe_webhook_events {}", where_clause);
+        let param_refs: Vec<&dyn ic_rusqlite::ToSql> = query_params.iter().map(|p| p.as_ref()).collect();
+        let total_count: i64 = conn.query_row(&count_sql, param_refs.as_slice(), |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+
+        let page = params.page.unwrap_or(1).max(1);
+        let per_page = params.per_page.unwrap_or(20).clamp(1, 100);
+        let offset = (page - 1) * per_page;
+
+        let sql = format!(
+            r#"SELECT id, stripe_event_id, event_type, COALESCE(processed, 0), source, result, error, attempts,
+               stripe_created_at, processed_at, created_at
+               FROM stripe_webhook_events
+               {}
+               ORDER BY created_at DESC, id DESC
+               LIMIT ?{} OFFSET ?{}"#,
+            where_clause,
+            query_params.len() + 1,
+            query_params.len() + 2
+        );
+
+        query_params.push(Box::new(per_page));
+        query_params.push(Box::new(offset));
+        let param_refs: Vec<&dyn ic_rusqlite::ToSql> = query_params.iter().map(|p| p.as_ref()).collect();
+
+        let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+        let events = stmt.query_map(param_refs.as_slice(), |row| {
+            Ok(WebhookEvent {
+                id: row.get(0)?,
+                stripe_event_id: row.get(1)?,
+                event_type: row.get(2)?,
+                processed: row.get(3)?,
+                source: row.get(4)?,
+                result: row.get(5)?,
+                error: row.get(6)?,
+                attempts: row.get(7)?,
+                stripe_created_at: row.get(8)?,
+                processed_at: row.get(9)?,
+                created_at: row.get(10)?,
+                payload: None,
+            })
+        }).map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?;
+
+        let total_pages = ((total_count as f64) / (per_page as f64)).ceil() as i64;
+
+        Ok(WebhookEventListResponse {
+            events,
+            total_count,
+            page,
+            per_page,
+            total_pages,
+        })
+    })
+}
+
+/// A stored Stripe event including its raw payload
+#[ic_cdk::query]
+fn admin_get_webhook_event(id: i64) -> Result<WebhookEvent, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    with_connection(|conn| {
+        conn.query_row(
+            r#"SELECT id, stripe_event_id, event_type, COALESCE(processed, 0), source, result, error, attempts,
+               stripe_created_at, processed_at, created_at, payload
+               FROM stripe_webhook_events WHERE id = ?1"#,
+            (id,),
+            |row| Ok(WebhookEvent {
+                id: row.get(0)?,
+                stripe_event_id: row.get(1)?,
+                event_type: row.get(2)?,
+                processed: row.get(3)?,
+                source: row.get(4)?,
+                result: row.get(5)?,
+                error: row.get(6)?,
+                attempts: row.get(7)?,
+                stripe_created_at: row.get(8)?,
+                processed_at: row.get(9)?,
+                created_at: row.get(10)?,
+                payload: row.get(11)?,
+            })
+        ).map_err(|_| "Event not found".to_string())
+    })
+}
+
+/// Re-run a stored event that has not been processed successfully
+#[ic_cdk::update]
+fn admin_replay_webhook_event(id: i64) -> Result<String, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    let (processed, payload): (bool, Option<String>) = with_connection(|conn| {
+        conn.query_row(
+            "SELECT COALESCE(processed, 0), payload FROM stripe_webhook_events WHERE id = ?1",
+            (id,),
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).map_err(|_| "Event not found".to_string())
+    })?;
+
+    if processed {
+        return Err("Event already processed".to_string());
+    }
+    // Events received before payloads were stored can't be replayed
+    let payload = payload.ok_or("Event has no stored payload")?;
+    let event: serde_json::Value = serde_json::from_str(&payload)
+        .map_err(|e| format!("Failed to parse stored payload: {}", e))?;
+
+    let result = process_stripe_event(&event);
+    record_stripe_event_result(id, &result)?;
+    result
+}
+
+/// Poll Stripe now for events the webhook missed. Returns how many were applied.
+#[ic_cdk::update]
+async fn admin_reconcile_stripe_events() -> Result<i64, String> {
+    if !is_admin() { return Err("Admin only".to_string()); }
+
+    reconcile_stripe_events().await
+}
+
 #[ic_cdk::query]
 fn get_option_types() -> Result<Vec<OptionType>, String> {
     with_connection(|conn| {
@@ -4989,9 +12203,8 @@ fn create_variant(input: CreateVariantInput) -> Result<i64, String> {
     })
 }
 
-#[ic_cdk::update]
-fn subscribe_newsletter(email: String) -> Result<String, String> {
-    // Validate email format more thoroughly
+// Trimmed, lowercased email; rejects malformed addresses
+fn validate_email(email: &str) -> Result<String, String> {
     let email = email.trim().to_lowercase();
 
     if email.is_empty() {
@@ -5024,6 +12237,13 @@ fn subscribe_newsletter(email: String) -> Result<String, String> {
         return Err("Invalid email address format".to_string());
     }
 
+    Ok(email)
+}
+
+#[ic_cdk::update]
+fn subscribe_newsletter(email: String) -> Result<String, String> {
+    let email = validate_email(&email)?;
+
     with_connection(|conn| {
         let now = now();
         conn.execute(
@@ -5084,7 +12304,7 @@ fn get_email_settings() -> Result<EmailSettings, String> {
     if !is_admin() { return Err("Admin only".to_string()); }
     with_connection(|conn| {
         conn.query_row(
-            "SELECT provider, api_key, sender_email, active FROM email_settings WHERE id = 1",
+            "SELECT provider, api_key, sender_email, active, notification_email FROM email_settings WHERE id = 1",
             [],
             |row| {
                 let api_key: Option<String> = row.get(1)?;
@@ -5099,6 +12319,7 @@ fn get_email_settings() -> Result<EmailSettings, String> {
                     api_key: masked_key,
                     sender_email: row.get(2)?,
                     active: row.get::<_, i64>(3)? == 1,
+                    notification_email: row.get(4)?,
                 })
             }
         ).map_err(|e| e.to_string())
@@ -5111,8 +12332,8 @@ fn update_email_settings(input: UpdateEmailSettingsInput) -> Result<(), String>
     with_connection(|conn| {
         let now = now();
         conn.execute(
-            "UPDATE email_settings SET provider = ?1, api_key = ?2, sender_email = ?3, active = ?4, updated_at = ?5 WHERE id = 1",
-            (input.provider, input.api_key, input.sender_email, if input.active { 1 } else { 0 }, now)
+            "UPDATE email_settings SET provider = ?1, api_key = ?2, sender_email = ?3, active = ?4, notification_email = COALESCE(?5, notification_email), updated_at = ?6 WHERE id = 1",
+            (input.provider, input.api_key, input.sender_email, if input.active { 1 } else { 0 }, input.notification_email, now)
         ).map_err(|e| e.to_string())?;
         Ok(())
     })
@@ -5228,13 +12449,14 @@ async fn send_test_email(event_type: String, to_email: String) -> Result<String,
     // Get email settings and template
     let (settings, template) = with_connection(|conn| {
         let settings = conn.query_row(
-            "SELECT provider, api_key, sender_email, active FROM email_settings WHERE id = 1",
+            "SELECT provider, api_key, sender_email, active, notification_email FROM email_settings WHERE id = 1",
             [],
             |row| Ok(EmailSettings {
                 provider: row.get(0)?,
                 api_key: row.get(1)?,
                 sender_email: row.get(2)?,
                 active: row.get::<_, i64>(3)? == 1,
+                notification_email: row.get(4)?,
             })
         ).map_err(|e| format!("Email settings not configured: {}", e))?;
 
@@ -5344,13 +12566,14 @@ async fn send_templated_email(event_type: &str, to: String, order_number: String
     // Get settings and template
     let data = with_connection(|conn| {
         let settings = conn.query_row(
-            "SELECT provider, api_key, sender_email, active FROM email_settings WHERE id = 1",
+            "SELECT provider, api_key, sender_email, active, notification_email FROM email_settings WHERE id = 1",
             [],
             |row| Ok(EmailSettings {
                 provider: row.get(0)?,
                 api_key: row.get(1)?,
                 sender_email: row.get(2)?,
                 active: row.get::<_, i64>(3)? == 1,
+                notification_email: row.get(4)?,
             })
         ).ok();
 
@@ -5506,6 +12729,88 @@ async fn send_templated_email(event_type: &str, to: String, order_number: String
     Ok(())
 }
 
+// Send a template with arbitrary {{placeholders}} ({{store_name}} is always filled in).
+// Returns false when email is not configured or the template is disabled.
+async fn send_template_email(event_type: &str, to: String, vars: Vec<(&str, String)>) -> Result<bool, String> {
+    let (settings, template, store_name) = with_connection(|conn| {
+        let settings = conn.query_row(
+            "SELECT provider, api_key, sender_email, active, notification_email FROM email_settings WHERE id = 1",
+            [],
+            |row| Ok(EmailSettings {
+                provider: row.get(0)?,
+                api_key: row.get(1)?,
+                sender_email: row.get(2)?,
+                active: row.get::<_, i64>(3)? == 1,
+                notification_email: row.get(4)?,
+            })
+        ).ok();
+
+        let template = conn.query_row(
+            "SELECT subject, body_html, body_text FROM email_templates WHERE event_type = ?1 AND active = 1",
+            (event_type,),
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+        ).ok();
+
+        let store_name: String = conn.query_row(
+            "SELECT value FROM store_settings WHERE key = 'store_name'",
+            [],
+            |row| row.get(0)
+        ).unwrap_or_else(|_| "Canister Shop".to_string());
+
+        (settings, template, store_name)
+    });
+
+    let settings = match settings {
+        Some(s) if s.active && !s.api_key.is_empty() && s.provider == "sendgrid" => s,
+        _ => return Ok(false),
+    };
+    let (mut subject, mut html_body, mut text_body) = match template {
+        Some(t) => t,
+        None => return Ok(false),
+    };
+
+    let mut vars = vars;
+    vars.push(("store_name", store_name));
+    for (key, value) in &vars {
+        let placeholder = format!("{{{{{}}}}}", key);
+        subject = subject.replace(&placeholder, value);
+        html_body = html_body.replace(&placeholder, &html_escape(value).replace('\n', "<br>"));
+        text_body = text_body.replace(&placeholder, value);
+    }
+
+    let json_body = serde_json::json!({
+        "personalizations": [{"to": [{"email": to}]}],
+        "from": {"email": settings.sender_email},
+        "subject": subject,
+        "content": [
+            {"type": "text/plain", "value": text_body},
+            {"type": "text/html", "value": html_body}
+        ]
+    }).to_string();
+
+    let request = CanisterHttpRequestArgument {
+        url: "https://api.sendgrid.com/v3/mail/send".to_string(),
+        method: HttpMethod::POST,
+        body: Some(json_body.into_bytes()),
+        max_response_bytes: Some(2048),
+        transform: None,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "Authorization".to_string(), value: format!("Bearer {}", settings.api_key) },
+        ],
+    };
+
+    let (response,) = http_request(request, 2_000_000_000).await
+        .map_err(|(code, msg)| format!("Failed to send email: {:?} {}", code, msg))?;
+
+    if response.status >= 300u64 {
+        return Err(format!("SendGrid error ({}): {}", response.status, String::from_utf8_lossy(&response.body)));
+    }
+
+    ic_cdk::print(format!("Email ({}) sent to {}", event_type, to));
+    Ok(true)
+}
+
 async fn send_order_confirmation(
     to: String,
     order_number: String,
@@ -5526,6 +12831,94 @@ async fn send_order_confirmation(
     ).await
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_offsets() {
+        assert_eq!(parse_utc_offset("UTC").unwrap(), 0);
+        assert_eq!(parse_utc_offset("Z").unwrap(), 0);
+        assert_eq!(parse_utc_offset("+05:30").unwrap(), 19_800);
+        assert_eq!(parse_utc_offset("-0800").unwrap(), -28_800);
+        assert_eq!(parse_utc_offset("+5").unwrap(), 18_000);
+        assert_eq!(parse_utc_offset("UTC-08:00").unwrap(), -28_800);
+        assert_eq!(parse_utc_offset("GMT+1").unwrap(), 3_600);
+    }
+
+    #[test]
+    fn unsupported_utc_offsets() {
+        assert!(parse_utc_offset("Europe/Paris").is_err());
+        assert!(parse_utc_offset("+15").is_err());
+        assert!(parse_utc_offset("+05:60").is_err());
+        assert!(parse_utc_offset("+").is_err());
+        assert!(parse_utc_offset("+é").is_err());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_date("2024-02-29").unwrap(), 19_782);
+        assert_eq!(parse_date(" 2000-02-29 ").unwrap(), 11_016);
+        assert_eq!(parse_date("1900-01-01").unwrap(), -25_567);
+        assert_eq!(format_date(19_782), "2024-02-29");
+    }
+
+    #[test]
+    fn invalid_dates() {
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("2024-04-31").is_err());
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("2024-00-10").is_err());
+        assert!(parse_date("2024-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+        assert!(parse_date("1899-12-31").is_err());
+        assert!(parse_date("9999999999999999-01-01").is_err());
+        assert!(parse_date("2024-01-9223372036854775807").is_err());
+        assert!(parse_date("-9223372036854775808-01-01").is_err());
+    }
+
+    #[test]
+    fn civil_dates_round_trip() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        for days in (-800_000..800_000).step_by(37) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn month_arithmetic() {
+        // 2024-01-31 + 1 month = 2024-02-01 (first of the month)
+        assert_eq!(add_months(19_753, 1), 19_754);
+        assert_eq!(add_months(19_737, 0), 19_723);
+        assert_eq!(add_months(19_737, -1), 19_692);
+        assert_eq!(add_months(19_692, 1), 19_723);
+    }
+
+    #[test]
+    fn day_and_week_buckets() {
+        assert_eq!(sales_buckets("day", 19_723, 19_725).unwrap(), vec![19_723, 19_724, 19_725, 19_726]);
+        // Wednesday 2024-01-03 falls in the week of Monday 2024-01-01
+        assert_eq!(sales_buckets("week", 19_725, 19_731).unwrap(), vec![19_723, 19_730, 19_737]);
+    }
+
+    #[test]
+    fn month_buckets() {
+        // 2024-01-15 .. 2024-03-01: Jan, Feb, Mar, then the start of April
+        assert_eq!(sales_buckets("month", 19_737, 19_783).unwrap(), vec![19_723, 19_754, 19_783, 19_814]);
+    }
+
+    #[test]
+    fn bucket_limits() {
+        assert!(sales_buckets("year", 19_723, 19_725).is_err());
+        assert!(sales_buckets("day", 19_000, 19_000 + SALES_REPORT_MAX_BUCKETS as i64).is_err());
+        assert!(sales_buckets("day", 19_000, 19_000 + SALES_REPORT_MAX_BUCKETS as i64 - 1).is_ok());
+    }
+}
+
 // Generate Candid interface - must be at end of file to export all functions
 export_candid!();
 
@@ -0,0 +1,594 @@
+// Promotion engine
+// Rule evaluation and action calculators, based on Solidus promotions.
+// Everything here works on a snapshot of the order (PromoOrder) so it can be
+// tested without a database; lib.rs loads the snapshot and writes adjustments.
+
+use serde_json::Value;
+
+// Line item as seen by the promotion engine
+#[derive(Clone, Debug, Default)]
+pub struct PromoLine {
+    pub line_item_id: i64,
+    pub product_id: i64,
+    pub taxon_ids: Vec<i64>,  // includes ancestor taxons
+    pub quantity: i64,
+    pub price: i64,
+}
+
+// Snapshot of an order used to evaluate rules and compute actions
+#[derive(Clone, Debug, Default)]
+pub struct PromoOrder {
+    pub item_total: i64,
+    pub lines: Vec<PromoLine>,
+    pub user_id: Option<i64>,
+    pub user_roles: Vec<String>,
+    pub completed_orders: i64,  // completed orders of the user, excluding this one
+    pub ship_country: Option<String>,
+    pub shipments: Vec<(i64, i64)>,  // (shipment_id, cost)
+}
+
+// Discount produced by an action. Amounts are negative cents.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PromoAdjustment {
+    Order { amount: i64 },
+    LineItem { line_item_id: i64, amount: i64 },
+    Shipment { shipment_id: i64, amount: i64 },
+}
+
+impl PromoAdjustment {
+    pub fn amount(&self) -> i64 {
+        match self {
+            PromoAdjustment::Order { amount }
+            | PromoAdjustment::LineItem { amount, .. }
+            | PromoAdjustment::Shipment { amount, .. } => *amount,
+        }
+    }
+
+    fn with_amount(self, amount: i64) -> Self {
+        match self {
+            PromoAdjustment::Order { .. } => PromoAdjustment::Order { amount },
+            PromoAdjustment::LineItem { line_item_id, .. } => PromoAdjustment::LineItem { line_item_id, amount },
+            PromoAdjustment::Shipment { shipment_id, .. } => PromoAdjustment::Shipment { shipment_id, amount },
+        }
+    }
+}
+
+pub const RULE_TYPES: &[&str] = &[
+    "ItemTotal", "FirstOrder", "Product", "Taxon", "User", "UserRole",
+    "Quantity", "NthOrder", "ShipToCountry",
+];
+
+pub const ACTION_TYPES: &[&str] = &[
+    "CreateAdjustment", "CreateItemAdjustments", "FreeShipping", "BuyXGetY",
+];
+
+pub const CALCULATOR_TYPES: &[&str] = &["FlatRate", "PercentOff", "TieredPercent"];
+
+fn id_list(prefs: &Value, key: &str) -> Vec<i64> {
+    prefs[key].as_array()
+        .map(|a| a.iter().filter_map(|v| v.as_i64()).collect())
+        .unwrap_or_default()
+}
+
+fn string_list(prefs: &Value, key: &str) -> Vec<String> {
+    prefs[key].as_array()
+        .map(|a| a.iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default()
+}
+
+fn match_policy(prefs: &Value) -> &str {
+    prefs["match_policy"].as_str().unwrap_or("any")
+}
+
+// Check a list of wanted ids against what the order has, using the rule's match policy
+fn matches_policy(policy: &str, wanted: &[i64], present: &[i64]) -> Result<bool, String> {
+    if wanted.is_empty() {
+        return Ok(true);
+    }
+    match policy {
+        "any" => Ok(wanted.iter().any(|id| present.contains(id))),
+        "all" => Ok(wanted.iter().all(|id| present.contains(id))),
+        "none" => Ok(!wanted.iter().any(|id| present.contains(id))),
+        other => Err(format!("Unknown match policy: '{}'", other)),
+    }
+}
+
+// A line is eligible for line-level actions when it matches the optional
+// product_ids / taxon_ids preferences (no preference = every line)
+fn line_eligible(line: &PromoLine, prefs: &Value) -> bool {
+    let product_ids = id_list(prefs, "product_ids");
+    let taxon_ids = id_list(prefs, "taxon_ids");
+    (product_ids.is_empty() || product_ids.contains(&line.product_id))
+        && (taxon_ids.is_empty() || taxon_ids.iter().any(|t| line.taxon_ids.contains(t)))
+}
+
+/// Evaluate a single promotion rule against the order
+pub fn rule_eligible(rule_type: &str, prefs: &Value, order: &PromoOrder) -> Result<bool, String> {
+    match rule_type {
+        "ItemTotal" => {
+            let threshold = prefs["amount"].as_i64().unwrap_or(0);
+            Ok(order.item_total >= threshold)
+        },
+        "FirstOrder" => {
+            // Guests can't be tracked across orders
+            Ok(order.user_id.is_some() && order.completed_orders == 0)
+        },
+        "Product" => {
+            let present: Vec<i64> = order.lines.iter().map(|l| l.product_id).collect();
+            matches_policy(match_policy(prefs), &id_list(prefs, "product_ids"), &present)
+        },
+        "Taxon" => {
+            let present: Vec<i64> = order.lines.iter().flat_map(|l| l.taxon_ids.iter().copied()).collect();
+            matches_policy(match_policy(prefs), &id_list(prefs, "taxon_ids"), &present)
+        },
+        "User" => {
+            let user_ids = id_list(prefs, "user_ids");
+            Ok(order.user_id.map(|uid| user_ids.contains(&uid)).unwrap_or(false))
+        },
+        "UserRole" => {
+            let roles = string_list(prefs, "roles");
+            if order.user_id.is_none() {
+                return Ok(false);
+            }
+            Ok(match match_policy(prefs) {
+                "all" => roles.iter().all(|r| order.user_roles.contains(r)),
+                _ => roles.iter().any(|r| order.user_roles.contains(r)),
+            })
+        },
+        "Quantity" => {
+            let min_quantity = prefs["min_quantity"].as_i64().unwrap_or(1);
+            let quantity: i64 = order.lines.iter()
+                .filter(|l| line_eligible(l, prefs))
+                .map(|l| l.quantity)
+                .sum();
+            Ok(quantity >= min_quantity)
+        },
+        "NthOrder" => {
+            let nth = prefs["nth"].as_i64().unwrap_or(0);
+            Ok(order.user_id.is_some() && nth > 0 && order.completed_orders + 1 == nth)
+        },
+        "ShipToCountry" => {
+            let countries = string_list(prefs, "country_codes");
+            Ok(order.ship_country.as_ref()
+                .map(|c| countries.iter().any(|wanted| wanted.eq_ignore_ascii_case(c)))
+                .unwrap_or(false))
+        },
+        unknown => {
+            // Reject unknown rule types to prevent unintended discounts
+            Err(format!("Unknown promotion rule type: '{}'", unknown))
+        }
+    }
+}
+
+// Percent for a TieredPercent calculator: highest tier whose threshold is reached,
+// otherwise the base percent
+fn tiered_percent(prefs: &Value, base: i64) -> i64 {
+    let mut percent = prefs["base_percent"].as_i64().unwrap_or(0);
+    let mut best_threshold = -1;
+    if let Some(tiers) = prefs["tiers"].as_array() {
+        for tier in tiers {
+            let threshold = tier["threshold"].as_i64().unwrap_or(0);
+            if base >= threshold && threshold > best_threshold {
+                best_threshold = threshold;
+                percent = tier["percent"].as_i64().unwrap_or(0);
+            }
+        }
+    }
+    percent
+}
+
+// Discount (positive cents) for an amount using a calculator, never more than the amount
+fn calculate(calc_type: &str, prefs: &Value, base: i64, quantity: i64) -> Result<i64, String> {
+    let discount = match calc_type {
+        "FlatRate" => prefs["amount"].as_i64().unwrap_or(0) * quantity,
+        "PercentOff" => base * prefs["percent"].as_i64().unwrap_or(0) / 100,
+        "TieredPercent" => base * tiered_percent(prefs, base) / 100,
+        // Reject unknown calculator types
+        other => return Err(format!("Unknown promotion calculator type: '{}'", other)),
+    };
+    Ok(discount.clamp(0, base.max(0)))
+}
+
+/// Compute the adjustments a promotion action gives the order
+pub fn compute_action(action_type: &str, calc_type: &str, prefs: &Value, order: &PromoOrder) -> Result<Vec<PromoAdjustment>, String> {
+    let mut adjustments = Vec::new();
+
+    match action_type {
+        "CreateAdjustment" => {
+            // Whole-order discount; FlatRate is applied once
+            let discount = calculate(calc_type, prefs, order.item_total, 1)?;
+            if discount != 0 {
+                adjustments.push(PromoAdjustment::Order { amount: -discount });
+            }
+        },
+        "CreateItemAdjustments" => {
+            // Per line item; FlatRate is an amount off each unit
+            for line in order.lines.iter().filter(|l| line_eligible(l, prefs)) {
+                let discount = calculate(calc_type, prefs, line.price * line.quantity, line.quantity)?;
+                if discount != 0 {
+                    adjustments.push(PromoAdjustment::LineItem { line_item_id: line.line_item_id, amount: -discount });
+                }
+            }
+        },
+        "FreeShipping" => {
+            for (shipment_id, cost) in &order.shipments {
+                if *cost > 0 {
+                    adjustments.push(PromoAdjustment::Shipment { shipment_id: *shipment_id, amount: -cost });
+                }
+            }
+        },
+        "BuyXGetY" => {
+            let buy = prefs["buy_quantity"].as_i64().unwrap_or(1);
+            let get = prefs["get_quantity"].as_i64().unwrap_or(1);
+            let percent = prefs["percent"].as_i64().unwrap_or(100).clamp(0, 100);
+            if buy < 1 || get < 1 {
+                return Err("BuyXGetY requires buy_quantity and get_quantity of at least 1".to_string());
+            }
+
+            // Expand eligible lines into units, most expensive first
+            let mut units: Vec<(i64, i64)> = order.lines.iter()
+                .filter(|l| line_eligible(l, prefs))
+                .flat_map(|l| std::iter::repeat_n((l.line_item_id, l.price), l.quantity.max(0) as usize))
+                .collect();
+            units.sort_by_key(|u| std::cmp::Reverse(u.1));
+
+            let mut groups = units.len() as i64 / (buy + get);
+            if let Some(max) = prefs["max_applications"].as_i64() {
+                groups = groups.min(max.max(0));
+            }
+            let free_count = (groups * get) as usize;
+
+            // The cheapest units are the discounted ones
+            let mut per_line: Vec<(i64, i64)> = Vec::new();
+            for (line_item_id, price) in units.iter().rev().take(free_count) {
+                let discount = price * percent / 100;
+                match per_line.iter_mut().find(|(id, _)| id == line_item_id) {
+                    Some(entry) => entry.1 += discount,
+                    None => per_line.push((*line_item_id, discount)),
+                }
+            }
+            for (line_item_id, discount) in per_line {
+                if discount != 0 {
+                    adjustments.push(PromoAdjustment::LineItem { line_item_id, amount: -discount });
+                }
+            }
+        },
+        unknown => {
+            // Reject unknown action types
+            return Err(format!("Unknown promotion action type: '{}'", unknown));
+        }
+    }
+
+    Ok(adjustments)
+}
+
+/// Best-promotion-wins: only the promotion with the largest total discount
+/// applies, whatever it adjusts. Ties go to the promotion listed first. Its
+/// adjustments are trimmed so they never take more than the items and shipping.
+pub fn best_adjustments(candidates: Vec<(i64, PromoAdjustment)>, order: &PromoOrder) -> Vec<(i64, PromoAdjustment)> {
+    let mut totals: Vec<(i64, i64)> = Vec::new();
+    for (promo_id, adjustment) in &candidates {
+        match totals.iter_mut().find(|(id, _)| id == promo_id) {
+            Some(entry) => entry.1 += adjustment.amount(),
+            None => totals.push((*promo_id, adjustment.amount())),
+        }
+    }
+    let Some(&(winner, _)) = totals.iter().min_by_key(|(_, total)| *total) else {
+        return Vec::new();
+    };
+
+    let mut remaining = order.item_total + order.shipments.iter().map(|(_, cost)| cost).sum::<i64>();
+    let mut best = Vec::new();
+    for (promo_id, adjustment) in candidates.into_iter().filter(|(id, _)| *id == winner) {
+        let amount = adjustment.amount().max(-remaining.max(0));
+        remaining += amount;
+        if amount != 0 {
+            best.push((promo_id, adjustment.with_amount(amount)));
+        }
+    }
+    best
+}
+
+/// Validate a rule before it is stored
+pub fn validate_rule(rule_type: &str, preferences: &str) -> Result<(), String> {
+    if !RULE_TYPES.contains(&rule_type) {
+        return Err(format!("Unknown promotion rule type: '{}'", rule_type));
+    }
+    let prefs: Value = serde_json::from_str(preferences).map_err(|e| format!("Invalid preferences JSON: {}", e))?;
+    if let Some(policy) = prefs["match_policy"].as_str() {
+        if !["any", "all", "none"].contains(&policy) {
+            return Err(format!("Unknown match policy: '{}'", policy));
+        }
+    }
+    Ok(())
+}
+
+/// Validate an action before it is stored
+pub fn validate_action(action_type: &str, calc_type: &str, preferences: &str) -> Result<(), String> {
+    if !ACTION_TYPES.contains(&action_type) {
+        return Err(format!("Unknown promotion action type: '{}'", action_type));
+    }
+    let needs_calculator = action_type == "CreateAdjustment" || action_type == "CreateItemAdjustments";
+    if needs_calculator && !CALCULATOR_TYPES.contains(&calc_type) {
+        return Err(format!("Unknown promotion calculator type: '{}'", calc_type));
+    }
+    let prefs: Value = serde_json::from_str(preferences).map_err(|e| format!("Invalid preferences JSON: {}", e))?;
+    if action_type == "BuyXGetY" {
+        // Run against an empty order to surface preference errors early
+        compute_action(action_type, calc_type, &prefs, &PromoOrder::default())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn line(line_item_id: i64, product_id: i64, taxon_ids: Vec<i64>, quantity: i64, price: i64) -> PromoLine {
+        PromoLine { line_item_id, product_id, taxon_ids, quantity, price }
+    }
+
+    fn order() -> PromoOrder {
+        let lines = vec![
+            line(1, 10, vec![100, 1], 2, 1000),  // 2 x $10.00
+            line(2, 20, vec![200, 1], 1, 2500),  // 1 x $25.00
+        ];
+        PromoOrder {
+            item_total: 4500,
+            lines,
+            user_id: Some(7),
+            user_roles: vec!["customer".to_string()],
+            completed_orders: 0,
+            ship_country: Some("US".to_string()),
+            shipments: vec![(50, 800)],
+        }
+    }
+
+    #[test]
+    fn item_total_rule() {
+        let o = order();
+        assert!(rule_eligible("ItemTotal", &json!({"amount": 4500}), &o).unwrap());
+        assert!(!rule_eligible("ItemTotal", &json!({"amount": 4501}), &o).unwrap());
+    }
+
+    #[test]
+    fn first_order_rule() {
+        let mut o = order();
+        assert!(rule_eligible("FirstOrder", &json!({}), &o).unwrap());
+        o.completed_orders = 1;
+        assert!(!rule_eligible("FirstOrder", &json!({}), &o).unwrap());
+        o.completed_orders = 0;
+        o.user_id = None;
+        assert!(!rule_eligible("FirstOrder", &json!({}), &o).unwrap());
+    }
+
+    #[test]
+    fn product_rule_policies() {
+        let o = order();
+        assert!(rule_eligible("Product", &json!({"product_ids": [10, 99]}), &o).unwrap());
+        assert!(!rule_eligible("Product", &json!({"product_ids": [10, 99], "match_policy": "all"}), &o).unwrap());
+        assert!(rule_eligible("Product", &json!({"product_ids": [10, 20], "match_policy": "all"}), &o).unwrap());
+        assert!(!rule_eligible("Product", &json!({"product_ids": [20], "match_policy": "none"}), &o).unwrap());
+        assert!(rule_eligible("Product", &json!({"product_ids": [99], "match_policy": "none"}), &o).unwrap());
+        assert!(rule_eligible("Product", &json!({"product_ids": [10], "match_policy": "most"}), &o).is_err());
+    }
+
+    #[test]
+    fn taxon_rule_matches_ancestors() {
+        let o = order();
+        assert!(rule_eligible("Taxon", &json!({"taxon_ids": [1]}), &o).unwrap());
+        assert!(rule_eligible("Taxon", &json!({"taxon_ids": [100, 200], "match_policy": "all"}), &o).unwrap());
+        assert!(!rule_eligible("Taxon", &json!({"taxon_ids": [300]}), &o).unwrap());
+    }
+
+    #[test]
+    fn user_rule() {
+        let mut o = order();
+        assert!(rule_eligible("User", &json!({"user_ids": [7, 8]}), &o).unwrap());
+        assert!(!rule_eligible("User", &json!({"user_ids": [8]}), &o).unwrap());
+        o.user_id = None;
+        assert!(!rule_eligible("User", &json!({"user_ids": [7]}), &o).unwrap());
+    }
+
+    #[test]
+    fn user_role_rule() {
+        let mut o = order();
+        o.user_roles.push("wholesale".to_string());
+        assert!(rule_eligible("UserRole", &json!({"roles": ["wholesale"]}), &o).unwrap());
+        assert!(!rule_eligible("UserRole", &json!({"roles": ["wholesale", "vip"], "match_policy": "all"}), &o).unwrap());
+        o.user_id = None;
+        assert!(!rule_eligible("UserRole", &json!({"roles": ["wholesale"]}), &o).unwrap());
+    }
+
+    #[test]
+    fn quantity_rule() {
+        let o = order();
+        assert!(rule_eligible("Quantity", &json!({"min_quantity": 3}), &o).unwrap());
+        assert!(!rule_eligible("Quantity", &json!({"min_quantity": 4}), &o).unwrap());
+        // Scoped to a product
+        assert!(rule_eligible("Quantity", &json!({"min_quantity": 2, "product_ids": [10]}), &o).unwrap());
+        assert!(!rule_eligible("Quantity", &json!({"min_quantity": 2, "product_ids": [20]}), &o).unwrap());
+    }
+
+    #[test]
+    fn nth_order_rule() {
+        let mut o = order();
+        o.completed_orders = 2;
+        assert!(rule_eligible("NthOrder", &json!({"nth": 3}), &o).unwrap());
+        assert!(!rule_eligible("NthOrder", &json!({"nth": 2}), &o).unwrap());
+        o.user_id = None;
+        assert!(!rule_eligible("NthOrder", &json!({"nth": 3}), &o).unwrap());
+    }
+
+    #[test]
+    fn ship_to_country_rule() {
+        let mut o = order();
+        assert!(rule_eligible("ShipToCountry", &json!({"country_codes": ["us", "CA"]}), &o).unwrap());
+        assert!(!rule_eligible("ShipToCountry", &json!({"country_codes": ["CA"]}), &o).unwrap());
+        o.ship_country = None;
+        assert!(!rule_eligible("ShipToCountry", &json!({"country_codes": ["US"]}), &o).unwrap());
+    }
+
+    #[test]
+    fn unknown_rule_is_rejected() {
+        assert!(rule_eligible("Bogus", &json!({}), &order()).is_err());
+    }
+
+    #[test]
+    fn order_adjustment_calculators() {
+        let o = order();
+        assert_eq!(
+            compute_action("CreateAdjustment", "FlatRate", &json!({"amount": 500}), &o).unwrap(),
+            vec![PromoAdjustment::Order { amount: -500 }]
+        );
+        assert_eq!(
+            compute_action("CreateAdjustment", "PercentOff", &json!({"percent": 10}), &o).unwrap(),
+            vec![PromoAdjustment::Order { amount: -450 }]
+        );
+        // Flat rate never exceeds the item total
+        assert_eq!(
+            compute_action("CreateAdjustment", "FlatRate", &json!({"amount": 10000}), &o).unwrap(),
+            vec![PromoAdjustment::Order { amount: -4500 }]
+        );
+        assert!(compute_action("CreateAdjustment", "Bogus", &json!({}), &o).is_err());
+    }
+
+    #[test]
+    fn tiered_percent_calculator() {
+        let o = order();
+        let prefs = json!({"base_percent": 5, "tiers": [{"threshold": 4000, "percent": 10}, {"threshold": 10000, "percent": 20}]});
+        assert_eq!(
+            compute_action("CreateAdjustment", "TieredPercent", &prefs, &o).unwrap(),
+            vec![PromoAdjustment::Order { amount: -450 }]
+        );
+        let mut small = order();
+        small.item_total = 1000;
+        assert_eq!(
+            compute_action("CreateAdjustment", "TieredPercent", &prefs, &small).unwrap(),
+            vec![PromoAdjustment::Order { amount: -50 }]
+        );
+    }
+
+    #[test]
+    fn line_item_adjustments() {
+        let o = order();
+        assert_eq!(
+            compute_action("CreateItemAdjustments", "PercentOff", &json!({"percent": 50, "product_ids": [10]}), &o).unwrap(),
+            vec![PromoAdjustment::LineItem { line_item_id: 1, amount: -1000 }]
+        );
+        // Flat rate is per unit
+        assert_eq!(
+            compute_action("CreateItemAdjustments", "FlatRate", &json!({"amount": 100}), &o).unwrap(),
+            vec![
+                PromoAdjustment::LineItem { line_item_id: 1, amount: -200 },
+                PromoAdjustment::LineItem { line_item_id: 2, amount: -100 },
+            ]
+        );
+        assert_eq!(
+            compute_action("CreateItemAdjustments", "PercentOff", &json!({"percent": 10, "taxon_ids": [200]}), &o).unwrap(),
+            vec![PromoAdjustment::LineItem { line_item_id: 2, amount: -250 }]
+        );
+    }
+
+    #[test]
+    fn free_shipping() {
+        let mut o = order();
+        assert_eq!(
+            compute_action("FreeShipping", "", &json!({}), &o).unwrap(),
+            vec![PromoAdjustment::Shipment { shipment_id: 50, amount: -800 }]
+        );
+        o.shipments = vec![(50, 0)];
+        assert!(compute_action("FreeShipping", "", &json!({}), &o).unwrap().is_empty());
+    }
+
+    #[test]
+    fn buy_x_get_y_discounts_cheapest_units() {
+        let o = order();
+        // 3 units: 2500, 1000, 1000 -> buy 2 get 1 frees one $10 unit
+        assert_eq!(
+            compute_action("BuyXGetY", "", &json!({"buy_quantity": 2, "get_quantity": 1}), &o).unwrap(),
+            vec![PromoAdjustment::LineItem { line_item_id: 1, amount: -1000 }]
+        );
+        // Buy 1 get 1 at 50% off
+        assert_eq!(
+            compute_action("BuyXGetY", "", &json!({"buy_quantity": 1, "get_quantity": 1, "percent": 50}), &o).unwrap(),
+            vec![PromoAdjustment::LineItem { line_item_id: 1, amount: -500 }]
+        );
+        // Not enough units
+        assert!(compute_action("BuyXGetY", "", &json!({"buy_quantity": 3, "get_quantity": 1}), &o).unwrap().is_empty());
+        // Scoped to product 10 only (2 units)
+        assert_eq!(
+            compute_action("BuyXGetY", "", &json!({"buy_quantity": 1, "get_quantity": 1, "product_ids": [10]}), &o).unwrap(),
+            vec![PromoAdjustment::LineItem { line_item_id: 1, amount: -1000 }]
+        );
+        assert!(compute_action("BuyXGetY", "", &json!({"buy_quantity": 0}), &o).is_err());
+    }
+
+    #[test]
+    fn buy_x_get_y_respects_max_applications() {
+        let mut o = order();
+        o.lines = vec![line(1, 10, vec![], 6, 1000)];
+        let prefs = json!({"buy_quantity": 1, "get_quantity": 1, "max_applications": 2});
+        assert_eq!(
+            compute_action("BuyXGetY", "", &prefs, &o).unwrap(),
+            vec![PromoAdjustment::LineItem { line_item_id: 1, amount: -2000 }]
+        );
+    }
+
+    #[test]
+    fn unknown_action_is_rejected() {
+        assert!(compute_action("Bogus", "", &json!({}), &order()).is_err());
+    }
+
+    #[test]
+    fn best_promotion_wins_per_order() {
+        // Promotion 2's line item and shipment discounts beat promotion 1's order discount
+        let candidates = vec![
+            (1, PromoAdjustment::Order { amount: -500 }),
+            (2, PromoAdjustment::LineItem { line_item_id: 1, amount: -300 }),
+            (2, PromoAdjustment::Shipment { shipment_id: 50, amount: -400 }),
+            (3, PromoAdjustment::Order { amount: -700 }),
+        ];
+        assert_eq!(
+            best_adjustments(candidates, &order()),
+            vec![
+                (2, PromoAdjustment::LineItem { line_item_id: 1, amount: -300 }),
+                (2, PromoAdjustment::Shipment { shipment_id: 50, amount: -400 }),
+            ]
+        );
+        assert!(best_adjustments(Vec::new(), &order()).is_empty());
+    }
+
+    #[test]
+    fn best_promotion_is_capped_at_order_total() {
+        let mut small = order();
+        small.item_total = 1000;
+        small.shipments = vec![(50, 500)];
+        let candidates = vec![
+            (1, PromoAdjustment::Order { amount: -1200 }),
+            (1, PromoAdjustment::Shipment { shipment_id: 50, amount: -500 }),
+            (1, PromoAdjustment::LineItem { line_item_id: 1, amount: -100 }),
+        ];
+        assert_eq!(
+            best_adjustments(candidates, &small),
+            vec![
+                (1, PromoAdjustment::Order { amount: -1200 }),
+                (1, PromoAdjustment::Shipment { shipment_id: 50, amount: -300 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn validation() {
+        assert!(validate_rule("Product", r#"{"product_ids": [1]}"#).is_ok());
+        assert!(validate_rule("Product", "not json").is_err());
+        assert!(validate_rule("Product", r#"{"match_policy": "most"}"#).is_err());
+        assert!(validate_rule("Bogus", "{}").is_err());
+        assert!(validate_action("CreateAdjustment", "PercentOff", r#"{"percent": 10}"#).is_ok());
+        assert!(validate_action("CreateAdjustment", "Bogus", "{}").is_err());
+        assert!(validate_action("FreeShipping", "", "{}").is_ok());
+        assert!(validate_action("BuyXGetY", "", r#"{"buy_quantity": 0}"#).is_err());
+        assert!(validate_action("Bogus", "", "{}").is_err());
+    }
+}
@@ -83,6 +83,58 @@ pub struct CustomerQueryParams {
     pub per_page: Option<i64>,
 }
 
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CustomerMetricsQuery {
+    pub q: Option<String>,        // search by email
+    pub segment: Option<String>,  // RFM segment, e.g. "champions", "at_risk"
+    pub sort: Option<String>,     // ltv, orders, aov, recent, first
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct CustomerMetrics {
+    pub email: String,
+    pub customer_type: String,  // "registered" or "guest"
+    pub first_order_at: i64,
+    pub last_order_at: i64,
+    pub order_count: i64,
+    pub lifetime_value: i64,  // order totals less refunds
+    pub average_order_value: i64,
+    pub refunded: i64,
+    pub refund_rate: f64,  // refunded / order totals
+    pub favourite_taxon: Option<String>,
+    pub recency_score: i64,    // 1-5
+    pub frequency_score: i64,  // 1-5
+    pub monetary_score: i64,   // 1-5
+    pub rfm_segment: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct RfmSegmentCount {
+    pub segment: String,
+    pub customers: i64,
+    pub lifetime_value: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CustomerMetricsResponse {
+    pub customers: Vec<CustomerMetrics>,
+    pub total_count: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub total_pages: i64,
+    pub segments: Vec<RfmSegmentCount>,  // every customer, regardless of filters
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct CustomerCohort {
+    pub cohort: String,  // YYYY-MM of the first order
+    pub customers: i64,
+    pub retained: Vec<i64>,  // customers ordering k months after acquisition
+    pub retention_rates: Vec<f64>,
+}
+
 // ============================================
 // PRODUCTS
 // ============================================
@@ -91,7 +143,7 @@ pub struct CustomerQueryParams {
 pub struct ProductQueryParams {
     pub q: Option<String>,           // search query
     pub taxon_id: Option<i64>,       // category filter
-    pub sort: Option<String>,        // price_asc, price_desc, name_asc, name_desc, created_at
+    pub sort: Option<String>,        // price_asc, price_desc, name_asc, name_desc, rating, created_at
     pub page: Option<i64>,
     pub per_page: Option<i64>,
     pub in_stock: Option<bool>,
@@ -107,9 +159,11 @@ pub struct ProductSummary {
     pub stock: i64,
     pub image_url: Option<String>,
     pub available: bool,
+    pub rating_average: f64,         // approved reviews, 0 when none
+    pub rating_count: i64,
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct ProductListResponse {
     pub products: Vec<ProductSummary>,
     pub total_count: i64,
@@ -129,15 +183,52 @@ pub struct ProductDetail {
     pub available_on: Option<i64>,
     pub discontinue_on: Option<i64>,
     pub promotionable: bool,
+    pub gift_card: bool,
     pub price: i64,
     pub variants: Vec<VariantDetail>,
     pub images: Vec<ProductImage>,
     pub taxons: Vec<TaxonRef>,
     pub properties: Vec<ProductProperty>,
+    pub rating_average: f64,
+    pub rating_count: i64,
+    pub bundle: Option<ProductBundle>,
     pub created_at: i64,
     pub updated_at: i64,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ProductBundle {
+    pub pricing: String,  // fixed or components
+    pub discount_percent: i64,
+    pub components: Vec<BundleComponent>,
+    pub components_total: i64,  // sum of component prices x quantities
+    pub stock: i64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct BundleComponent {
+    pub variant_id: i64,
+    pub product_id: i64,
+    pub product_name: String,
+    pub sku: String,
+    pub quantity: i64,
+    pub price: i64,
+    pub stock: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BundleComponentInput {
+    pub variant_id: i64,
+    pub quantity: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SetBundleInput {
+    pub components: Vec<BundleComponentInput>,  // empty = no longer a bundle
+    pub pricing: Option<String>,
+    pub discount_percent: Option<i64>,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct VariantDetail {
     pub id: i64,
@@ -202,6 +293,7 @@ pub struct CreateProductInput {
     pub backorderable: Option<bool>,
     pub available_on: Option<i64>,
     pub promotionable: Option<bool>,
+    pub gift_card: Option<bool>,
     pub image_url: Option<String>,
     pub taxon_ids: Option<Vec<i64>>,
 }
@@ -217,9 +309,90 @@ pub struct UpdateProductInput {
     pub stock: Option<i64>,
     pub available_on: Option<i64>,
     pub discontinue_on: Option<i64>,
+    pub gift_card: Option<bool>,
     pub taxon_ids: Option<Vec<i64>>,
 }
 
+// ============================================
+// PRODUCT REVIEWS
+// ============================================
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ProductReview {
+    pub id: i64,
+    pub product_id: i64,
+    pub product_name: String,
+    pub user_id: i64,
+    pub rating: i64,  // 1-5
+    pub title: Option<String>,
+    pub body: Option<String>,
+    pub name: String,  // author name shown with the review
+    pub verified_purchase: bool,
+    pub state: String,  // pending, approved, rejected
+    pub moderation_note: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+// A review as shown on the storefront, without the author's account or moderation details
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PublicProductReview {
+    pub id: i64,
+    pub product_id: i64,
+    pub product_name: String,
+    pub rating: i64,
+    pub title: Option<String>,
+    pub body: Option<String>,
+    pub name: String,
+    pub verified_purchase: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SubmitReviewInput {
+    pub product_id: i64,
+    pub rating: i64,
+    pub title: Option<String>,
+    pub body: Option<String>,
+    pub name: Option<String>,  // defaults to first name and last initial
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ReviewQueryParams {
+    pub product_id: Option<i64>,
+    pub state: Option<String>,  // admin only
+    pub rating: Option<i64>,
+    pub verified_only: Option<bool>,
+    pub sort: Option<String>,   // newest, oldest, rating_desc, rating_asc
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ProductReviewListResponse {
+    pub reviews: Vec<ProductReview>,
+    pub total_count: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub total_pages: i64,
+    pub rating_average: f64,  // approved reviews in the product scope
+    pub rating_count: i64,
+    pub rating_breakdown: Vec<i64>,  // counts of 1..5 star reviews
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PublicReviewListResponse {
+    pub reviews: Vec<PublicProductReview>,
+    pub total_count: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub total_pages: i64,
+    pub rating_average: f64,
+    pub rating_count: i64,
+    pub rating_breakdown: Vec<i64>,
+}
+
 // ============================================
 // TAXONOMIES & TAXONS
 // ============================================
@@ -284,7 +457,8 @@ pub struct AddressDetail {
     pub zipcode: String,
     pub country_code: String,
     pub phone: Option<String>,
-    pub is_default: bool,
+    pub is_default: bool,          // default shipping address
+    pub is_default_billing: bool,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -298,21 +472,98 @@ pub struct AddressInput {
     pub zipcode: String,
     pub country_code: Option<String>,
     pub phone: Option<String>,
-    pub is_default: Option<bool>,
+    pub is_default: Option<bool>,  // default shipping address
+    pub is_default_billing: Option<bool>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct SetAddressInput {
     pub email: String,
-    pub shipping: AddressInput,
+    pub shipping: Option<AddressInput>,
     pub billing: Option<AddressInput>,
     pub use_shipping_for_billing: Option<bool>,
+    // Saved addresses from the address book (signed-in users), instead of shipping/billing
+    pub ship_address_id: Option<i64>,
+    pub bill_address_id: Option<i64>,
+}
+
+// ============================================
+// WISHLISTS
+// ============================================
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct WishlistItem {
+    pub id: i64,
+    pub variant_id: i64,
+    pub product_id: i64,
+    pub product_name: String,
+    pub product_slug: String,
+    pub sku: String,
+    pub price: i64,
+    pub image_url: Option<String>,
+    pub in_stock: bool,
+    pub available: bool,  // false once the product or variant is deleted
+    pub quantity: i64,
+    pub notify_in_stock: bool,
+    pub added_at: i64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Wishlist {
+    pub id: i64,
+    pub name: String,
+    pub is_public: bool,
+    pub share_token: Option<String>,  // owner only
+    pub items: Vec<WishlistItem>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CreateWishlistInput {
+    pub name: String,
+    pub is_public: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UpdateWishlistInput {
+    pub name: Option<String>,
+    pub is_public: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AddToWishlistInput {
+    pub variant_id: i64,
+    pub wishlist_id: Option<i64>,  // defaults to the caller's first wishlist
+    pub quantity: Option<i64>,
+    pub notify_in_stock: Option<bool>,
+}
+
+// Back-in-stock request for a variant; state is active, notified or canceled
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct StockSubscription {
+    pub id: i64,
+    pub variant_id: i64,
+    pub product_name: String,
+    pub sku: String,
+    pub email: String,
+    pub state: String,  // pending (awaiting email confirmation), active, notified, canceled
+    pub notified_at: Option<i64>,
+    pub created_at: i64,
 }
 
 // ============================================
 // ORDERS
 // ============================================
 
+// Guest lookup: order number plus the order email or the access token from checkout
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GuestOrderLookupInput {
+    pub number: String,
+    pub email: Option<String>,
+    pub access_token: Option<String>,
+}
+
 #[derive(CandidType, Deserialize, Clone, Default)]
 pub struct OrderQueryParams {
     pub state: Option<String>,
@@ -382,6 +633,10 @@ pub struct OrderDetail {
     pub bill_address: Option<AddressDetail>,
     pub shipment: Option<ShipmentDetail>,
     pub payments: Vec<PaymentDetail>,
+    pub gift_card_total: i64,  // gift card balance applied to the order
+    pub amount_due: i64,       // total minus gift card payments
+    pub disputed: bool,        // a Stripe dispute has been opened on this order
+    pub access_token: Option<String>,  // guest order lookup token, returned to guests when the order is paid
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -426,6 +681,7 @@ pub struct PaymentDetail {
     pub amount: i64,
     pub state: String,
     pub payment_method_name: String,
+    pub authorization_expires_at: Option<i64>,  // set while a Stripe authorization awaits capture
     pub created_at: i64,
 }
 
@@ -482,6 +738,59 @@ pub struct ShippingRate {
     pub delivery_estimate: Option<String>,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PackingSlipLine {
+    pub variant_id: i64,
+    pub line_item_id: Option<i64>,
+    pub sku: Option<String>,
+    pub product_name: String,
+    pub quantity: i64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PackingSlip {
+    pub shipment_id: i64,
+    pub shipment_number: String,
+    pub shipment_state: String,
+    pub order_number: String,
+    pub order_email: Option<String>,
+    pub tracking: Option<String>,
+    pub label_url: Option<String>,
+    pub shipping_method_name: Option<String>,
+    pub ship_address: Option<AddressDetail>,
+    pub stock_location: Option<StockLocation>,
+    pub lines: Vec<PackingSlipLine>,
+    pub total_quantity: i64,
+    pub generated_at: i64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ShippingLabel {
+    pub shipment_id: i64,
+    pub tracking: String,
+    pub label_url: Option<String>,
+    pub carrier: Option<String>,
+    pub service_level: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct CarrierSettings {
+    pub provider: String,
+    pub api_url: String,
+    pub api_key: String,
+    pub label_format: String,
+    pub active: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UpdateCarrierSettingsInput {
+    pub provider: String,
+    pub api_url: String,
+    pub api_key: String,
+    pub label_format: String,
+    pub active: bool,
+}
+
 // ============================================
 // STOCK MANAGEMENT
 // ============================================
@@ -692,8 +1001,11 @@ pub struct Promotion {
     pub starts_at: Option<i64>,
     pub expires_at: Option<i64>,
     pub usage_limit: Option<i64>,
+    pub per_code_usage_limit: Option<i64>,
     pub usage_count: i64,
+    pub code_count: i64,
     pub active: bool,
+    pub apply_automatically: bool,  // code-less, applied whenever the rules match
     pub rules: Vec<PromotionRule>,
     pub actions: Vec<PromotionAction>,
 }
@@ -701,18 +1013,29 @@ pub struct Promotion {
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct PromotionRule {
     pub id: i64,
-    pub rule_type: String,  // ItemTotal, Product, User, FirstOrder
+    pub rule_type: String,  // ItemTotal, FirstOrder, Product, Taxon, User, UserRole, Quantity, NthOrder, ShipToCountry
     pub preferences: String,  // JSON
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct PromotionAction {
     pub id: i64,
-    pub action_type: String,  // CreateAdjustment, CreateItemAdjustment, FreeShipping
-    pub calculator_type: String,  // FlatRate, PercentOff, FreeShipping
+    pub action_type: String,  // CreateAdjustment, CreateItemAdjustments, FreeShipping, BuyXGetY
+    pub calculator_type: String,  // FlatRate, PercentOff, TieredPercent
     pub preferences: String,  // JSON with amount/percent
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PromotionCode {
+    pub id: i64,
+    pub value: String,
+    pub usage_count: i64,  // completed orders that used this code
+    pub usage_limit: Option<i64>,  // promotions.per_code_usage_limit
+    pub status: String,  // unused, redeemed, exhausted
+    pub last_used_at: Option<i64>,
+    pub created_at: i64,
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 pub struct CreatePromotionInput {
     pub name: String,
@@ -721,7 +1044,9 @@ pub struct CreatePromotionInput {
     pub starts_at: Option<i64>,
     pub expires_at: Option<i64>,
     pub usage_limit: Option<i64>,
+    pub per_code_usage_limit: Option<i64>,
     pub active: Option<bool>,
+    pub apply_automatically: Option<bool>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -732,7 +1057,9 @@ pub struct UpdatePromotionInput {
     pub starts_at: Option<i64>,
     pub expires_at: Option<i64>,
     pub usage_limit: Option<i64>,
+    pub per_code_usage_limit: Option<i64>,
     pub active: Option<bool>,
+    pub apply_automatically: Option<bool>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -755,20 +1082,121 @@ pub struct ApplyCouponInput {
     pub code: String,
 }
 
+// ============================================
+// ABANDONED CARTS
+// ============================================
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct AbandonedCartQuery {
+    pub hours: Option<i64>,  // idle for at least this long; defaults to cart_recovery_after_hours
+    pub days: Option<i64>,   // window for the recovery stats (default 30)
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AbandonedCart {
+    pub order_id: i64,
+    pub number: String,
+    pub email: String,
+    pub state: String,
+    pub customer_type: String,  // "registered" or "guest"
+    pub item_count: i64,
+    pub total: i64,
+    pub updated_at: i64,
+    pub emails_sent: i64,
+    pub last_email_at: Option<i64>,
+    pub restored_at: Option<i64>,
+    pub promotion_code: Option<String>,
+}
+
+// Recovery sequences started in the stats window
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CartRecoveryStats {
+    pub carts_emailed: i64,
+    pub emails_sent: i64,
+    pub carts_restored: i64,
+    pub orders_recovered: i64,
+    pub revenue_recovered: i64,
+    pub codes_redeemed: i64,
+    pub recovery_rate: f64,  // orders_recovered / carts_emailed
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AbandonedCartListResponse {
+    pub carts: Vec<AbandonedCart>,
+    pub total_count: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub total_pages: i64,
+    pub stats: CartRecoveryStats,
+}
+
+// ============================================
+// GIFT CARDS
+// ============================================
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct GiftCard {
+    pub id: i64,
+    pub code: Option<String>,  // None until activated
+    pub initial_amount: i64,
+    pub balance: i64,
+    pub currency: String,
+    pub state: String,  // pending, active, depleted, disabled
+    pub order_number: Option<String>,
+    pub purchaser_email: Option<String>,
+    pub recipient_email: Option<String>,
+    pub recipient_name: Option<String>,
+    pub expires_at: Option<i64>,
+    pub sent_at: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct GiftCardBalance {
+    pub balance: i64,
+    pub currency: String,
+    pub state: String,
+    pub expires_at: Option<i64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct GiftCardTransaction {
+    pub id: i64,
+    pub order_number: Option<String>,
+    pub action: String,  // issue, redeem, refund, adjust
+    pub amount: i64,     // negative for redemptions
+    pub balance_after: i64,
+    pub created_at: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ApplyGiftCardInput {
+    pub code: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GiftCardRecipientInput {
+    pub email: String,
+    pub name: Option<String>,
+    pub message: Option<String>,
+}
+
 // ============================================
 // REFUNDS
 // ============================================
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
-#[allow(dead_code)]
 pub struct Refund {
     pub id: i64,
     pub payment_id: i64,
     pub order_number: String,
     pub amount: i64,
     pub reason: Option<String>,
-    pub state: String,
-    pub transaction_id: Option<String>,
+    pub state: String,  // pending, requires_action, succeeded, failed, canceled
+    pub transaction_id: Option<String>,  // Stripe refund id
+    pub failure_reason: Option<String>,
     pub created_at: i64,
 }
 
@@ -831,6 +1259,170 @@ pub struct UpdatePaymentMethodInput {
     pub test_mode: Option<bool>,
 }
 
+// Stripe dispute (chargeback or inquiry) on a payment
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Dispute {
+    pub id: i64,
+    pub stripe_dispute_id: String,
+    pub payment_id: Option<i64>,
+    pub order_id: Option<i64>,
+    pub order_number: Option<String>,
+    pub amount: i64,
+    pub currency: String,
+    pub reason: Option<String>,
+    pub status: String,
+    pub evidence_due_by: Option<i64>,
+    pub closed_at: Option<i64>,
+    pub created_at: i64,
+}
+
+// Stripe payment authorized at checkout and not yet captured
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PendingAuthorization {
+    pub payment_id: i64,
+    pub order_id: i64,
+    pub order_number: String,
+    pub amount: i64,
+    pub authorized_at: i64,
+    pub expires_at: Option<i64>,
+}
+
+// Stored Stripe event; payload is only filled in by the single-event lookup
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct WebhookEvent {
+    pub id: i64,
+    pub stripe_event_id: String,
+    pub event_type: String,
+    pub processed: bool,
+    pub source: String,  // webhook, reconcile
+    pub result: Option<String>,
+    pub error: Option<String>,
+    pub attempts: i64,
+    pub stripe_created_at: Option<i64>,
+    pub processed_at: Option<i64>,
+    pub created_at: i64,
+    pub payload: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct WebhookEventQueryParams {
+    pub status: Option<String>,  // processed, failed, pending
+    pub event_type: Option<String>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WebhookEventListResponse {
+    pub events: Vec<WebhookEvent>,
+    pub total_count: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub total_pages: i64,
+}
+
+// Background job run from the canister global timer
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ScheduledJob {
+    pub name: String,
+    pub interval_seconds: i64,
+    pub enabled: bool,
+    pub next_run_at: i64,
+    pub last_run_at: Option<i64>,
+    pub last_result: Option<String>,
+    pub last_error: Option<String>,
+}
+
+// One category of the data retention policy; removed is 0 for a dry run
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RetentionResult {
+    pub category: String,  // payment_intents, carts, addresses, log_entries
+    pub retention_days: i64,  // 0 = kept forever
+    pub action: String,  // purge, archive, keep
+    pub eligible: i64,
+    pub removed: i64,
+    pub oldest_at: Option<i64>,
+}
+
+// Card saved on the user's Stripe customer (no card number or Stripe ids exposed)
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct SavedCard {
+    pub id: i64,
+    pub card_brand: Option<String>,
+    pub card_last4: Option<String>,
+    pub card_exp_month: Option<i64>,
+    pub card_exp_year: Option<i64>,
+    pub created_at: i64,
+}
+
+// Result of charging a saved card; client_secret is set when Stripe needs customer action (3DS)
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct SavedCardPayment {
+    pub payment_intent_id: String,
+    pub status: String,
+    pub client_secret: Option<String>,
+}
+
+// ============================================
+// SUBSCRIPTION ORDERS
+// ============================================
+
+// Recurring order of one variant; state is active, paused, past_due or canceled
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct OrderSubscription {
+    pub id: i64,
+    pub user_id: i64,
+    pub variant_id: i64,
+    pub product_name: String,
+    pub sku: String,
+    pub quantity: i64,
+    pub price: i64,  // current unit price, cents
+    pub interval_days: i64,
+    pub next_run_at: i64,
+    pub payment_source_id: i64,
+    pub card_brand: Option<String>,
+    pub card_last4: Option<String>,
+    pub ship_address_id: Option<i64>,     // None = source order's address
+    pub shipping_method_id: Option<i64>,  // None = source order's shipping method
+    pub state: String,
+    pub failed_attempts: i64,
+    pub last_error: Option<String>,
+    pub last_order_number: Option<String>,
+    pub canceled_at: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CreateOrderSubscriptionInput {
+    pub line_item_id: i64,
+    pub interval_days: i64,
+    pub payment_source_id: i64,
+    pub quantity: Option<i64>,  // defaults to the line item's quantity
+    pub start_at: Option<i64>,  // first recurring order; defaults to one interval from now
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UpdateOrderSubscriptionInput {
+    pub quantity: Option<i64>,
+    pub interval_days: Option<i64>,
+    pub payment_source_id: Option<i64>,
+    pub ship_address_id: Option<i64>,
+    pub shipping_method_id: Option<i64>,
+    pub next_run_at: Option<i64>,
+}
+
+// One charge attempt (paid, failed) or skipped cycle
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct OrderSubscriptionRun {
+    pub id: i64,
+    pub subscription_id: i64,
+    pub order_number: Option<String>,
+    pub state: String,
+    pub amount: i64,
+    pub error: Option<String>,
+    pub created_at: i64,
+}
+
 // ============================================
 // DASHBOARD / ANALYTICS
 // ============================================
@@ -882,21 +1474,63 @@ pub struct RevenueData {
     pub recent_orders: Vec<RecentOrderSummary>,
 }
 
+// Sales per calendar bucket, with the preceding period of the same length for comparison
 #[derive(CandidType, Deserialize, Serialize, Clone)]
-#[allow(dead_code)]
 pub struct SalesReport {
     pub period: String,  // day, week, month
+    pub timezone: String,
+    pub from: String,  // YYYY-MM-DD, first day of the first bucket
+    pub to: String,    // YYYY-MM-DD, last day of the last bucket
     pub data: Vec<SalesDataPoint>,
-    pub total_revenue: i64,
-    pub total_orders: i64,
+    pub totals: SalesTotals,
+    pub previous_from: String,
+    pub previous_to: String,
+    pub previous_data: Vec<SalesDataPoint>,
+    pub previous_totals: SalesTotals,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
-#[allow(dead_code)]
 pub struct SalesDataPoint {
-    pub date: String,
+    pub date: String,  // bucket start: YYYY-MM-DD, or YYYY-MM for months
+    pub revenue: i64,
+    pub orders: i64,
+    pub units: i64,
+    pub discounts: i64,
+    pub tax: i64,
+    pub shipping: i64,
+    pub refunds: i64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct SalesTotals {
     pub revenue: i64,
     pub orders: i64,
+    pub units: i64,
+    pub discounts: i64,
+    pub tax: i64,
+    pub shipping: i64,
+    pub refunds: i64,
+    pub average_order_value: i64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FinancialExportInput {
+    pub report: String,  // orders, line_items, payments, refunds, tax, tax_summary, shipping
+    pub format: String,  // csv, json
+    pub from: String,    // YYYY-MM-DD
+    pub to: String,      // YYYY-MM-DD, inclusive
+    pub timezone: Option<String>,  // UTC offset; defaults to the store's timezone setting
+    pub cursor: Option<String>,    // next_cursor from the previous chunk
+    pub limit: Option<i64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FinancialExportChunk {
+    pub report: String,
+    pub format: String,
+    pub data: String,  // CSV rows (header on the first chunk only) or a JSON array
+    pub row_count: i64,
+    pub next_cursor: Option<String>,  // None once the range is exhausted
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -916,6 +1550,7 @@ pub struct EmailSettings {
     pub api_key: String,
     pub sender_email: String,
     pub active: bool,
+    pub notification_email: Option<String>,  // store notifications (disputes); defaults to sender_email
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -924,4 +1559,27 @@ pub struct UpdateEmailSettingsInput {
     pub api_key: String,
     pub sender_email: String,
     pub active: bool,
+    pub notification_email: Option<String>,  // store notifications (disputes); defaults to sender_email. None keeps the current one, "" clears it
+}
+
+// ============================================
+// HTTP GATEWAY
+// ============================================
+
+// Request/response shapes of the IC HTTP gateway interface
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>,  // true asks the gateway to resend the request as an update call
 }